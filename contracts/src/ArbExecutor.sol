@@ -0,0 +1,83 @@
+// SPDX-License-Identifier: MIT
+pragma solidity ^0.8.13;
+
+import "openzeppelin-contracts/token/ERC20/IERC20.sol";
+import "openzeppelin-contracts/token/ERC20/utils/SafeERC20.sol";
+
+import "./interface/IUniswapV2.sol";
+
+contract ArbExecutor {
+    // swaps its own balance through Uniswap V2 pairs directly, no router
+    // this is what the Rust strategy's build_arb_tx calls
+    using SafeERC20 for IERC20;
+
+    address public immutable owner;
+
+    constructor(address _owner) {
+        owner = _owner;
+    }
+
+    function recoverToken(address token) public {
+        require(msg.sender == owner, "not owner");
+        IERC20(token).safeTransfer(
+            msg.sender,
+            IERC20(token).balanceOf(address(this))
+        );
+    }
+
+    function execute(
+        address[] calldata pools,
+        bool[] calldata zeroForOne,
+        uint amountIn,
+        uint minAmountOut
+    ) external {
+        require(msg.sender == owner, "not owner");
+
+        uint nhop = pools.length;
+        require(nhop > 0 && nhop == zeroForOne.length, "bad path");
+
+        IUniswapV2Pair first = IUniswapV2Pair(pools[0]);
+        IERC20 tokenIn = IERC20(
+            zeroForOne[0] ? first.token0() : first.token1()
+        );
+        uint balanceBefore = tokenIn.balanceOf(address(this));
+        tokenIn.safeTransfer(pools[0], amountIn);
+
+        for (uint i; i < nhop; ) {
+            IUniswapV2Pair pair = IUniswapV2Pair(pools[i]);
+            bool direction = zeroForOne[i];
+
+            (uint112 reserve0, uint112 reserve1, ) = pair.getReserves();
+            (uint reserveIn, uint reserveOut) = direction
+                ? (uint(reserve0), uint(reserve1))
+                : (uint(reserve1), uint(reserve0));
+
+            // what the pair actually received, fee-on-transfer tokens arrive short
+            address input = direction ? pair.token0() : pair.token1();
+            uint received = IERC20(input).balanceOf(address(pair)) - reserveIn;
+
+            // Uniswap V2's 0.3% fee: forks charging less still settle, ones charging more revert
+            uint amountOut = (received * 997 * reserveOut) /
+                (reserveIn * 1000 + received * 997);
+
+            // each hop pays the next pair directly, the last one pays us
+            address to = i + 1 < nhop ? pools[i + 1] : address(this);
+            if (direction) {
+                pair.swap(0, amountOut, to, new bytes(0));
+            } else {
+                pair.swap(amountOut, 0, to, new bytes(0));
+            }
+
+            unchecked {
+                i++;
+            }
+        }
+
+        // the path has to end in the token it started with and pay back at least minAmountOut
+        uint balanceAfter = tokenIn.balanceOf(address(this));
+        require(
+            balanceAfter + amountIn >= balanceBefore + minAmountOut,
+            "insufficient output"
+        );
+    }
+}
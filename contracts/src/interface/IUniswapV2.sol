@@ -4,6 +4,13 @@ pragma solidity ^0.8.13;
 interface IUniswapV2Pair {
     function token0() external returns (address);
 
+    function token1() external returns (address);
+
+    function getReserves()
+        external
+        view
+        returns (uint112 reserve0, uint112 reserve1, uint32 blockTimestampLast);
+
     function swap(
         uint amount0Out,
         uint amount1Out,
@@ -9,70 +9,65 @@ use ethers::{
 };
 use std::{path::Path, str::FromStr, sync::Arc, time::Instant};
 use tokio::runtime::Runtime;
-use tokio::sync::broadcast::{self, Sender};
+use tokio::sync::mpsc::Receiver;
 use tokio::task::JoinSet;
 
 use rust::bundler::{Bundler, Flashloan};
 use rust::constants::{Env, ZERO_ADDRESS};
-use rust::multi::{batch_get_uniswap_v2_reserves, get_uniswap_v2_reserves};
+use rust::multi::{batch_get_uniswap_v2_reserves, get_uniswap_v2_reserves, ReserveFetchConfig};
 use rust::paths::generate_triangular_paths;
 use rust::pools::load_all_pools_from_v2;
-use rust::streams::{stream_new_blocks, stream_pending_transactions, Event};
+use rust::streams::{broadcast_new_blocks, stream_pending_transactions, Event, NewBlock};
 use rust::utils::{calculate_next_block_base_fee, get_touched_pool_reserves};
 
-pub async fn logging_event_handler(_: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+pub async fn logging_event_handler(_: Arc<Provider<Ws>>, mut events: Receiver<Event>) {
     let benchmark_file = Path::new("benches/.benchmark.csv");
     let mut writer = csv::Writer::from_path(benchmark_file).unwrap();
 
-    let mut event_receiver = event_sender.subscribe();
-
-    loop {
-        match event_receiver.recv().await {
-            Ok(event) => match event {
-                Event::Block(_) => {}
-                Event::PendingTx(tx) => {
-                    let now = Local::now().timestamp_micros();
-                    writer.serialize((tx.hash, now)).unwrap();
-                }
-                Event::Log(_) => {}
-            },
-            Err(_) => {}
+    while let Some(event) = events.recv().await {
+        match event {
+            Event::Block(_) => {}
+            Event::PendingTx(tx) => {
+                let now = Local::now().timestamp_micros();
+                writer.serialize((tx.hash, now)).unwrap();
+            }
+            Event::Log(_) => {}
+            Event::Reconnected => {}
+            Event::ResetCircuitBreaker => {}
         }
     }
 }
 
-pub async fn touched_pools_event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
-    let mut event_receiver = event_sender.subscribe();
-
-    loop {
-        match event_receiver.recv().await {
-            Ok(event) => match event {
-                Event::Block(block) => {
-                    let s = Instant::now();
-                    match get_touched_pool_reserves(provider.clone(), block.block_number).await {
-                        Ok(reserves) => {
-                            let took = s.elapsed().as_millis();
-                            let now = Instant::now();
-                            println!(
-                                "[{:?}] Block #{:?} {:?} pools touched | Took: {:?} ms",
-                                now,
-                                block.block_number,
-                                reserves.len(),
-                                took
-                            );
-                        }
-                        Err(_) => {}
+pub async fn touched_pools_event_handler(provider: Arc<Provider<Ws>>, mut events: Receiver<Event>) {
+    while let Some(event) = events.recv().await {
+        match event {
+            Event::Block(block) => {
+                let block = NewBlock::from(&block);
+                let s = Instant::now();
+                match get_touched_pool_reserves(provider.clone(), block.block_number).await {
+                    Ok(reserves) => {
+                        let took = s.elapsed().as_millis();
+                        let now = Instant::now();
+                        println!(
+                            "[{:?}] Block #{:?} {:?} pools touched | Took: {:?} ms",
+                            now,
+                            block.block_number,
+                            reserves.len(),
+                            took
+                        );
                     }
+                    Err(_) => {}
                 }
-                Event::PendingTx(_) => {}
-                Event::Log(_) => {}
-            },
-            Err(_) => {}
+            }
+            Event::PendingTx(_) => {}
+            Event::Log(_) => {}
+            Event::Reconnected => {}
+            Event::ResetCircuitBreaker => {}
         }
     }
 }
 
-pub async fn full_course_event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+pub async fn full_course_event_handler(provider: Arc<Provider<Ws>>, events: Receiver<Event>) {
     // pass
 }
 
@@ -129,7 +124,7 @@ pub fn benchmark_function(_: &mut Criterion) {
         let factory_blocks = vec![10794229u64];
 
         let s = Instant::now();
-        let pools = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
+        let pools = load_all_pools_from_v2(client.clone(), factory_addresses, factory_blocks)
             .await
             .unwrap();
         let took = s.elapsed().as_millis();
@@ -145,13 +140,13 @@ pub fn benchmark_function(_: &mut Criterion) {
     let task = async {
         let factory_addresses = vec!["0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"];
         let factory_blocks = vec![10794229u64];
-        let pools = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
+        let pools = load_all_pools_from_v2(client.clone(), factory_addresses, factory_blocks)
             .await
             .unwrap();
         let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
 
         let s = Instant::now();
-        let paths = generate_triangular_paths(&pools, usdc_address);
+        let paths = generate_triangular_paths(&pools, usdc_address, &[]);
         let took = s.elapsed().as_millis();
         println!(
             "4. Generated {:?} 3-hop paths | Took: {:?} ms",
@@ -168,12 +163,12 @@ pub fn benchmark_function(_: &mut Criterion) {
     let task = async {
         let factory_addresses = vec!["0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"];
         let factory_blocks = vec![10794229u64];
-        let pools = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
+        let pools = load_all_pools_from_v2(client.clone(), factory_addresses, factory_blocks)
             .await
             .unwrap();
 
         let s = Instant::now();
-        let reserves = get_uniswap_v2_reserves(env.https_url.clone(), pools[0..250].to_vec())
+        let reserves = get_uniswap_v2_reserves(client.clone(), pools[0..250].to_vec(), None)
             .await
             .unwrap();
         let took = s.elapsed().as_millis();
@@ -189,12 +184,19 @@ pub fn benchmark_function(_: &mut Criterion) {
     let task = async {
         let factory_addresses = vec!["0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"];
         let factory_blocks = vec![10794229u64];
-        let pools = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
+        let pools = load_all_pools_from_v2(client.clone(), factory_addresses, factory_blocks)
             .await
             .unwrap();
 
         let s = Instant::now();
-        let reserves = batch_get_uniswap_v2_reserves(env.https_url.clone(), pools).await;
+        let reserves = batch_get_uniswap_v2_reserves(
+            client.clone(),
+            pools,
+            &ReserveFetchConfig::default(),
+            None,
+        )
+        .await
+        .unwrap();
         let took = s.elapsed().as_millis();
         println!(
             "5. Bulk multicall result for {:?} | Took: {:?} ms",
@@ -218,19 +220,19 @@ pub fn benchmark_function(_: &mut Criterion) {
     //     let ws = Ws::connect(env.wss_url.clone()).await.unwrap();
     //     let provider = Arc::new(Provider::new(ws));
 
-    //     let (event_sender, _): (Sender<Event>, _) = broadcast::channel(512);
+    //     let (queue, events) = EventQueue::new(512);
 
     //     let mut set = JoinSet::new();
 
     //     // try running the stream for n seconds
     //     set.spawn(tokio::time::timeout(
     //         std::time::Duration::from_secs(180),
-    //         stream_pending_transactions(provider.clone(), event_sender.clone()),
+    //         stream_pending_transactions(provider.clone(), queue.clone()),
     //     ));
 
     //     set.spawn(tokio::time::timeout(
     //         std::time::Duration::from_secs(180),
-    //         logging_event_handler(provider.clone(), event_sender.clone()),
+    //         logging_event_handler(provider.clone(), events),
     //     ));
 
     //     println!("6. Logging receive time for pending transaction streams. Wait 180 seconds...");
@@ -245,19 +247,19 @@ pub fn benchmark_function(_: &mut Criterion) {
     //     let ws = Ws::connect(env.wss_url.clone()).await.unwrap();
     //     let provider = Arc::new(Provider::new(ws));
 
-    //     let (event_sender, _): (Sender<Event>, _) = broadcast::channel(512);
+    //     let (queue, events) = EventQueue::new(512);
 
     //     let mut set = JoinSet::new();
 
     //     // try running the stream for n seconds
     //     set.spawn(tokio::time::timeout(
     //         std::time::Duration::from_secs(60 * 5),
-    //         stream_new_blocks(provider.clone(), event_sender.clone()),
+    //         broadcast_new_blocks(provider.clone(), queue.clone()),
     //     ));
 
     //     set.spawn(tokio::time::timeout(
     //         std::time::Duration::from_secs(60 * 5),
-    //         touched_pools_event_handler(provider.clone(), event_sender.clone()),
+    //         touched_pools_event_handler(provider.clone(), events),
     //     ));
 
     //     // test for at least 5 minutes
@@ -272,13 +274,20 @@ pub fn benchmark_function(_: &mut Criterion) {
     let task = async {
         let factory_addresses = vec!["0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"];
         let factory_blocks = vec![10794229u64];
-        let pools = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
+        let pools = load_all_pools_from_v2(client.clone(), factory_addresses, factory_blocks)
             .await
             .unwrap();
         let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
 
-        let paths = generate_triangular_paths(&pools, usdc_address);
-        let reserves = batch_get_uniswap_v2_reserves(env.https_url.clone(), pools).await;
+        let paths = generate_triangular_paths(&pools, usdc_address, &[]);
+        let reserves = batch_get_uniswap_v2_reserves(
+            client.clone(),
+            pools,
+            &ReserveFetchConfig::default(),
+            None,
+        )
+        .await
+        .unwrap();
 
         let took = paths.iter().map(|path| {
             let s = Instant::now();
@@ -302,13 +311,13 @@ pub fn benchmark_function(_: &mut Criterion) {
     let task = async {
         let factory_addresses = vec!["0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"];
         let factory_blocks = vec![10794229u64];
-        let pools = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
+        let pools = load_all_pools_from_v2(client.clone(), factory_addresses, factory_blocks)
             .await
             .unwrap();
         let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
         let usdc_decimals = 6;
 
-        let paths = generate_triangular_paths(&pools, usdc_address);
+        let paths = generate_triangular_paths(&pools, usdc_address, &[]);
 
         let unit = U256::from(10).pow(U256::from(usdc_decimals));
         let gwei = U256::from(10).pow(U256::from(9));
@@ -0,0 +1,178 @@
+use criterion::{criterion_group, criterion_main, Criterion};
+use ethers::types::{H160, I256, U256};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::{
+    collections::{HashMap, HashSet},
+    time::Instant,
+};
+
+use rust::multi::Reserve;
+use rust::paths::generate_triangular_paths;
+use rust::pools::{DexKind, Pool};
+use rust::strategy::best_path;
+
+/*
+Synthetic Uniswap V2 pool set shaped like a mainnet dump:
+a handful of base tokens show up in most pairs, the long tail trades against them.
+*/
+fn synthetic_pools(pool_cnt: usize, token_cnt: u64, seed: u64) -> Vec<Pool> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    let base_tokens = 5u64;
+
+    (0..pool_cnt)
+        .map(|i| {
+            let token0 = if rng.gen_bool(0.7) {
+                rng.gen_range(1..=base_tokens)
+            } else {
+                rng.gen_range(1..=token_cnt)
+            };
+            let mut token1 = rng.gen_range(1..=token_cnt);
+            if token1 == token0 {
+                token1 = token_cnt + 1 + i as u64;
+            }
+            Pool {
+                address: H160::from_low_u64_be(1_000_000 + i as u64),
+                dex: DexKind::UniswapV2,
+                token0: H160::from_low_u64_be(token0),
+                token1: H160::from_low_u64_be(token1),
+                fee: 300,
+                ..Default::default()
+            }
+        })
+        .collect()
+}
+
+// The pre-index O(n^3) scan, kept here as the baseline (counts unique pool triples)
+fn naive_triangular_path_count(pools: &Vec<Pool>, token_in: H160) -> usize {
+    let mut triples = HashSet::new();
+    for pool_1 in pools {
+        if pool_1.token0 != token_in && pool_1.token1 != token_in {
+            continue;
+        }
+        let token_out_1 = if pool_1.token0 == token_in {
+            pool_1.token1
+        } else {
+            pool_1.token0
+        };
+        for pool_2 in pools {
+            if pool_2.token0 != token_out_1 && pool_2.token1 != token_out_1 {
+                continue;
+            }
+            let token_out_2 = if pool_2.token0 == token_out_1 {
+                pool_2.token1
+            } else {
+                pool_2.token0
+            };
+            for pool_3 in pools {
+                let closes = (pool_3.token0 == token_out_2 && pool_3.token1 == token_in)
+                    || (pool_3.token1 == token_out_2 && pool_3.token0 == token_in);
+                if closes
+                    && pool_1.address != pool_2.address
+                    && pool_2.address != pool_3.address
+                    && pool_1.address != pool_3.address
+                {
+                    let mut triple = [pool_1.address, pool_2.address, pool_3.address];
+                    triple.sort();
+                    triples.insert(triple);
+                }
+            }
+        }
+    }
+    triples.len()
+}
+
+pub fn path_generation_benchmark(c: &mut Criterion) {
+    let token_in = H160::from_low_u64_be(1);
+
+    // Baseline vs indexed on a set small enough for the nested loops to finish
+    let pools = synthetic_pools(2_000, 500, 7);
+
+    let s = Instant::now();
+    let naive_cnt = naive_triangular_path_count(&pools, token_in);
+    let naive_took = s.elapsed().as_millis();
+
+    let s = Instant::now();
+    let indexed_cnt = generate_triangular_paths(&pools, token_in, &[]).len();
+    let indexed_took = s.elapsed().as_millis();
+
+    assert_eq!(naive_cnt, indexed_cnt);
+    println!(
+        "2k pools: naive {:?} ms | indexed {:?} ms | {:?} paths",
+        naive_took, indexed_took, indexed_cnt
+    );
+
+    // 20k pools: only the indexed search is feasible here
+    let pools = synthetic_pools(20_000, 5_000, 7);
+
+    let mut group = c.benchmark_group("paths");
+    group.sample_size(10);
+    group.bench_function("generate_triangular_paths_20k", |b| {
+        b.iter(|| generate_triangular_paths(&pools, token_in, &[]))
+    });
+    group.finish();
+}
+
+// Reserves between 1k and 2k units on both sides, so some triangles are off balance
+fn synthetic_reserves(pools: &[Pool], seed: u64) -> HashMap<H160, Reserve> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    pools
+        .iter()
+        .map(|pool| {
+            let reserve = Reserve {
+                reserve0: U256::from(rng.gen_range(1_000u64..2_000)) * U256::exp10(18),
+                reserve1: U256::from(rng.gen_range(1_000u64..2_000)) * U256::exp10(18),
+                ..Default::default()
+            };
+            (pool.address, reserve)
+        })
+        .collect()
+}
+
+pub fn best_path_benchmark(c: &mut Criterion) {
+    let token_in = H160::from_low_u64_be(1);
+    let pools = synthetic_pools(20_000, 5_000, 7);
+    let reserves = synthetic_reserves(&pools, 7);
+    let paths = generate_triangular_paths(&pools, token_in, &[]);
+    let grid: Vec<U256> = (1..=10).map(|n| U256::exp10(17) * n).collect();
+
+    let serial = |paths: &[rust::paths::ArbPath]| {
+        paths
+            .iter()
+            .filter_map(|path| {
+                grid.iter()
+                    .filter_map(|amount_in| {
+                        let profit = path.gross_profit(*amount_in, &reserves)?;
+                        (profit > I256::zero()).then_some(profit)
+                    })
+                    .max()
+            })
+            .max()
+    };
+
+    let s = Instant::now();
+    let serial_profit = serial(&paths);
+    let serial_took = s.elapsed().as_millis();
+
+    let s = Instant::now();
+    let best = best_path(&paths, &reserves, &grid);
+    let parallel_took = s.elapsed().as_millis();
+
+    // best_path also tries optimal_amount_in, so it can only do better than the grid alone
+    assert!(best.map(|(_, _, profit)| profit) >= serial_profit);
+    println!(
+        "{:?} paths: serial {:?} ms | best_path {:?} ms",
+        paths.len(),
+        serial_took,
+        parallel_took
+    );
+
+    let mut group = c.benchmark_group("paths");
+    group.sample_size(10);
+    group.bench_function("best_path_20k_pools", |b| {
+        b.iter(|| best_path(&paths, &reserves, &grid))
+    });
+    group.finish();
+}
+
+criterion_group!(benches, path_generation_benchmark, best_path_benchmark);
+criterion_main!(benches);
@@ -1,5 +1,70 @@
-use ethers_core::abi::Abi;
-use std::fs;
+use ethers::prelude::Lazy;
+use ethers::types::{Bytes, U256};
+use ethers_core::abi::{Abi, Token};
+
+use crate::paths::ArbPath;
+
+// ABIs are embedded at compile time and parsed on first use
+static ERC20: Lazy<Abi> = Lazy::new(|| parse(include_str!("ERC20.json")));
+static WETH: Lazy<Abi> = Lazy::new(|| parse(include_str!("WETH.json")));
+static UNISWAP_V2_FACTORY: Lazy<Abi> = Lazy::new(|| parse(include_str!("UniswapV2Factory.json")));
+static UNISWAP_V2_PAIR: Lazy<Abi> = Lazy::new(|| parse(include_str!("UniswapV2Pair.json")));
+static V2_ARB_BOT: Lazy<Abi> = Lazy::new(|| parse(include_str!("V2ArbBot.json")));
+static ARB_EXECUTOR: Lazy<Abi> = Lazy::new(|| parse(include_str!("ArbExecutor.json")));
+
+fn parse(json: &str) -> Abi {
+    serde_json::from_str(json).unwrap()
+}
+
+pub fn erc20() -> &'static Abi {
+    &ERC20
+}
+
+pub fn weth() -> &'static Abi {
+    &WETH
+}
+
+pub fn uniswap_v2_factory() -> &'static Abi {
+    &UNISWAP_V2_FACTORY
+}
+
+pub fn uniswap_v2_pair() -> &'static Abi {
+    &UNISWAP_V2_PAIR
+}
+
+pub fn v2_arb_bot() -> &'static Abi {
+    &V2_ARB_BOT
+}
+
+// ArbExecutor.execute(address[] pools, bool[] zeroForOne, uint256 amountIn, uint256 minAmountOut)
+pub fn executor() -> &'static Abi {
+    &ARB_EXECUTOR
+}
+
+// ArbExecutor.execute(pools, zeroForOne, amountIn, minAmountOut) calldata for the path
+pub fn arb_calldata(path: &ArbPath, amount_in: U256, min_amount_out: U256) -> Bytes {
+    let pools = path
+        .pools
+        .iter()
+        .map(|pool| Token::Address(pool.address))
+        .collect();
+    let zero_for_one = path
+        .zero_for_one
+        .iter()
+        .map(|direction| Token::Bool(*direction))
+        .collect();
+    executor()
+        .function("execute")
+        .unwrap()
+        .encode_input(&[
+            Token::Array(pools),
+            Token::Array(zero_for_one),
+            Token::Uint(amount_in),
+            Token::Uint(min_amount_out),
+        ])
+        .unwrap()
+        .into()
+}
 
 pub struct ABI {
     pub erc20: Abi,
@@ -7,21 +72,36 @@ pub struct ABI {
     pub uniswap_v2_factory: Abi,
     pub uniswap_v2_pair: Abi,
     pub v2_arb_bot: Abi,
+    pub arb_executor: Abi,
 }
 
 impl ABI {
     pub fn new() -> Self {
-        let erc20_json = fs::read_to_string("src/abi/ERC20.json").unwrap();
-        let weth_json = fs::read_to_string("src/abi/WETH.json").unwrap();
-        let uniswap_v2_factory_json = fs::read_to_string("src/abi/UniswapV2Factory.json").unwrap();
-        let uniswap_v2_pair_json = fs::read_to_string("src/abi/UniswapV2Pair.json").unwrap();
-        let v2_arb_bot_json = fs::read_to_string("src/abi/V2ArbBot.json").unwrap();
         Self {
-            erc20: serde_json::from_str(&erc20_json).unwrap(),
-            weth: serde_json::from_str(&weth_json).unwrap(),
-            uniswap_v2_factory: serde_json::from_str(&uniswap_v2_factory_json).unwrap(),
-            uniswap_v2_pair: serde_json::from_str(&uniswap_v2_pair_json).unwrap(),
-            v2_arb_bot: serde_json::from_str(&v2_arb_bot_json).unwrap(),
+            erc20: erc20().clone(),
+            weth: weth().clone(),
+            uniswap_v2_factory: uniswap_v2_factory().clone(),
+            uniswap_v2_pair: uniswap_v2_pair().clone(),
+            v2_arb_bot: v2_arb_bot().clone(),
+            arb_executor: executor().clone(),
         }
     }
 }
+
+#[cfg(test)]
+mod abi_tests {
+    use super::*;
+
+    #[test]
+    fn abis_expose_expected_functions() {
+        assert!(erc20().function("balanceOf").is_ok());
+        assert!(weth().function("deposit").is_ok());
+        assert!(uniswap_v2_factory().function("getPair").is_ok());
+        assert!(uniswap_v2_pair().function("getReserves").is_ok());
+        assert!(uniswap_v2_pair().event("Sync").is_ok());
+        assert!(executor().function("execute").is_ok());
+
+        // Parsed once: every call hands out the same instance
+        assert!(std::ptr::eq(uniswap_v2_pair(), uniswap_v2_pair()));
+    }
+}
@@ -4,6 +4,7 @@ use ethers::types::{
     transaction::{eip2718::TypedTransaction, eip2930::AccessList},
     Address, Eip1559TransactionRequest, U256,
 };
+use ethers::utils::{hash_message, keccak256};
 use ethers::{
     abi,
     middleware::MiddlewareBuilder,
@@ -11,10 +12,15 @@ use ethers::{
     signers::{LocalWallet, Signer},
 };
 use ethers_flashbots::*;
-use std::{str::FromStr, sync::Arc};
+use futures::future::join_all;
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use std::{str::FromStr, sync::Arc, time::Duration};
 use url::Url;
 
 use crate::constants::Env;
+use crate::metrics;
+use crate::submitter::SimulationReverted;
+use crate::utils::with_timeout;
 
 abigen!(
     ArbBot,
@@ -56,12 +62,17 @@ pub struct Bundler {
     pub bot: ArbBot<SignerProvider>,
     pub provider: SignerProvider,
     pub flashbots: SignerMiddleware<FlashbotsMiddleware<SignerProvider, LocalWallet>, LocalWallet>,
+    // deadline of every node or relay call send_bundle and the submitters make
+    pub rpc_timeout: Duration,
 }
 
 impl Bundler {
     pub fn new<'a>() -> Self {
-        let env = Env::new();
+        Self::from_env(Env::new())
+    }
 
+    // new with the endpoints and keys given instead of read from the environment
+    pub fn from_env(env: Env) -> Self {
         let sender = env
             .private_key
             .parse::<LocalWallet>()
@@ -95,9 +106,15 @@ impl Bundler {
             bot,
             provider: provider,
             flashbots: flashbots,
+            rpc_timeout: Duration::from_secs(10),
         }
     }
 
+    pub fn with_rpc_timeout(mut self, rpc_timeout: Duration) -> Self {
+        self.rpc_timeout = rpc_timeout;
+        self
+    }
+
     pub async fn _common_fields(&self) -> Result<(H160, U256, U64)> {
         let nonce = self
             .provider
@@ -131,20 +148,36 @@ impl Bundler {
             .set_simulation_timestamp(0)
     }
 
+    /*
+    Simulates the bundle, sends it to the relay and waits for it to be included.
+    The simulation and the send are relay calls bounded by rpc_timeout (an RpcTimeout error).
+    A reverting tx in the simulation is a SimulationReverted error.
+    The wait isn't: it ends at the bundle's target block either way.
+    */
     pub async fn send_bundle(&self, bundle: BundleRequest) -> Result<TxHash> {
-        let simulated = self.flashbots.inner().simulate_bundle(&bundle).await?;
+        let simulated = with_timeout("Bundle simulation", self.rpc_timeout, async {
+            Ok(self.flashbots.inner().simulate_bundle(&bundle).await?)
+        })
+        .await?;
 
         for tx in &simulated.transactions {
             if let Some(e) = &tx.error {
                 return Err(anyhow!("Simulation error: {:?}", e));
             }
             if let Some(r) = &tx.revert {
-                return Err(anyhow!("Simulation revert: {:?}", r));
+                return Err(SimulationReverted(format!("{:?}", r)).into());
             }
         }
 
-        let pending_bundle = self.flashbots.inner().send_bundle(&bundle).await?;
+        let pending_bundle = with_timeout("Bundle send", self.rpc_timeout, async {
+            Ok(self.flashbots.inner().send_bundle(&bundle).await?)
+        })
+        .await?;
+        metrics::record_bundle_submitted();
+        tracing::info!(block = ?bundle.block(), "bundle submitted");
+        // resolves once the bundle is included, errors if the target block passes without it
         let bundle_hash = pending_bundle.await?;
+        metrics::record_bundle_included();
         Ok(bundle_hash)
     }
 
@@ -277,10 +310,299 @@ impl Bundler {
     }
 }
 
+/*
+eth_sendBundle params as the Flashbots relay expects them.
+minTimestamp/maxTimestamp (unix seconds) restrict the bundle to blocks within that window.
+*/
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SendBundleParams {
+    pub txs: Vec<Bytes>,
+    pub block_number: U64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_timestamp: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_timestamp: Option<u64>,
+}
+
+impl SendBundleParams {
+    pub fn new(txs: Vec<Bytes>, target_block: u64) -> Self {
+        Self {
+            txs,
+            block_number: U64::from(target_block),
+            min_timestamp: None,
+            max_timestamp: None,
+        }
+    }
+
+    pub fn with_timestamps(
+        mut self,
+        min_timestamp: Option<u64>,
+        max_timestamp: Option<u64>,
+    ) -> Self {
+        self.min_timestamp = min_timestamp;
+        self.max_timestamp = max_timestamp;
+        self
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct RelayRequest<'a, T> {
+    jsonrpc: &'a str,
+    id: u64,
+    method: &'a str,
+    params: [T; 1],
+}
+
+#[derive(Debug, Deserialize)]
+struct RelayResponse<T> {
+    result: Option<T>,
+    error: Option<RelayError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RelayError {
+    code: i64,
+    message: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct SendBundleResult {
+    bundle_hash: BundleHash,
+}
+
+pub fn bundle_request_body<T: Serialize>(method: &str, params: T) -> Result<String> {
+    let request = RelayRequest {
+        jsonrpc: "2.0",
+        id: 1,
+        method,
+        params: [params],
+    };
+    Ok(serde_json::to_string(&request)?)
+}
+
+/*
+X-Flashbots-Signature header value: "<identity address>:<signature>",
+where the identity key signs (EIP-191) the hex string of keccak256(body).
+The identity key only builds searcher reputation, it should not hold funds.
+*/
+pub fn flashbots_signature(identity: &LocalWallet, body: &str) -> Result<String> {
+    let body_hash = H256::from(keccak256(body.as_bytes()));
+    let signature = identity.sign_hash(hash_message(format!("{:?}", body_hash)))?;
+    Ok(format!("{:?}:0x{}", identity.address(), signature))
+}
+
+// Builds the (header name, value) a builder wants for a given request body, None to send no auth
+pub type AuthHeader = Arc<dyn Fn(&str) -> Result<Option<(String, String)>> + Send + Sync>;
+
+pub fn flashbots_auth(identity: LocalWallet) -> AuthHeader {
+    Arc::new(move |body: &str| {
+        let signature = flashbots_signature(&identity, body)?;
+        Ok(Some(("X-Flashbots-Signature".to_string(), signature)))
+    })
+}
+
+#[derive(Clone)]
+pub struct BuilderEndpoint {
+    pub url: String,
+    pub auth: AuthHeader,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct CallBundleParams {
+    txs: Vec<Bytes>,
+    block_number: U64,
+    state_block_number: String,
+}
+
+// The relay reports wei amounts as decimal strings
+fn u256_from_dec_str<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<U256, D::Error> {
+    let value = String::deserialize(deserializer)?;
+    U256::from_dec_str(&value).map_err(serde::de::Error::custom)
+}
+
+/*
+eth_callBundle result.
+coinbase_diff is what the builder earns from the bundle (gas fees plus direct coinbase transfers),
+a bundle that doesn't pay the builder won't be included.
+*/
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleSimResult {
+    pub bundle_hash: BundleHash,
+    #[serde(deserialize_with = "u256_from_dec_str")]
+    pub coinbase_diff: U256,
+    #[serde(deserialize_with = "u256_from_dec_str")]
+    pub gas_fees: U256,
+    #[serde(deserialize_with = "u256_from_dec_str")]
+    pub eth_sent_to_coinbase: U256,
+    pub total_gas_used: u64,
+    pub state_block_number: u64,
+    pub results: Vec<BundleTxSimResult>,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BundleTxSimResult {
+    pub tx_hash: H256,
+    pub gas_used: u64,
+    #[serde(deserialize_with = "u256_from_dec_str")]
+    pub coinbase_diff: U256,
+    pub error: Option<String>,
+    pub revert: Option<String>,
+}
+
+impl BundleSimResult {
+    pub fn failed_txs(&self) -> Vec<&BundleTxSimResult> {
+        self.results
+            .iter()
+            .filter(|tx| tx.error.is_some() || tx.revert.is_some())
+            .collect()
+    }
+
+    pub fn should_submit(&self) -> bool {
+        !self.coinbase_diff.is_zero() && self.failed_txs().is_empty()
+    }
+}
+
+/*
+Talks to a bundle relay over plain JSON-RPC, without going through FlashbotsMiddleware.
+Takes already signed raw txs, so it can be used with any signer setup.
+Extra builders can be added to broadcast the same bundle to several of them with send_bundle_to_all.
+*/
+pub struct BundleRelay {
+    pub client: reqwest::Client,
+    pub relay: BuilderEndpoint,
+    pub builders: Vec<BuilderEndpoint>,
+    identity: LocalWallet,
+}
+
+impl BundleRelay {
+    pub fn new(client: reqwest::Client, identity: LocalWallet, relay_url: &str) -> Self {
+        Self {
+            client,
+            relay: BuilderEndpoint {
+                url: relay_url.to_string(),
+                auth: flashbots_auth(identity.clone()),
+            },
+            builders: Vec::new(),
+            identity,
+        }
+    }
+
+    // Builders that accept the Flashbots signature header (most of them do)
+    pub fn with_builders(mut self, urls: Vec<String>) -> Self {
+        for url in urls {
+            let auth = flashbots_auth(self.identity.clone());
+            self.builders.push(BuilderEndpoint { url, auth });
+        }
+        self
+    }
+
+    pub fn with_builder(mut self, url: &str, auth: AuthHeader) -> Self {
+        self.builders.push(BuilderEndpoint {
+            url: url.to_string(),
+            auth,
+        });
+        self
+    }
+
+    pub async fn send_bundle(&self, txs: Vec<Bytes>, target_block: u64) -> Result<BundleHash> {
+        self.submit(SendBundleParams::new(txs, target_block)).await
+    }
+
+    pub async fn submit(&self, params: SendBundleParams) -> Result<BundleHash> {
+        let target_block = params.block_number;
+        let result: SendBundleResult = self.request(&self.relay, "eth_sendBundle", params).await?;
+        metrics::record_bundle_submitted();
+        tracing::info!(%target_block, bundle_hash = ?result.bundle_hash, "bundle submitted");
+        Ok(result.bundle_hash)
+    }
+
+    /*
+    Sends the bundle to every builder in self.builders concurrently (the relay is not included
+    unless it's also listed as a builder). One builder failing doesn't affect the others,
+    every endpoint gets its own result.
+    */
+    pub async fn send_bundle_to_all(
+        &self,
+        txs: Vec<Bytes>,
+        target_block: u64,
+    ) -> Vec<(String, Result<BundleHash>)> {
+        let params = SendBundleParams::new(txs, target_block);
+        let sends = self.builders.iter().map(|builder| {
+            let params = params.clone();
+            async move {
+                let result = self
+                    .request::<_, SendBundleResult>(builder, "eth_sendBundle", params)
+                    .await
+                    .map(|result| result.bundle_hash);
+                if result.is_ok() {
+                    metrics::record_bundle_submitted();
+                }
+                (builder.url.clone(), result)
+            }
+        });
+        join_all(sends).await
+    }
+
+    // Simulates the bundle on top of the latest state as if it was included in block_number
+    pub async fn simulate_bundle(
+        &self,
+        txs: Vec<Bytes>,
+        block_number: u64,
+    ) -> Result<BundleSimResult> {
+        let params = CallBundleParams {
+            txs,
+            block_number: U64::from(block_number),
+            state_block_number: "latest".to_string(),
+        };
+        self.request(&self.relay, "eth_callBundle", params).await
+    }
+
+    async fn request<P: Serialize, R: DeserializeOwned>(
+        &self,
+        endpoint: &BuilderEndpoint,
+        method: &str,
+        params: P,
+    ) -> Result<R> {
+        let body = bundle_request_body(method, params)?;
+        let auth = (endpoint.auth)(&body)?;
+
+        let mut request = self
+            .client
+            .post(&endpoint.url)
+            .header("Content-Type", "application/json");
+        if let Some((name, value)) = auth {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send().await?.error_for_status()?;
+        let response: RelayResponse<R> = response.json().await?;
+
+        match (response.result, response.error) {
+            (_, Some(e)) => Err(anyhow!(
+                "{} to {} failed ({}): {}",
+                method,
+                endpoint.url,
+                e.code,
+                e.message
+            )),
+            (Some(result), None) => Ok(result),
+            (None, None) => Err(anyhow!("{} to {} returned no result", method, endpoint.url)),
+        }
+    }
+}
+
 #[cfg(test)]
 mod bundler_tests {
     use super::*;
     use crate::constants::{GWEI, WEI};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpListener;
 
     #[tokio::test]
     async fn bundler_test() {
@@ -340,4 +662,166 @@ mod bundler_tests {
         // let tx_hash = bundler.send_tx(tx).await?;
         // println!("{:?}", tx_hash);
     }
+
+    #[test]
+    fn flashbots_signature_matches_fixture() {
+        let identity = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse::<LocalWallet>()
+            .unwrap();
+        let params =
+            SendBundleParams::new(vec![Bytes::from(vec![0xde, 0xad, 0xbe, 0xef])], 17_000_000)
+                .with_timestamps(Some(1_700_000_000), Some(1_700_000_120));
+
+        let body = bundle_request_body("eth_sendBundle", params).unwrap();
+        assert_eq!(
+            body,
+            r#"{"jsonrpc":"2.0","id":1,"method":"eth_sendBundle","params":[{"txs":["0xdeadbeef"],"blockNumber":"0x1036640","minTimestamp":1700000000,"maxTimestamp":1700000120}]}"#
+        );
+
+        let signature = flashbots_signature(&identity, &body).unwrap();
+        assert_eq!(
+            signature,
+            "0x2c7536e3605d9c16a7a3d7b1898e529396a65c23:0xee11b8a1032eea4992584ec9bf30578d0c8986a5d73d826ef3733ea06ef5a2de00d0f2c03cefc414aa1caa8394ae299ed55942c37060eb4401f72cf93f7369431b"
+        );
+    }
+
+    #[test]
+    fn call_bundle_response_is_parsed() {
+        let response = r#"{
+            "jsonrpc": "2.0",
+            "id": 1,
+            "result": {
+                "bundleGasPrice": "476190476193",
+                "bundleHash": "0x73b1e258c7a42fd0230b2fd05529c5d4b6fcb66c227783f8bece8aeacdd1db2e",
+                "coinbaseDiff": "20000000000126000",
+                "ethSentToCoinbase": "20000000000000000",
+                "gasFees": "126000",
+                "results": [
+                    {
+                        "coinbaseDiff": "20000000000126000",
+                        "ethSentToCoinbase": "20000000000000000",
+                        "fromAddress": "0x02A727155aef8609c9f7F2179b2a1f560B39F5A0",
+                        "gasFees": "126000",
+                        "gasPrice": "476190476193",
+                        "gasUsed": 42000,
+                        "toAddress": "0x73625f59CAdc5009Cb458B751b3E7b6b48C06f2C",
+                        "txHash": "0x669b4704a7d993a946cdd6e2f95233f308ce0c4649d2e04944e8299efcaa098a",
+                        "value": "0x"
+                    },
+                    {
+                        "coinbaseDiff": "0",
+                        "error": "execution reverted",
+                        "revert": "UniswapV2: K",
+                        "ethSentToCoinbase": "0",
+                        "fromAddress": "0x02A727155aef8609c9f7F2179b2a1f560B39F5A0",
+                        "gasFees": "0",
+                        "gasPrice": "476190476193",
+                        "gasUsed": 35000,
+                        "toAddress": "0x73625f59CAdc5009Cb458B751b3E7b6b48C06f2C",
+                        "txHash": "0xa839ee83465657cac01adc1d50d96c1b586ed498120a84a64749c0034b4f19fa"
+                    }
+                ],
+                "stateBlockNumber": 5221585,
+                "totalGasUsed": 77000
+            }
+        }"#;
+
+        let response: RelayResponse<BundleSimResult> = serde_json::from_str(response).unwrap();
+        let simulated = response.result.unwrap();
+
+        assert_eq!(
+            simulated.coinbase_diff,
+            U256::from_dec_str("20000000000126000").unwrap()
+        );
+        assert_eq!(simulated.total_gas_used, 77000);
+        assert_eq!(simulated.results[0].gas_used, 42000);
+        assert_eq!(simulated.results[0].error, None);
+
+        let failed = simulated.failed_txs();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].revert.as_deref(), Some("UniswapV2: K"));
+
+        // A reverting tx blocks submission even though the bundle pays the builder
+        assert!(!simulated.coinbase_diff.is_zero());
+        assert!(!simulated.should_submit());
+    }
+
+    // Minimal HTTP server answering a single request with a canned JSON body
+    async fn mock_builder(response_body: &'static str) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 4096];
+            loop {
+                let n = socket.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+                let text = String::from_utf8_lossy(&request);
+                if let Some(header_end) = text.find("\r\n\r\n") {
+                    let content_length = text[..header_end]
+                        .lines()
+                        .find_map(|line| {
+                            let (name, value) = line.split_once(':')?;
+                            if name.eq_ignore_ascii_case("content-length") {
+                                value.trim().parse::<usize>().ok()
+                            } else {
+                                None
+                            }
+                        })
+                        .unwrap_or(0);
+                    if request.len() >= header_end + 4 + content_length {
+                        break;
+                    }
+                }
+            }
+            let response = format!(
+                "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                response_body.len(),
+                response_body
+            );
+            socket.write_all(response.as_bytes()).await.unwrap();
+        });
+
+        url
+    }
+
+    #[tokio::test]
+    async fn send_bundle_to_all_reports_every_builder() {
+        let accepting = mock_builder(
+            r#"{"jsonrpc":"2.0","id":1,"result":{"bundleHash":"0x73b1e258c7a42fd0230b2fd05529c5d4b6fcb66c227783f8bece8aeacdd1db2e"}}"#,
+        )
+        .await;
+        let rejecting = mock_builder(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"bundle rejected"}}"#,
+        )
+        .await;
+
+        let identity = "4c0883a69102937d6231471b5dbb6204fe5129617082792ae468d01a3f362318"
+            .parse::<LocalWallet>()
+            .unwrap();
+        let no_auth: AuthHeader = Arc::new(|_: &str| Ok(None));
+        let relay = BundleRelay::new(reqwest::Client::new(), identity, "http://127.0.0.1:1")
+            .with_builders(vec![accepting.clone()])
+            .with_builder(&rejecting, no_auth);
+
+        let results = relay
+            .send_bundle_to_all(vec![Bytes::from(vec![0xde, 0xad, 0xbe, 0xef])], 17_000_000)
+            .await;
+
+        assert_eq!(results.len(), 2);
+        assert_eq!(results[0].0, accepting);
+        assert_eq!(
+            *results[0].1.as_ref().unwrap(),
+            H256::from_str("0x73b1e258c7a42fd0230b2fd05529c5d4b6fcb66c227783f8bece8aeacdd1db2e")
+                .unwrap()
+        );
+        assert_eq!(results[1].0, rejecting);
+        let error = results[1].1.as_ref().unwrap_err().to_string();
+        assert!(error.contains("bundle rejected"));
+    }
 }
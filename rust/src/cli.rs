@@ -0,0 +1,394 @@
+use anyhow::{anyhow, Result};
+use ethers::{
+    providers::Middleware,
+    types::{H160, I256, U256},
+};
+use std::{collections::HashMap, str::FromStr, sync::Arc};
+
+use crate::multi::{get_uniswap_v2_reserves, Reserve};
+use crate::paths::{cycle_from_pools, ArbPath};
+use crate::pools::{enrich_token_metadata, load_v2_pools};
+use crate::strategy::StrategyConfig;
+
+/*
+Arguments of `simulate --pools <addr,addr,addr> --amount <n> [--weth <addr>]`.
+Pools are V2 pairs in swap order, amount is in the smallest unit of the input token
+(the first pool's token the second pool doesn't trade). weth, mainnet WETH unless given,
+is what gas is priced against, see SimulationReport::net_profit.
+*/
+#[derive(Debug, Clone, PartialEq)]
+pub struct SimulateArgs {
+    pub pools: Vec<H160>,
+    pub amount: U256,
+    pub weth: H160,
+}
+
+// Mainnet WETH
+pub const WETH: &str = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2";
+
+impl SimulateArgs {
+    // args without the program name and the subcommand
+    pub fn parse(args: &[String]) -> Result<Self> {
+        let mut pools = None;
+        let mut amount = None;
+        let mut weth = H160::from_str(WETH).unwrap();
+        let mut args = args.iter();
+        while let Some(flag) = args.next() {
+            let value = args
+                .next()
+                .ok_or_else(|| anyhow!("{} needs a value", flag))?;
+            match flag.as_str() {
+                "--pools" => {
+                    pools = Some(
+                        value
+                            .split(',')
+                            .map(|address| {
+                                H160::from_str(address.trim())
+                                    .map_err(|e| anyhow!("Bad pool address {:?}: {}", address, e))
+                            })
+                            .collect::<Result<Vec<H160>>>()?,
+                    )
+                }
+                "--amount" => {
+                    amount = Some(
+                        U256::from_dec_str(value)
+                            .map_err(|e| anyhow!("Bad amount {:?}: {}", value, e))?,
+                    )
+                }
+                "--weth" => {
+                    weth = H160::from_str(value.trim())
+                        .map_err(|e| anyhow!("Bad WETH address {:?}: {}", value, e))?
+                }
+                other => return Err(anyhow!("Unknown argument {:?}", other)),
+            }
+        }
+        Ok(Self {
+            pools: pools.ok_or_else(|| anyhow!("Missing --pools <addr,addr,addr>"))?,
+            amount: amount.ok_or_else(|| anyhow!("Missing --amount <n>"))?,
+            weth,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SimulationReport {
+    pub path: ArbPath,
+    pub block_number: u64,
+    pub amount_in: U256,
+    // amount out of every hop, the last one is gross_out
+    pub hop_amounts: Vec<U256>,
+    pub gross_out: U256,
+    /*
+    See ArbPath::net_profit. Gas is priced in the input token through the path's own pool
+    pairing it with WETH, None when the input token isn't WETH and no such pool is on the path.
+    */
+    pub net_profit: Option<I256>,
+}
+
+impl std::fmt::Display for SimulationReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "{}", self.path.describe(&HashMap::new()))?;
+        writeln!(f, "block      {}", self.block_number)?;
+        writeln!(f, "amount in  {}", self.amount_in)?;
+        for (i, (pool, amount)) in self.path.pools.iter().zip(&self.hop_amounts).enumerate() {
+            writeln!(f, "hop {}      {:?} -> {}", i, pool.address, amount)?;
+        }
+        writeln!(f, "gross out  {}", self.gross_out)?;
+        match self.net_profit {
+            Some(net_profit) => write!(f, "net profit {}", net_profit),
+            None => write!(
+                f,
+                "net profit n/a, no WETH pool on the path to price gas with"
+            ),
+        }
+    }
+}
+
+/*
+Simulates one path at the latest block without running the bot: loads the pools and their
+reserves, builds the cycle (erroring if the pools don't form one) and prices it with the
+strategy's default gas_per_hop at the node's current gas price.
+*/
+pub async fn simulate<M: Middleware + 'static>(
+    provider: Arc<M>,
+    args: &SimulateArgs,
+) -> Result<SimulationReport> {
+    let mut pools = load_v2_pools(provider.clone(), &args.pools).await?;
+    enrich_token_metadata(provider.clone(), &mut pools).await?;
+    let path = cycle_from_pools(pools)?;
+
+    let block_number = provider
+        .get_block_number()
+        .await
+        .map_err(|e| anyhow!("get_block_number failed: {}", e))?
+        .as_u64();
+    let reserves =
+        get_uniswap_v2_reserves(provider.clone(), path.pools.clone(), Some(block_number)).await?;
+    let gas_price = provider
+        .get_gas_price()
+        .await
+        .map_err(|e| anyhow!("get_gas_price failed: {}", e))?;
+
+    let hop_amounts = path
+        .simulate_v2_path_verbose(args.amount, &reserves)
+        .ok_or_else(|| anyhow!("Path can't be simulated, missing or empty reserves"))?;
+    let gross_out = hop_amounts.last().cloned().unwrap_or_default();
+    let cfg = StrategyConfig::default();
+    let net_profit = match token_in_per_eth(&path, &reserves, args.weth) {
+        Some(token_in_per_eth) => Some(
+            path.net_profit(
+                args.amount,
+                &reserves,
+                gas_price,
+                cfg.gas_per_hop,
+                token_in_per_eth,
+            )
+            .ok_or_else(|| anyhow!("Net profit overflows"))?,
+        ),
+        None => None,
+    };
+
+    Ok(SimulationReport {
+        path,
+        block_number,
+        amount_in: args.amount,
+        hop_amounts,
+        gross_out,
+        net_profit,
+    })
+}
+
+/*
+Smallest units of the path's input token 1 ETH is worth: 1e18 when it is weth, else the spot
+price of the first pool on the path that pairs it with weth. None if there's no such pool or
+its reserves are empty.
+*/
+pub fn token_in_per_eth(
+    path: &ArbPath,
+    reserves: &HashMap<H160, Reserve>,
+    weth: H160,
+) -> Option<U256> {
+    let token_in = path.base_token();
+    if token_in == weth {
+        return Some(U256::exp10(18));
+    }
+    path.pools.iter().find_map(|pool| {
+        let reserve = reserves.get(&pool.address)?;
+        let (token_reserve, weth_reserve) = if (pool.token0, pool.token1) == (token_in, weth) {
+            (reserve.reserve0, reserve.reserve1)
+        } else if (pool.token0, pool.token1) == (weth, token_in) {
+            (reserve.reserve1, reserve.reserve0)
+        } else {
+            return None;
+        };
+        if weth_reserve.is_zero() {
+            return None;
+        }
+        token_reserve
+            .checked_mul(U256::exp10(18))
+            .map(|value| value / weth_reserve)
+    })
+}
+
+#[cfg(test)]
+mod cli_tests {
+    use super::*;
+    use crate::paths::BASE_ARB_GAS;
+    use ethers::abi::{encode, Token};
+    use ethers::providers::Provider;
+    use ethers::types::{Bytes, U64};
+
+    fn addr(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    // aggregate3 output, None for a call that reverted
+    fn aggregate3(results: Vec<Option<Vec<u8>>>) -> Bytes {
+        let results = results
+            .into_iter()
+            .map(|result| {
+                Token::Tuple(vec![
+                    Token::Bool(result.is_some()),
+                    Token::Bytes(result.unwrap_or_default()),
+                ])
+            })
+            .collect();
+        encode(&[Token::Array(results)]).into()
+    }
+
+    // token0() and token1() results of every pair
+    fn pair_tokens(pairs: &[(u64, u64)]) -> Vec<Option<Vec<u8>>> {
+        pairs
+            .iter()
+            .flat_map(|(token0, token1)| {
+                [*token0, *token1].map(|token| Some(encode(&[Token::Address(addr(token))])))
+            })
+            .collect()
+    }
+
+    fn reserves(values: &[(u128, u128)]) -> Bytes {
+        aggregate3(
+            values
+                .iter()
+                .map(|(reserve0, reserve1)| {
+                    Some(encode(&[
+                        Token::Uint(U256::from(*reserve0)),
+                        Token::Uint(U256::from(*reserve1)),
+                        Token::Uint(U256::zero()),
+                    ]))
+                })
+                .collect(),
+        )
+    }
+
+    fn args(pools: &[u64], amount: u64) -> SimulateArgs {
+        SimulateArgs {
+            pools: pools.iter().map(|&n| addr(n)).collect(),
+            amount: U256::from(amount),
+            weth: H160::from_str(WETH).unwrap(),
+        }
+    }
+
+    #[test]
+    fn simulate_args_parse_pools_and_amount() {
+        let raw: Vec<String> = [
+            "--pools",
+            "0x000000000000000000000000000000000000000b,0x000000000000000000000000000000000000000c,0x000000000000000000000000000000000000000d",
+            "--amount",
+            "1000000",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect();
+        assert_eq!(
+            SimulateArgs::parse(&raw).unwrap(),
+            args(&[11, 12, 13], 1_000_000)
+        );
+
+        let mut with_weth = raw.clone();
+        with_weth.extend([
+            "--weth".to_string(),
+            "0x0000000000000000000000000000000000000001".to_string(),
+        ]);
+        assert_eq!(SimulateArgs::parse(&with_weth).unwrap().weth, addr(1));
+
+        assert!(SimulateArgs::parse(&raw[..2]).is_err());
+        assert!(SimulateArgs::parse(&["--pools".to_string()]).is_err());
+    }
+
+    // Mocks a triangle 1 -> 2 -> 3 -> 1 through pools 11, 12 and 13 and simulates it
+    async fn simulate_triangle(args: &SimulateArgs) -> SimulationReport {
+        let (provider, mock) = Provider::mocked();
+        let e18 = 10u128.pow(18);
+        // LIFO: pair tokens, token metadata, block number, reserves, gas price
+        mock.push(U256::from(10_000_000_000u64)).unwrap();
+        mock.push(reserves(&[
+            (1000 * e18, 2000 * e18),
+            (1000 * e18, 1000 * e18),
+            (1000 * e18, 1000 * e18),
+        ]))
+        .unwrap();
+        mock.push(U64::from(17_000_000)).unwrap();
+        mock.push(aggregate3(vec![None; 6])).unwrap();
+        mock.push(aggregate3(pair_tokens(&[(1, 2), (2, 3), (3, 1)])))
+            .unwrap();
+        simulate(Arc::new(provider), args).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn simulates_a_triangle_against_mocked_reserves() {
+        let e18 = 10u128.pow(18);
+        // The input token is WETH, gas is paid 1:1
+        let args = SimulateArgs {
+            weth: addr(1),
+            ..args(&[11, 12, 13], 10u64.pow(18))
+        };
+        let report = simulate_triangle(&args).await;
+
+        assert_eq!(report.path.zero_for_one, vec![true, true, true]);
+        assert_eq!(report.block_number, 17_000_000);
+        assert_eq!(report.hop_amounts.len(), 3);
+
+        let reserves: HashMap<H160, Reserve> = [
+            (addr(11), 1000 * e18, 2000 * e18),
+            (addr(12), 1000 * e18, 1000 * e18),
+            (addr(13), 1000 * e18, 1000 * e18),
+        ]
+        .into_iter()
+        .map(|(address, reserve0, reserve1)| {
+            (
+                address,
+                Reserve {
+                    reserve0: U256::from(reserve0),
+                    reserve1: U256::from(reserve1),
+                    ..Default::default()
+                },
+            )
+        })
+        .collect();
+        let expected = report
+            .path
+            .simulate_v2_path_verbose(args.amount, &reserves)
+            .unwrap();
+        assert_eq!(report.hop_amounts, expected);
+        assert_eq!(report.gross_out, expected[2]);
+
+        let gas = U256::from(60_000 * 3 + BASE_ARB_GAS) * U256::from(10_000_000_000u64);
+        let net = I256::try_from(report.gross_out).unwrap()
+            - I256::try_from(args.amount).unwrap()
+            - I256::try_from(gas).unwrap();
+        assert_eq!(report.net_profit, Some(net));
+        assert!(net > I256::zero());
+    }
+
+    #[tokio::test]
+    async fn gas_is_priced_through_the_input_tokens_weth_pool() {
+        // Pool 11 holds 1000 of the input token against 2000 WETH: 1 ETH buys 0.5 of it
+        let args = SimulateArgs {
+            weth: addr(2),
+            ..args(&[11, 12, 13], 10u64.pow(18))
+        };
+        let report = simulate_triangle(&args).await;
+        let gas = U256::from(60_000 * 3 + BASE_ARB_GAS) * U256::from(10_000_000_000u64) / 2;
+        let net = I256::try_from(report.gross_out).unwrap()
+            - I256::try_from(args.amount).unwrap()
+            - I256::try_from(gas).unwrap();
+        assert_eq!(report.net_profit, Some(net));
+
+        // No pool pairs the input token with WETH: only gross is reported
+        let report = simulate_triangle(&args(&[11, 12, 13], 10u64.pow(18))).await;
+        assert_eq!(report.net_profit, None);
+        assert!(report.to_string().contains("net profit n/a"));
+    }
+
+    #[tokio::test]
+    async fn pools_that_dont_close_the_cycle_are_rejected() {
+        let (provider, mock) = Provider::mocked();
+        mock.push(aggregate3(vec![None; 8])).unwrap();
+        mock.push(aggregate3(pair_tokens(&[(1, 2), (2, 3), (3, 4)])))
+            .unwrap();
+
+        let err = simulate(Arc::new(provider), &args(&[11, 12, 13], 1000))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("not a cycle"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn non_pair_addresses_are_reported() {
+        let (provider, mock) = Provider::mocked();
+        // 0x..0d reverts both calls
+        let mut results = pair_tokens(&[(1, 2), (2, 3)]);
+        results.extend([None, None]);
+        mock.push(aggregate3(results)).unwrap();
+
+        let err = simulate(Arc::new(provider), &args(&[11, 12, 13], 1000))
+            .await
+            .unwrap_err();
+        assert!(
+            err.to_string().contains(&format!("{:?}", addr(13))),
+            "{}",
+            err
+        );
+    }
+}
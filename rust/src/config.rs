@@ -0,0 +1,305 @@
+use anyhow::{anyhow, Result};
+use ethers::{
+    signers::LocalWallet,
+    types::{H160, U256, U64},
+};
+use figment::{
+    providers::{Env, Format, Toml},
+    Figment,
+};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use std::{path::Path, time::Duration};
+
+use crate::constants;
+use crate::multi::ReserveFetchConfig;
+use crate::strategy::{GasStrategy, StrategyConfig};
+use crate::submitter::SubmitterKind;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FactoryConfig {
+    pub address: H160,
+    // block the factory was deployed at, pool syncing starts here
+    pub from_block: u64,
+}
+
+/*
+Everything the bot needs to connect and trade, read from a TOML file.
+Any top level key can be overridden from the environment with an MEV_ prefix,
+e.g. MEV_RPC_URL or MEV_DRY_RUN=false, so secrets don't have to live in the file.
+Addresses are typed, a malformed one fails the load rather than the first trade.
+Nothing is submitted unless dry_run is turned off, which also needs private_key and executor.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    pub rpc_url: String,
+    pub ws_url: String,
+    pub chain_id: u64,
+    pub private_key: String,
+    pub signing_key: String,
+    pub bot_address: H160,
+    // deployed ArbExecutor the arb txs call
+    pub executor: H160,
+    pub factories: Vec<FactoryConfig>,
+    // a decimal string, TOML integers stop at i64::MAX
+    #[serde(with = "decimal_u256")]
+    pub min_profit_wei: U256,
+    // see StrategyConfig::slippage_bps
+    pub slippage_bps: u32,
+    pub blacklist_tokens: Vec<H160>,
+    // pools per reserve multicall
+    pub chunk_size: usize,
+    pub dry_run: bool,
+    // deadline of a single RPC call, see StrategyConfig::rpc_timeout
+    pub rpc_timeout_ms: u64,
+    // see GasStrategy, e.g. { kind = "fraction_of_profit", bps = 2000 }
+    pub gas: GasStrategy,
+    // "flashbots" or "public_mempool", ignored on dry runs
+    pub submitter: SubmitterKind,
+    // see StrategyConfig::path_budget, unset simulates every touched path
+    pub path_budget: Option<usize>,
+    pub sample_seed: u64,
+    // see CircuitBreaker
+    pub max_consecutive_reverts: u32,
+    pub revert_window_secs: u64,
+    pub breaker_cooldown_secs: u64,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            rpc_url: String::new(),
+            ws_url: String::new(),
+            chain_id: 1,
+            private_key: String::new(),
+            signing_key: String::new(),
+            bot_address: H160::zero(),
+            executor: H160::zero(),
+            factories: Vec::new(),
+            min_profit_wei: U256::zero(),
+            slippage_bps: StrategyConfig::default().slippage_bps,
+            blacklist_tokens: Vec::new(),
+            chunk_size: ReserveFetchConfig::default().chunk_size,
+            dry_run: true,
+            rpc_timeout_ms: StrategyConfig::default().rpc_timeout.as_millis() as u64,
+            gas: GasStrategy::default(),
+            submitter: SubmitterKind::default(),
+            path_budget: None,
+            sample_seed: 0,
+            max_consecutive_reverts: StrategyConfig::default().max_consecutive_reverts,
+            revert_window_secs: StrategyConfig::default().revert_window.as_secs(),
+            breaker_cooldown_secs: StrategyConfig::default().breaker_cooldown.as_secs(),
+        }
+    }
+}
+
+// U256 as a decimal string, integers are read too
+mod decimal_u256 {
+    use super::*;
+
+    pub fn serialize<S: Serializer>(value: &U256, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&value.to_string())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<U256, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Raw {
+            Int(u64),
+            Str(String),
+        }
+        match Raw::deserialize(deserializer)? {
+            Raw::Int(value) => Ok(U256::from(value)),
+            Raw::Str(value) => U256::from_dec_str(&value).map_err(de::Error::custom),
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self> {
+        let config: Config = Figment::new()
+            .merge(Toml::file(path))
+            .merge(Env::prefixed("MEV_"))
+            .extract()
+            .map_err(|e| anyhow!("Invalid config {:?}: {}", path, e))?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    pub fn validate(&self) -> Result<()> {
+        if self.rpc_url.is_empty() {
+            return Err(anyhow!("rpc_url is not set"));
+        }
+        if self.ws_url.is_empty() {
+            return Err(anyhow!("ws_url is not set"));
+        }
+        if self.chunk_size == 0 {
+            return Err(anyhow!("chunk_size must be at least 1"));
+        }
+        if self.rpc_timeout_ms == 0 {
+            return Err(anyhow!("rpc_timeout_ms must be at least 1"));
+        }
+        // Dry runs never sign anything
+        if !self.dry_run {
+            if self.private_key.is_empty() {
+                return Err(anyhow!("private_key is required unless dry_run is set"));
+            }
+            self.private_key
+                .parse::<LocalWallet>()
+                .map_err(|e| anyhow!("private_key does not parse: {}", e))?;
+            if self.executor.is_zero() {
+                return Err(anyhow!("executor is required unless dry_run is set"));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn strategy_config(&self) -> StrategyConfig {
+        StrategyConfig {
+            min_profit_wei: self.min_profit_wei,
+            dry_run: self.dry_run,
+            executor: self.executor,
+            slippage_bps: self.slippage_bps,
+            rpc_timeout: Duration::from_millis(self.rpc_timeout_ms),
+            gas: self.gas,
+            submitter: self.submitter,
+            max_consecutive_reverts: self.max_consecutive_reverts,
+            revert_window: Duration::from_secs(self.revert_window_secs),
+            breaker_cooldown: Duration::from_secs(self.breaker_cooldown_secs),
+            path_budget: self.path_budget,
+            sample_seed: self.sample_seed,
+            ..Default::default()
+        }
+    }
+
+    // What Bundler::from_env signs and sends with
+    pub fn bundler_env(&self) -> constants::Env {
+        constants::Env {
+            https_url: self.rpc_url.clone(),
+            wss_url: self.ws_url.clone(),
+            chain_id: U64::from(self.chain_id),
+            private_key: self.private_key.clone(),
+            signing_key: self.signing_key.clone(),
+            bot_address: format!("{:?}", self.bot_address),
+        }
+    }
+
+    pub fn reserve_fetch_config(&self) -> ReserveFetchConfig {
+        ReserveFetchConfig {
+            chunk_size: self.chunk_size,
+            call_timeout: Duration::from_millis(self.rpc_timeout_ms),
+            ..Default::default()
+        }
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+    use figment::Jail;
+    use std::str::FromStr;
+
+    const PRIVATE_KEY: &str = "0xb3e5dc08b18918cce982438a28877e440aafc01fef4c314b95d0609bf946585f";
+
+    fn sample() -> Config {
+        Config {
+            rpc_url: "http://localhost:8545".to_string(),
+            ws_url: "ws://localhost:8546".to_string(),
+            chain_id: 137,
+            private_key: PRIVATE_KEY.to_string(),
+            signing_key: String::new(),
+            bot_address: H160::from_str("0xEc1f2DADF368D5a20D494a2974bC19e421812017").unwrap(),
+            executor: H160::from_low_u64_be(0xe0e0),
+            factories: vec![FactoryConfig {
+                address: H160::from_str("0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac").unwrap(),
+                from_block: 10794229,
+            }],
+            // above i64::MAX, which a TOML integer can't hold
+            min_profit_wei: U256::exp10(20),
+            slippage_bps: 30,
+            blacklist_tokens: vec![
+                H160::from_str("0x9469603F3Efbcf17e4A5868d81C701BDbD222555").unwrap()
+            ],
+            chunk_size: 500,
+            dry_run: false,
+            rpc_timeout_ms: 5_000,
+            gas: GasStrategy::FractionOfProfit { bps: 2_000 },
+            submitter: SubmitterKind::PublicMempool,
+            path_budget: Some(2_000),
+            sample_seed: 42,
+            max_consecutive_reverts: 5,
+            revert_window_secs: 300,
+            breaker_cooldown_secs: 900,
+        }
+    }
+
+    #[test]
+    fn sample_toml_round_trips_with_env_overrides() {
+        Jail::expect_with(|jail| {
+            let config = sample();
+            jail.create_file("config.toml", &toml::to_string(&config).unwrap())?;
+            assert_eq!(Config::load(Path::new("config.toml")).unwrap(), config);
+
+            jail.set_env("MEV_CHUNK_SIZE", 50);
+            jail.set_env("MEV_RPC_URL", "http://node:8545");
+            let overridden = Config::load(Path::new("config.toml")).unwrap();
+            assert_eq!(overridden.chunk_size, 50);
+            assert_eq!(overridden.rpc_url, "http://node:8545");
+            assert_eq!(overridden.factories, config.factories);
+
+            // Everything the strategy loop reads comes through
+            let strategy = config.strategy_config();
+            assert_eq!(strategy.min_profit_wei, U256::exp10(20));
+            assert_eq!(strategy.executor, config.executor);
+            assert_eq!(strategy.path_budget, Some(2_000));
+            assert_eq!(strategy.sample_seed, 42);
+            assert_eq!(strategy.max_consecutive_reverts, 5);
+            assert_eq!(strategy.breaker_cooldown, Duration::from_secs(900));
+            Ok(())
+        });
+    }
+
+    #[test]
+    fn bad_addresses_and_missing_keys_are_rejected() {
+        Jail::expect_with(|jail| {
+            jail.create_file(
+                "bad_address.toml",
+                r#"
+                rpc_url = "http://localhost:8545"
+                ws_url = "ws://localhost:8546"
+                blacklist_tokens = ["0x1234"]
+                dry_run = true
+                "#,
+            )?;
+            assert!(Config::load(Path::new("bad_address.toml")).is_err());
+
+            jail.create_file(
+                "no_key.toml",
+                r#"
+                rpc_url = "http://localhost:8545"
+                ws_url = "ws://localhost:8546"
+                dry_run = false
+                "#,
+            )?;
+            assert!(Config::load(Path::new("no_key.toml")).is_err());
+
+            // A live run needs somewhere to send the arb to as well
+            let mut no_executor = sample();
+            no_executor.executor = H160::zero();
+            assert!(no_executor.validate().is_err());
+
+            // Fine without a key once nothing gets signed, which is the default
+            jail.create_file(
+                "defaults.toml",
+                r#"
+                rpc_url = "http://localhost:8545"
+                ws_url = "ws://localhost:8546"
+                "#,
+            )?;
+            assert!(Config::load(Path::new("defaults.toml")).unwrap().dry_run);
+            jail.set_env("MEV_DRY_RUN", true);
+            assert!(Config::load(Path::new("no_key.toml")).unwrap().dry_run);
+            Ok(())
+        });
+    }
+}
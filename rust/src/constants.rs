@@ -30,8 +30,9 @@ impl Env {
             https_url: get_env("HTTPS_URL"),
             wss_url: get_env("WSS_URL"),
             chain_id: U64::from_str(&get_env("CHAIN_ID")).unwrap(),
-            private_key: get_env("PRIVATE_KEY"),
-            signing_key: get_env("SIGNING_KEY"),
+            // keys may be left unset in dry-run mode, Bundler::new is what needs them
+            private_key: std::env::var("PRIVATE_KEY").unwrap_or_default(),
+            signing_key: std::env::var("SIGNING_KEY").unwrap_or_default(),
             bot_address: get_env("BOT_ADDRESS"),
         }
     }
@@ -0,0 +1,489 @@
+use anyhow::{anyhow, Result};
+use ethers::{
+    abi::parse_abi,
+    contract::BaseContract,
+    providers::Middleware,
+    types::{BlockId, BlockNumber, H160, U256},
+};
+use revm::{
+    db::{CacheDB, EthersDB},
+    primitives::{
+        AccountInfo, Address as rAddress, Bytes as rBytes, ExecutionResult, Output, TransactTo,
+        U256 as rU256,
+    },
+    EVM,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::utils::u256_to_f64;
+
+// 0.1 ETH: big enough to clear min-amount checks, small enough to not move most pools
+pub const PROBE_AMOUNT_WEI: u128 = 100_000_000_000_000_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct HoneypotResult {
+    pub token: H160,
+    // fraction of the expected amount lost on each leg, 0.05 = 5%
+    pub buy_tax: f64,
+    pub sell_tax: f64,
+    pub sellable: bool,
+}
+
+impl HoneypotResult {
+    /*
+    expected_* are router quotes (getAmountsOut, which knows nothing about transfer taxes),
+    received_* what actually arrived after the swap.
+    */
+    pub fn from_swaps(
+        token: H160,
+        expected_buy: U256,
+        received_buy: U256,
+        expected_sell: U256,
+        received_sell: Option<U256>,
+    ) -> Self {
+        let sell_tax = match received_sell {
+            Some(received) => tax(expected_sell, received),
+            None => 1.0,
+        };
+        Self {
+            token,
+            buy_tax: tax(expected_buy, received_buy),
+            sell_tax,
+            sellable: matches!(received_sell, Some(received) if !received.is_zero()),
+        }
+    }
+
+    pub fn is_safe(&self, max_tax: f64) -> bool {
+        self.sellable && self.buy_tax <= max_tax && self.sell_tax <= max_tax
+    }
+}
+
+/*
+check_token verdicts by token, reused for ttl so a token isn't re-simulated on every block.
+Holds at most capacity tokens: inserting past that evicts the least recently used one, where
+both inserting a verdict and reading one through check count as a use. is_safe and get only
+peek, they don't change the order.
+A token is safe when HoneypotResult::is_safe(max_tax) holds. The *_at variants take the
+current time.
+*/
+#[derive(Debug, Clone)]
+pub struct HoneypotCache {
+    ttl: Duration,
+    capacity: usize,
+    max_tax: f64,
+    // token -> (verdict, checked at, last use)
+    entries: HashMap<H160, (HoneypotResult, Instant, u64)>,
+    // last use -> token, oldest first
+    recency: BTreeMap<u64, H160>,
+    uses: u64,
+}
+
+impl HoneypotCache {
+    pub fn new(ttl: Duration, capacity: usize, max_tax: f64) -> Self {
+        Self {
+            ttl,
+            capacity: capacity.max(1),
+            max_tax,
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            uses: 0,
+        }
+    }
+
+    // The cached verdict, None if the token was never checked or its result expired
+    pub fn is_safe(&self, token: H160) -> Option<bool> {
+        self.is_safe_at(token, Instant::now())
+    }
+
+    pub fn is_safe_at(&self, token: H160, now: Instant) -> Option<bool> {
+        self.get_at(token, now)
+            .map(|result| result.is_safe(self.max_tax))
+    }
+
+    pub fn get(&self, token: H160) -> Option<&HoneypotResult> {
+        self.get_at(token, Instant::now())
+    }
+
+    pub fn get_at(&self, token: H160, now: Instant) -> Option<&HoneypotResult> {
+        match self.entries.get(&token) {
+            Some((result, checked_at, _)) if now.duration_since(*checked_at) < self.ttl => {
+                Some(result)
+            }
+            _ => None,
+        }
+    }
+
+    pub fn insert(&mut self, result: HoneypotResult) {
+        self.insert_at(result, Instant::now())
+    }
+
+    pub fn insert_at(&mut self, result: HoneypotResult, now: Instant) {
+        if !self.entries.contains_key(&result.token) && self.entries.len() >= self.capacity {
+            if let Some((_, oldest)) = self.recency.pop_first() {
+                self.entries.remove(&oldest);
+            }
+        }
+        let token = result.token;
+        let last_use = self.next_use();
+        if let Some((_, _, previous)) = self.entries.insert(token, (result, now, last_use)) {
+            self.recency.remove(&previous);
+        }
+        self.recency.insert(last_use, token);
+    }
+
+    // Moves token to the most recently used end
+    fn touch(&mut self, token: H160) {
+        let last_use = self.next_use();
+        if let Some((_, _, previous)) = self.entries.get_mut(&token) {
+            self.recency.remove(previous);
+            *previous = last_use;
+            self.recency.insert(last_use, token);
+        }
+    }
+
+    fn next_use(&mut self) -> u64 {
+        self.uses += 1;
+        self.uses
+    }
+
+    /*
+    Cached verdict for token, or probe(token) (e.g. check_token with a provider and router)
+    when there's none, caching what it returns. A failed probe isn't cached.
+    */
+    pub async fn check<F, Fut>(&mut self, token: H160, probe: F) -> Result<bool>
+    where
+        F: FnOnce(H160) -> Fut,
+        Fut: Future<Output = Result<HoneypotResult>>,
+    {
+        self.check_at(token, probe, Instant::now()).await
+    }
+
+    pub async fn check_at<F, Fut>(&mut self, token: H160, probe: F, now: Instant) -> Result<bool>
+    where
+        F: FnOnce(H160) -> Fut,
+        Fut: Future<Output = Result<HoneypotResult>>,
+    {
+        if let Some(safe) = self.is_safe_at(token, now) {
+            self.touch(token);
+            return Ok(safe);
+        }
+        let result = probe(token).await?;
+        let safe = result.is_safe(self.max_tax);
+        self.insert_at(result, now);
+        Ok(safe)
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+fn tax(expected: U256, received: U256) -> f64 {
+    if expected.is_zero() {
+        return 0.0;
+    }
+    (1.0 - u256_to_f64(received) / u256_to_f64(expected)).clamp(0.0, 1.0)
+}
+
+fn router_abi() -> BaseContract {
+    BaseContract::from(
+        parse_abi(&[
+            "function WETH() external view returns (address)",
+            "function getAmountsOut(uint amountIn, address[] path) external view returns (uint[] amounts)",
+            "function swapExactETHForTokensSupportingFeeOnTransferTokens(uint amountOutMin, address[] path, address to, uint deadline) external payable",
+            "function swapExactTokensForTokensSupportingFeeOnTransferTokens(uint amountIn, uint amountOutMin, address[] path, address to, uint deadline) external",
+        ])
+        .unwrap(),
+    )
+}
+
+fn erc20_abi() -> BaseContract {
+    BaseContract::from(
+        parse_abi(&[
+            "function balanceOf(address owner) external view returns (uint)",
+            "function approve(address spender, uint value) external returns (bool)",
+        ])
+        .unwrap(),
+    )
+}
+
+/*
+Buys PROBE_AMOUNT_WEI worth of token through a Uniswap V2 style router and sells everything
+straight back, on a local revm fork of the latest block. Nothing is sent on-chain.
+A sell that reverts or returns nothing marks the token as not sellable.
+*/
+pub async fn check_token<M: Middleware + 'static>(
+    provider: Arc<M>,
+    token: H160,
+    router: H160,
+) -> Result<HoneypotResult> {
+    let block = provider
+        .get_block(BlockNumber::Latest)
+        .await
+        .map_err(|e| anyhow!("get_block failed: {}", e))?
+        .ok_or_else(|| anyhow!("Latest block not found"))?;
+    let block_number = block
+        .number
+        .ok_or_else(|| anyhow!("Latest block has no number"))?;
+
+    // EthersDB fetches state lazily with block_in_place, so it has to run off the async executor
+    tokio::task::spawn_blocking(move || {
+        let db = EthersDB::new(provider, Some(BlockId::from(block_number)))
+            .ok_or_else(|| anyhow!("Failed to create fork at block {}", block_number))?;
+        let mut fork = Fork::new(CacheDB::new(db), block_number.as_u64(), block.timestamp);
+        fork.probe_token(token, router)
+    })
+    .await?
+}
+
+// Probing account, funded directly in the fork's state
+const PROBER: H160 = H160([
+    0x13, 0x37, 0x13, 0x37, 0x13, 0x37, 0x13, 0x37, 0x13, 0x37, 0x13, 0x37, 0x13, 0x37, 0x13, 0x37,
+    0x13, 0x37, 0x13, 0x37,
+]);
+
+struct Fork<M: Middleware + 'static> {
+    evm: EVM<CacheDB<EthersDB<M>>>,
+}
+
+impl<M: Middleware + 'static> Fork<M> {
+    fn new(mut db: CacheDB<EthersDB<M>>, block_number: u64, timestamp: U256) -> Self {
+        db.insert_account_info(
+            rAddress::from(PROBER.0),
+            AccountInfo {
+                balance: rU256::from(PROBE_AMOUNT_WEI) * rU256::from(10),
+                ..Default::default()
+            },
+        );
+
+        let mut evm = EVM::new();
+        evm.database(db);
+        evm.env.block.number = rU256::from(block_number);
+        evm.env.block.timestamp = rU256::from_limbs(timestamp.0);
+        evm.env.block.basefee = rU256::ZERO;
+        evm.env.tx.gas_price = rU256::ZERO;
+        evm.env.tx.gas_limit = 5_000_000;
+        Self { evm }
+    }
+
+    // Executes and commits a call from PROBER, None if it reverted or halted
+    fn call(&mut self, to: H160, data: Vec<u8>, value: U256) -> Result<Option<Vec<u8>>> {
+        self.evm.env.tx.caller = rAddress::from(PROBER.0);
+        self.evm.env.tx.transact_to = TransactTo::Call(rAddress::from(to.0));
+        self.evm.env.tx.data = rBytes::from(data);
+        self.evm.env.tx.value = rU256::from_limbs(value.0);
+
+        let result = self
+            .evm
+            .transact_commit()
+            .map_err(|e| anyhow!("EVM error: {:?}", e))?;
+        match result {
+            ExecutionResult::Success {
+                output: Output::Call(output),
+                ..
+            } => Ok(Some(output.to_vec())),
+            ExecutionResult::Success { .. } => Ok(Some(Vec::new())),
+            ExecutionResult::Revert { .. } | ExecutionResult::Halt { .. } => Ok(None),
+        }
+    }
+
+    fn view<T: ethers::abi::Detokenize>(
+        &mut self,
+        contract: &BaseContract,
+        to: H160,
+        name: &str,
+        args: impl ethers::abi::Tokenize,
+    ) -> Result<T> {
+        let data = contract.encode(name, args)?;
+        let output = self
+            .call(to, data.to_vec(), U256::zero())?
+            .ok_or_else(|| anyhow!("{} on {:?} reverted", name, to))?;
+        Ok(contract.decode_output(name, output)?)
+    }
+
+    fn probe_token(&mut self, token: H160, router: H160) -> Result<HoneypotResult> {
+        let router_abi = router_abi();
+        let erc20_abi = erc20_abi();
+        let amount_in = U256::from(PROBE_AMOUNT_WEI);
+        let deadline = U256::from(u64::MAX);
+
+        let weth: H160 = self.view(&router_abi, router, "WETH", ())?;
+        let buy_path = vec![weth, token];
+        let sell_path = vec![token, weth];
+
+        let quote: Vec<U256> = self.view(
+            &router_abi,
+            router,
+            "getAmountsOut",
+            (amount_in, buy_path.clone()),
+        )?;
+        let expected_buy = quote[quote.len() - 1];
+
+        let buy = router_abi.encode(
+            "swapExactETHForTokensSupportingFeeOnTransferTokens",
+            (U256::zero(), buy_path, PROBER, deadline),
+        )?;
+        if self.call(router, buy.to_vec(), amount_in)?.is_none() {
+            return Ok(HoneypotResult::from_swaps(
+                token,
+                expected_buy,
+                U256::zero(),
+                U256::zero(),
+                None,
+            ));
+        }
+        let received_buy: U256 = self.view(&erc20_abi, token, "balanceOf", (PROBER,))?;
+
+        let quote: Vec<U256> = self.view(
+            &router_abi,
+            router,
+            "getAmountsOut",
+            (received_buy, sell_path.clone()),
+        )?;
+        let expected_sell = quote[quote.len() - 1];
+
+        let approve = erc20_abi.encode("approve", (router, U256::MAX))?;
+        let sell = router_abi.encode(
+            "swapExactTokensForTokensSupportingFeeOnTransferTokens",
+            (received_buy, U256::zero(), sell_path, PROBER, deadline),
+        )?;
+        let sold = self.call(token, approve.to_vec(), U256::zero())?.is_some()
+            && self.call(router, sell.to_vec(), U256::zero())?.is_some();
+        let received_sell = if sold {
+            Some(self.view(&erc20_abi, weth, "balanceOf", (PROBER,))?)
+        } else {
+            None
+        };
+
+        Ok(HoneypotResult::from_swaps(
+            token,
+            expected_buy,
+            received_buy,
+            expected_sell,
+            received_sell,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod honeypot_tests {
+    use super::*;
+
+    /*
+    Amounts recorded for a token with a 5% tax on every transfer:
+    0.1 ETH was quoted at 1,000 tokens and 950 arrived, selling those 950 was quoted
+    at 0.095 WETH and 0.09025 WETH arrived.
+    */
+    #[test]
+    fn fee_on_transfer_fixture_reports_taxes() {
+        let token = H160::from_low_u64_be(1);
+        let unit = U256::exp10(18);
+        let finney = U256::exp10(15);
+
+        let result = HoneypotResult::from_swaps(
+            token,
+            unit * 1000,
+            unit * 950,
+            finney * 95,
+            Some(U256::from(90_250_000_000_000_000u128)),
+        );
+
+        assert!(result.sellable);
+        assert!((result.buy_tax - 0.05).abs() < 1e-9);
+        assert!((result.sell_tax - 0.05).abs() < 1e-9);
+        assert!(result.is_safe(0.1));
+        assert!(!result.is_safe(0.01));
+    }
+
+    #[tokio::test]
+    async fn cached_verdicts_are_reused_until_they_expire() {
+        let token = H160::from_low_u64_be(1);
+        let probes = std::sync::atomic::AtomicUsize::new(0);
+        let probe = |token: H160| {
+            probes.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            async move {
+                Ok::<_, anyhow::Error>(HoneypotResult {
+                    token,
+                    buy_tax: 0.0,
+                    sell_tax: 1.0,
+                    sellable: false,
+                })
+            }
+        };
+        let mut cache = HoneypotCache::new(Duration::from_secs(60), 16, 0.1);
+        let start = Instant::now();
+        assert_eq!(cache.is_safe_at(token, start), None);
+
+        assert!(!cache.check_at(token, probe, start).await.unwrap());
+        let later = start + Duration::from_secs(59);
+        assert!(!cache.check_at(token, probe, later).await.unwrap());
+        assert_eq!(cache.is_safe_at(token, later), Some(false));
+        assert_eq!(probes.load(std::sync::atomic::Ordering::SeqCst), 1);
+
+        // Using a verdict doesn't extend it
+        let expired = start + Duration::from_secs(60);
+        assert_eq!(cache.is_safe_at(token, expired), None);
+        assert!(!cache.check_at(token, probe, expired).await.unwrap());
+        assert_eq!(probes.load(std::sync::atomic::Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn full_cache_evicts_the_least_recently_used_token() {
+        let result = |token: u64| HoneypotResult {
+            token: H160::from_low_u64_be(token),
+            buy_tax: 0.0,
+            sell_tax: 0.0,
+            sellable: true,
+        };
+        let unreachable = |_: H160| async { Err(anyhow!("should have been cached")) };
+        let mut cache = HoneypotCache::new(Duration::from_secs(60), 2, 0.1);
+        cache.insert(result(1));
+        cache.insert(result(2));
+        // Reading 1 through check makes 2 the least recently used
+        assert!(cache
+            .check(H160::from_low_u64_be(1), unreachable)
+            .await
+            .unwrap());
+        // A peek doesn't
+        assert_eq!(cache.is_safe(H160::from_low_u64_be(2)), Some(true));
+        cache.insert(result(3));
+
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.is_safe(H160::from_low_u64_be(1)), Some(true));
+        assert_eq!(cache.is_safe(H160::from_low_u64_be(2)), None);
+        assert_eq!(cache.is_safe(H160::from_low_u64_be(3)), Some(true));
+
+        // Re-inserting a cached token counts as a use too
+        cache.insert(result(1));
+        cache.insert(result(4));
+        assert_eq!(cache.is_safe(H160::from_low_u64_be(1)), Some(true));
+        assert_eq!(cache.is_safe(H160::from_low_u64_be(3)), None);
+    }
+
+    #[test]
+    fn failed_sell_is_not_sellable() {
+        let unit = U256::exp10(18);
+        let result = HoneypotResult::from_swaps(
+            H160::from_low_u64_be(1),
+            unit * 1000,
+            unit * 1000,
+            unit,
+            None,
+        );
+
+        assert_eq!(result.buy_tax, 0.0);
+        assert_eq!(result.sell_tax, 1.0);
+        assert!(!result.sellable);
+        assert!(!result.is_safe(1.0));
+    }
+}
@@ -1,10 +1,16 @@
 pub mod abi;
 pub mod bundler;
+pub mod cli;
+pub mod config;
 pub mod constants;
+pub mod honeypot;
+pub mod metrics;
 pub mod multi;
+pub mod output;
 pub mod paths;
 pub mod pools;
 pub mod simulator;
 pub mod strategy;
 pub mod streams;
+pub mod submitter;
 pub mod utils;
@@ -1,43 +1,132 @@
 use anyhow::{Ok, Result};
-use ethers::providers::{Provider, Ws};
-use log::info;
+use ethers::providers::{Http, Provider};
+use log::{info, warn};
+use std::path::Path;
 use std::sync::Arc;
-use tokio::sync::broadcast::{self, Sender};
+use std::time::Duration;
+use tokio::sync::broadcast;
 use tokio::task::JoinSet;
+use tokio_util::sync::CancellationToken;
 
+use rust::bundler::Bundler;
+use rust::cli::{simulate, SimulateArgs};
+use rust::config::Config;
 use rust::constants::Env;
-use rust::strategy::event_handler;
+use rust::metrics;
+use rust::output::{JsonLines, OutputFormat};
+use rust::strategy::{event_handler, StrategyConfig};
 use rust::streams::{
-    stream_new_blocks, stream_pending_transactions, stream_uniswap_v2_events, Event,
+    broadcast_new_blocks, stream_pending_transactions, stream_uniswap_v2_events, EventQueue,
 };
-use rust::utils::setup_logger;
+use rust::submitter::{NullSubmitter, Submitter};
+use rust::utils::{init_tracing, init_tracing_stderr, ProviderSet};
 
 #[tokio::main]
 async fn main() -> Result<()> {
     dotenv::dotenv().ok();
-    setup_logger()?;
 
-    let env = Env::new();
+    // `simulate --pools <addr,addr,addr> --amount <n> [--weth <addr>]` prices one path and exits
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).map(String::as_str) == Some("simulate") {
+        init_tracing(tracing::Level::WARN)?;
+        let simulate_args = SimulateArgs::parse(&args[2..])?;
+        let provider = Provider::<Http>::try_from(Env::new().https_url)?;
+        let report = simulate(Arc::new(provider), &simulate_args).await?;
+        println!("{}", report);
+        return Ok(());
+    }
+
+    // --json (or OUTPUT_FORMAT=json) writes paths and opportunities as JSON lines to stdout,
+    // or to OUTPUT_FILE when set, and moves the logs to stderr
+    let format = if args.iter().skip(1).any(|arg| arg == "--json") {
+        OutputFormat::Json
+    } else {
+        match std::env::var("OUTPUT_FORMAT") {
+            std::result::Result::Ok(format) => format.parse()?,
+            Err(_) => OutputFormat::Human,
+        }
+    };
+    let json = match format {
+        OutputFormat::Json => {
+            init_tracing_stderr(tracing::Level::INFO)?;
+            Some(match std::env::var("OUTPUT_FILE") {
+                std::result::Result::Ok(file) => JsonLines::file(Path::new(&file))?,
+                Err(_) => JsonLines::stdout(),
+            })
+        }
+        OutputFormat::Human => {
+            init_tracing(tracing::Level::INFO)?;
+            None
+        }
+    };
+
+    // CONFIG_FILE defaults to config.toml (see config.example.toml), MEV_* variables override it
+    let config_file = std::env::var("CONFIG_FILE").unwrap_or_else(|_| "config.toml".to_string());
+    let config = Config::load(Path::new(&config_file))?;
+
+    // Prometheus scrape endpoint, METRICS_PORT defaults to 9000
+    let metrics_port = std::env::var("METRICS_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(9000);
+    metrics::install(metrics_port)?;
 
-    // Start async websocket streams
-    let ws = Ws::connect(env.wss_url).await?;
-    let provider = Arc::new(Provider::new(ws));
+    // Streams subscribe over the websocket, everything else goes over http
+    let providers = ProviderSet::connect(&config.rpc_url, &config.ws_url).await?;
 
-    let (event_sender, _): (Sender<Event>, _) = broadcast::channel(512);
+    // Pending txs are shed once this many events are waiting, EVENT_QUEUE_CAPACITY defaults to 1024
+    let queue_capacity = std::env::var("EVENT_QUEUE_CAPACITY")
+        .ok()
+        .and_then(|capacity| capacity.parse().ok())
+        .unwrap_or(1024);
+    let (queue, events) = EventQueue::new(queue_capacity);
 
     let mut set = JoinSet::new();
 
-    set.spawn(stream_new_blocks(provider.clone(), event_sender.clone()));
+    set.spawn(broadcast_new_blocks(providers.ws.clone(), queue.clone()));
     // we're not using the mempool data here, but uncomment it to use pending txs
-    // set.spawn(stream_pending_transactions(
-    //     provider.clone(),
-    //     event_sender.clone(),
-    // ));
-    set.spawn(event_handler(provider.clone(), event_sender.clone()));
-
-    while let Some(res) = set.join_next().await {
-        info!("{:?}", res);
+    // set.spawn(stream_pending_transactions(providers.ws.clone(), queue.clone()));
+    // kill -USR1 <pid> resets a tripped circuit breaker, kept out of set so it can't end the run
+    #[cfg(unix)]
+    tokio::spawn(rust::streams::reset_breaker_on_signal(queue.clone()));
+    let cfg = StrategyConfig {
+        json,
+        ..config.strategy_config()
+    };
+    // Would-be bundles are only logged unless dry_run is off, keys are only read for live runs
+    let submitter: Box<dyn Submitter> = if cfg.dry_run {
+        Box::new(NullSubmitter)
+    } else {
+        cfg.submitter
+            .build(Bundler::from_env(config.bundler_env()).with_rpc_timeout(cfg.rpc_timeout))
+    };
+    // Detected opportunities, subscribe here to feed dashboards or alerts
+    let (opportunities, _) = broadcast::channel(256);
+    let shutdown = CancellationToken::new();
+    let strategy = tokio::spawn(event_handler(
+        providers.clone(),
+        events,
+        opportunities.clone(),
+        submitter,
+        cfg,
+        shutdown.clone(),
+    ));
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => info!("Ctrl-C received, shutting down"),
+        res = set.join_next() => info!("Stream task ended: {:?}", res),
+    }
+
+    // The strategy finishes the block it's on (and any submission in flight) before returning
+    shutdown.cancel();
+    if tokio::time::timeout(Duration::from_secs(30), strategy)
+        .await
+        .is_err()
+    {
+        warn!("Strategy did not stop within 30s");
     }
+    // Dropping the stream tasks unsubscribes and closes the websocket
+    set.shutdown().await;
 
     Ok(())
 }
@@ -1,95 +1,984 @@
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, Ok, Result};
 use ethers::{
-    abi,
-    providers::{Http, Provider},
-    types::{H160, H256, U256},
+    abi::{self, ParamType},
+    providers::Middleware,
+    types::{Block, BlockId, Filter, Log, H160, H256, U256},
+    utils::{id, keccak256},
+};
+use futures::stream::{self, StreamExt};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, VecDeque},
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
 };
-use ethers_contract::{Contract, Multicall};
-use log::info;
-use std::{collections::HashMap, sync::Arc, time::Instant};
 
-use crate::{abi::ABI, pools::Pool};
+use crate::{
+    metrics,
+    pools::{aggregate3, decode_aggregate3_output, Pool},
+    utils::{with_timeout, RpcCallError, RpcTimeout},
+};
 
-#[derive(Default, Debug, Clone)]
+#[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Reserve {
     pub reserve0: U256,
     pub reserve1: U256,
+    // Uniswap V3 state, left at zero for V2 pools
+    pub sqrt_price_x96: U256,
+    pub liquidity: u128,
+    pub tick: i32,
+    // block the reserves were read at
+    pub block_number: u64,
 }
 
-pub async fn get_uniswap_v2_reserves(
-    https_url: String,
+/*
+Reserves of every pool in one aggregate3 multicall, all read at the same block.
+block pins the calls to a historical block, None reads at the latest one.
+Works over any transport, HTTP is the usual choice (see utils::ProviderSet).
+*/
+pub async fn get_uniswap_v2_reserves<M: Middleware + 'static>(
+    client: Arc<M>,
     pools: Vec<Pool>,
+    block: Option<u64>,
 ) -> Result<HashMap<H160, Reserve>> {
-    let client = Provider::<Http>::try_from(https_url)?;
-    let client = Arc::new(client);
+    let block_number = resolve_block(client.as_ref(), block).await?;
+    get_uniswap_v2_reserves_at(client, &pools, block_number, pools.len()).await
+}
 
-    let abi = ABI::new();
-    let mut multicall = Multicall::new(client.clone(), None).await?;
+async fn resolve_block<M: Middleware>(client: &M, block: Option<u64>) -> Result<u64> {
+    match block {
+        Some(block_number) => Ok(block_number),
+        None => client
+            .get_block_number()
+            .await
+            .map(|block_number| block_number.as_u64())
+            .map_err(|e| RpcCallError::new("get_block_number", &e).into()),
+    }
+}
 
-    for pool in &pools {
-        let contract = Contract::<Provider<Http>>::new(
-            pool.address,
-            abi.uniswap_v2_pair.clone(),
-            client.clone(),
-        );
-        let call = contract.method::<_, H256>("getReserves", ())?;
-        multicall.add_call(call, false);
+#[derive(Debug, Clone)]
+pub struct ReserveFetchConfig {
+    // pools per multicall
+    pub chunk_size: usize,
+    // multicalls in flight at once
+    pub max_concurrency: usize,
+    // retries per multicall on transport/timeout errors, waiting base_delay_ms * 2^attempt
+    pub max_retries: u32,
+    pub base_delay_ms: u64,
+    // deadline of every multicall attempt, a call that hasn't answered by then is retried
+    pub call_timeout: Duration,
+}
+
+impl Default for ReserveFetchConfig {
+    fn default() -> Self {
+        Self {
+            chunk_size: 1000,
+            max_concurrency: 8,
+            max_retries: 3,
+            base_delay_ms: 200,
+            call_timeout: Duration::from_secs(10),
+        }
+    }
+}
+
+// Every chunk is read at the same block, block as in get_uniswap_v2_reserves
+pub async fn batch_get_uniswap_v2_reserves<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pools: Vec<Pool>,
+    config: &ReserveFetchConfig,
+    block: Option<u64>,
+) -> Result<HashMap<H160, Reserve>> {
+    let start_time = Instant::now();
+
+    let block_number = resolve_block(provider.as_ref(), block).await?;
+    let reserves = fetch_reserves_in_chunks(pools, config, |chunk| {
+        get_uniswap_v2_reserves(provider.clone(), chunk, Some(block_number))
+    })
+    .await?;
+    metrics::record_reserve_refresh(start_time.elapsed());
+
+    info!(
+        "Batch reserves call took: {} seconds",
+        start_time.elapsed().as_secs()
+    );
+    Ok(reserves)
+}
+
+/*
+Splits pools into chunks of chunk_size and issues one fetch (a multicall) per chunk,
+keeping at most max_concurrency of them in flight, then merges the results into a single map.
+Each fetch is cut off after config.call_timeout and retried on timeouts and other transient
+errors (see with_retry).
+If any chunk fails, the error lists the pool addresses that were in that chunk.
+*/
+pub async fn fetch_reserves_in_chunks<F, Fut>(
+    pools: Vec<Pool>,
+    config: &ReserveFetchConfig,
+    fetch: F,
+) -> Result<HashMap<H160, Reserve>>
+where
+    F: Fn(Vec<Pool>) -> Fut,
+    Fut: Future<Output = Result<HashMap<H160, Reserve>>>,
+{
+    let fetch = &fetch;
+    let calls = pools.chunks(config.chunk_size.max(1)).map(|chunk| {
+        let addresses: Vec<H160> = chunk.iter().map(|pool| pool.address).collect();
+        let chunk = chunk.to_vec();
+        async move {
+            let operation = format!("Reserve fetch of {} pools", chunk.len());
+            let result = with_retry(config, || {
+                with_timeout(&operation, config.call_timeout, fetch(chunk.clone()))
+            })
+            .await;
+            (addresses, result)
+        }
+    });
+    let results: Vec<_> = stream::iter(calls)
+        .buffer_unordered(config.max_concurrency.max(1))
+        .collect()
+        .await;
+
+    let mut reserves: HashMap<H160, Reserve> = HashMap::new();
+    for (addresses, result) in results {
+        let chunk_reserves = result.map_err(|e| {
+            e.context(format!(
+                "Reserve fetch failed for chunk of {} pools: {:?}",
+                addresses.len(),
+                addresses
+            ))
+        })?;
+        reserves.extend(chunk_reserves);
+    }
+    Ok(reserves)
+}
+
+/*
+Runs op until it succeeds, retrying up to config.max_retries times with exponential backoff.
+Only transport/timeout failures are retried: reverts and decode errors won't change on retry.
+*/
+pub async fn with_retry<T, F, Fut>(config: &ReserveFetchConfig, mut op: F) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        match op().await {
+            std::result::Result::Ok(value) => return Ok(value),
+            Err(e) if attempt < config.max_retries && is_transient_error(&e) => {
+                let delay = config.base_delay_ms.saturating_mul(1 << attempt.min(16));
+                attempt += 1;
+                warn!(
+                    "Retrying RPC call ({}/{}) in {} ms: {:#}",
+                    attempt, config.max_retries, delay, e
+                );
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+            }
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/*
+Whether a failure is worth retrying, going by the error types in its chain: timeouts,
+RpcCallErrors marked transient, and transport errors. Anything else (reverts, decode
+errors, untyped errors) is final.
+*/
+pub fn is_transient_error(e: &anyhow::Error) -> bool {
+    e.chain().any(|cause| {
+        if let Some(e) = cause.downcast_ref::<RpcCallError>() {
+            return e.transient;
+        }
+        if let Some(e) = cause.downcast_ref::<reqwest::Error>() {
+            return e.is_timeout()
+                || e.is_connect()
+                || e.status()
+                    .is_some_and(|status| status.as_u16() == 429 || status.is_server_error());
+        }
+        cause.downcast_ref::<RpcTimeout>().is_some()
+            || cause
+                .downcast_ref::<tokio::time::error::Elapsed>()
+                .is_some()
+            || cause.downcast_ref::<std::io::Error>().is_some()
+    })
+}
+
+/*
+Applies Uniswap V2 Sync(uint112,uint112) logs to the reserve map in place.
+Logs are applied in the given order, so the last Sync of a pool wins.
+Logs of other events and of pools missing from the map are ignored.
+*/
+pub fn apply_sync_logs(reserves: &mut HashMap<H160, Reserve>, logs: &[Log]) {
+    let sync_topic = H256::from(keccak256("Sync(uint112,uint112)"));
+
+    for log in logs {
+        if log.topics.first() != Some(&sync_topic) {
+            continue;
+        }
+        let reserve = match reserves.get_mut(&log.address) {
+            Some(reserve) => reserve,
+            None => continue,
+        };
+        if let std::result::Result::Ok(data) =
+            abi::decode(&[ParamType::Uint(112), ParamType::Uint(112)], &log.data)
+        {
+            if let (Some(reserve0), Some(reserve1)) =
+                (data[0].clone().into_uint(), data[1].clone().into_uint())
+            {
+                reserve.reserve0 = reserve0;
+                reserve.reserve1 = reserve1;
+                if let Some(block_number) = log.block_number {
+                    reserve.block_number = block_number.as_u64();
+                }
+            }
+        }
+    }
+}
+
+/*
+The last few blocks of reserve updates, kept so they can be undone when a block is reorged out.
+Each entry holds the reserves its block overwrote, so rolling back restores exactly the state
+before that block. Only depth blocks are kept, a reorg deeper than that needs a full refetch.
+*/
+#[derive(Debug, Clone)]
+pub struct ReserveHistory {
+    depth: usize,
+    blocks: VecDeque<(u64, HashMap<H160, Reserve>)>,
+    // last block applied, retained or not
+    head: Option<u64>,
+}
+
+impl ReserveHistory {
+    pub fn new(depth: usize) -> Self {
+        Self {
+            depth: depth.max(1),
+            blocks: VecDeque::new(),
+            head: None,
+        }
+    }
+
+    // apply_sync_logs for the logs of block_number, remembering what they overwrote
+    pub fn apply_sync_logs(
+        &mut self,
+        reserves: &mut HashMap<H160, Reserve>,
+        block_number: u64,
+        logs: &[Log],
+    ) {
+        let mut previous = HashMap::new();
+        for log in logs {
+            if let Some(reserve) = reserves.get(&log.address) {
+                previous
+                    .entry(log.address)
+                    .or_insert_with(|| reserve.clone());
+            }
+        }
+        apply_sync_logs(reserves, logs);
+        self.record(block_number, previous);
+    }
+
+    /*
+    Overwrites the reserves of the pools in updates that are already in reserves (e.g. the
+    output of get_touched_pool_reserves for block_number), remembering what they overwrote.
+    Returns those pools.
+    */
+    pub fn apply_reserves(
+        &mut self,
+        reserves: &mut HashMap<H160, Reserve>,
+        block_number: u64,
+        updates: HashMap<H160, Reserve>,
+    ) -> Vec<H160> {
+        let mut previous = HashMap::new();
+        let mut touched = Vec::new();
+        for (pool, reserve) in updates {
+            if let Some(old) = reserves.insert(pool, reserve) {
+                previous.insert(pool, old);
+                touched.push(pool);
+            } else {
+                reserves.remove(&pool);
+            }
+        }
+        self.record(block_number, previous);
+        touched
+    }
+
+    // Logs of a block seen again extend that block's entry, earlier values win
+    fn record(&mut self, block_number: u64, previous: HashMap<H160, Reserve>) {
+        match self.blocks.back_mut() {
+            Some((last, overwritten)) if *last == block_number => {
+                for (pool, reserve) in previous {
+                    overwritten.entry(pool).or_insert(reserve);
+                }
+            }
+            _ => {
+                self.blocks.push_back((block_number, previous));
+                if self.blocks.len() > self.depth {
+                    self.blocks.pop_front();
+                }
+            }
+        }
+        self.head = Some(block_number);
     }
 
-    let result = multicall.call_raw().await?;
+    /*
+    Undoes every block after `block`, newest first, so reserves are back to how they were
+    at the end of `block`. Returns the pools that were restored.
+    Errors without touching reserves if a block after `block` was applied but is no longer
+    retained (the reorg is deeper than the history, or it was cleared).
+    */
+    pub fn rollback_to(
+        &mut self,
+        reserves: &mut HashMap<H160, Reserve>,
+        block: u64,
+    ) -> Result<Vec<H160>> {
+        match self.head {
+            Some(head) if head > block => {}
+            _ => return Ok(Vec::new()),
+        }
+        match self.blocks.front() {
+            Some((oldest, _)) if *oldest <= block.saturating_add(1) => {}
+            Some((oldest, _)) => {
+                return Err(anyhow!(
+                    "Reserve history starts at block {}, can't roll back to {}",
+                    oldest,
+                    block
+                ))
+            }
+            None => {
+                return Err(anyhow!(
+                    "Reserve history is empty, can't roll back to {}",
+                    block
+                ))
+            }
+        }
 
+        let mut restored = Vec::new();
+        while matches!(self.blocks.back(), Some((last, _)) if *last > block) {
+            let (_, overwritten) = self.blocks.pop_back().unwrap();
+            for (pool, reserve) in overwritten {
+                reserves.insert(pool, reserve);
+                if !restored.contains(&pool) {
+                    restored.push(pool);
+                }
+            }
+        }
+        self.head = Some(block);
+        Ok(restored)
+    }
+
+    /*
+    Forgets every retained block, for when reserves were replaced wholesale (e.g. refetched
+    after a reconnect). Rolling back past that point errors from then on.
+    */
+    pub fn clear(&mut self) {
+        self.blocks.clear();
+    }
+}
+
+/*
+Applies the Sync logs emitted in `block` and returns the pools whose reserves changed.
+Logs are requested by block hash, so a header from a block that gets reorged out
+can't pull in logs from its replacement.
+*/
+pub async fn refresh_reserves_at_block<M: Middleware + 'static>(
+    provider: &M,
+    block: &Block<H256>,
+    reserves: &mut HashMap<H160, Reserve>,
+) -> Result<Vec<H160>> {
+    let block_hash = block
+        .hash
+        .ok_or_else(|| anyhow!("Block {:?} has no hash yet", block.number))?;
+    let filter = Filter::new()
+        .at_block_hash(block_hash)
+        .topic0(H256::from(keccak256("Sync(uint112,uint112)")));
+    let logs = provider
+        .get_logs(&filter)
+        .await
+        .map_err(|e| anyhow!("get_logs failed for block {:?}: {}", block_hash, e))?;
+
+    let mut touched = Vec::new();
+    for log in &logs {
+        if reserves.contains_key(&log.address) && !touched.contains(&log.address) {
+            touched.push(log.address);
+        }
+    }
+    apply_sync_logs(reserves, &logs);
+
+    Ok(touched)
+}
+
+/*
+Decodes the aggregate3 output of one getReserves() call per pool, in pool order.
+Every call is decoded on its own: a pool whose call reverted or returned something that isn't
+(uint112, uint112, uint32) (e.g. a stale address that's no longer a pair) ends up in the
+returned Vec instead of the map, so callers can prune it. Only an undecodable aggregate3
+output as a whole is an error.
+*/
+pub fn decode_reserves(
+    pools: &[Pool],
+    output: &[u8],
+    block_number: u64,
+) -> Result<(HashMap<H160, Reserve>, Vec<H160>)> {
+    let results = decode_aggregate3_output(output)?;
+    Ok(reserves_from_results(pools, &results, block_number))
+}
+
+// decode_reserves for the already decoded results, see aggregate3
+fn reserves_from_results(
+    pools: &[Pool],
+    results: &[Option<Vec<u8>>],
+    block_number: u64,
+) -> (HashMap<H160, Reserve>, Vec<H160>) {
     let mut reserves = HashMap::new();
+    let mut failed = Vec::new();
 
-    for i in 0..result.len() {
-        let pool = &pools[i];
-        let reserve = result[i].clone();
-        match reserve.unwrap() {
-            abi::Token::Tuple(response) => {
-                let reserve_data = Reserve {
-                    reserve0: response[0].clone().into_uint().unwrap(),
-                    reserve1: response[1].clone().into_uint().unwrap(),
-                };
-                reserves.insert(pool.address.clone(), reserve_data);
+    for (i, pool) in pools.iter().enumerate() {
+        let values = results.get(i).cloned().flatten().and_then(|data| {
+            abi::decode(
+                &[
+                    ParamType::Uint(112),
+                    ParamType::Uint(112),
+                    ParamType::Uint(32),
+                ],
+                &data,
+            )
+            .ok()
+        });
+        match values {
+            Some(values) => {
+                reserves.insert(
+                    pool.address,
+                    Reserve {
+                        reserve0: values[0].clone().into_uint().unwrap_or_default(),
+                        reserve1: values[1].clone().into_uint().unwrap_or_default(),
+                        block_number,
+                        ..Default::default()
+                    },
+                );
             }
-            _ => {}
+            None => failed.push(pool.address),
         }
     }
+    (reserves, failed)
+}
+
+/*
+Reserves of every pool as of the end of block_number, read with Multicall3's aggregate3
+pinned to that block (needs an archive node for anything but recent blocks).
+Pools whose getReserves reverts or doesn't decode are logged and left out of the map, which is
+how they're reported back: see missing_reserves.
+*/
+pub async fn get_uniswap_v2_reserves_at<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pools: &[Pool],
+    block_number: u64,
+    chunk_size: usize,
+) -> Result<HashMap<H160, Reserve>> {
+    let selector = id("getReserves()").to_vec();
+    let mut reserves = HashMap::new();
+
+    for chunk in pools.chunks(chunk_size.max(1)) {
+        let calls = chunk
+            .iter()
+            .map(|pool| (pool.address, selector.clone()))
+            .collect();
+        let results = aggregate3(provider.as_ref(), calls, Some(BlockId::from(block_number)))
+            .await
+            .map_err(|e| e.context(format!("getReserves at block {}", block_number)))?;
+
+        let (chunk_reserves, failed) = reserves_from_results(chunk, &results, block_number);
+        if !failed.is_empty() {
+            warn!(
+                "getReserves failed for {} pools at block {}: {:?}",
+                failed.len(),
+                block_number,
+                failed
+            );
+        }
+        reserves.extend(chunk_reserves);
+    }
 
     Ok(reserves)
 }
 
-pub async fn batch_get_uniswap_v2_reserves(
-    https_url: String,
-    pools: Vec<Pool>,
-) -> HashMap<H160, Reserve> {
-    let start_time = Instant::now();
+/*
+The pools of a fetch that came back without reserves, in pool order: their getReserves
+reverted or didn't decode (see decode_reserves), e.g. addresses that are no longer pairs.
+They won't price anything until they're refetched successfully, so callers prune them.
+*/
+pub fn missing_reserves(pools: &[Pool], reserves: &HashMap<H160, Reserve>) -> Vec<H160> {
+    pools
+        .iter()
+        .map(|pool| pool.address)
+        .filter(|address| !reserves.contains_key(address))
+        .collect()
+}
 
-    let pools_cnt = pools.len();
-    let batch = ((pools_cnt / 250) as f32).ceil();
-    let pools_per_batch = ((pools_cnt as f32) / batch).ceil() as usize;
+#[cfg(test)]
+mod multi_tests {
+    use super::*;
+    use crate::pools::test_pool;
+    use crate::utils::get_touched_pool_reserves;
+    use ethers::abi::Token;
+    use ethers::providers::{HttpClientError, JsonRpcError, Provider, ProviderError};
+    use ethers::types::{transaction::eip2718::TypedTransaction, Bytes, TransactionRequest, U64};
+    use ethers_contract::MULTICALL_ADDRESS;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
 
-    let mut handles = vec![];
+    fn pools(cnt: u64) -> Vec<Pool> {
+        (0..cnt)
+            .map(|i| test_pool(i + 1, 1_000_001, 1_000_002))
+            .collect()
+    }
 
-    for i in 0..(batch as usize) {
-        let start_idx = i * pools_per_batch;
-        let end_idx = std::cmp::min(start_idx + pools_per_batch, pools_cnt);
-        let handle = tokio::spawn(get_uniswap_v2_reserves(
-            https_url.clone(),
-            pools[start_idx..end_idx].to_vec(),
-        ));
-        handles.push(handle);
+    fn config(chunk_size: usize, max_concurrency: usize) -> ReserveFetchConfig {
+        ReserveFetchConfig {
+            chunk_size,
+            max_concurrency,
+            max_retries: 3,
+            base_delay_ms: 1,
+            call_timeout: Duration::from_secs(1),
+        }
     }
 
-    let mut reserves: HashMap<H160, Reserve> = HashMap::new();
+    // Stands in for the multicall provider: answers every pool in the chunk
+    async fn mock_multicall(chunk: Vec<Pool>) -> Result<HashMap<H160, Reserve>> {
+        Ok(chunk
+            .iter()
+            .map(|pool| {
+                let reserve = Reserve {
+                    reserve0: U256::from(pool.address.to_low_u64_be()),
+                    reserve1: U256::from(1),
+                    ..Default::default()
+                };
+                (pool.address, reserve)
+            })
+            .collect())
+    }
 
-    for handle in handles {
-        let result = handle.await.unwrap();
-        reserves.extend(result.unwrap());
+    #[tokio::test]
+    async fn reserves_are_fetched_in_chunks() {
+        let calls = AtomicUsize::new(0);
+        let chunk_sizes = std::sync::Mutex::new(Vec::new());
+
+        let reserves = fetch_reserves_in_chunks(pools(2500), &config(1000, 1), |chunk| {
+            calls.fetch_add(1, Ordering::SeqCst);
+            chunk_sizes.lock().unwrap().push(chunk.len());
+            mock_multicall(chunk)
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(calls.load(Ordering::SeqCst), 3);
+        assert_eq!(*chunk_sizes.lock().unwrap(), vec![1000, 1000, 500]);
+        assert_eq!(reserves.len(), 2500);
+        assert_eq!(
+            reserves[&H160::from_low_u64_be(2500)].reserve0,
+            U256::from(2500)
+        );
     }
 
-    info!(
-        "Batch reserves call took: {} seconds",
-        start_time.elapsed().as_secs()
-    );
-    reserves
+    #[tokio::test]
+    async fn chunks_are_fetched_concurrently_up_to_the_limit() {
+        // Virtual time: the sleeps below advance the clock instantly, so the timings are exact
+        tokio::time::pause();
+        let starts = Arc::new(std::sync::Mutex::new(Vec::new()));
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let max_in_flight = Arc::new(AtomicUsize::new(0));
+
+        let start_time = tokio::time::Instant::now();
+        let reserves = fetch_reserves_in_chunks(pools(400), &config(100, 2), |chunk| {
+            let starts = starts.clone();
+            let in_flight = in_flight.clone();
+            let max_in_flight = max_in_flight.clone();
+            async move {
+                starts
+                    .lock()
+                    .unwrap()
+                    .push(tokio::time::Instant::now() - start_time);
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                max_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+                mock_multicall(chunk).await
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(reserves.len(), 400);
+        assert_eq!(max_in_flight.load(Ordering::SeqCst), 2);
+
+        // Two chunks start right away, the other two once a slot frees up: 2 rounds, not 4
+        let mut starts = starts.lock().unwrap().clone();
+        starts.sort();
+        assert_eq!(starts.len(), 4);
+        assert_eq!(starts[0], starts[1]);
+        assert!(starts[2] >= starts[0] + Duration::from_millis(100));
+        assert_eq!(starts[2], starts[3]);
+        assert!(start_time.elapsed() < Duration::from_millis(300));
+    }
+
+    #[tokio::test]
+    async fn failed_chunk_error_lists_its_pools() {
+        let result = fetch_reserves_in_chunks(pools(4), &config(2, 2), |chunk| async move {
+            if chunk
+                .iter()
+                .any(|pool| pool.address == H160::from_low_u64_be(3))
+            {
+                Err(anyhow::anyhow!("execution reverted"))
+            } else {
+                mock_multicall(chunk).await
+            }
+        })
+        .await;
+
+        let message = format!("{:#}", result.unwrap_err());
+        assert!(message.contains(&format!("{:?}", H160::from_low_u64_be(3))));
+        assert!(message.contains(&format!("{:?}", H160::from_low_u64_be(4))));
+        assert!(!message.contains(&format!("{:?}", H160::from_low_u64_be(1))));
+        assert!(message.contains("execution reverted"));
+    }
+
+    #[tokio::test]
+    async fn transient_failures_are_retried() {
+        let attempts = AtomicUsize::new(0);
+
+        let reserves = fetch_reserves_in_chunks(pools(10), &config(1000, 1), |chunk| {
+            let attempt = attempts.fetch_add(1, Ordering::SeqCst);
+            async move {
+                if attempt < 2 {
+                    // A node answering with JSON-RPC's "limit exceeded"
+                    let e = ProviderError::from(HttpClientError::JsonRpcError(JsonRpcError {
+                        code: -32005,
+                        message: "request rate exceeded".to_string(),
+                        data: None,
+                    }));
+                    Err(RpcCallError::new("aggregate3", &e).into())
+                } else {
+                    mock_multicall(chunk).await
+                }
+            }
+        })
+        .await
+        .unwrap();
+
+        assert_eq!(attempts.load(Ordering::SeqCst), 3);
+        assert_eq!(reserves.len(), 10);
+    }
+
+    #[tokio::test]
+    async fn reverts_are_not_retried() {
+        let attempts = AtomicUsize::new(0);
+
+        let result =
+            fetch_reserves_in_chunks(pools(10), &config(1000, 1), |_| {
+                attempts.fetch_add(1, Ordering::SeqCst);
+                let e = ProviderError::from(HttpClientError::JsonRpcError(JsonRpcError {
+                    code: 3,
+                    message: "execution reverted".to_string(),
+                    data: None,
+                }));
+                async move {
+                    Err::<HashMap<H160, Reserve>, _>(RpcCallError::new("aggregate3", &e).into())
+                }
+            })
+            .await;
+
+        assert!(result.is_err());
+        assert_eq!(attempts.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn hung_calls_time_out() {
+        let attempts = AtomicUsize::new(0);
+        let config = ReserveFetchConfig {
+            max_retries: 1,
+            call_timeout: Duration::from_millis(50),
+            ..config(1000, 1)
+        };
+
+        // A provider that never answers
+        let started = Instant::now();
+        let result = fetch_reserves_in_chunks(pools(10), &config, |_| {
+            attempts.fetch_add(1, Ordering::SeqCst);
+            futures::future::pending::<Result<HashMap<H160, Reserve>>>()
+        })
+        .await;
+
+        let error = result.unwrap_err();
+        let timeout = error.downcast_ref::<RpcTimeout>().unwrap();
+        assert_eq!(timeout.operation, "Reserve fetch of 10 pools");
+        assert_eq!(timeout.deadline, Duration::from_millis(50));
+        // Timeouts are transient, so the call gets its one retry
+        assert_eq!(attempts.load(Ordering::SeqCst), 2);
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[test]
+    fn sync_logs_update_known_pools() {
+        let pair = H160::from_str("0x397FF1542f962076d0BFE58eA045FfA2d347ACa0").unwrap();
+        let unknown = H160::from_low_u64_be(42);
+        let mut reserves = HashMap::new();
+        reserves.insert(pair, Reserve::default());
+
+        // Sync(27_000_000 USDC, 14_500 WETH)
+        let reserve0 = U256::from(27_000_000u64) * U256::exp10(6);
+        let reserve1 = U256::from(14_500u64) * U256::exp10(18);
+        let data = abi::encode(&[abi::Token::Uint(reserve0), abi::Token::Uint(reserve1)]);
+        let sync_topic =
+            H256::from_str("0x1c411e9a96e071241c2f21f7726b17ae89e3cab4c78be50e062b03a9fffbbad1")
+                .unwrap();
+
+        let log = Log {
+            address: pair,
+            topics: vec![sync_topic],
+            data: data.clone().into(),
+            block_number: Some(18_000_000u64.into()),
+            ..Default::default()
+        };
+        let unknown_log = Log {
+            address: unknown,
+            ..log.clone()
+        };
+        let other_event = Log {
+            topics: vec![H256::zero()],
+            data: abi::encode(&[abi::Token::Uint(1.into()), abi::Token::Uint(1.into())]).into(),
+            ..log.clone()
+        };
+
+        apply_sync_logs(&mut reserves, &[log, unknown_log, other_event]);
+
+        assert_eq!(reserves.len(), 1);
+        assert_eq!(reserves[&pair].reserve0, reserve0);
+        assert_eq!(reserves[&pair].reserve1, reserve1);
+        assert_eq!(reserves[&pair].block_number, 18_000_000);
+    }
+
+    #[test]
+    fn rollback_restores_the_reserves_before_the_orphaned_block() {
+        let pair = H160::from_low_u64_be(1);
+        let other = H160::from_low_u64_be(2);
+        let sync = |pool: H160, reserve0: u64, reserve1: u64, block: u64| Log {
+            address: pool,
+            topics: vec![H256::from(keccak256("Sync(uint112,uint112)"))],
+            data: abi::encode(&[
+                abi::Token::Uint(reserve0.into()),
+                abi::Token::Uint(reserve1.into()),
+            ])
+            .into(),
+            block_number: Some(block.into()),
+            ..Default::default()
+        };
+        let mut reserves = HashMap::from([(pair, Reserve::default()), (other, Reserve::default())]);
+        let mut history = ReserveHistory::new(8);
+
+        history.apply_sync_logs(&mut reserves, 100, &[sync(pair, 10, 20, 100)]);
+        let after_n = reserves.clone();
+        history.apply_sync_logs(
+            &mut reserves,
+            101,
+            &[
+                sync(pair, 11, 19, 101),
+                sync(other, 5, 5, 101),
+                sync(pair, 12, 18, 101),
+            ],
+        );
+        assert_eq!(reserves[&pair].reserve0, U256::from(12));
+
+        let restored = history.rollback_to(&mut reserves, 100).unwrap();
+        assert_eq!(reserves, after_n);
+        assert_eq!(restored.len(), 2);
+
+        // Nothing after 100 is left to undo, going back to 99 undoes block 100 as well
+        assert!(history.rollback_to(&mut reserves, 100).unwrap().is_empty());
+        assert_eq!(history.rollback_to(&mut reserves, 99).unwrap(), vec![pair]);
+        assert_eq!(reserves[&pair], Reserve::default());
+        // Blocks before 100 were never recorded, so they can't be undone
+        assert!(history.rollback_to(&mut reserves, 99).unwrap().is_empty());
+        assert!(history.rollback_to(&mut reserves, 50).is_err());
+        assert_eq!(reserves[&pair], Reserve::default());
+
+        let mut shallow = ReserveHistory::new(1);
+        shallow.apply_sync_logs(&mut reserves, 100, &[sync(pair, 10, 20, 100)]);
+        shallow.apply_sync_logs(&mut reserves, 101, &[sync(pair, 11, 19, 101)]);
+        assert!(shallow.rollback_to(&mut reserves, 99).is_err());
+        assert_eq!(reserves[&pair].reserve0, U256::from(11));
+    }
+
+    #[test]
+    fn fetched_reserves_can_be_rolled_back_until_cleared() {
+        let pair = H160::from_low_u64_be(1);
+        let unknown = H160::from_low_u64_be(2);
+        let reserve = |reserve0: u64, block_number: u64| Reserve {
+            reserve0: reserve0.into(),
+            reserve1: 1.into(),
+            block_number,
+            ..Default::default()
+        };
+        let mut reserves = HashMap::from([(pair, reserve(10, 100))]);
+        let mut history = ReserveHistory::new(8);
+
+        let touched = history.apply_reserves(
+            &mut reserves,
+            101,
+            HashMap::from([(pair, reserve(11, 101)), (unknown, reserve(5, 101))]),
+        );
+        assert_eq!(touched, vec![pair]);
+        assert!(!reserves.contains_key(&unknown));
+
+        assert_eq!(history.rollback_to(&mut reserves, 100).unwrap(), vec![pair]);
+        assert_eq!(reserves[&pair], reserve(10, 100));
+
+        history.apply_reserves(
+            &mut reserves,
+            101,
+            HashMap::from([(pair, reserve(12, 101))]),
+        );
+        history.clear();
+        assert!(history.rollback_to(&mut reserves, 100).is_err());
+        assert_eq!(reserves[&pair], reserve(12, 101));
+    }
+
+    #[tokio::test]
+    async fn new_block_refreshes_touched_reserves() {
+        let (provider, mock) = Provider::mocked();
+        let pair = H160::from_low_u64_be(1);
+        let mut reserves = HashMap::from([(pair, Reserve::default())]);
+
+        let sync_topic = H256::from(keccak256("Sync(uint112,uint112)"));
+        let log = Log {
+            address: pair,
+            topics: vec![sync_topic],
+            data: abi::encode(&[abi::Token::Uint(5.into()), abi::Token::Uint(7.into())]).into(),
+            block_number: Some(100u64.into()),
+            ..Default::default()
+        };
+        let unknown_log = Log {
+            address: H160::from_low_u64_be(2),
+            ..log.clone()
+        };
+        mock.push::<Vec<Log>, _>(vec![log.clone(), unknown_log, log])
+            .unwrap();
+
+        let block = Block::<H256> {
+            number: Some(100u64.into()),
+            hash: Some(H256::from_low_u64_be(0xabc)),
+            ..Default::default()
+        };
+        let touched = refresh_reserves_at_block(&provider, &block, &mut reserves)
+            .await
+            .unwrap();
+
+        let filter = Filter::new()
+            .at_block_hash(H256::from_low_u64_be(0xabc))
+            .topic0(sync_topic);
+        mock.assert_request("eth_getLogs", [&filter]).unwrap();
+        assert_eq!(touched, vec![pair]);
+        assert_eq!(reserves[&pair].reserve0, U256::from(5));
+        assert_eq!(reserves[&pair].reserve1, U256::from(7));
+        assert_eq!(reserves[&pair].block_number, 100);
+    }
+
+    #[tokio::test]
+    async fn touched_reserves_are_read_through_any_middleware() {
+        let (provider, mock) = Provider::mocked();
+        let pair = H160::from_low_u64_be(1);
+        let log = Log {
+            address: pair,
+            topics: vec![H256::from(keccak256("Sync(uint112,uint112)"))],
+            data: abi::encode(&[abi::Token::Uint(5.into()), abi::Token::Uint(7.into())]).into(),
+            transaction_index: Some(U64::zero()),
+            ..Default::default()
+        };
+        mock.push::<Vec<Log>, _>(vec![log]).unwrap();
+
+        let reserves = get_touched_pool_reserves(Arc::new(provider), U64::from(100))
+            .await
+            .unwrap();
+
+        assert_eq!(reserves[&pair].reserve0, U256::from(5));
+        assert_eq!(reserves[&pair].reserve1, U256::from(7));
+        assert_eq!(reserves[&pair].block_number, 100);
+    }
+
+    #[test]
+    fn non_pair_addresses_are_reported_instead_of_failing_the_batch() {
+        let pools = pools(3);
+        let reserves_data = |reserve0: u64, reserve1: u64| {
+            abi::encode(&[
+                Token::Uint(reserve0.into()),
+                Token::Uint(reserve1.into()),
+                Token::Uint(0.into()),
+            ])
+        };
+        // The middle address answers getReserves() with something that isn't reserves
+        let output = abi::encode(&[Token::Array(vec![
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(reserves_data(5, 7))]),
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(vec![0xde, 0xad])]),
+            Token::Tuple(vec![Token::Bool(true), Token::Bytes(reserves_data(11, 13))]),
+        ])]);
+
+        let (reserves, failed) = decode_reserves(&pools, &output, 100).unwrap();
+
+        assert_eq!(failed, vec![pools[1].address]);
+        // What the fetchers hand back is enough to tell which pools failed
+        assert_eq!(missing_reserves(&pools, &reserves), failed);
+        assert_eq!(reserves.len(), 2);
+        assert_eq!(reserves[&pools[0].address].reserve0, U256::from(5));
+        assert_eq!(reserves[&pools[2].address].reserve1, U256::from(13));
+        assert_eq!(reserves[&pools[2].address].block_number, 100);
+
+        // Reverted calls are reported the same way, a garbled batch is still an error
+        let output = abi::encode(&[Token::Array(vec![Token::Tuple(vec![
+            Token::Bool(false),
+            Token::Bytes(vec![]),
+        ])])]);
+        let (reserves, failed) = decode_reserves(&pools[..1], &output, 100).unwrap();
+        assert!(reserves.is_empty());
+        assert_eq!(failed, vec![pools[0].address]);
+        assert!(decode_reserves(&pools, &[0xde, 0xad], 100).is_err());
+    }
+
+    #[tokio::test]
+    async fn historical_reserves_are_pinned_to_the_block() {
+        let (provider, mock) = Provider::mocked();
+        let pool = pools(1).remove(0);
+        let return_data = abi::encode(&[
+            Token::Uint(5.into()),
+            Token::Uint(7.into()),
+            Token::Uint(0.into()),
+        ]);
+        let output = abi::encode(&[Token::Array(vec![Token::Tuple(vec![
+            Token::Bool(true),
+            Token::Bytes(return_data),
+        ])])]);
+        mock.push::<Bytes, _>(output.into()).unwrap();
+
+        let reserves =
+            get_uniswap_v2_reserves(Arc::new(provider), vec![pool.clone()], Some(17_000_000))
+                .await
+                .unwrap();
+
+        // One eth_call, tagged with the block instead of "latest"
+        let call = Token::Tuple(vec![
+            Token::Address(pool.address),
+            Token::Bool(true),
+            Token::Bytes(id("getReserves()").to_vec()),
+        ]);
+        let mut calldata = id("aggregate3((address,bool,bytes)[])").to_vec();
+        calldata.extend(abi::encode(&[Token::Array(vec![call])]));
+        let tx: TypedTransaction = TransactionRequest::new()
+            .to(MULTICALL_ADDRESS)
+            .data(calldata)
+            .into();
+        mock.assert_request("eth_call", (tx, BlockId::from(17_000_000u64)))
+            .unwrap();
+
+        assert_eq!(reserves[&pool.address].reserve0, U256::from(5));
+        assert_eq!(reserves[&pool.address].reserve1, U256::from(7));
+        assert_eq!(reserves[&pool.address].block_number, 17_000_000);
+    }
 }
@@ -0,0 +1,161 @@
+use anyhow::{anyhow, Result};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::Path,
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    // progress bar and log lines
+    #[default]
+    Human,
+    // one JSON object per line, nothing else on the stream
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "human" | "text" => Ok(Self::Human),
+            "json" => Ok(Self::Json),
+            other => Err(anyhow!(
+                "Unknown output format {:?}, expected human or json",
+                other
+            )),
+        }
+    }
+}
+
+/*
+Newline delimited JSON sink shared by everything that reports (path generation, the strategy
+loop), clones write to the same stream. Every emit writes and flushes one whole line, so
+concurrent writers never interleave inside an object.
+*/
+#[derive(Clone)]
+pub struct JsonLines {
+    writer: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+
+impl std::fmt::Debug for JsonLines {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("JsonLines")
+    }
+}
+
+impl JsonLines {
+    pub fn new(writer: impl Write + Send + 'static) -> Self {
+        Self {
+            writer: Arc::new(Mutex::new(Box::new(writer))),
+        }
+    }
+
+    pub fn stdout() -> Self {
+        Self::new(std::io::stdout())
+    }
+
+    // Creates (or truncates) path
+    pub fn file(path: &Path) -> Result<Self> {
+        let file = File::create(path).map_err(|e| anyhow!("Can't create {:?}: {}", path, e))?;
+        Ok(Self::new(BufWriter::new(file)))
+    }
+
+    pub fn emit<T: Serialize>(&self, item: &T) -> Result<()> {
+        let mut line = serde_json::to_vec(item)?;
+        line.push(b'\n');
+        let mut writer = self
+            .writer
+            .lock()
+            .map_err(|_| anyhow!("JSON output writer poisoned"))?;
+        writer.write_all(&line)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod output_tests {
+    use super::*;
+    use crate::paths::ArbPath;
+    use crate::pools::{test_pool, Pool};
+    use crate::strategy::Opportunity;
+    use ethers::types::{H160, I256, U256};
+
+    // Write half of a shared buffer, so the test can read back what was emitted
+    #[derive(Clone, Default)]
+    struct Buffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, data: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(data);
+            Ok(data.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn pool(address: u64, token0: u64, token1: u64) -> Pool {
+        Pool {
+            decimals1: 6,
+            ..test_pool(address, token0, token1)
+        }
+    }
+
+    #[test]
+    fn every_line_parses_back() {
+        let buffer = Buffer::default();
+        let output = JsonLines::new(buffer.clone());
+
+        let paths = vec![
+            ArbPath::new(
+                vec![pool(11, 1, 2), pool(12, 2, 3), pool(13, 3, 1)],
+                vec![true, true, true],
+            ),
+            ArbPath::new(
+                vec![pool(13, 3, 1), pool(12, 2, 3), pool(11, 1, 2)],
+                vec![false, false, false],
+            ),
+        ];
+        let opportunity = Opportunity::new(
+            1,
+            U256::exp10(18),
+            U256::exp10(18) * 2,
+            I256::from(-5),
+            17_000_000,
+        );
+        for path in &paths {
+            output.emit(path).unwrap();
+        }
+        // Clones share the stream
+        output.clone().emit(&opportunity).unwrap();
+
+        let written = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = written.lines().collect();
+        assert_eq!(lines.len(), 3);
+        for (line, path) in lines.iter().zip(&paths) {
+            let parsed: ArbPath = serde_json::from_str(line).unwrap();
+            assert_eq!(parsed.pools, path.pools);
+            assert_eq!(parsed.zero_for_one, path.zero_for_one);
+        }
+        let parsed: Opportunity = serde_json::from_str(lines[2]).unwrap();
+        assert_eq!(parsed, opportunity);
+        assert!(written.ends_with('\n'));
+    }
+
+    #[test]
+    fn output_format_parses_from_flags_and_env() {
+        assert_eq!("json".parse::<OutputFormat>().unwrap(), OutputFormat::Json);
+        assert_eq!(
+            "Human".parse::<OutputFormat>().unwrap(),
+            OutputFormat::Human
+        );
+        assert!("yaml".parse::<OutputFormat>().is_err());
+    }
+}
@@ -1,98 +1,757 @@
-use ethers::types::{H160, U256};
-use indicatif::{ProgressBar, ProgressStyle};
-use itertools::Itertools;
-use std::{collections::HashMap, time::Instant};
+use anyhow::{anyhow, Result};
+use ethers::{
+    types::{H160, I256, U256},
+    utils::format_units,
+};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::info;
+use rand::{rngs::StdRng, seq::index, SeedableRng};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+    str::FromStr,
+    time::Instant,
+};
 
 use crate::bundler::PathParam;
+use crate::honeypot::HoneypotCache;
 use crate::multi::Reserve;
-use crate::pools::Pool;
-use crate::simulator::UniswapV2Simulator;
+use crate::pools::{rank_pools, DexKind, Pool};
+use crate::simulator::{
+    CurveSimulator, StableSwapSimulator, UniswapV2Simulator, UniswapV3Simulator, WeightedSimulator,
+    PIPS,
+};
+use crate::utils::u256_to_f64;
 
-#[derive(Debug, Clone)]
+// Gas of the executor call itself (intrinsic cost, calldata, loop), on top of gas_per_hop
+pub const BASE_ARB_GAS: u64 = 50_000;
+// WETH deposit() and transfer() to the executor before the arb, each its own tx with its 21k
+// base cost
+pub const WRAP_GAS: u64 = 100_000;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct ArbPath {
     pub nhop: u8,
-    pub pool_1: Pool,
-    pub pool_2: Pool,
-    pub pool_3: Pool,
-    pub zero_for_one_1: bool,
-    pub zero_for_one_2: bool,
-    pub zero_for_one_3: bool,
+    pub pools: Vec<Pool>,
+    pub zero_for_one: Vec<bool>,
+    // Capital is held as ETH: wrap it and hand it to the executor first (see build_arb_txs)
+    #[serde(default)]
+    pub requires_wrap: bool,
 }
 
 impl ArbPath {
+    pub fn new(pools: Vec<Pool>, zero_for_one: Vec<bool>) -> Self {
+        assert_eq!(pools.len(), zero_for_one.len());
+        Self {
+            nhop: pools.len() as u8,
+            pools,
+            zero_for_one,
+            requires_wrap: false,
+        }
+    }
+
+    pub fn new_triangular(
+        pool_1: Pool,
+        pool_2: Pool,
+        pool_3: Pool,
+        zero_for_one_1: bool,
+        zero_for_one_2: bool,
+        zero_for_one_3: bool,
+    ) -> Self {
+        Self::new(
+            vec![pool_1, pool_2, pool_3],
+            vec![zero_for_one_1, zero_for_one_2, zero_for_one_3],
+        )
+    }
+
     pub fn has_pool(&self, pool: &H160) -> bool {
-        let is_pool_1 = self.pool_1.address == *pool;
-        let is_pool_2 = self.pool_2.address == *pool;
-        let is_pool_3 = self.pool_3.address == *pool;
-        return is_pool_1 || is_pool_2 || is_pool_3;
+        self.pools.iter().any(|p| p.address == *pool)
     }
 
     pub fn _get_pool(&self, i: u8) -> &Pool {
-        match i {
-            0 => Some(&self.pool_1),
-            1 => Some(&self.pool_2),
-            2 => Some(&self.pool_3),
-            _ => None,
-        }
-        .unwrap()
+        &self.pools[i as usize]
     }
 
     pub fn _get_zero_for_one(&self, i: u8) -> bool {
-        match i {
-            0 => Some(self.zero_for_one_1),
-            1 => Some(self.zero_for_one_2),
-            2 => Some(self.zero_for_one_3),
-            _ => None,
+        self.zero_for_one[i as usize]
+    }
+
+    /*
+    Tokens in swap order, input token first and last: nhop + 1 entries.
+    Each hop goes token0 -> token1 when zero_for_one, like simulate_hop.
+    */
+    pub fn token_path(&self) -> Vec<H160> {
+        let mut tokens = Vec::with_capacity(self.pools.len() + 1);
+        for (i, (pool, &zero_for_one)) in self.pools.iter().zip(&self.zero_for_one).enumerate() {
+            let (token_in, token_out) = if zero_for_one {
+                (pool.token0, pool.token1)
+            } else {
+                (pool.token1, pool.token0)
+            };
+            if i == 0 {
+                tokens.push(token_in);
+            }
+            tokens.push(token_out);
         }
-        .unwrap()
+        tokens
+    }
+
+    // Same cycle traded the other way: pools in reverse order, every direction flipped
+    pub fn reverse(&self) -> ArbPath {
+        ArbPath {
+            requires_wrap: self.requires_wrap,
+            ..ArbPath::new(
+                self.pools.iter().rev().cloned().collect(),
+                self.zero_for_one.iter().rev().map(|z| !z).collect(),
+            )
+        }
+    }
+
+    /*
+    A real cycle: every hop swaps between two different tokens, each hop takes the token
+    the previous one produced, the last hop returns the first hop's input token, and no
+    pool is used twice.
+    */
+    pub fn is_valid_cycle(&self) -> bool {
+        if self.pools.is_empty() || self.pools.len() != self.zero_for_one.len() {
+            return false;
+        }
+        if self.pools.iter().any(|pool| pool.token0 == pool.token1) {
+            return false;
+        }
+        let mut seen = HashSet::new();
+        if !self.pools.iter().all(|pool| seen.insert(pool.address)) {
+            return false;
+        }
+
+        let mut current = None;
+        for (pool, &zero_for_one) in self.pools.iter().zip(&self.zero_for_one) {
+            let (token_in, token_out) = if zero_for_one {
+                (pool.token0, pool.token1)
+            } else {
+                (pool.token1, pool.token0)
+            };
+            if matches!(current, Some(token) if token != token_in) {
+                return false;
+            }
+            current = Some(token_out);
+        }
+        current == self.token_path().first().copied()
+    }
+
+    /*
+    One line summary like "WETH -[UniV2]-> USDC -[UniV3/500]-> WETH".
+    Symbols come from the pools' symbol0/symbol1, then from `symbols`, and fall back
+    to a shortened address.
+    */
+    pub fn describe(&self, symbols: &HashMap<H160, String>) -> String {
+        let symbol_of = |token: H160| -> String {
+            let from_pool = self.pools.iter().find_map(|pool| {
+                if pool.token0 == token && !pool.symbol0.is_empty() {
+                    Some(pool.symbol0.clone())
+                } else if pool.token1 == token && !pool.symbol1.is_empty() {
+                    Some(pool.symbol1.clone())
+                } else {
+                    None
+                }
+            });
+            from_pool
+                .or_else(|| symbols.get(&token).cloned())
+                .unwrap_or_else(|| short_address(&token))
+        };
+
+        let tokens = self.token_path();
+        let mut out = symbol_of(tokens[0]);
+        for (pool, token) in self.pools.iter().zip(&tokens[1..]) {
+            let dex = if pool.stable {
+                format!("{}-stable", pool.dex)
+            } else {
+                pool.dex.to_string()
+            };
+            out.push_str(&format!(" -[{}]-> {}", dex, symbol_of(*token)));
+        }
+        out
+    }
+
+    /*
+    The state every hop would be simulated against, one line per hop: pool address, dex, fee,
+    direction, then both reserves scaled by the tokens' decimals (V3 pools show sqrt price,
+    liquidity and tick instead) and the block they were read at. For debugging reverts and
+    odd simulations, not meant to be parsed.
+    */
+    pub fn dump_reserves(&self, reserves: &HashMap<H160, Reserve>) -> String {
+        let name = |symbol: &str, token: &H160| {
+            if symbol.is_empty() {
+                short_address(token)
+            } else {
+                symbol.to_string()
+            }
+        };
+        let mut lines = Vec::with_capacity(self.pools.len());
+        for (i, (pool, &zero_for_one)) in self.pools.iter().zip(&self.zero_for_one).enumerate() {
+            let (token0, token1) = (
+                name(&pool.symbol0, &pool.token0),
+                name(&pool.symbol1, &pool.token1),
+            );
+            let direction = if zero_for_one {
+                format!("{} -> {}", token0, token1)
+            } else {
+                format!("{} -> {}", token1, token0)
+            };
+            let state = match reserves.get(&pool.address) {
+                Some(reserve) => match pool.dex {
+                    DexKind::UniswapV3 { .. } => format!(
+                        "sqrt_price_x96 {} liquidity {} tick {} (block {})",
+                        reserve.sqrt_price_x96,
+                        reserve.liquidity,
+                        reserve.tick,
+                        reserve.block_number
+                    ),
+                    _ => format!(
+                        "reserve0 {} {} reserve1 {} {} (block {})",
+                        format_units(reserve.reserve0, pool.decimals0 as u32)
+                            .unwrap_or_else(|_| reserve.reserve0.to_string()),
+                        token0,
+                        format_units(reserve.reserve1, pool.decimals1 as u32)
+                            .unwrap_or_else(|_| reserve.reserve1.to_string()),
+                        token1,
+                        reserve.block_number
+                    ),
+                },
+                None => "no reserves".to_string(),
+            };
+            lines.push(format!(
+                "hop {}: {:?} {}{} fee {}% {}: {}",
+                i,
+                pool.address,
+                pool.dex,
+                if pool.stable { "-stable" } else { "" },
+                pool.fee_pips() as f64 / 10_000.0,
+                direction,
+                state
+            ));
+        }
+        lines.join("\n")
     }
 
     pub fn should_blacklist(&self, blacklist_tokens: &Vec<H160>) -> bool {
         for i in 0..self.nhop {
             let pool = self._get_pool(i);
-            return blacklist_tokens.contains(&pool.token0)
-                || blacklist_tokens.contains(&pool.token1);
+            if blacklist_tokens.contains(&pool.token0) || blacklist_tokens.contains(&pool.token1) {
+                return true;
+            }
         }
         false
     }
 
+    /*
+    Token the path starts and ends in (its first hop's input), the base token it was
+    generated for. Profits are in this token's smallest unit.
+    */
+    pub fn base_token(&self) -> H160 {
+        match (self.pools.first(), self.zero_for_one.first()) {
+            (Some(pool), Some(true)) => pool.token0,
+            (Some(pool), Some(false)) => pool.token1,
+            _ => H160::zero(),
+        }
+    }
+
+    // Sorted pool addresses: identical for a cycle and its reverse/rotations (up to 3 hops)
+    pub fn canonical_key(&self) -> (H160, H160, H160) {
+        let mut addresses: Vec<H160> = self.pools.iter().map(|p| p.address).collect();
+        addresses.resize(3, H160::zero());
+        addresses.sort();
+        (addresses[0], addresses[1], addresses[2])
+    }
+
+    // True if any hop's reserve is missing or was read more than max_age blocks ago
+    pub fn reserves_stale(
+        &self,
+        reserves: &HashMap<H160, Reserve>,
+        current_block: u64,
+        max_age: u64,
+    ) -> bool {
+        self.pools
+            .iter()
+            .any(|pool| match reserves.get(&pool.address) {
+                Some(reserve) => current_block.saturating_sub(reserve.block_number) > max_age,
+                None => true,
+            })
+    }
+
+    pub fn _token_in_decimals(&self) -> u8 {
+        if self.zero_for_one[0] {
+            self.pools[0].decimals0
+        } else {
+            self.pools[0].decimals1
+        }
+    }
+
+    /*
+    amount_in is in the smallest unit (wei) of the path's input token, and the returned
+    amount is in the smallest unit of the last hop's output token. No decimal scaling
+    happens here, so profits of paths with different input tokens are only comparable
+    after converting them into a common token.
+    */
     pub fn simulate_v2_path(
         &self,
         amount_in: U256,
         reserves: &HashMap<H160, Reserve>,
     ) -> Option<U256> {
-        let token_in_decimals = if self.zero_for_one_1 {
-            self.pool_1.decimals0
-        } else {
-            self.pool_1.decimals1
+        let amounts = self.simulate_v2_path_verbose(amount_in, reserves)?;
+        amounts.last().cloned()
+    }
+
+    // Running amount_out after each hop, one entry per pool in the path
+    pub fn simulate_v2_path_verbose(
+        &self,
+        amount_in: U256,
+        reserves: &HashMap<H160, Reserve>,
+    ) -> Option<Vec<U256>> {
+        self.hop_amounts(amount_in, reserves, Self::simulate_hop, None)
+    }
+
+    /*
+    simulate_v2_path for cycles that go through the same pool more than once.
+    Each hop's swap is applied to a working copy of its pool's reserves with
+    UniswapV2Simulator::apply_swap, so a later hop through that pool sees the price the earlier
+    one left behind. reserves itself isn't modified.
+    Generated paths never repeat a pool, for those this equals simulate_v2_path.
+    Only constant product V2 pools move: stable, Curve, Balancer and V3 pools are priced from
+    the reserves they started with on every visit.
+    */
+    pub fn simulate_v2_path_sequential(
+        &self,
+        amount_in: U256,
+        reserves: &HashMap<H160, Reserve>,
+    ) -> Option<U256> {
+        let mut moved = HashMap::new();
+        self.hop_amounts(amount_in, reserves, Self::simulate_hop, Some(&mut moved))?
+            .last()
+            .cloned()
+    }
+
+    /*
+    Reserves of every pool of the path once amount_in has been swapped through it, computed the
+    same way as simulate_v2_path_sequential (a pool used twice moves twice). Only the path's
+    pools are returned, reserves itself isn't modified. Empty if the path can't be simulated.
+    */
+    pub fn resulting_reserves(
+        &self,
+        amount_in: U256,
+        reserves: &HashMap<H160, Reserve>,
+    ) -> HashMap<H160, Reserve> {
+        let mut moved = HashMap::new();
+        self.hop_amounts(amount_in, reserves, Self::simulate_hop, Some(&mut moved))
+            .map(|_| moved)
+            .unwrap_or_default()
+    }
+
+    /*
+    simulate_v2_path with the pricing of each hop swapped out: hop(pool, zero_for_one,
+    amount_in, reserve) is called for every pool in order and returns that hop's output.
+    Passing ArbPath::simulate_hop gives simulate_v2_path back.
+    Transfer taxes are still taken before each hop, they belong to the token, not the pool.
+    */
+    pub fn simulate_path_with<F>(
+        &self,
+        amount_in: U256,
+        reserves: &HashMap<H160, Reserve>,
+        hop: F,
+    ) -> Option<U256>
+    where
+        F: Fn(&Pool, bool, U256, &Reserve) -> Option<U256>,
+    {
+        self.hop_amounts(amount_in, reserves, hop, None)?
+            .last()
+            .cloned()
+    }
+
+    /*
+    The one hop loop behind every simulate_* method. With moved given, each hop's pool is
+    also written to it, moved by UniswapV2Simulator::apply_swap for constant product pools and
+    as it was for the others, and later hops read their pool from moved before reserves.
+    */
+    fn hop_amounts<F>(
+        &self,
+        amount_in: U256,
+        reserves: &HashMap<H160, Reserve>,
+        hop: F,
+        mut moved: Option<&mut HashMap<H160, Reserve>>,
+    ) -> Option<Vec<U256>>
+    where
+        F: Fn(&Pool, bool, U256, &Reserve) -> Option<U256>,
+    {
+        // A hop without liquidity kills the path, don't run any math before finding out
+        if !self.has_liquidity(reserves) {
+            return None;
+        }
+
+        let mut amount_out = amount_in;
+        let mut amounts = Vec::with_capacity(self.pools.len());
+        for (pool, &zero_for_one) in self.pools.iter().zip(&self.zero_for_one) {
+            let reserve = match moved.as_deref().and_then(|m| m.get(&pool.address)) {
+                Some(reserve) => reserve,
+                None => reserves.get(&pool.address)?,
+            };
+            // fee-on-transfer tokens shrink the amount on its way into the pool
+            let amount_in = pool.after_transfer_fee(zero_for_one, amount_out);
+            amount_out = hop(pool, zero_for_one, amount_in, reserve)?;
+            amounts.push(amount_out);
+
+            if moved.is_some() {
+                let mut reserve = reserve.clone();
+                if pool.dex == DexKind::UniswapV2 && !pool.stable {
+                    let fee = pool.fee_pips();
+                    if zero_for_one {
+                        (reserve.reserve0, reserve.reserve1) = UniswapV2Simulator::apply_swap(
+                            reserve.reserve0,
+                            reserve.reserve1,
+                            amount_in,
+                            fee,
+                        )?;
+                    } else {
+                        (reserve.reserve1, reserve.reserve0) = UniswapV2Simulator::apply_swap(
+                            reserve.reserve1,
+                            reserve.reserve0,
+                            amount_in,
+                            fee,
+                        )?;
+                    }
+                }
+                moved.as_deref_mut()?.insert(pool.address, reserve);
+            }
+        }
+
+        Some(amounts)
+    }
+
+    /*
+    Whether every hop has a reserve with both sides non-zero (sqrt price and liquidity for V3).
+    A missing reserve counts as no liquidity.
+    */
+    pub fn has_liquidity(&self, reserves: &HashMap<H160, Reserve>) -> bool {
+        self.pools
+            .iter()
+            .all(|pool| match (reserves.get(&pool.address), pool.dex) {
+                (None, _) => false,
+                (Some(reserve), DexKind::UniswapV3 { .. }) => {
+                    !reserve.sqrt_price_x96.is_zero() && reserve.liquidity != 0
+                }
+                (Some(reserve), _) => !reserve.reserve0.is_zero() && !reserve.reserve1.is_zero(),
+            })
+    }
+
+    pub fn simulate_hop(
+        pool: &Pool,
+        zero_for_one: bool,
+        amount_in: U256,
+        reserve: &Reserve,
+    ) -> Option<U256> {
+        match pool.dex {
+            DexKind::UniswapV2 => {
+                let (reserve_in, reserve_out, decimals_in, decimals_out) = if zero_for_one {
+                    (
+                        reserve.reserve0,
+                        reserve.reserve1,
+                        pool.decimals0,
+                        pool.decimals1,
+                    )
+                } else {
+                    (
+                        reserve.reserve1,
+                        reserve.reserve0,
+                        pool.decimals1,
+                        pool.decimals0,
+                    )
+                };
+                let fee = pool.fee_pips();
+                if pool.stable {
+                    StableSwapSimulator::get_amount_out(
+                        amount_in,
+                        reserve_in,
+                        reserve_out,
+                        decimals_in,
+                        decimals_out,
+                        fee,
+                    )
+                } else {
+                    UniswapV2Simulator::get_amount_out(amount_in, reserve_in, reserve_out, fee)
+                }
+            }
+            DexKind::UniswapV3 { fee_tier } => UniswapV3Simulator::get_amount_out(
+                amount_in,
+                reserve.sqrt_price_x96,
+                reserve.liquidity,
+                fee_tier,
+                zero_for_one,
+            ),
+            DexKind::Curve { amp } => {
+                let (i, j) = if zero_for_one { (0, 1) } else { (1, 0) };
+                CurveSimulator::get_dy_with_rates(
+                    i,
+                    j,
+                    amount_in,
+                    &[reserve.reserve0, reserve.reserve1],
+                    &[curve_rate(pool.decimals0)?, curve_rate(pool.decimals1)?],
+                    amp,
+                    // pips to Curve's 1e-10
+                    pool.fee_pips() as u64 * 10_000,
+                )
+            }
+            DexKind::Balancer { weight0, weight1 } => {
+                let (balance_in, weight_in, balance_out, weight_out) = if zero_for_one {
+                    (reserve.reserve0, weight0, reserve.reserve1, weight1)
+                } else {
+                    (reserve.reserve1, weight1, reserve.reserve0, weight0)
+                };
+                WeightedSimulator::get_amount_out(
+                    amount_in,
+                    balance_in,
+                    weight_in,
+                    balance_out,
+                    weight_out,
+                    pool.fee_pips(),
+                )
+            }
+        }
+    }
+
+    /*
+    Fractional gap between the marginal (spot) rate of the path and the rate actually
+    executed for amount_in:
+        spot      = prod_i (1 - fee_i) * p_i      (p_i: marginal price of hop i, out per in)
+        effective = amount_out / amount_in        (from simulate_v2_path_verbose)
+        impact    = 1 - effective / spot
+    Both rates are in raw token units, so decimals cancel out. Returns None if any
+    reserve is missing or the swap can't be simulated.
+    */
+    pub fn price_impact(&self, amount_in: U256, reserves: &HashMap<H160, Reserve>) -> Option<f64> {
+        if amount_in.is_zero() {
+            return None;
+        }
+
+        let mut spot = 1.0;
+        for (pool, &zero_for_one) in self.pools.iter().zip(&self.zero_for_one) {
+            let reserve = reserves.get(&pool.address)?;
+            spot *= Self::spot_rate(pool, zero_for_one, reserve)?;
+        }
+
+        let amounts = self.simulate_v2_path_verbose(amount_in, reserves)?;
+        let amount_out = *amounts.last()?;
+        let effective = u256_to_f64(amount_out) / u256_to_f64(amount_in);
+
+        Some(1.0 - effective / spot)
+    }
+
+    // Marginal output per unit of input for a single hop, fee included
+    fn spot_rate(pool: &Pool, zero_for_one: bool, reserve: &Reserve) -> Option<f64> {
+        let rate = match pool.dex {
+            DexKind::UniswapV2 => {
+                let (reserve_in, reserve_out) = if zero_for_one {
+                    (reserve.reserve0, reserve.reserve1)
+                } else {
+                    (reserve.reserve1, reserve.reserve0)
+                };
+                if reserve_in.is_zero() || reserve_out.is_zero() {
+                    return None;
+                }
+                let fee = 1.0 - (pool.fee_pips() as f64) / PIPS as f64;
+
+                if pool.stable {
+                    // dy/dx of x^3 * y + y^3 * x = k on 18-decimal normalized reserves
+                    let (decimals_in, decimals_out) = if zero_for_one {
+                        (pool.decimals0, pool.decimals1)
+                    } else {
+                        (pool.decimals1, pool.decimals0)
+                    };
+                    let x = u256_to_f64(reserve_in) / 10f64.powi(decimals_in as i32);
+                    let y = u256_to_f64(reserve_out) / 10f64.powi(decimals_out as i32);
+                    let normalized = (3.0 * x * x * y + y * y * y) / (x * x * x + 3.0 * x * y * y);
+                    fee * normalized * 10f64.powi(decimals_out as i32 - decimals_in as i32)
+                } else {
+                    fee * u256_to_f64(reserve_out) / u256_to_f64(reserve_in)
+                }
+            }
+            DexKind::UniswapV3 { fee_tier } => {
+                if reserve.sqrt_price_x96.is_zero() {
+                    return None;
+                }
+                let sqrt_price = u256_to_f64(reserve.sqrt_price_x96) / 2f64.powi(96);
+                let price = sqrt_price * sqrt_price;
+                let fee = 1.0 - (fee_tier as f64) / 1_000_000.0;
+                if zero_for_one {
+                    fee * price
+                } else {
+                    fee / price
+                }
+            }
+            DexKind::Curve { .. } => {
+                // Marginal rate taken from a swap of a millionth of the input side
+                let reserve_in = if zero_for_one {
+                    reserve.reserve0
+                } else {
+                    reserve.reserve1
+                };
+                let probe = reserve_in / 1_000_000;
+                if probe.is_zero() {
+                    return None;
+                }
+                let out = Self::simulate_hop(pool, zero_for_one, probe, reserve)?;
+                u256_to_f64(out) / u256_to_f64(probe)
+            }
+            DexKind::Balancer { .. } => {
+                let price = pool.price(reserve, zero_for_one);
+                if price <= 0.0 {
+                    return None;
+                }
+                // price() is decimal adjusted, the rate here is in raw units
+                let (decimals_in, decimals_out) = if zero_for_one {
+                    (pool.decimals0, pool.decimals1)
+                } else {
+                    (pool.decimals1, pool.decimals0)
+                };
+                let fee = 1.0 - (pool.fee_pips() as f64) / PIPS as f64;
+                fee * price * 10f64.powi(decimals_out as i32 - decimals_in as i32)
+            }
         };
-        let unit = U256::from(10).pow(U256::from(token_in_decimals));
-        let mut amount_out = amount_in * unit;
+        Some(rate)
+    }
+
+    /*
+    Closed form optimal input for the cycle, in the smallest unit of the input token.
+
+    Consecutive pools are folded into a single virtual pool (Ea, Eb):
+        Ea = Ea * a / (a + g * Eb)
+        Eb = g * Eb * b / (a + g * Eb)
+    where (a, b) are the next hop's (reserve_in, reserve_out) and g its fee multiplier.
+    Maximizing Eb * g1 * x / (Ea + g1 * x) - x gives:
+        x = (sqrt(Ea * Eb * g1) - Ea) / g1
+    with g1 being the fee multiplier of the first hop (997_000/1_000_000 for a 30bps pool).
+    */
+    pub fn optimal_amount_in(&self, reserves: &HashMap<H160, Reserve>) -> Option<U256> {
+        let denom = U256::from(PIPS);
+        let mut ea = U256::zero();
+        let mut eb = U256::zero();
+        let mut first_gamma = U256::zero();
 
         for i in 0..self.nhop {
             let pool = self._get_pool(i);
             let zero_for_one = self._get_zero_for_one(i);
-
             let reserve = reserves.get(&pool.address)?;
-            let reserve0 = reserve.reserve0;
-            let reserve1 = reserve.reserve1;
-            let fee = U256::from(pool.fee);
+            let (reserve_in, reserve_out) = if zero_for_one {
+                (reserve.reserve0, reserve.reserve1)
+            } else {
+                (reserve.reserve1, reserve.reserve0)
+            };
+            let gamma = denom.checked_sub(U256::from(pool.fee_pips()))?;
 
-            let reserve_in;
-            let reserve_out;
-            if zero_for_one {
-                reserve_in = reserve0;
-                reserve_out = reserve1;
+            if i == 0 {
+                ea = reserve_in;
+                eb = reserve_out;
+                first_gamma = gamma;
             } else {
-                reserve_in = reserve1;
-                reserve_out = reserve0;
+                let d = reserve_in
+                    .checked_mul(denom)?
+                    .checked_add(gamma.checked_mul(eb)?)?;
+                let next_ea = ea
+                    .checked_mul(reserve_in)?
+                    .checked_mul(denom)?
+                    .checked_div(d)?;
+                let next_eb = gamma
+                    .checked_mul(eb)?
+                    .checked_mul(reserve_out)?
+                    .checked_div(d)?;
+                ea = next_ea;
+                eb = next_eb;
             }
+        }
+
+        let root = ea
+            .checked_mul(eb)?
+            .checked_mul(first_gamma)?
+            .checked_div(denom)?
+            .integer_sqrt();
+        if root <= ea {
+            return None;
+        }
+
+        let optimal = (root - ea).checked_mul(denom)?.checked_div(first_gamma)?;
+        if optimal.is_zero() {
+            None
+        } else {
+            Some(optimal)
+        }
+    }
+
+    /*
+    amount_out - amount_in before gas, in the input token's smallest unit.
+    Negative when the path loses money at this size, None only if it can't be simulated.
+    */
+    pub fn gross_profit(&self, amount_in: U256, reserves: &HashMap<H160, Reserve>) -> Option<I256> {
+        let amount_out = self.simulate_v2_path(amount_in, reserves)?;
+        I256::try_from(amount_out)
+            .ok()?
+            .checked_sub(I256::try_from(amount_in).ok()?)
+    }
 
-            amount_out =
-                UniswapV2Simulator::get_amount_out(amount_out, reserve_in, reserve_out, fee)?;
+    /*
+    Profit after gas, in the smallest unit of the input token:
+        amount_out - amount_in - (gas_per_hop * nhop + BASE_ARB_GAS [+ WRAP_GAS]) * gas_price
+    token_in_per_eth is the reference price converting the gas bill (paid in ETH) into the
+    input token: how many of its smallest units 1 ETH is worth (1e18 for WETH paths).
+    WRAP_GAS is only added for paths with requires_wrap.
+    Negative when the path loses money, None if it can't be simulated.
+    */
+    pub fn net_profit(
+        &self,
+        amount_in: U256,
+        reserves: &HashMap<H160, Reserve>,
+        gas_price: U256,
+        gas_per_hop: u64,
+        token_in_per_eth: U256,
+    ) -> Option<I256> {
+        let gross = self.gross_profit(amount_in, reserves)?;
+        let mut gas = U256::from(gas_per_hop) * U256::from(self.nhop) + U256::from(BASE_ARB_GAS);
+        if self.requires_wrap {
+            gas += U256::from(WRAP_GAS);
         }
+        let gas_cost = gas
+            .checked_mul(gas_price)?
+            .checked_mul(token_in_per_eth)?
+            .checked_div(U256::exp10(18))?;
 
-        Some(amount_out)
+        gross.checked_sub(I256::try_from(gas_cost).ok()?)
+    }
+
+    /*
+    net_profit at each of inputs, in the input token's smallest unit, the gas arguments being
+    the same as net_profit's. None where the path can't be simulated.
+    */
+    pub fn profit_curve(
+        &self,
+        reserves: &HashMap<H160, Reserve>,
+        inputs: &[U256],
+        gas_price: U256,
+        gas_per_hop: u64,
+        token_in_per_eth: U256,
+    ) -> Vec<Option<I256>> {
+        inputs
+            .iter()
+            .map(|&amount_in| {
+                self.net_profit(
+                    amount_in,
+                    reserves,
+                    gas_price,
+                    gas_per_hop,
+                    token_in_per_eth,
+                )
+            })
+            .collect()
     }
 
     pub fn optimize_amount_in(
@@ -100,22 +759,17 @@ impl ArbPath {
         max_amount_in: U256,
         step_size: usize,
         reserves: &HashMap<H160, Reserve>,
-    ) -> (U256, U256) {
-        let token_in_decimals = if self.zero_for_one_1 {
-            self.pool_1.decimals0
-        } else {
-            self.pool_1.decimals1
-        };
+    ) -> (U256, I256) {
+        // max_amount_in and the returned amount are in whole tokens, profit is in wei
+        let token_in_decimals = self._token_in_decimals();
+        let unit = U256::from(10).pow(U256::from(token_in_decimals));
 
         let mut optimized_in = U256::zero();
-        let mut profit = 0;
+        let mut profit = I256::zero();
 
         for amount_in in (0..max_amount_in.as_u64()).step_by(step_size) {
             let amount_in = U256::from(amount_in);
-            let unit = U256::from(10).pow(U256::from(token_in_decimals));
-            if let Some(amount_out) = self.simulate_v2_path(amount_in, &reserves) {
-                let this_profit =
-                    (amount_out.as_u128() as i128) - ((amount_in * unit).as_u128() as i128);
+            if let Some(this_profit) = self.gross_profit(amount_in * unit, reserves) {
                 if this_profit >= profit {
                     optimized_in = amount_in;
                     profit = this_profit;
@@ -125,7 +779,7 @@ impl ArbPath {
             }
         }
 
-        (optimized_in, U256::from(profit))
+        (optimized_in, profit)
     }
 
     pub fn to_path_params(&self, routers: &Vec<H160>) -> Vec<PathParam> {
@@ -155,13 +809,261 @@ impl ArbPath {
     }
 }
 
-pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPath> {
+/*
+Builds an ArbPath hop by hop, checking that every hop takes the token the previous one produced:
+    ArbPathBuilder::new().hop(weth_usdc, true).hop(usdc_weth, false).build()?
+The first bad hop is remembered and reported by build(), later hops are ignored.
+*/
+#[derive(Debug, Default)]
+pub struct ArbPathBuilder {
+    pools: Vec<Pool>,
+    zero_for_one: Vec<bool>,
+    token_out: Option<H160>,
+    error: Option<String>,
+}
+
+impl ArbPathBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hop(mut self, pool: Pool, zero_for_one: bool) -> Self {
+        if self.error.is_some() {
+            return self;
+        }
+        let (token_in, token_out) = if zero_for_one {
+            (pool.token0, pool.token1)
+        } else {
+            (pool.token1, pool.token0)
+        };
+        if let Some(previous_out) = self.token_out {
+            if previous_out != token_in {
+                self.error = Some(format!(
+                    "hop {} through pool {:?} takes {:?}, but the previous hop outputs {:?}",
+                    self.pools.len(),
+                    pool.address,
+                    token_in,
+                    previous_out
+                ));
+                return self;
+            }
+        }
+        self.token_out = Some(token_out);
+        self.pools.push(pool);
+        self.zero_for_one.push(zero_for_one);
+        self
+    }
+
+    pub fn build(self) -> Result<ArbPath> {
+        if let Some(error) = self.error {
+            return Err(anyhow!(error));
+        }
+        if self.pools.is_empty() {
+            return Err(anyhow!("ArbPath needs at least one hop"));
+        }
+        Ok(ArbPath::new(self.pools, self.zero_for_one))
+    }
+}
+
+/*
+The cycle trading through pools in the given order, directions worked out from the tokens:
+the input token is the first pool's token that the second pool doesn't trade.
+Errors name the first pool that doesn't connect, or the token the path ends on when it
+doesn't come back to where it started.
+*/
+pub fn cycle_from_pools(pools: Vec<Pool>) -> Result<ArbPath> {
+    if pools.len() < 2 {
+        return Err(anyhow!(
+            "A cycle needs at least two pools, got {}",
+            pools.len()
+        ));
+    }
+    let token_in = match (
+        pools[1].contains_token(pools[0].token0),
+        pools[1].contains_token(pools[0].token1),
+    ) {
+        (false, true) => pools[0].token0,
+        (true, _) => pools[0].token1,
+        (false, false) => {
+            return Err(anyhow!(
+                "Pools {:?} and {:?} share no token",
+                pools[0].address,
+                pools[1].address
+            ))
+        }
+    };
+
+    let mut builder = ArbPathBuilder::new();
+    let mut token = token_in;
+    for pool in pools {
+        let zero_for_one = pool.token0 == token;
+        token = pool.other_token(token).unwrap_or(token);
+        builder = builder.hop(pool, zero_for_one);
+    }
+    let path = builder.build()?;
+    if token != token_in {
+        return Err(anyhow!(
+            "Path starts at {:?} but ends at {:?}, not a cycle",
+            token_in,
+            token
+        ));
+    }
+    if !path.is_valid_cycle() {
+        return Err(anyhow!(
+            "Path repeats a pool or swaps a token for itself, not a cycle"
+        ));
+    }
+    Ok(path)
+}
+
+// Curve's RATES entry for a coin: 10^(36 - decimals)
+fn curve_rate(decimals: u8) -> Option<U256> {
+    36usize.checked_sub(decimals as usize).map(U256::exp10)
+}
+
+impl std::fmt::Display for ArbPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.describe(&HashMap::new()))
+    }
+}
+
+// 0xc02a…6cc2
+fn short_address(address: &H160) -> String {
+    let hex = format!("{:x}", address);
+    format!("0x{}…{}", &hex[..4], &hex[hex.len() - 4..])
+}
+
+pub fn build_token_index(pools: &Vec<Pool>) -> HashMap<H160, Vec<usize>> {
+    let mut index: HashMap<H160, Vec<usize>> = HashMap::new();
+    for (i, pool) in pools.iter().enumerate() {
+        index.entry(pool.token0).or_default().push(i);
+        if pool.token1 != pool.token0 {
+            index.entry(pool.token1).or_default().push(i);
+        }
+    }
+    index
+}
+
+fn pools_of<'a>(token_index: &'a HashMap<H160, Vec<usize>>, token: &H160) -> &'a [usize] {
+    token_index
+        .get(token)
+        .map(|indices| indices.as_slice())
+        .unwrap_or(&[])
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct PathOptions<'a> {
+    // When set, pools missing from the map or with either reserve below min_reserve are skipped
+    pub reserves: Option<&'a HashMap<H160, Reserve>>,
+    pub min_reserve: U256,
+    // Pools with a token the cache currently holds as unsafe are skipped, unchecked ones pass
+    pub honeypots: Option<&'a HoneypotCache>,
+    // Pools trading any of these tokens are skipped
+    pub blacklist: &'a [H160],
+    /*
+    No progress bar, for when stdout carries machine readable output (see output::JsonLines)
+    or in tests. Without it the bar is still left out when stderr isn't a terminal (CI, logs
+    redirected to a file), the generated paths are the same either way.
+    */
+    pub quiet: bool,
+    /*
+    Only the K deepest pools of every token (see pools::rank_pools) are used to hop through it,
+    capping the path count on large pool sets. Needs reserves, ignored without them.
+    */
+    pub top_pools_per_token: Option<usize>,
+    // Keep pools pools::flag_rebasing marked, their reserves can't be trusted by the simulators
+    pub include_rebasing: bool,
+}
+
+impl<'a> PathOptions<'a> {
+    pub fn allows(&self, pool: &Pool) -> bool {
+        if pool.is_rebasing && !self.include_rebasing {
+            return false;
+        }
+        if self.blacklist.contains(&pool.token0) || self.blacklist.contains(&pool.token1) {
+            return false;
+        }
+        if let Some(honeypots) = self.honeypots {
+            let unsafe_token = |token: H160| honeypots.is_safe(token) == Some(false);
+            if unsafe_token(pool.token0) || unsafe_token(pool.token1) {
+                return false;
+            }
+        }
+        match self.reserves {
+            Some(reserves) => match reserves.get(&pool.address) {
+                Some(reserve) => {
+                    reserve.reserve0 >= self.min_reserve && reserve.reserve1 >= self.min_reserve
+                }
+                None => false,
+            },
+            None => true,
+        }
+    }
+}
+
+/*
+Generates every 3-hop cycle starting and ending at token_in, skipping any path that touches
+a token in blacklist.
+
+The first hop is spread across threads with rayon. Each first-hop pool yields its own
+Vec<ArbPath> and the results are concatenated in first-hop order, so the returned paths
+are identical (including ordering) to a sequential run. Only the progress bar position is
+updated out of order, as threads finish their first hops.
+*/
+pub fn generate_triangular_paths(
+    pools: &Vec<Pool>,
+    token_in: H160,
+    blacklist: &[H160],
+) -> Vec<ArbPath> {
+    let options = PathOptions {
+        blacklist,
+        ..Default::default()
+    };
+    generate_triangular_paths_with(pools, token_in, &options)
+}
+
+pub fn generate_triangular_paths_with(
+    pools: &Vec<Pool>,
+    token_in: H160,
+    options: &PathOptions,
+) -> Vec<ArbPath> {
+    generate_triangular_paths_drawing_to(pools, token_in, options, ProgressDrawTarget::stderr())
+}
+
+// The progress bar goes to target, which indicatif reports as hidden if it isn't a terminal
+fn generate_triangular_paths_drawing_to(
+    pools: &Vec<Pool>,
+    token_in: H160,
+    options: &PathOptions,
+    target: ProgressDrawTarget,
+) -> Vec<ArbPath> {
     let start_time = Instant::now();
 
-    let token_out = token_in.clone();
-    let mut paths = Vec::new();
+    // token -> indices of the pools trading it, so each hop only visits pools it can connect to
+    let mut token_index = build_token_index(pools);
+    for indices in token_index.values_mut() {
+        indices.retain(|&i| options.allows(&pools[i]));
+    }
+    if let (Some(k), Some(reserves)) = (options.top_pools_per_token, options.reserves) {
+        // position in the ranking, deepest first
+        let mut rank = vec![0; pools.len()];
+        for (position, (i, _)) in rank_pools(pools, reserves).into_iter().enumerate() {
+            rank[i] = position;
+        }
+        for indices in token_index.values_mut() {
+            indices.sort_by_key(|&i| rank[i]);
+            indices.truncate(k);
+            // back in pool order, so paths come out in the same order as without the cap
+            indices.sort_unstable();
+        }
+    }
+    let first_hops = pools_of(&token_index, &token_in);
 
-    let pb = ProgressBar::new(pools.len() as u64);
+    let pb = if options.quiet || target.is_hidden() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::with_draw_target(Some(first_hops.len() as u64), target)
+    };
     pb.set_style(
         ProgressStyle::with_template(
             "[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}",
@@ -170,90 +1072,1687 @@ pub fn generate_triangular_paths(pools: &Vec<Pool>, token_in: H160) -> Vec<ArbPa
         .progress_chars("##-"),
     );
 
-    for i in 0..pools.len() {
-        let pool_1 = &pools[i];
-        let can_trade_1 = (pool_1.token0 == token_in) || (pool_1.token1 == token_in);
+    let paths: Vec<ArbPath> = first_hops
+        .par_iter()
+        .map(|&i| {
+            let paths = triangular_paths_from(pools, &token_index, i, token_in);
+            pb.inc(1);
+            paths
+        })
+        .collect::<Vec<Vec<ArbPath>>>()
+        .into_iter()
+        .flatten()
+        .collect();
+    let paths = dedup_paths(paths);
 
-        if can_trade_1 {
-            let zero_for_one_1 = pool_1.token0 == token_in;
-            let (token_in_1, token_out_1) = if zero_for_one_1 {
-                (pool_1.token0, pool_1.token1)
-            } else {
-                (pool_1.token1, pool_1.token0)
-            };
-            if token_in_1 != token_in {
-                continue;
-            }
+    pb.finish_with_message(format!(
+        "Generated {} 3-hop arbitrage paths in {} seconds",
+        paths.len(),
+        start_time.elapsed().as_secs()
+    ));
+    paths
+}
 
-            for j in 0..pools.len() {
-                let pool_2 = &pools[j];
-                let can_trade_2 = (pool_2.token0 == token_out_1) || (pool_2.token1 == token_out_1);
+/*
+generate_triangular_paths for a basket of base tokens: the cycles of every token in token_ins,
+in that order. Each path starts and ends in its base token (see ArbPath::base_token).
+A cycle through three base tokens shows up once per base, each is a different trade
+(different capital, profit in a different token). A token listed twice is only generated for
+once, generate_triangular_paths already drops duplicates within a base.
+*/
+pub fn generate_triangular_paths_multi(pools: &Vec<Pool>, token_ins: &[H160]) -> Vec<ArbPath> {
+    generate_triangular_paths_multi_with(pools, token_ins, &PathOptions::default())
+}
 
-                if can_trade_2 {
-                    let zero_for_one_2 = pool_2.token0 == token_out_1;
-                    let (token_in_2, token_out_2) = if zero_for_one_2 {
-                        (pool_2.token0, pool_2.token1)
-                    } else {
-                        (pool_2.token1, pool_2.token0)
-                    };
-                    if token_out_1 != token_in_2 {
-                        continue;
-                    }
+pub fn generate_triangular_paths_multi_with(
+    pools: &Vec<Pool>,
+    token_ins: &[H160],
+    options: &PathOptions,
+) -> Vec<ArbPath> {
+    let mut seen_bases = HashSet::new();
+    token_ins
+        .iter()
+        .filter(|token_in| seen_bases.insert(**token_in))
+        .flat_map(|&token_in| generate_triangular_paths_with(pools, token_in, options))
+        .collect()
+}
 
-                    for k in 0..pools.len() {
-                        let pool_3 = &pools[k];
-                        let can_trade_3 =
-                            (pool_3.token0 == token_out_2) || (pool_3.token1 == token_out_2);
-
-                        if can_trade_3 {
-                            let zero_for_one_3 =
-                                (pool_3.token0 == token_out_2) || (pool_3.token1 == token_out_2);
-                            let (token_in_3, token_out_3) = if zero_for_one_3 {
-                                (pool_3.token0, pool_3.token1)
-                            } else {
-                                (pool_3.token1, pool_3.token0)
-                            };
-                            if token_out_2 != token_in_3 {
-                                continue;
-                            }
-
-                            if token_out_3 == token_out {
-                                let unique_pool_cnt =
-                                    vec![pool_1.address, pool_2.address, pool_3.address]
-                                        .into_iter()
-                                        .unique()
-                                        .collect::<Vec<H160>>()
-                                        .len();
-
-                                if unique_pool_cnt < 3 {
-                                    continue;
-                                }
-
-                                let arb_path = ArbPath {
-                                    nhop: 3,
-                                    pool_1: pool_1.clone(),
-                                    pool_2: pool_2.clone(),
-                                    pool_3: pool_3.clone(),
-                                    zero_for_one_1: zero_for_one_1,
-                                    zero_for_one_2: zero_for_one_2,
-                                    zero_for_one_3: zero_for_one_3,
-                                };
-
-                                paths.push(arb_path);
-                            }
-                        }
-                    }
+/*
+Paths plus, for every pool, the indices of the paths going through it, so an update to a
+single pool (a Sync log) only re-evaluates the paths it can move.
+Indices point into paths().
+*/
+#[derive(Debug, Clone, Default)]
+pub struct PathIndex {
+    paths: Vec<ArbPath>,
+    by_pool: HashMap<H160, Vec<usize>>,
+}
+
+impl PathIndex {
+    pub fn new(paths: Vec<ArbPath>) -> Self {
+        let mut by_pool: HashMap<H160, Vec<usize>> = HashMap::new();
+        for (idx, path) in paths.iter().enumerate() {
+            for pool in &path.pools {
+                let indices = by_pool.entry(pool.address).or_default();
+                // a path through the same pool twice is listed once
+                if indices.last() != Some(&idx) {
+                    indices.push(idx);
                 }
             }
         }
+        Self { paths, by_pool }
+    }
 
-        pb.inc(1);
+    pub fn paths(&self) -> &[ArbPath] {
+        &self.paths
     }
 
-    pb.finish_with_message(format!(
-        "Generated {} 3-hop arbitrage paths in {} seconds",
-        paths.len(),
-        start_time.elapsed().as_secs()
-    ));
+    // Ascending indices of the paths through pool, empty for pools no path uses
+    pub fn paths_touching(&self, pool: H160) -> &[usize] {
+        self.by_pool
+            .get(&pool)
+            .map(|indices| indices.as_slice())
+            .unwrap_or(&[])
+    }
+
+    // Paths through any of pools, each once, ascending
+    pub fn paths_touching_any(&self, pools: &[H160]) -> Vec<usize> {
+        let mut indices: Vec<usize> = pools
+            .iter()
+            .flat_map(|&pool| self.paths_touching(pool).iter().copied())
+            .collect();
+        indices.sort_unstable();
+        indices.dedup();
+        indices
+    }
+}
+
+// Keeps the first path of every canonical_key, dropping mirrored/rotated duplicates
+pub fn dedup_paths(paths: Vec<ArbPath>) -> Vec<ArbPath> {
+    let mut seen = HashSet::new();
     paths
+        .into_iter()
+        .filter(|path| seen.insert(path.canonical_key()))
+        .collect()
+}
+
+/*
+n of paths picked at random, for when there are more than can be simulated in a block.
+The pick only depends on seed (and paths.len()), so a run can be replayed exactly.
+Picked paths keep their relative order, all of them are returned when n >= paths.len().
+*/
+pub fn sample(paths: &[ArbPath], n: usize, seed: u64) -> Vec<ArbPath> {
+    sample_indices(paths.len(), n, seed)
+        .into_iter()
+        .map(|i| paths[i].clone())
+        .collect()
+}
+
+// Indices behind sample, ascending
+pub fn sample_indices(len: usize, n: usize, seed: u64) -> Vec<usize> {
+    if n >= len {
+        return (0..len).collect();
+    }
+    let mut rng = StdRng::seed_from_u64(seed);
+    let mut picked = index::sample(&mut rng, len, n).into_vec();
+    picked.sort_unstable();
+    picked
+}
+
+// Drops every path going through one of removed (see pools::diff), the rest keep their order
+pub fn drop_paths_through(paths: Vec<ArbPath>, removed: &[Pool]) -> Vec<ArbPath> {
+    let removed: HashSet<H160> = removed.iter().map(|pool| pool.address).collect();
+    paths
+        .into_iter()
+        .filter(|path| {
+            !path
+                .pools
+                .iter()
+                .any(|pool| removed.contains(&pool.address))
+        })
+        .collect()
+}
+
+fn triangular_paths_from(
+    pools: &Vec<Pool>,
+    token_index: &HashMap<H160, Vec<usize>>,
+    first_hop: usize,
+    token_in: H160,
+) -> Vec<ArbPath> {
+    let mut paths = Vec::new();
+
+    let pool_1 = &pools[first_hop];
+    let token_out_1 = match pool_1.other_token(token_in) {
+        Some(token) => token,
+        None => return paths,
+    };
+
+    for &j in pools_of(token_index, &token_out_1) {
+        let pool_2 = &pools[j];
+        let token_out_2 = match pool_2.other_token(token_out_1) {
+            Some(token) => token,
+            None => continue,
+        };
+
+        for &k in pools_of(token_index, &token_out_2) {
+            let pool_3 = &pools[k];
+            if pool_3.other_token(token_out_2) == Some(token_in) {
+                let arb_path = ArbPath::new_triangular(
+                    pool_1.clone(),
+                    pool_2.clone(),
+                    pool_3.clone(),
+                    pool_1.token0 == token_in,
+                    pool_2.token0 == token_out_1,
+                    pool_3.token0 == token_out_2,
+                );
+
+                if arb_path.is_valid_cycle() {
+                    paths.push(arb_path);
+                }
+            }
+        }
+    }
+
+    paths
+}
+
+pub fn generate_paths(pools: &Vec<Pool>, token_in: H160, max_hops: usize) -> Vec<ArbPath> {
+    let start_time = Instant::now();
+
+    let mut paths = Vec::new();
+    let mut hops = Vec::new();
+    extend_paths(pools, token_in, token_in, max_hops, &mut hops, &mut paths);
+
+    info!(
+        "Generated {} arbitrage paths (up to {} hops) in {} seconds",
+        paths.len(),
+        max_hops,
+        start_time.elapsed().as_secs()
+    );
+    paths
+}
+
+fn extend_paths(
+    pools: &Vec<Pool>,
+    token_in: H160,
+    current_token: H160,
+    max_hops: usize,
+    hops: &mut Vec<(usize, bool)>,
+    paths: &mut Vec<ArbPath>,
+) {
+    for (i, pool) in pools.iter().enumerate() {
+        let next_token = match pool.other_token(current_token) {
+            Some(token) => token,
+            None => continue,
+        };
+        if hops.iter().any(|(j, _)| pools[*j].address == pool.address) {
+            continue;
+        }
+
+        let zero_for_one = pool.token0 == current_token;
+        hops.push((i, zero_for_one));
+
+        if next_token == token_in {
+            if hops.len() >= 2 {
+                let path = ArbPath::new(
+                    hops.iter().map(|(j, _)| pools[*j].clone()).collect(),
+                    hops.iter().map(|(_, z)| *z).collect(),
+                );
+                if path.is_valid_cycle() {
+                    paths.push(path);
+                }
+            }
+        } else if hops.len() < max_hops {
+            extend_paths(pools, token_in, next_token, max_hops, hops, paths);
+        }
+
+        hops.pop();
+    }
+}
+
+// token_in -> token_out: (index of the pool with the best rate, -ln(rate))
+type RateGraph = HashMap<H160, Vec<(H160, usize, f64)>>;
+
+fn rate_graph(pools: &[Pool], reserves: &HashMap<H160, Reserve>) -> RateGraph {
+    let mut best: HashMap<(H160, H160), (usize, f64)> = HashMap::new();
+    for (i, pool) in pools.iter().enumerate() {
+        let reserve = match reserves.get(&pool.address) {
+            Some(reserve) => reserve,
+            None => continue,
+        };
+        for zero_for_one in [true, false] {
+            let rate = match ArbPath::spot_rate(pool, zero_for_one, reserve) {
+                Some(rate) if rate > 0.0 && rate.is_finite() => rate,
+                _ => continue,
+            };
+            let edge = if zero_for_one {
+                (pool.token0, pool.token1)
+            } else {
+                (pool.token1, pool.token0)
+            };
+            let weight = -rate.ln();
+            match best.get(&edge) {
+                Some((_, best_weight)) if *best_weight <= weight => {}
+                _ => {
+                    best.insert(edge, (i, weight));
+                }
+            }
+        }
+    }
+
+    let mut graph: RateGraph = HashMap::new();
+    for ((from, to), (i, weight)) in best {
+        graph.entry(from).or_default().push((to, i, weight));
+    }
+    graph
+}
+
+/*
+Profitable loops of up to max_len hops, found with Bellman-Ford on edge weights
+-ln(rate), rate being a hop's marginal output per input with the fee taken out
+(see spot_rate). A loop whose rates multiply to more than 1 has a negative total weight.
+Between two tokens only the pool with the best rate is used.
+Every cycle is returned once, as a closed token sequence (first token == last token).
+Marginal rates ignore size, so a cycle here still needs sizing/simulation before trading.
+*/
+pub fn find_negative_cycles(
+    pools: &[Pool],
+    reserves: &HashMap<H160, Reserve>,
+    max_len: usize,
+) -> Vec<Vec<H160>> {
+    const EPSILON: f64 = 1e-12;
+    let graph = rate_graph(pools, reserves);
+
+    // Every token starts at distance 0, as if linked from a virtual source
+    let mut tokens: Vec<H160> = graph
+        .iter()
+        .flat_map(|(from, edges)| std::iter::once(*from).chain(edges.iter().map(|e| e.0)))
+        .collect::<HashSet<H160>>()
+        .into_iter()
+        .collect();
+    tokens.sort();
+    let mut dist: HashMap<H160, f64> = tokens.iter().map(|token| (*token, 0.0)).collect();
+    let mut pred: HashMap<H160, H160> = HashMap::new();
+
+    let relax = |dist: &mut HashMap<H160, f64>, pred: &mut HashMap<H160, H160>| {
+        let mut changed = Vec::new();
+        for from in &tokens {
+            for (to, _, weight) in graph.get(from).map(|e| e.as_slice()).unwrap_or(&[]) {
+                let candidate = dist[from] + weight;
+                if candidate < dist[to] - EPSILON {
+                    dist.insert(*to, candidate);
+                    pred.insert(*to, *from);
+                    changed.push(*to);
+                }
+            }
+        }
+        changed
+    };
+    for _ in 1..tokens.len() {
+        if relax(&mut dist, &mut pred).is_empty() {
+            break;
+        }
+    }
+    let still_relaxing = relax(&mut dist, &mut pred);
+
+    let mut seen = HashSet::new();
+    let mut cycles = Vec::new();
+    for start in still_relaxing {
+        // Walking back |V| predecessors is guaranteed to end up inside the cycle
+        let mut token = start;
+        for _ in 0..tokens.len() {
+            match pred.get(&token) {
+                Some(previous) => token = *previous,
+                None => break,
+            }
+        }
+
+        let mut cycle = vec![token];
+        let mut current = token;
+        loop {
+            current = match pred.get(&current) {
+                Some(previous) => *previous,
+                None => break,
+            };
+            if current == token || cycle.len() > max_len {
+                break;
+            }
+            cycle.push(current);
+        }
+        if current != token || cycle.len() < 2 || cycle.len() > max_len {
+            continue;
+        }
+
+        // pred points backwards: flip into swap order, rotate to the lowest token, close it
+        cycle.reverse();
+        let lowest = (0..cycle.len()).min_by_key(|&i| cycle[i]).unwrap();
+        cycle.rotate_left(lowest);
+        cycle.push(cycle[0]);
+        if seen.insert(cycle.clone()) {
+            cycles.push(cycle);
+        }
+    }
+    cycles
+}
+
+// find_negative_cycles turned into ArbPaths, each hop through the pool with the best rate
+pub fn negative_cycle_paths(
+    pools: &[Pool],
+    reserves: &HashMap<H160, Reserve>,
+    max_len: usize,
+) -> Vec<ArbPath> {
+    let graph = rate_graph(pools, reserves);
+    find_negative_cycles(pools, reserves, max_len)
+        .into_iter()
+        .filter_map(|cycle| {
+            let mut builder = ArbPathBuilder::new();
+            for hop in cycle.windows(2) {
+                let (_, i, _) = graph.get(&hop[0])?.iter().find(|edge| edge.0 == hop[1])?;
+                let pool = &pools[*i];
+                builder = builder.hop(pool.clone(), pool.token0 == hop[0]);
+            }
+            builder.build().ok()
+        })
+        .collect()
+}
+
+/*
+Reads a token blacklist: one hex address per line.
+Blank lines and anything after a # are ignored, a line that isn't an address is an error.
+*/
+pub fn load_blacklist(path: &Path) -> Result<Vec<H160>> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut tokens = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default().trim();
+        if line.is_empty() {
+            continue;
+        }
+        let token = H160::from_str(line).map_err(|e| {
+            anyhow!(
+                "{:?} line {}: {:?} is not an address ({})",
+                path,
+                i + 1,
+                line,
+                e
+            )
+        })?;
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+pub fn save_paths(paths: &[ArbPath], path: &Path) -> Result<()> {
+    let writer = BufWriter::new(File::create(path)?);
+    serde_json::to_writer(writer, paths)?;
+    Ok(())
+}
+
+pub fn load_paths(path: &Path) -> Result<Vec<ArbPath>> {
+    let reader = BufReader::new(File::open(path)?);
+    let paths: Vec<ArbPath> = serde_json::from_reader(reader)?;
+
+    for (i, arb_path) in paths.iter().enumerate() {
+        let nhop = arb_path.nhop as usize;
+        if nhop != arb_path.pools.len() || nhop != arb_path.zero_for_one.len() {
+            return Err(anyhow!(
+                "Cached path #{} has nhop {} but {} pools and {} directions",
+                i,
+                nhop,
+                arb_path.pools.len(),
+                arb_path.zero_for_one.len()
+            ));
+        }
+    }
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod paths_tests {
+    use super::*;
+    use crate::honeypot::HoneypotResult;
+    use crate::pools::test_pool as pool;
+    use std::time::Duration;
+
+    fn addr(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn reserve(reserve0: U256, reserve1: U256) -> Reserve {
+        Reserve {
+            reserve0,
+            reserve1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn repeated_pools_see_the_reserves_left_by_earlier_hops() {
+        // 1 -> 2 -> 1 -> 2 -> 1 through pools 11 and 12 twice each
+        let path = ArbPath::new(
+            vec![
+                pool(11, 1, 2),
+                pool(12, 1, 2),
+                pool(11, 1, 2),
+                pool(12, 1, 2),
+            ],
+            vec![true, false, true, false],
+        );
+        let e18 = U256::exp10(18);
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(11), reserve(e18 * 100, e18 * 200));
+        reserves.insert(addr(12), reserve(e18 * 100, e18 * 180));
+        let amount_in = e18 * 5;
+
+        let out = |amount_in, reserve_in, reserve_out| {
+            UniswapV2Simulator::get_amount_out(amount_in, reserve_in, reserve_out, 3000).unwrap()
+        };
+        let a = out(amount_in, e18 * 100, e18 * 200);
+        let b = out(a, e18 * 180, e18 * 100);
+        // Second time through, both pools have already moved against us
+        let c = out(b, e18 * 100 + amount_in, e18 * 200 - a);
+        let d = out(c, e18 * 180 + a, e18 * 100 - b);
+
+        let sequential = path
+            .simulate_v2_path_sequential(amount_in, &reserves)
+            .unwrap();
+        assert_eq!(sequential, d);
+        assert!(sequential < path.simulate_v2_path(amount_in, &reserves).unwrap());
+        // The caller's map is untouched
+        assert_eq!(reserves[&addr(11)].reserve0, e18 * 100);
+
+        // Both visits are reflected in the reserves left behind
+        let after = path.resulting_reserves(amount_in, &reserves);
+        assert_eq!(after.len(), 2);
+        assert_eq!(after[&addr(11)].reserve0, e18 * 100 + amount_in + b);
+        assert_eq!(after[&addr(11)].reserve1, e18 * 200 - a - c);
+        assert_eq!(after[&addr(12)].reserve1, e18 * 180 + a + c);
+        assert_eq!(after[&addr(12)].reserve0, e18 * 100 - b - d);
+
+        // No repeats, no difference
+        let path = ArbPath::new(vec![pool(11, 1, 2), pool(12, 1, 2)], vec![true, false]);
+        assert_eq!(
+            path.simulate_v2_path_sequential(amount_in, &reserves),
+            path.simulate_v2_path(amount_in, &reserves)
+        );
+    }
+
+    #[test]
+    fn token_path_follows_swap_directions() {
+        // 1 -> 2 (token0 in), 2 -> 3 (token1 in), 3 -> 1 (token0 in)
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 3, 2),
+            pool(102, 3, 1),
+            true,
+            false,
+            true,
+        );
+
+        let tokens = path.token_path();
+        assert_eq!(tokens, vec![addr(1), addr(2), addr(3), addr(1)]);
+        assert_eq!(tokens.first(), tokens.last());
+    }
+
+    #[test]
+    fn describe_uses_symbols_and_dex_names() {
+        let mut weth_usdc = pool(100, 1, 2);
+        weth_usdc.symbol0 = "WETH".to_string();
+        weth_usdc.symbol1 = "USDC".to_string();
+        let mut usdc_dai = pool(101, 2, 3);
+        usdc_dai.dex = DexKind::UniswapV3 { fee_tier: 500 };
+        let dai_weth = pool(102, 3, 1);
+
+        let path = ArbPath::new_triangular(weth_usdc, usdc_dai, dai_weth, true, true, true);
+        let symbols = HashMap::from([(addr(3), "DAI".to_string())]);
+
+        assert_eq!(
+            path.describe(&symbols),
+            "WETH -[UniV2]-> USDC -[UniV3/500]-> DAI -[UniV2]-> WETH"
+        );
+        // Without the map DAI is only known by its address
+        assert_eq!(
+            path.to_string(),
+            "WETH -[UniV2]-> USDC -[UniV3/500]-> 0x0000…0003 -[UniV2]-> WETH"
+        );
+    }
+
+    #[test]
+    fn dump_reserves_shows_every_hops_state() {
+        let mut weth_usdc = pool(100, 1, 2);
+        weth_usdc.decimals1 = 6;
+        weth_usdc.symbol0 = "WETH".to_string();
+        weth_usdc.symbol1 = "USDC".to_string();
+        let path = ArbPath::new_triangular(
+            weth_usdc,
+            pool(101, 3, 2),
+            pool(102, 3, 1),
+            true,
+            false,
+            true,
+        );
+        let reserves = HashMap::from([
+            (
+                addr(100),
+                reserve(U256::exp10(21), U256::from(2_000_500) * U256::exp10(6)),
+            ),
+            (
+                addr(101),
+                reserve(U256::exp10(24), U256::from(1_500) * U256::exp10(24)),
+            ),
+            (addr(102), reserve(U256::exp10(22), U256::exp10(22))),
+        ]);
+
+        let dump = path.dump_reserves(&reserves);
+        assert_eq!(dump.lines().count(), 3);
+        for n in [100, 101, 102] {
+            assert!(dump.contains(&format!("{:?}", addr(n))), "{}", dump);
+        }
+        let first = dump.lines().next().unwrap();
+        assert!(first.contains("UniV2 fee 0.3% WETH -> USDC"), "{}", first);
+        assert!(
+            first.contains("reserve0 1000.000000000000000000 WETH"),
+            "{}",
+            first
+        );
+        assert!(first.contains("reserve1 2000500.000000 USDC"), "{}", first);
+        // Hop 1 goes token1 -> token0
+        assert!(dump
+            .lines()
+            .nth(1)
+            .unwrap()
+            .contains("0x0000…0002 -> 0x0000…0003"));
+
+        let missing = path.dump_reserves(&HashMap::new());
+        assert_eq!(missing.matches("no reserves").count(), 3);
+    }
+
+    #[test]
+    fn valid_triangle_is_a_cycle() {
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 3, 2),
+            pool(102, 3, 1),
+            true,
+            false,
+            true,
+        );
+        assert!(path.is_valid_cycle());
+
+        // Same pools, but hop 2 flipped: it would need token 3 while holding token 2
+        let broken = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 3, 2),
+            pool(102, 3, 1),
+            true,
+            true,
+            true,
+        );
+        assert!(!broken.is_valid_cycle());
+    }
+
+    #[test]
+    fn degenerate_paths_are_rejected_and_not_generated() {
+        // Hop 2 "swaps" token 2 for token 2 in a pool listing the same token twice
+        let pools = vec![pool(100, 1, 2), pool(101, 2, 2), pool(102, 2, 1)];
+        let degenerate = ArbPath::new_triangular(
+            pools[0].clone(),
+            pools[1].clone(),
+            pools[2].clone(),
+            true,
+            true,
+            true,
+        );
+        assert!(!degenerate.is_valid_cycle());
+        assert!(generate_triangular_paths(&pools, addr(1), &[]).is_empty());
+
+        // The same pair pool used on the way out and back
+        let reused = ArbPath::new(vec![pool(100, 1, 2), pool(100, 1, 2)], vec![true, false]);
+        assert!(!reused.is_valid_cycle());
+    }
+
+    #[test]
+    fn builder_chains_connected_hops() {
+        let path = ArbPathBuilder::new()
+            .hop(pool(100, 1, 2), true)
+            .hop(pool(101, 3, 2), false)
+            .hop(pool(102, 3, 1), true)
+            .build()
+            .unwrap();
+
+        assert_eq!(path.nhop, 3);
+        assert_eq!(path.zero_for_one, vec![true, false, true]);
+        assert_eq!(path.token_path(), vec![addr(1), addr(2), addr(3), addr(1)]);
+        assert!(path.is_valid_cycle());
+    }
+
+    #[test]
+    fn builder_rejects_a_disconnected_hop() {
+        // Hop 1 outputs token 2, but the pool is entered from the token 3 side
+        let result = ArbPathBuilder::new()
+            .hop(pool(100, 1, 2), true)
+            .hop(pool(101, 3, 2), true)
+            .hop(pool(102, 3, 1), true)
+            .build();
+
+        let error = result.unwrap_err().to_string();
+        assert!(error.starts_with("hop 1 through pool"), "{}", error);
+        assert!(ArbPathBuilder::new().build().is_err());
+    }
+
+    #[test]
+    fn cycle_directions_follow_the_shared_tokens() {
+        let path =
+            cycle_from_pools(vec![pool(100, 1, 2), pool(101, 3, 2), pool(102, 3, 1)]).unwrap();
+        assert_eq!(path.zero_for_one, vec![true, false, true]);
+        assert_eq!(path.token_path(), vec![addr(1), addr(2), addr(3), addr(1)]);
+
+        let open = cycle_from_pools(vec![pool(100, 1, 2), pool(101, 3, 2), pool(102, 3, 4)]);
+        assert!(open.unwrap_err().to_string().contains("not a cycle"));
+        let disjoint = cycle_from_pools(vec![pool(100, 1, 2), pool(101, 3, 4), pool(102, 4, 1)]);
+        assert!(disjoint.unwrap_err().to_string().contains("share no token"));
+    }
+
+    #[test]
+    fn bellman_ford_finds_the_four_hop_loop() {
+        // 1 -> 2 -> 3 -> 4 -> 1 pays 3% on the last hop, everything else trades at par
+        let mut pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 4),
+            pool(103, 4, 1),
+            pool(105, 2, 5),
+            pool(106, 5, 1),
+        ];
+        let par = reserve(U256::exp10(24), U256::exp10(24));
+        let mut reserves: HashMap<H160, Reserve> = pools
+            .iter()
+            .map(|pool| (pool.address, par.clone()))
+            .collect();
+        reserves.insert(addr(103), reserve(U256::exp10(24), U256::exp10(22) * 103));
+        // Unknown reserves keep a pool out of the graph
+        pools.push(pool(107, 3, 5));
+
+        let cycles = find_negative_cycles(&pools, &reserves, 4);
+        assert_eq!(
+            cycles,
+            vec![vec![addr(1), addr(2), addr(3), addr(4), addr(1)]]
+        );
+        // Too long for a 3 hop limit
+        assert!(find_negative_cycles(&pools, &reserves, 3).is_empty());
+
+        let paths = negative_cycle_paths(&pools, &reserves, 4);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].is_valid_cycle());
+        assert_eq!(
+            paths[0].pools.iter().map(|p| p.address).collect::<Vec<_>>(),
+            vec![addr(100), addr(101), addr(102), addr(103)]
+        );
+        let amount_in = U256::exp10(18);
+        assert!(paths[0].simulate_v2_path(amount_in, &reserves).unwrap() > amount_in);
+    }
+
+    #[test]
+    fn reverse_trades_the_cycle_the_other_way() {
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 3, 2),
+            pool(102, 3, 1),
+            true,
+            false,
+            true,
+        );
+        let reversed = path.reverse();
+
+        assert_eq!(reversed.reverse(), path);
+        assert!(reversed.is_valid_cycle());
+        assert_eq!(
+            reversed.token_path(),
+            vec![addr(1), addr(3), addr(2), addr(1)]
+        );
+
+        // Token 2 is cheap in pool 100: buying it there pays, the other way round loses
+        let reserves = HashMap::from([
+            (addr(100), reserve(U256::exp10(24), U256::exp10(22) * 102)),
+            (addr(101), reserve(U256::exp10(24), U256::exp10(24))),
+            (addr(102), reserve(U256::exp10(24), U256::exp10(24))),
+        ]);
+        let amount_in = U256::exp10(18);
+        assert!(path.simulate_v2_path(amount_in, &reserves).unwrap() > amount_in);
+        assert!(reversed.simulate_v2_path(amount_in, &reserves).unwrap() < amount_in);
+
+        // Each reversed hop goes through the same pool in the opposite direction
+        for (hop, reversed_hop) in (0..3).zip((0..3).rev()) {
+            assert_eq!(path.pools[hop], reversed.pools[reversed_hop]);
+            assert_ne!(path.zero_for_one[hop], reversed.zero_for_one[reversed_hop]);
+        }
+    }
+
+    #[test]
+    fn should_blacklist_checks_every_hop() {
+        // token 3 only appears as pool_3.token1
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 2, 4),
+            pool(102, 4, 3),
+            true,
+            true,
+            true,
+        );
+
+        assert!(!path.should_blacklist(&vec![addr(9)]));
+        assert!(path.should_blacklist(&vec![addr(3)]));
+    }
+
+    #[test]
+    fn generate_paths_finds_two_and_three_hop_cycles() {
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 1, 2),
+        ];
+
+        let paths = generate_paths(&pools, addr(1), 3);
+        let two_hop = paths.iter().filter(|p| p.nhop == 2).count();
+        let three_hop = paths.iter().filter(|p| p.nhop == 3).count();
+
+        // 100 <-> 103 in both orders
+        assert_eq!(two_hop, 2);
+        // 1 -> 2 -> 3 -> 1 through 100 or 103, and the reverse direction
+        assert_eq!(three_hop, 4);
+        assert!(generate_paths(&pools, addr(1), 2)
+            .iter()
+            .all(|p| p.nhop == 2));
+    }
+
+    #[test]
+    fn parallel_generation_matches_sequential() {
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 1, 2),
+            pool(104, 2, 4),
+            pool(105, 4, 1),
+            pool(106, 3, 4),
+        ];
+        let token_in = addr(1);
+        let key = |path: &ArbPath| {
+            path.pools
+                .iter()
+                .map(|p| p.address)
+                .zip(path.zero_for_one.iter().cloned())
+                .collect::<Vec<(H160, bool)>>()
+        };
+
+        let token_index = build_token_index(&pools);
+        let sequential: Vec<_> = pools_of(&token_index, &token_in)
+            .iter()
+            .flat_map(|&i| triangular_paths_from(&pools, &token_index, i, token_in))
+            .collect();
+        let sequential: Vec<_> = dedup_paths(sequential).iter().map(key).collect();
+        let parallel: Vec<_> = generate_triangular_paths(&pools, token_in, &[])
+            .iter()
+            .map(key)
+            .collect();
+
+        assert!(!sequential.is_empty());
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn mirrored_cycles_are_deduplicated() {
+        // 1 -> 2 -> 3 -> 1 and 1 -> 3 -> 2 -> 1 go through the same three pools
+        let pools = vec![pool(100, 2, 1), pool(101, 2, 3), pool(102, 3, 1)];
+        let token_in = addr(1);
+
+        let token_index = build_token_index(&pools);
+        let undeduped: Vec<_> = pools_of(&token_index, &token_in)
+            .iter()
+            .flat_map(|&i| triangular_paths_from(&pools, &token_index, i, token_in))
+            .collect();
+        assert_eq!(undeduped.len(), 2);
+        assert_eq!(undeduped[0].canonical_key(), undeduped[1].canonical_key());
+
+        let paths = generate_triangular_paths(&pools, token_in, &[]);
+        assert_eq!(paths.len(), 1);
+    }
+
+    #[test]
+    fn closing_hop_direction_is_computed_from_token0() {
+        // 1 -> 2 -> 3 -> 1 where the last pool stores (1, 3), so hop 3 is one-for-zero
+        let pools = vec![pool(100, 1, 2), pool(101, 2, 3), pool(102, 1, 3)];
+
+        let paths = generate_triangular_paths(&pools, addr(1), &[]);
+        assert_eq!(paths.len(), 1);
+
+        let path = &paths[0];
+        assert_eq!(path.pools[2].address, addr(102));
+        assert_eq!(path.zero_for_one, vec![true, true, false]);
+    }
+
+    #[test]
+    fn blacklisted_tokens_are_skipped_at_generation() {
+        // 1 -> 2 -> 3 -> 1 and 1 -> 2 -> 4 -> 1, token 4 is blacklisted
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 2, 4),
+            pool(104, 4, 1),
+        ];
+        let file = std::env::temp_dir().join("mev-templates-blacklist.txt");
+        std::fs::write(
+            &file,
+            format!("# known scam tokens\n\n{:?}  # fee on transfer\n", addr(4)),
+        )
+        .unwrap();
+        let blacklist = load_blacklist(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(blacklist, vec![addr(4)]);
+
+        assert_eq!(generate_triangular_paths(&pools, addr(1), &[]).len(), 2);
+        let paths = generate_triangular_paths(&pools, addr(1), &blacklist);
+        assert_eq!(paths.len(), 1);
+        assert!(!paths[0].should_blacklist(&blacklist));
+
+        let file = std::env::temp_dir().join("mev-templates-blacklist-broken.txt");
+        std::fs::write(&file, "0x1234\n").unwrap();
+        assert!(load_blacklist(&file).is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn paths_round_trip_through_json() {
+        let pools = vec![pool(100, 1, 2), pool(101, 2, 3), pool(102, 1, 3)];
+        let paths = generate_triangular_paths(&pools, addr(1), &[]);
+
+        let file = std::env::temp_dir().join("mev-templates-paths-round-trip.json");
+        save_paths(&paths, &file).unwrap();
+        let loaded = load_paths(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(loaded, paths);
+
+        let mut broken = paths.clone();
+        broken[0].nhop = 2;
+        let file = std::env::temp_dir().join("mev-templates-paths-broken.json");
+        save_paths(&broken, &file).unwrap();
+        assert!(load_paths(&file).is_err());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn top_k_keeps_the_deepest_pools_per_token() {
+        // 1 -> 2 -> 3 -> 1 with three parallel 2/3 pools of falling depth
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 2, 3),
+            pool(104, 2, 3),
+        ];
+        let depth = |n: usize| reserve(U256::exp10(n), U256::exp10(n));
+        let reserves = HashMap::from([
+            (addr(100), depth(26)),
+            (addr(101), depth(24)),
+            (addr(102), depth(26)),
+            (addr(103), depth(22)),
+            (addr(104), depth(20)),
+        ]);
+
+        let ranked = rank_pools(&pools, &reserves);
+        let order: Vec<usize> = ranked.iter().map(|(i, _)| *i).collect();
+        assert_eq!(order, vec![0, 2, 1, 3, 4]);
+        assert_eq!(ranked[0].1, 1.0);
+        assert!((ranked[2].1 - 0.01).abs() < 1e-12);
+
+        let uncapped = PathOptions {
+            reserves: Some(&reserves),
+            ..Default::default()
+        };
+        assert_eq!(
+            generate_triangular_paths_with(&pools, addr(1), &uncapped).len(),
+            3
+        );
+
+        // Tokens 2 and 3 keep the 1/2 or 3/1 pool plus 101, the deepest of the parallel ones
+        let capped = PathOptions {
+            top_pools_per_token: Some(2),
+            ..uncapped
+        };
+        let paths = generate_triangular_paths_with(&pools, addr(1), &capped);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].has_pool(&addr(101)));
+    }
+
+    #[test]
+    fn dust_pools_are_excluded_when_reserves_are_given() {
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 2, 3),
+        ];
+        let deep = reserve(U256::exp10(24), U256::exp10(24));
+        let dust = reserve(U256::exp10(24), U256::exp10(12));
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), deep.clone());
+        reserves.insert(addr(101), deep.clone());
+        reserves.insert(addr(102), deep.clone());
+        reserves.insert(addr(103), dust);
+
+        assert_eq!(generate_triangular_paths(&pools, addr(1), &[]).len(), 2);
+
+        let options = PathOptions {
+            reserves: Some(&reserves),
+            min_reserve: U256::exp10(18),
+            ..Default::default()
+        };
+        let paths = generate_triangular_paths_with(&pools, addr(1), &options);
+        assert_eq!(paths.len(), 1);
+        assert!(!paths[0].has_pool(&addr(103)));
+    }
+
+    #[test]
+    fn unsellable_tokens_are_skipped() {
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 2, 4),
+            pool(104, 4, 1),
+        ];
+        let honeypot = HoneypotResult {
+            token: addr(4),
+            buy_tax: 0.0,
+            sell_tax: 1.0,
+            sellable: false,
+        };
+        let mut honeypots = HoneypotCache::new(Duration::from_secs(60), 100, 0.1);
+        honeypots.insert(honeypot);
+
+        assert_eq!(generate_triangular_paths(&pools, addr(1), &[]).len(), 2);
+
+        let options = PathOptions {
+            honeypots: Some(&honeypots),
+            ..Default::default()
+        };
+        let paths = generate_triangular_paths_with(&pools, addr(1), &options);
+        assert_eq!(paths.len(), 1);
+        assert!(paths[0].has_pool(&addr(101)));
+    }
+
+    // Terminal that records what a progress bar draws, cursor movements as escape codes
+    #[derive(Debug, Clone, Default)]
+    struct RecordingTerm(std::sync::Arc<std::sync::Mutex<String>>);
+
+    impl indicatif::TermLike for RecordingTerm {
+        fn width(&self) -> u16 {
+            80
+        }
+        fn move_cursor_up(&self, n: usize) -> std::io::Result<()> {
+            self.write_str(&format!("\x1b[{}A", n))
+        }
+        fn move_cursor_down(&self, n: usize) -> std::io::Result<()> {
+            self.write_str(&format!("\x1b[{}B", n))
+        }
+        fn move_cursor_right(&self, n: usize) -> std::io::Result<()> {
+            self.write_str(&format!("\x1b[{}C", n))
+        }
+        fn move_cursor_left(&self, n: usize) -> std::io::Result<()> {
+            self.write_str(&format!("\x1b[{}D", n))
+        }
+        fn write_line(&self, s: &str) -> std::io::Result<()> {
+            self.write_str(&format!("{}\n", s))
+        }
+        fn write_str(&self, s: &str) -> std::io::Result<()> {
+            self.0.lock().unwrap().push_str(s);
+            std::io::Result::Ok(())
+        }
+        fn clear_line(&self) -> std::io::Result<()> {
+            self.write_str("\r\x1b[2K")
+        }
+        fn flush(&self) -> std::io::Result<()> {
+            std::io::Result::Ok(())
+        }
+    }
+
+    #[test]
+    fn sampling_is_reproducible_per_seed() {
+        let paths: Vec<ArbPath> = (0..50)
+            .map(|i| {
+                ArbPath::new(
+                    vec![pool(100 + i, 1, 2), pool(200 + i, 2, 1)],
+                    vec![true, false],
+                )
+            })
+            .collect();
+        let picked = |seed: u64| -> Vec<H160> {
+            sample(&paths, 10, seed)
+                .iter()
+                .map(|path| path.pools[0].address)
+                .collect()
+        };
+
+        assert_eq!(picked(7).len(), 10);
+        assert_eq!(picked(7), picked(7));
+        assert_ne!(picked(7), picked(8));
+        // Original order is kept
+        let mut sorted = picked(7);
+        sorted.sort();
+        assert_eq!(picked(7), sorted);
+
+        assert_eq!(sample(&paths, 80, 7).len(), 50);
+    }
+
+    #[test]
+    fn every_base_token_gets_its_cycles() {
+        // 1 -> 2 -> 3 -> 1 and 4 -> 5 -> 6 -> 4, which share no token
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 4, 5),
+            pool(104, 5, 6),
+            pool(105, 6, 4),
+        ];
+        let options = PathOptions {
+            quiet: true,
+            ..Default::default()
+        };
+
+        let paths =
+            generate_triangular_paths_multi_with(&pools, &[addr(1), addr(4), addr(1)], &options);
+        let bases: Vec<H160> = paths.iter().map(|path| path.base_token()).collect();
+        assert_eq!(bases, vec![addr(1), addr(4)]);
+        assert!(paths[0].has_pool(&addr(100)));
+        assert!(paths[1].has_pool(&addr(103)));
+
+        // The same cycle seen from two of its tokens is kept for both
+        let paths = generate_triangular_paths_multi_with(&pools, &[addr(1), addr(2)], &options);
+        let bases: Vec<H160> = paths.iter().map(|path| path.base_token()).collect();
+        assert_eq!(bases, vec![addr(1), addr(2)]);
+        assert_eq!(paths[0].canonical_key(), paths[1].canonical_key());
+    }
+
+    #[test]
+    fn quiet_generation_draws_nothing() {
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 2, 4),
+            pool(104, 4, 1),
+        ];
+        let generate = |quiet: bool| {
+            let term = RecordingTerm::default();
+            let options = PathOptions {
+                quiet,
+                ..Default::default()
+            };
+            let target = ProgressDrawTarget::term_like(Box::new(term.clone()));
+            let paths = generate_triangular_paths_drawing_to(&pools, addr(1), &options, target);
+            let drawn = term.0.lock().unwrap().clone();
+            (paths, drawn)
+        };
+
+        let (quiet_paths, quiet_drawn) = generate(true);
+        assert_eq!(quiet_drawn, "");
+        assert!(!quiet_drawn.chars().any(|c| c.is_control()));
+
+        // Same paths as with the bar, which does draw to a terminal
+        let (paths, drawn) = generate(false);
+        assert!(
+            drawn.contains("Generated 2 3-hop arbitrage paths"),
+            "{:?}",
+            drawn
+        );
+        assert_eq!(
+            quiet_paths
+                .iter()
+                .map(|p| p.to_string())
+                .collect::<Vec<_>>(),
+            paths.iter().map(|p| p.to_string()).collect::<Vec<_>>()
+        );
+        assert_eq!(quiet_paths.len(), 2);
+    }
+
+    #[test]
+    fn zero_reserve_hop_short_circuits_the_simulation() {
+        let e18 = U256::exp10(18);
+        let path = ArbPath::new(
+            vec![pool(100, 1, 2), pool(101, 2, 3), pool(102, 3, 1)],
+            vec![true, true, true],
+        );
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), reserve(e18 * 1000, e18 * 1000));
+        reserves.insert(addr(101), reserve(e18 * 1000, U256::zero()));
+        reserves.insert(addr(102), reserve(e18 * 1000, e18 * 1000));
+
+        assert!(!path.has_liquidity(&reserves));
+        assert_eq!(path.simulate_v2_path(e18, &reserves), None);
+
+        // Not even the first hop is priced
+        let hops = std::cell::Cell::new(0);
+        let out =
+            path.simulate_path_with(e18, &reserves, |pool, zero_for_one, amount_in, reserve| {
+                hops.set(hops.get() + 1);
+                ArbPath::simulate_hop(pool, zero_for_one, amount_in, reserve)
+            });
+        assert_eq!(out, None);
+        assert_eq!(hops.get(), 0);
+
+        reserves.insert(addr(101), reserve(e18 * 1000, e18 * 1000));
+        assert!(path.has_liquidity(&reserves));
+        assert!(path.simulate_v2_path(e18, &reserves).is_some());
+    }
+
+    #[test]
+    fn rebasing_pools_are_skipped_unless_included() {
+        let mut rebasing = pool(104, 4, 1);
+        rebasing.is_rebasing = true;
+        let pools = vec![
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 3, 1),
+            pool(103, 2, 4),
+            rebasing,
+        ];
+
+        let paths = generate_triangular_paths(&pools, addr(1), &[]);
+        assert_eq!(paths.len(), 1);
+        assert!(!paths[0].has_pool(&addr(104)));
+
+        let options = PathOptions {
+            include_rebasing: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            generate_triangular_paths_with(&pools, addr(1), &options).len(),
+            2
+        );
+    }
+
+    #[test]
+    fn balancer_hops_are_priced_as_weighted_pools() {
+        let e18 = U256::exp10(18);
+        let mut weighted = pool(101, 2, 1);
+        weighted.dex = DexKind::Balancer {
+            weight0: 800_000_000_000_000_000,
+            weight1: 200_000_000_000_000_000,
+        };
+        weighted.fee = 10_000;
+        let path = ArbPath::new(vec![pool(100, 1, 2), weighted.clone()], vec![true, true]);
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), reserve(e18 * 1000, e18 * 2_000_000));
+        reserves.insert(addr(101), reserve(e18 * 2_000_000, e18 * 1000));
+
+        let amounts = path.simulate_v2_path_verbose(e18, &reserves).unwrap();
+        let expected = WeightedSimulator::get_amount_out(
+            amounts[0],
+            e18 * 2_000_000,
+            800_000_000_000_000_000,
+            e18 * 1000,
+            200_000_000_000_000_000,
+            10_000,
+        );
+        assert_eq!(Some(amounts[1]), expected);
+        // Both pools price token 1 at 2000 token 2, the fees make the round trip a loss
+        assert!(amounts[1] < e18);
+        assert!((weighted.price(&reserves[&addr(101)], true) - 0.002).abs() < 1e-12);
+    }
+
+    #[test]
+    fn simulate_mixed_v2_v3_path() {
+        let q96 = U256::one() << 96;
+        let mut v3_pool_1 = pool(101, 2, 3);
+        v3_pool_1.dex = DexKind::UniswapV3 { fee_tier: 3000 };
+        v3_pool_1.fee = 3000;
+        let mut v3_pool_2 = pool(102, 1, 3);
+        v3_pool_2.dex = DexKind::UniswapV3 { fee_tier: 500 };
+        v3_pool_2.fee = 500;
+
+        // 1 -[V2]-> 2 -[V3 30bps]-> 3 -[V3 5bps]-> 1, all priced around 1:1
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            v3_pool_1.clone(),
+            v3_pool_2.clone(),
+            true,
+            true,
+            false,
+        );
+        let v3_state = Reserve {
+            sqrt_price_x96: q96,
+            liquidity: 10u128.pow(24),
+            tick: 0,
+            ..Default::default()
+        };
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), reserve(U256::exp10(24), U256::exp10(24)));
+        reserves.insert(addr(101), v3_state.clone());
+        reserves.insert(addr(102), v3_state.clone());
+
+        let amount_out = path.simulate_v2_path(U256::exp10(18), &reserves).unwrap();
+
+        let hop_1 =
+            ArbPath::simulate_hop(&path.pools[0], true, U256::exp10(18), &reserves[&addr(100)])
+                .unwrap();
+        let hop_2 =
+            UniswapV3Simulator::get_amount_out(hop_1, q96, 10u128.pow(24), 3000, true).unwrap();
+        let hop_3 =
+            UniswapV3Simulator::get_amount_out(hop_2, q96, 10u128.pow(24), 500, false).unwrap();
+        assert_eq!(amount_out, hop_3);
+
+        // ~0.3% + 0.3% + 0.05% in fees, negligible price impact
+        assert!(amount_out < U256::exp10(18) * 9940 / 10000);
+        assert!(amount_out > U256::exp10(18) * 9930 / 10000);
+    }
+
+    #[test]
+    fn verbose_simulation_reports_every_hop() {
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 1, 3),
+            true,
+            true,
+            false,
+        );
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), reserve(U256::exp10(24), U256::exp10(24) * 2));
+        reserves.insert(addr(101), reserve(U256::exp10(24), U256::exp10(24)));
+        reserves.insert(addr(102), reserve(U256::exp10(24), U256::exp10(24)));
+
+        let amounts = path
+            .simulate_v2_path_verbose(U256::exp10(18), &reserves)
+            .unwrap();
+        assert_eq!(amounts.len(), path.nhop as usize);
+        assert_eq!(
+            amounts.last().cloned(),
+            path.simulate_v2_path(U256::exp10(18), &reserves)
+        );
+    }
+
+    #[test]
+    fn simulation_amounts_are_in_token_wei() {
+        // USDC (6 decimals) -> WETH (18 decimals) at 2000 USDC/WETH
+        let mut usdc_weth = pool(100, 1, 2);
+        usdc_weth.decimals0 = 6;
+        usdc_weth.decimals1 = 18;
+        let path = ArbPath::new(vec![usdc_weth], vec![true]);
+
+        let reserve_usdc = U256::from(2_000_000) * U256::exp10(6);
+        let reserve_weth = U256::from(1_000) * U256::exp10(18);
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), reserve(reserve_usdc, reserve_weth));
+
+        let amount_in = U256::from(2_000) * U256::exp10(6);
+        let amount_out = path.simulate_v2_path(amount_in, &reserves).unwrap();
+
+        assert_eq!(
+            Some(amount_out),
+            UniswapV2Simulator::get_amount_out(amount_in, reserve_usdc, reserve_weth, 3000)
+        );
+        // just under 1 WETH, expressed in WETH wei
+        assert!(amount_out > U256::exp10(18) * 99 / 100);
+        assert!(amount_out < U256::exp10(18));
+    }
+
+    #[test]
+    fn path_index_finds_paths_through_a_pool() {
+        let paths = vec![
+            ArbPath::new(
+                vec![pool(100, 1, 2), pool(101, 2, 3), pool(102, 3, 1)],
+                vec![true, true, false],
+            ),
+            ArbPath::new(
+                vec![pool(100, 1, 2), pool(103, 2, 4), pool(104, 4, 1)],
+                vec![true, true, false],
+            ),
+            ArbPath::new(
+                vec![pool(105, 1, 3), pool(101, 2, 3), pool(106, 2, 1)],
+                vec![true, false, false],
+            ),
+        ];
+        let index = PathIndex::new(paths.clone());
+
+        assert_eq!(index.paths(), &paths[..]);
+        assert_eq!(index.paths_touching(addr(100)), &[0, 1]);
+        assert_eq!(index.paths_touching(addr(101)), &[0, 2]);
+        assert_eq!(index.paths_touching(addr(104)), &[1]);
+        assert!(index.paths_touching(addr(999)).is_empty());
+        assert_eq!(
+            index.paths_touching_any(&[addr(104), addr(101)]),
+            vec![0, 1, 2]
+        );
+
+        // Same answer as scanning with has_pool
+        for pool in [addr(100), addr(101), addr(103), addr(106)] {
+            let scanned: Vec<usize> = (0..paths.len())
+                .filter(|&i| paths[i].has_pool(&pool))
+                .collect();
+            assert_eq!(index.paths_touching(pool), &scanned[..]);
+        }
+    }
+
+    #[test]
+    fn custom_hop_pricing_composes_across_the_path() {
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 3, 2),
+            pool(102, 3, 1),
+            true,
+            false,
+            true,
+        );
+        let reserves = HashMap::from([
+            (addr(100), reserve(U256::exp10(24), U256::exp10(24))),
+            (addr(101), reserve(U256::exp10(24), U256::exp10(24))),
+            (addr(102), reserve(U256::exp10(24), U256::exp10(24) * 2)),
+        ]);
+        let amount_in = U256::exp10(18);
+
+        // Every hop doubles: three hops give 8x, visiting the pools in path order
+        let visited = std::sync::Mutex::new(Vec::new());
+        let doubled =
+            path.simulate_path_with(amount_in, &reserves, |pool, zero_for_one, amount, _| {
+                visited.lock().unwrap().push((pool.address, zero_for_one));
+                Some(amount * 2)
+            });
+        assert_eq!(doubled, Some(amount_in * 8));
+        assert_eq!(
+            visited.into_inner().unwrap(),
+            vec![(addr(100), true), (addr(101), false), (addr(102), true)]
+        );
+
+        // A hop returning None fails the whole path
+        let failed = path.simulate_path_with(amount_in, &reserves, |pool, _, amount, _| {
+            (pool.address != addr(101)).then_some(amount)
+        });
+        assert_eq!(failed, None);
+
+        assert_eq!(
+            path.simulate_path_with(amount_in, &reserves, ArbPath::simulate_hop),
+            path.simulate_v2_path(amount_in, &reserves)
+        );
+    }
+
+    #[test]
+    fn hop_fees_follow_the_pool_fee() {
+        let amount_in = U256::exp10(18);
+        let depth = U256::exp10(24);
+        let balanced = reserve(depth, depth);
+        // x * g * R / (R + x * g) with g = numerator / denominator
+        let constant_product = |numerator: u64, denominator: u64| {
+            amount_in * numerator * depth / (depth * denominator + amount_in * numerator)
+        };
+
+        let uniswap = pool(100, 1, 2);
+        assert_eq!(
+            ArbPath::simulate_hop(&uniswap, true, amount_in, &balanced),
+            Some(constant_product(997, 1000))
+        );
+
+        // 1% fork
+        let mut fork = pool(101, 1, 2);
+        fork.fee = 1000;
+        assert_eq!(
+            ArbPath::simulate_hop(&fork, true, amount_in, &balanced),
+            Some(constant_product(99, 100))
+        );
+
+        // 5bps used to round down to a free swap
+        let mut cheap = pool(102, 1, 2);
+        cheap.fee = 50;
+        assert_eq!(
+            ArbPath::simulate_hop(&cheap, true, amount_in, &balanced),
+            Some(constant_product(9995, 10000))
+        );
+
+        // 5bps stable pair: almost exactly the fee is lost on a pegged pool
+        let mut stable = pool(103, 1, 2);
+        stable.stable = true;
+        stable.fee = 50;
+        let out = ArbPath::simulate_hop(&stable, true, amount_in, &balanced).unwrap();
+        assert!(out < amount_in * 9995 / 10000);
+        assert!(out > amount_in * 9994 / 10000);
+    }
+
+    #[test]
+    fn transfer_tax_reduces_simulated_output() {
+        // token 2 takes 5% on every transfer
+        let mut pool_1 = pool(100, 1, 2);
+        pool_1.transfer_fee_bps1 = 500;
+        let mut pool_2 = pool(101, 2, 3);
+        pool_2.transfer_fee_bps0 = 500;
+        let pool_3 = pool(102, 3, 1);
+
+        let reserves = HashMap::from([
+            (addr(100), reserve(U256::exp10(24), U256::exp10(24))),
+            (addr(101), reserve(U256::exp10(24), U256::exp10(24))),
+            (addr(102), reserve(U256::exp10(24), U256::exp10(24))),
+        ]);
+        let amount_in = U256::exp10(20);
+
+        let taxed = ArbPath::new_triangular(
+            pool_1.clone(),
+            pool_2.clone(),
+            pool_3.clone(),
+            true,
+            true,
+            true,
+        );
+        pool_1.transfer_fee_bps1 = 0;
+        pool_2.transfer_fee_bps0 = 0;
+        let untaxed = ArbPath::new_triangular(pool_1, pool_2.clone(), pool_3, true, true, true);
+
+        let taxed_amounts = taxed
+            .simulate_v2_path_verbose(amount_in, &reserves)
+            .unwrap();
+        let untaxed_amounts = untaxed
+            .simulate_v2_path_verbose(amount_in, &reserves)
+            .unwrap();
+
+        // Only the hop receiving token 2 is affected, and by exactly 5% of its input
+        assert_eq!(taxed_amounts[0], untaxed_amounts[0]);
+        let hop_2 = ArbPath::simulate_hop(
+            &pool_2,
+            true,
+            taxed_amounts[0] * 95 / 100,
+            &reserves[&addr(101)],
+        )
+        .unwrap();
+        assert_eq!(taxed_amounts[1], hop_2);
+        assert!(taxed_amounts[2] < untaxed_amounts[2] * 96 / 100);
+        assert!(taxed_amounts[2] > untaxed_amounts[2] * 94 / 100);
+    }
+
+    fn two_pool_path() -> ArbPath {
+        // 1 -> 2 on a 1:1 pool, 2 -> 1 on a pool 1% cheaper in token 2
+        ArbPath::new(vec![pool(100, 1, 2), pool(101, 2, 1)], vec![true, true])
+    }
+
+    fn two_pool_reserves() -> HashMap<H160, Reserve> {
+        HashMap::from([
+            (addr(100), reserve(U256::exp10(24), U256::exp10(24))),
+            (addr(101), reserve(U256::exp10(24), U256::exp10(22) * 101)),
+        ])
+    }
+
+    #[test]
+    fn gross_profit_is_signed_both_ways() {
+        let path = two_pool_path();
+        let reserves = two_pool_reserves();
+        let amount_in = U256::exp10(18);
+
+        let profit = path.gross_profit(amount_in, &reserves).unwrap();
+        assert_eq!(profit, I256::from(3_947_091_132_306_030i64));
+        // The same cycle the other way buys the expensive side, a loss rather than None
+        let loss = path.reverse().gross_profit(amount_in, &reserves).unwrap();
+        assert_eq!(loss, I256::from(-15_834_613_348_655_755i64));
+
+        let unknown = ArbPath::new(vec![pool(100, 1, 2), pool(102, 2, 1)], vec![true, true]);
+        assert_eq!(unknown.gross_profit(amount_in, &reserves), None);
+    }
+
+    #[test]
+    fn net_profit_keeps_barely_profitable_paths_positive() {
+        let path = two_pool_path();
+        let reserves = two_pool_reserves();
+        let amount_in = U256::exp10(18);
+
+        // ~0.4% gross edge on 1 ETH after fees, 4e15 wei
+        let gross = path.simulate_v2_path(amount_in, &reserves).unwrap() - amount_in;
+        // 150k gas at 10 gwei = 1.5e15 wei
+        let gas_price = U256::exp10(10);
+        let profit = path
+            .net_profit(amount_in, &reserves, gas_price, 50_000, U256::exp10(18))
+            .unwrap();
+
+        let gas_cost = U256::from(150_000) * gas_price;
+        assert_eq!(profit, I256::try_from(gross - gas_cost).unwrap());
+        assert!(profit > I256::zero());
+    }
+
+    #[test]
+    fn wrapping_costs_extra_gas() {
+        let mut path = two_pool_path();
+        let reserves = two_pool_reserves();
+        let gas_price = U256::exp10(10);
+        let profit = |path: &ArbPath| {
+            path.net_profit(
+                U256::exp10(18),
+                &reserves,
+                gas_price,
+                50_000,
+                U256::exp10(18),
+            )
+            .unwrap()
+        };
+
+        let unwrapped = profit(&path);
+        path.requires_wrap = true;
+        let wrapped = profit(&path);
+        let wrap_cost = U256::from(WRAP_GAS) * gas_price;
+        assert_eq!(unwrapped - wrapped, I256::try_from(wrap_cost).unwrap());
+        assert!(path.reverse().requires_wrap);
+    }
+
+    #[test]
+    fn profit_curve_rises_then_falls() {
+        let path = two_pool_path();
+        let reserves = two_pool_reserves();
+        let inputs: Vec<U256> = [1u64, 10, 100, 500, 1_000, 2_000, 5_000, 10_000]
+            .iter()
+            .map(|&eth| U256::from(eth) * U256::exp10(18))
+            .collect();
+
+        // 1 gwei, WETH in
+        let gas_price = U256::exp10(9);
+        let curve: Vec<I256> = path
+            .profit_curve(&reserves, &inputs, gas_price, 60_000, U256::exp10(18))
+            .into_iter()
+            .map(|profit| profit.unwrap())
+            .collect();
+
+        // Every point pays the same gas bill
+        let gas_cost = U256::from(60_000 * path.nhop as u64 + BASE_ARB_GAS) * gas_price;
+        for (amount_in, profit) in inputs.iter().zip(&curve) {
+            let gross = path.gross_profit(*amount_in, &reserves).unwrap();
+            assert_eq!(gross - *profit, I256::try_from(gas_cost).unwrap());
+        }
+
+        // Peaks strictly inside the grid: small sizes leave edge on the table, big ones eat it
+        let peak = (0..curve.len()).max_by_key(|&i| curve[i]).unwrap();
+        assert!(peak > 0 && peak < curve.len() - 1);
+        assert!(curve[..=peak].windows(2).all(|w| w[0] < w[1]));
+        assert!(curve[peak..].windows(2).all(|w| w[0] > w[1]));
+        assert!(curve[0] > I256::zero());
+        assert!(curve[curve.len() - 1] < I256::zero());
+    }
+
+    #[test]
+    fn net_profit_goes_negative_when_gas_eats_the_edge() {
+        let path = two_pool_path();
+        let reserves = two_pool_reserves();
+        let amount_in = U256::exp10(18);
+
+        // 150k gas at 100 gwei = 1.5e16 wei, more than the gross edge
+        let profit = path
+            .net_profit(
+                amount_in,
+                &reserves,
+                U256::exp10(11),
+                50_000,
+                U256::exp10(18),
+            )
+            .unwrap();
+        assert!(profit < I256::zero());
+
+        // Same path with a token worth 2 ETH: the gas bill halves in token terms
+        let halved = path
+            .net_profit(
+                amount_in,
+                &reserves,
+                U256::exp10(11),
+                50_000,
+                U256::exp10(18) / 2,
+            )
+            .unwrap();
+        assert_eq!(halved - profit, I256::from(7_500_000_000_000_000i64));
+    }
+
+    #[test]
+    fn price_impact_grows_with_trade_size() {
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 1, 3),
+            true,
+            true,
+            false,
+        );
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), reserve(U256::exp10(24), U256::exp10(24) * 2));
+        reserves.insert(addr(101), reserve(U256::exp10(24), U256::exp10(24)));
+        reserves.insert(addr(102), reserve(U256::exp10(24), U256::exp10(24)));
+
+        let small = path.price_impact(U256::exp10(15), &reserves).unwrap();
+        assert!(small.abs() < 1e-4);
+
+        let large = path.price_impact(U256::exp10(23), &reserves).unwrap();
+        assert!(large > 0.1);
+
+        reserves.remove(&addr(101));
+        assert!(path.price_impact(U256::exp10(15), &reserves).is_none());
+    }
+
+    #[test]
+    fn reserves_stale_checks_every_hop() {
+        let path = ArbPath::new_triangular(
+            pool(100, 1, 2),
+            pool(101, 2, 3),
+            pool(102, 1, 3),
+            true,
+            true,
+            false,
+        );
+        let at_block = |block_number: u64| Reserve {
+            block_number,
+            ..reserve(U256::exp10(24), U256::exp10(24))
+        };
+        let mut reserves = HashMap::new();
+        reserves.insert(addr(100), at_block(100));
+        reserves.insert(addr(101), at_block(100));
+        reserves.insert(addr(102), at_block(97));
+
+        assert!(!path.reserves_stale(&reserves, 100, 3));
+        assert!(path.reserves_stale(&reserves, 100, 2));
+
+        reserves.remove(&addr(101));
+        assert!(path.reserves_stale(&reserves, 100, 3));
+    }
 }
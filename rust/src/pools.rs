@@ -1,4 +1,4 @@
-use anyhow::{Ok, Result};
+use anyhow::{anyhow, Ok, Result};
 use cfmms::{
     dex::{Dex, DexVariant as CfmmsDexVariant},
     pool::Pool as CfmmsPool,
@@ -6,67 +6,317 @@ use cfmms::{
 };
 use csv::StringRecord;
 use ethers::{
-    providers::{Provider, Ws},
-    types::H160,
+    abi::{self, ParamType, Token},
+    providers::Middleware,
+    types::{BlockId, Filter, Log, TransactionRequest, H160, H256, U256, U512},
+    utils::{id, keccak256},
 };
-use log::info;
-use std::{path::Path, str::FromStr, sync::Arc};
+use ethers_contract::MULTICALL_ADDRESS;
+use log::{info, warn};
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+
+use crate::multi::Reserve;
+use crate::simulator::{CurveSimulator, PIPS};
+use crate::utils::{u256_to_f64, RpcCallError};
 
-#[derive(Debug, Clone)]
-pub enum DexVariant {
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DexKind {
     UniswapV2,
-    UniswapV3,
+    // fee_tier is in pips (1e-6), e.g. 3000 for the 30bps tier
+    UniswapV3 { fee_tier: u32 },
+    // StableSwap pool priced over its two listed coins, amp is Curve's plain A
+    Curve { amp: u64 },
+    // Balancer weighted pool over two tokens, weights as normalized 1e18 values (0.8e18 / 0.2e18)
+    Balancer { weight0: u64, weight1: u64 },
+}
+
+impl std::fmt::Display for DexKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DexKind::UniswapV2 => write!(f, "UniV2"),
+            DexKind::UniswapV3 { fee_tier } => write!(f, "UniV3/{}", fee_tier),
+            DexKind::Curve { .. } => write!(f, "Curve"),
+            DexKind::Balancer { weight0, weight1 } => write!(
+                f,
+                "Balancer/{}-{}",
+                weight0 * 100 / (weight0 + weight1).max(1),
+                weight1 * 100 / (weight0 + weight1).max(1)
+            ),
+        }
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
 pub struct Pool {
     pub address: H160,
-    pub version: DexVariant,
+    pub dex: DexKind,
     pub token0: H160,
     pub token1: H160,
     pub decimals0: u8,
     pub decimals1: u8,
+    /*
+    Swap fee as the pool was loaded: in 1e-5 for V2, stable and Curve pools (300 = 30bps,
+    cfmms' convention) but in pips (1e-6) for V3 and Balancer pools, same as fee_tier.
+    Simulators take pips, go through fee_pips() rather than reading this directly.
+    */
     pub fee: u32,
+    // Solidly-style stable pair (x^3 * y + y^3 * x = k)
+    #[serde(default)]
+    pub stable: bool,
+    // ERC20 symbols, empty until enrich_token_metadata runs
+    #[serde(default)]
+    pub symbol0: String,
+    #[serde(default)]
+    pub symbol1: String,
+    // Tax (in bps) taken by token0/token1 on every transfer, 0 for regular ERC20s
+    #[serde(default)]
+    pub transfer_fee_bps0: u32,
+    #[serde(default)]
+    pub transfer_fee_bps1: u32,
+    // A token of the pool rebases (see flag_rebasing), reserves drift without swaps
+    #[serde(default)]
+    pub is_rebasing: bool,
 }
 
-impl From<StringRecord> for Pool {
-    fn from(record: StringRecord) -> Self {
-        let version = if record.get(1).unwrap() == "2" {
-            DexVariant::UniswapV2
-        } else {
-            DexVariant::UniswapV3
-        };
+// A V2 pair at Uniswap's 30bps between two 18 decimals tokens, fill in the rest
+impl Default for Pool {
+    fn default() -> Self {
         Self {
-            address: H160::from_str(record.get(0).unwrap()).unwrap(),
-            version,
-            token0: H160::from_str(record.get(2).unwrap()).unwrap(),
-            token1: H160::from_str(record.get(3).unwrap()).unwrap(),
-            decimals0: record.get(4).unwrap().parse().unwrap(),
-            decimals1: record.get(5).unwrap().parse().unwrap(),
-            fee: record.get(6).unwrap().parse().unwrap(),
+            address: H160::zero(),
+            dex: DexKind::UniswapV2,
+            token0: H160::zero(),
+            token1: H160::zero(),
+            decimals0: 18,
+            decimals1: 18,
+            fee: DEFAULT_V2_FEE,
+            stable: false,
+            symbol0: String::new(),
+            symbol1: String::new(),
+            transfer_fee_bps0: 0,
+            transfer_fee_bps1: 0,
+            is_rebasing: false,
         }
     }
 }
 
+// Default pool with addresses from small numbers, shared by the test modules
+#[cfg(test)]
+pub(crate) fn test_pool(address: u64, token0: u64, token1: u64) -> Pool {
+    Pool {
+        address: H160::from_low_u64_be(address),
+        token0: H160::from_low_u64_be(token0),
+        token1: H160::from_low_u64_be(token1),
+        ..Default::default()
+    }
+}
+
+/*
+A row of the pool cache written from Pool::cache_row. Curve rows carry amp and Balancer rows
+their two weights after fee, an unknown version is an error rather than a guess at the math.
+*/
+impl TryFrom<StringRecord> for Pool {
+    type Error = anyhow::Error;
+
+    fn try_from(record: StringRecord) -> Result<Self> {
+        let field = |i: usize| {
+            record
+                .get(i)
+                .ok_or_else(|| anyhow!("pool cache row without column {}", i))
+        };
+        let address = |i: usize| {
+            let field = field(i)?;
+            H160::from_str(field).map_err(|e| anyhow!("{:?} is not an address ({})", field, e))
+        };
+        let fee = field(6)?.parse()?;
+        let dex = match field(1)? {
+            "2" => DexKind::UniswapV2,
+            "3" => DexKind::UniswapV3 { fee_tier: fee },
+            "4" => DexKind::Curve {
+                amp: field(7)?.parse()?,
+            },
+            "5" => DexKind::Balancer {
+                weight0: field(7)?.parse()?,
+                weight1: field(8)?.parse()?,
+            },
+            version => {
+                return Err(anyhow!(
+                    "unknown pool version {} in the pool cache",
+                    version
+                ))
+            }
+        };
+        Ok(Self {
+            address: address(0)?,
+            dex,
+            token0: address(2)?,
+            token1: address(3)?,
+            decimals0: field(4)?.parse()?,
+            decimals1: field(5)?.parse()?,
+            fee,
+            ..Default::default()
+        })
+    }
+}
+
 impl Pool {
-    pub fn cache_row(&self) -> (String, i32, String, String, u8, u8, u32) {
+    // Swap fee in pips (1e-6): 3000 for 30bps, 500 for 5bps, 10_000 for 1%
+    pub fn fee_pips(&self) -> u32 {
+        match self.dex {
+            DexKind::UniswapV3 { fee_tier } => fee_tier,
+            DexKind::Balancer { .. } => self.fee,
+            DexKind::UniswapV2 | DexKind::Curve { .. } => self.fee * 10,
+        }
+    }
+
+    pub fn contains_token(&self, token: H160) -> bool {
+        self.token0 == token || self.token1 == token
+    }
+
+    // The token on the other side of the pool, None if token isn't one of its two tokens
+    pub fn other_token(&self, token: H160) -> Option<H160> {
+        if token == self.token0 {
+            Some(self.token1)
+        } else if token == self.token1 {
+            Some(self.token0)
+        } else {
+            None
+        }
+    }
+
+    // The side of reserve holding token, None if token isn't one of the pool's two tokens
+    pub fn reserve_of(&self, reserve: &Reserve, token: H160) -> Option<U256> {
+        if token == self.token0 {
+            Some(reserve.reserve0)
+        } else if token == self.token1 {
+            Some(reserve.reserve1)
+        } else {
+            None
+        }
+    }
+
+    // What the pool actually receives when `amount` of its input token is sent to it
+    pub fn after_transfer_fee(&self, zero_for_one: bool, amount: U256) -> U256 {
+        let fee_bps = if zero_for_one {
+            self.transfer_fee_bps0
+        } else {
+            self.transfer_fee_bps1
+        };
+        if fee_bps == 0 {
+            return amount;
+        }
+        amount * U256::from(10_000 - fee_bps.min(10_000)) / U256::from(10_000)
+    }
+
+    /*
+    Decimal adjusted mid price (no fee): how many whole output tokens one whole input token
+    is worth, input being token0 when zero_for_one. 0.0 when the pool has no liquidity.
+    */
+    pub fn price(&self, reserve: &Reserve, zero_for_one: bool) -> f64 {
+        let (reserve_in, reserve_out, decimals_in, decimals_out) = if zero_for_one {
+            (
+                reserve.reserve0,
+                reserve.reserve1,
+                self.decimals0,
+                self.decimals1,
+            )
+        } else {
+            (
+                reserve.reserve1,
+                reserve.reserve0,
+                self.decimals1,
+                self.decimals0,
+            )
+        };
+        let scale = 10f64.powi(decimals_in as i32 - decimals_out as i32);
+
+        match self.dex {
+            DexKind::UniswapV3 { .. } => {
+                if reserve.sqrt_price_x96.is_zero() {
+                    return 0.0;
+                }
+                let sqrt_price = u256_to_f64(reserve.sqrt_price_x96) / 2f64.powi(96);
+                // token1 per token0 in raw units
+                let raw = sqrt_price * sqrt_price;
+                let raw = if zero_for_one { raw } else { 1.0 / raw };
+                raw * scale
+            }
+            _ if reserve_in.is_zero() || reserve_out.is_zero() => 0.0,
+            DexKind::UniswapV2 if self.stable => {
+                // dy/dx of x^3 * y + y^3 * x = k, on whole-token reserves
+                let x = u256_to_f64(reserve_in) / 10f64.powi(decimals_in as i32);
+                let y = u256_to_f64(reserve_out) / 10f64.powi(decimals_out as i32);
+                (3.0 * x * x * y + y * y * y) / (x * x * x + 3.0 * x * y * y)
+            }
+            DexKind::UniswapV2 => u256_to_f64(reserve_out) / u256_to_f64(reserve_in) * scale,
+            DexKind::Balancer { weight0, weight1 } => {
+                let (weight_in, weight_out) = if zero_for_one {
+                    (weight0, weight1)
+                } else {
+                    (weight1, weight0)
+                };
+                if weight_in == 0 || weight_out == 0 {
+                    return 0.0;
+                }
+                // spot price of a weighted pool: (balance_out / weight_out) / (balance_in / weight_in)
+                u256_to_f64(reserve_out) / u256_to_f64(reserve_in) * weight_in as f64
+                    / weight_out as f64
+                    * scale
+            }
+            DexKind::Curve { amp } => {
+                // Marginal rate of a fee-free swap of a millionth of the input side
+                let probe = reserve_in / 1_000_000;
+                let rates = [
+                    U256::exp10(36usize.saturating_sub(self.decimals0 as usize)),
+                    U256::exp10(36usize.saturating_sub(self.decimals1 as usize)),
+                ];
+                let (i, j) = if zero_for_one { (0, 1) } else { (1, 0) };
+                match CurveSimulator::get_dy_with_rates(
+                    i,
+                    j,
+                    probe,
+                    &[reserve.reserve0, reserve.reserve1],
+                    &rates,
+                    amp,
+                    0,
+                ) {
+                    Some(out) if !probe.is_zero() => u256_to_f64(out) / u256_to_f64(probe) * scale,
+                    _ => 0.0,
+                }
+            }
+        }
+    }
+
+    // amp for Curve, the weights for Balancer, zeros for the Uniswap versions
+    pub fn cache_row(&self) -> (String, i32, String, String, u8, u8, u32, u64, u64) {
+        let (version, param0, param1) = match self.dex {
+            DexKind::UniswapV2 => (2, 0, 0),
+            DexKind::UniswapV3 { .. } => (3, 0, 0),
+            DexKind::Curve { amp } => (4, amp, 0),
+            DexKind::Balancer { weight0, weight1 } => (5, weight0, weight1),
+        };
         (
             format!("{:?}", self.address),
-            match self.version {
-                DexVariant::UniswapV2 => 2,
-                DexVariant::UniswapV3 => 3,
-            },
+            version,
             format!("{:?}", self.token0),
             format!("{:?}", self.token1),
             self.decimals0,
             self.decimals1,
             self.fee,
+            param0,
+            param1,
         )
     }
 }
 
-pub async fn load_all_pools_from_v2(
-    wss_url: String,
+pub async fn load_all_pools_from_v2<M: Middleware + 'static>(
+    provider: Arc<M>,
     factory_addresses: Vec<&str>,
     from_blocks: Vec<u64>,
 ) -> Result<Vec<Pool>> {
@@ -77,16 +327,11 @@ pub async fn load_all_pools_from_v2(
 
         let mut pools_vec: Vec<Pool> = Vec::new();
         for row in reader.records() {
-            let row = row.unwrap();
-            let pool = Pool::from(row);
-            pools_vec.push(pool);
+            pools_vec.push(Pool::try_from(row?)?);
         }
         return Ok(pools_vec);
     }
 
-    let ws = Ws::connect(wss_url).await?;
-    let provider = Arc::new(Provider::new(ws));
-
     let mut dexes_data = Vec::new();
 
     for i in 0..factory_addresses.len() {
@@ -115,21 +360,23 @@ pub async fn load_all_pools_from_v2(
         .map(|pool| match pool {
             CfmmsPool::UniswapV2(pool) => Pool {
                 address: pool.address,
-                version: DexVariant::UniswapV2,
+                dex: DexKind::UniswapV2,
                 token0: pool.token_a,
                 token1: pool.token_b,
                 decimals0: pool.token_a_decimals,
                 decimals1: pool.token_b_decimals,
                 fee: pool.fee,
+                ..Default::default()
             },
             CfmmsPool::UniswapV3(pool) => Pool {
                 address: pool.address,
-                version: DexVariant::UniswapV3,
+                dex: DexKind::UniswapV3 { fee_tier: pool.fee },
                 token0: pool.token_a,
                 token1: pool.token_b,
                 decimals0: pool.token_a_decimals,
                 decimals1: pool.token_b_decimals,
                 fee: pool.fee,
+                ..Default::default()
             },
         })
         .collect();
@@ -144,6 +391,8 @@ pub async fn load_all_pools_from_v2(
         "decimals0",
         "decimals1",
         "fee",
+        "param0",
+        "param1",
     ])?;
 
     for pool in &pools_vec {
@@ -153,3 +402,1546 @@ pub async fn load_all_pools_from_v2(
 
     Ok(pools_vec)
 }
+
+// Block span of a single eth_getLogs query, kept under common RPC limits
+pub const LOG_BLOCK_RANGE: u64 = 10_000;
+
+/*
+Loads pools created by every factory in `factories` between `from_block` and `to_block` (inclusive).
+V2 factories are scanned for PairCreated, V3 factories for PoolCreated.
+Pools are deduplicated by address across factories, the first factory listed wins.
+Token decimals are not part of the creation events and default to 18 here.
+*/
+pub async fn load_all_pools<M: Middleware + 'static>(
+    provider: Arc<M>,
+    factories: &[(H160, DexKind)],
+    from_block: u64,
+    to_block: u64,
+) -> Result<Vec<Pool>> {
+    let mut seen = HashSet::new();
+    let mut pools = Vec::new();
+
+    for (factory, dex) in factories {
+        let event = match dex {
+            DexKind::UniswapV2 => "PairCreated(address,address,address,uint256)",
+            DexKind::UniswapV3 { .. } => "PoolCreated(address,address,uint24,int24,address)",
+            DexKind::Curve { .. } | DexKind::Balancer { .. } => {
+                warn!(
+                    "Factory {:?}: {} pools can't be loaded from creation logs",
+                    factory, dex
+                );
+                continue;
+            }
+        };
+        let topic = H256::from(keccak256(event));
+
+        let mut range_start = from_block;
+        while range_start <= to_block {
+            let range_end = std::cmp::min(range_start + LOG_BLOCK_RANGE - 1, to_block);
+            let filter = Filter::new()
+                .address(*factory)
+                .topic0(topic)
+                .from_block(range_start)
+                .to_block(range_end);
+            let logs = provider.get_logs(&filter).await.map_err(|e| {
+                anyhow!(
+                    "get_logs failed for factory {:?} ({}..={}): {}",
+                    factory,
+                    range_start,
+                    range_end,
+                    e
+                )
+            })?;
+
+            for log in &logs {
+                if let Some(pool) = pool_from_creation_log(log, *dex) {
+                    if seen.insert(pool.address) {
+                        pools.push(pool);
+                    }
+                }
+            }
+            range_start = range_end + 1;
+        }
+        info!("Factory {:?}: {} pools loaded so far", factory, pools.len());
+    }
+
+    Ok(pools)
+}
+
+fn pool_from_creation_log(log: &Log, dex: DexKind) -> Option<Pool> {
+    if log.topics.len() < 3 {
+        return None;
+    }
+    let token0 = H160::from(log.topics[1]);
+    let token1 = H160::from(log.topics[2]);
+
+    let (address, dex, fee) = match dex {
+        // PairCreated(token0 indexed, token1 indexed, pair, allPairsLength)
+        DexKind::UniswapV2 => {
+            if log.data.len() < 32 {
+                return None;
+            }
+            (
+                H160::from_slice(&log.data[12..32]),
+                DexKind::UniswapV2,
+                DEFAULT_V2_FEE,
+            )
+        }
+        // PoolCreated(token0 indexed, token1 indexed, fee indexed, tickSpacing, pool)
+        DexKind::UniswapV3 { .. } => {
+            if log.topics.len() < 4 || log.data.len() < 64 {
+                return None;
+            }
+            let fee_tier = U256::from_big_endian(log.topics[3].as_bytes()).low_u32();
+            (
+                H160::from_slice(&log.data[44..64]),
+                DexKind::UniswapV3 { fee_tier },
+                fee_tier,
+            )
+        }
+        DexKind::Curve { .. } | DexKind::Balancer { .. } => return None,
+    };
+
+    Some(Pool {
+        address,
+        dex,
+        token0,
+        token1,
+        fee,
+        ..Default::default()
+    })
+}
+
+/*
+V2 pairs by address, for when only a handful of known pools are needed instead of a factory sync.
+token0() and token1() of every address go through one aggregate3 call. Decimals are left at 18,
+run enrich_token_metadata for the real ones. The fee comes from detect_fee.
+An address that doesn't answer both calls (not a pair, wrong network) is an error.
+*/
+pub async fn load_v2_pools<M: Middleware + 'static>(
+    provider: Arc<M>,
+    addresses: &[H160],
+) -> Result<Vec<Pool>> {
+    let selectors = [id("token0()").to_vec(), id("token1()").to_vec()];
+    let calls = addresses
+        .iter()
+        .flat_map(|address| {
+            selectors
+                .iter()
+                .map(|selector| (*address, selector.clone()))
+        })
+        .collect();
+    let results = aggregate3(provider.as_ref(), calls, None)
+        .await
+        .map_err(|e| e.context("loading pair tokens"))?;
+
+    let mut pools = Vec::with_capacity(addresses.len());
+    for (address, pair) in addresses.iter().zip(results.chunks(2)) {
+        let token = |data: &Option<Vec<u8>>| data.as_deref().and_then(decode_address);
+        let (token0, token1) = match (token(&pair[0]), token(&pair[1])) {
+            (Some(token0), Some(token1)) => (token0, token1),
+            _ => {
+                return Err(anyhow!(
+                    "{:?} didn't answer token0()/token1(), is it a V2 pair?",
+                    address
+                ))
+            }
+        };
+        let fee = detect_fee(provider.clone(), *address, DexKind::UniswapV2)
+            .await
+            .unwrap_or(DEFAULT_V2_FEE);
+        pools.push(Pool {
+            address: *address,
+            dex: DexKind::UniswapV2,
+            token0,
+            token1,
+            fee,
+            ..Default::default()
+        });
+    }
+    Ok(pools)
+}
+
+// Uniswap V2's 30bps, in 1e-5. Forks don't expose their fee on the pair
+pub const DEFAULT_V2_FEE: u32 = 300;
+
+/*
+Reads pool's swap fee from the contract, in the unit Pool.fee uses for dex (see Pool.fee):
+fee() for V3 (pips) and Curve (1e10, scaled down to 1e-5), getSwapFeePercentage() for
+Balancer (1e18, scaled down to pips). V2 pairs have no fee getter, DEFAULT_V2_FEE is used.
+When the call reverts or returns nonsense, V3 falls back to its fee_tier. Curve and Balancer
+fees are set per pool, there is no default to fall back to and the result is None.
+*/
+pub async fn detect_fee<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pool: H160,
+    dex: DexKind,
+) -> Option<u32> {
+    let (signature, default) = match dex {
+        DexKind::UniswapV2 => return Some(DEFAULT_V2_FEE),
+        DexKind::UniswapV3 { fee_tier } => ("fee()", Some(fee_tier)),
+        DexKind::Curve { .. } => ("fee()", None),
+        DexKind::Balancer { .. } => ("getSwapFeePercentage()", None),
+    };
+    let tx = TransactionRequest::new()
+        .to(pool)
+        .data(id(signature).to_vec());
+    let output = match provider.call(&tx.into(), None).await {
+        std::result::Result::Ok(output) => output,
+        Err(e) => {
+            warn!(
+                "{} failed on {:?}, using the default fee: {}",
+                signature, pool, e
+            );
+            return default;
+        }
+    };
+    if output.len() < 32 {
+        return default;
+    }
+    let raw = U256::from_big_endian(&output[..32]);
+    let fee = match dex {
+        DexKind::Curve { .. } => raw / U256::exp10(5),
+        DexKind::Balancer { .. } => raw / U256::exp10(12),
+        _ => raw,
+    };
+    // 100% in the unit of Pool.fee, anything above is not a fee
+    let max = match dex {
+        DexKind::Curve { .. } => PIPS / 10,
+        _ => PIPS,
+    };
+    if fee > U256::from(max) {
+        return default;
+    }
+    Some(fee.as_u32())
+}
+
+/*
+Reads a curated pool list, for chains where scanning factory logs isn't practical.
+The format goes by extension:
+- .json: an array of Pool objects, as serde writes them.
+- .csv: address,token0,token1,dex,fee per line, dex being v2 or v3 (fee in the unit of Pool.fee,
+  so the V3 fee is also its fee_tier). An optional header line, blank lines and anything after
+  a # are skipped. Decimals aren't part of a row and are left at 18.
+Curve and Balancer pools need parameters a row can't hold, list them in JSON.
+A bad address, a zero or repeated token, or a malformed row is an error naming the line
+(pool index for JSON). See load_from_file_with_metadata for real decimals.
+*/
+pub fn load_from_file(path: &Path) -> Result<Vec<Pool>> {
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase());
+    let pools = match extension.as_deref() {
+        Some("json") => {
+            let contents = std::fs::read_to_string(path)?;
+            let pools: Vec<Pool> = serde_json::from_str(&contents)
+                .map_err(|e| anyhow!("{:?}: not a pool list ({})", path, e))?;
+            for (i, pool) in pools.iter().enumerate() {
+                check_seed_tokens(pool.token0, pool.token1)
+                    .map_err(|e| anyhow!("{:?} pool #{}: {}", path, i, e))?;
+            }
+            pools
+        }
+        Some("csv") => {
+            let contents = std::fs::read_to_string(path)?;
+            let mut pools = Vec::new();
+            for (i, line) in contents.lines().enumerate() {
+                let line = line.split('#').next().unwrap_or_default().trim();
+                if line.is_empty() || (pools.is_empty() && line.starts_with("address")) {
+                    continue;
+                }
+                let pool = parse_seed_row(line)
+                    .map_err(|e| anyhow!("{:?} line {}: {}", path, i + 1, e))?;
+                pools.push(pool);
+            }
+            pools
+        }
+        _ => return Err(anyhow!("{:?}: expected a .json or .csv pool file", path)),
+    };
+    info!("Loaded {} pools from {:?}", pools.len(), path);
+    Ok(pools)
+}
+
+// load_from_file, then enrich_token_metadata for decimals and symbols
+pub async fn load_from_file_with_metadata<M: Middleware + 'static>(
+    provider: Arc<M>,
+    path: &Path,
+) -> Result<Vec<Pool>> {
+    let mut pools = load_from_file(path)?;
+    enrich_token_metadata(provider, &mut pools).await?;
+    Ok(pools)
+}
+
+// address,token0,token1,dex,fee
+fn parse_seed_row(line: &str) -> Result<Pool> {
+    let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+    if fields.len() != 5 {
+        return Err(anyhow!(
+            "expected address,token0,token1,dex,fee, got {} fields",
+            fields.len()
+        ));
+    }
+    let address = |field: &str| {
+        H160::from_str(field).map_err(|e| anyhow!("{:?} is not an address ({})", field, e))
+    };
+    let (token0, token1) = (address(fields[1])?, address(fields[2])?);
+    check_seed_tokens(token0, token1)?;
+    let fee: u32 = fields[4]
+        .parse()
+        .map_err(|_| anyhow!("{:?} is not a fee", fields[4]))?;
+    let dex = match fields[3].to_ascii_lowercase().as_str() {
+        "v2" | "uniswapv2" => DexKind::UniswapV2,
+        "v3" | "uniswapv3" => DexKind::UniswapV3 { fee_tier: fee },
+        other => return Err(anyhow!("unknown dex {:?}, expected v2 or v3", other)),
+    };
+    Ok(Pool {
+        address: address(fields[0])?,
+        dex,
+        token0,
+        token1,
+        fee,
+        ..Default::default()
+    })
+}
+
+fn check_seed_tokens(token0: H160, token1: H160) -> Result<()> {
+    if token0.is_zero() || token1.is_zero() {
+        return Err(anyhow!("zero address as a token"));
+    }
+    if token0 == token1 {
+        return Err(anyhow!("token0 and token1 are both {:?}", token0));
+    }
+    Ok(())
+}
+
+// Tokens per aggregate3 call (two calls each: symbol() and decimals())
+pub const TOKEN_METADATA_BATCH: usize = 500;
+
+/*
+Fills symbol0/symbol1 and corrects decimals0/decimals1 for every pool.
+Each unique token is queried once, symbol() and decimals() are batched through Multicall3's aggregate3
+with allowFailure set, so a token that reverts keeps whatever the pool already had.
+Symbols may be ABI strings or bytes32 (MKR, SAI and other early tokens), both are decoded.
+*/
+pub async fn enrich_token_metadata<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pools: &mut [Pool],
+) -> Result<()> {
+    let mut seen = HashSet::new();
+    let tokens: Vec<H160> = pools
+        .iter()
+        .flat_map(|pool| [pool.token0, pool.token1])
+        .filter(|token| seen.insert(*token))
+        .collect();
+
+    let symbol_selector = id("symbol()").to_vec();
+    let decimals_selector = id("decimals()").to_vec();
+
+    let mut metadata: HashMap<H160, (Option<String>, Option<u8>)> = HashMap::new();
+    for batch in tokens.chunks(TOKEN_METADATA_BATCH) {
+        let calls = batch
+            .iter()
+            .flat_map(|token| {
+                [&symbol_selector, &decimals_selector].map(|selector| (*token, selector.clone()))
+            })
+            .collect();
+        let results = aggregate3(provider.as_ref(), calls, None)
+            .await
+            .map_err(|e| e.context("loading token metadata"))?;
+        for (token, pair) in batch.iter().zip(results.chunks(2)) {
+            let symbol = pair[0].as_deref().and_then(decode_symbol);
+            let decimals = pair[1].as_deref().and_then(decode_decimals);
+            metadata.insert(*token, (symbol, decimals));
+        }
+    }
+
+    for pool in pools.iter_mut() {
+        if let Some((symbol, decimals)) = metadata.get(&pool.token0) {
+            if let Some(symbol) = symbol {
+                pool.symbol0 = symbol.clone();
+            }
+            if let Some(decimals) = decimals {
+                pool.decimals0 = *decimals;
+            }
+        }
+        if let Some((symbol, decimals)) = metadata.get(&pool.token1) {
+            if let Some(symbol) = symbol {
+                pool.symbol1 = symbol.clone();
+            }
+            if let Some(decimals) = decimals {
+                pool.decimals1 = *decimals;
+            }
+        }
+    }
+    info!("Token metadata loaded for {} tokens", tokens.len());
+
+    Ok(())
+}
+
+/*
+Sends calls, (target, calldata) pairs with allowFailure set on each, as one Multicall3
+aggregate3 eth_call at block (None for latest). Returns one result per call in call order,
+a failed call maps to None. A failed eth_call is an RpcCallError, an output that doesn't hold
+exactly one result per call an error too.
+*/
+pub(crate) async fn aggregate3<M: Middleware>(
+    provider: &M,
+    calls: Vec<(H160, Vec<u8>)>,
+    block: Option<BlockId>,
+) -> Result<Vec<Option<Vec<u8>>>> {
+    let count = calls.len();
+    let calls = calls
+        .into_iter()
+        .map(|(target, calldata)| {
+            Token::Tuple(vec![
+                Token::Address(target),
+                Token::Bool(true),
+                Token::Bytes(calldata),
+            ])
+        })
+        .collect();
+    let mut calldata = id("aggregate3((address,bool,bytes)[])").to_vec();
+    calldata.extend(abi::encode(&[Token::Array(calls)]));
+
+    let tx = TransactionRequest::new()
+        .to(MULTICALL_ADDRESS)
+        .data(calldata);
+    let output = provider
+        .call(&tx.into(), block)
+        .await
+        .map_err(|e| RpcCallError::new("aggregate3", &e))?;
+
+    let results = decode_aggregate3_output(&output)?;
+    if results.len() != count {
+        return Err(anyhow!(
+            "aggregate3 returned {} results for {} calls",
+            results.len(),
+            count
+        ));
+    }
+    Ok(results)
+}
+
+// aggregate3 returns (bool success, bytes returnData)[], failed calls map to None
+pub(crate) fn decode_aggregate3_output(output: &[u8]) -> Result<Vec<Option<Vec<u8>>>> {
+    let result_type = ParamType::Array(Box::new(ParamType::Tuple(vec![
+        ParamType::Bool,
+        ParamType::Bytes,
+    ])));
+    let tokens = abi::decode(&[result_type], output)?;
+
+    let mut results = Vec::new();
+    if let Some(Token::Array(items)) = tokens.into_iter().next() {
+        for item in items {
+            match item {
+                Token::Tuple(fields) => match (fields.first(), fields.get(1)) {
+                    (Some(Token::Bool(true)), Some(Token::Bytes(data))) => {
+                        results.push(Some(data.clone()))
+                    }
+                    _ => results.push(None),
+                },
+                _ => results.push(None),
+            }
+        }
+    }
+    Ok(results)
+}
+
+fn decode_symbol(data: &[u8]) -> Option<String> {
+    if let std::result::Result::Ok(tokens) = abi::decode(&[ParamType::String], data) {
+        if let Some(Token::String(symbol)) = tokens.into_iter().next() {
+            return Some(symbol);
+        }
+    }
+    // bytes32 symbol, right padded with zeros
+    if data.len() == 32 {
+        let end = data.iter().position(|b| *b == 0).unwrap_or(32);
+        return String::from_utf8(data[..end].to_vec()).ok();
+    }
+    None
+}
+
+fn decode_address(data: &[u8]) -> Option<H160> {
+    match abi::decode(&[ParamType::Address], data)
+        .ok()?
+        .into_iter()
+        .next()?
+    {
+        Token::Address(address) => Some(address),
+        _ => None,
+    }
+}
+
+fn decode_decimals(data: &[u8]) -> Option<u8> {
+    if data.len() < 32 {
+        return None;
+    }
+    let decimals = U256::from_big_endian(&data[..32]);
+    if decimals > U256::from(u8::MAX) {
+        return None;
+    }
+    Some(decimals.as_u32() as u8)
+}
+
+// A V2 pool's getReserves() next to what its tokens' balanceOf(pool) report, at one block
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct BalanceSnapshot {
+    pub reserve0: U256,
+    pub reserve1: U256,
+    pub balance0: U256,
+    pub balance1: U256,
+}
+
+// Pools per aggregate3 call (three calls each: getReserves() and two balanceOf())
+pub const BALANCE_SNAPSHOT_BATCH: usize = 300;
+
+/*
+Snapshots every V2 pool of pools at block, for flag_rebasing. Calls go through aggregate3 with
+allowFailure set, a pool any of whose calls revert is left out of the map.
+Other dexes have no getReserves() and are skipped.
+*/
+pub async fn snapshot_balances<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pools: &[Pool],
+    block: u64,
+) -> Result<HashMap<H160, BalanceSnapshot>> {
+    let pools: Vec<&Pool> = pools
+        .iter()
+        .filter(|pool| pool.dex == DexKind::UniswapV2)
+        .collect();
+    let get_reserves = id("getReserves()").to_vec();
+    let balance_of = |owner: H160| {
+        let mut calldata = id("balanceOf(address)").to_vec();
+        calldata.extend(abi::encode(&[Token::Address(owner)]));
+        calldata
+    };
+
+    let mut snapshots = HashMap::new();
+    for batch in pools.chunks(BALANCE_SNAPSHOT_BATCH) {
+        let calls = batch
+            .iter()
+            .flat_map(|pool| {
+                [
+                    (pool.address, get_reserves.clone()),
+                    (pool.token0, balance_of(pool.address)),
+                    (pool.token1, balance_of(pool.address)),
+                ]
+            })
+            .collect();
+        let results = aggregate3(provider.as_ref(), calls, Some(block.into()))
+            .await
+            .map_err(|e| e.context("loading pool balances"))?;
+        for (pool, calls) in batch.iter().zip(results.chunks(3)) {
+            let word = |data: &Option<Vec<u8>>, i: usize| {
+                data.as_deref()
+                    .filter(|data| data.len() >= (i + 1) * 32)
+                    .map(|data| U256::from_big_endian(&data[i * 32..(i + 1) * 32]))
+            };
+            if let (Some(reserve0), Some(reserve1), Some(balance0), Some(balance1)) = (
+                word(&calls[0], 0),
+                word(&calls[0], 1),
+                word(&calls[1], 0),
+                word(&calls[2], 0),
+            ) {
+                snapshots.insert(
+                    pool.address,
+                    BalanceSnapshot {
+                        reserve0,
+                        reserve1,
+                        balance0,
+                        balance1,
+                    },
+                );
+            }
+        }
+    }
+    Ok(snapshots)
+}
+
+// Blocks between the two snapshots detect_rebasing compares, recent enough for a full node
+pub const REBASE_CHECK_BLOCKS: u64 = 64;
+
+/*
+Smallest balance move, in millionths of the balance, that flag_rebasing counts as drift.
+stETH rebases by about 100 a day, dust sent to a pool is orders of magnitude below this.
+*/
+pub const REBASE_MIN_DRIFT_PPM: u64 = 10;
+
+/*
+Marks pools holding a rebasing / elastic supply token (OHM forks, stETH), whose balances move
+without transfers and break the constant product the simulators assume.
+before and after are snapshot_balances at two blocks. A pool is flagged when its reserves didn't
+move between them (no Sync, so nothing was swapped, minted or burned) but a token balance moved
+by at least min_drift_ppm of itself, or when a balance sits below its reserve, which only a
+negative rebase can cause. A sizeable plain transfer into the pool looks the same, the pass errs
+on the side of flagging it.
+Flags are only ever set, pools missing from either snapshot are left alone.
+Returns the pools that got newly flagged.
+*/
+pub fn flag_rebasing(
+    pools: &mut [Pool],
+    before: &HashMap<H160, BalanceSnapshot>,
+    after: &HashMap<H160, BalanceSnapshot>,
+    min_drift_ppm: u64,
+) -> Vec<H160> {
+    let drifted = |before: U256, after: U256| {
+        let moved = if after > before {
+            after - before
+        } else {
+            before - after
+        };
+        !moved.is_zero()
+            && moved.full_mul(U256::from(1_000_000)) >= before.full_mul(U256::from(min_drift_ppm))
+    };
+    let mut flagged = Vec::new();
+    for pool in pools.iter_mut().filter(|pool| !pool.is_rebasing) {
+        let (before, after) = match (before.get(&pool.address), after.get(&pool.address)) {
+            (Some(before), Some(after)) => (before, after),
+            _ => continue,
+        };
+        let no_sync = before.reserve0 == after.reserve0 && before.reserve1 == after.reserve1;
+        let drifted =
+            drifted(before.balance0, after.balance0) || drifted(before.balance1, after.balance1);
+        let shrunk = [before, after].iter().any(|snapshot| {
+            snapshot.balance0 < snapshot.reserve0 || snapshot.balance1 < snapshot.reserve1
+        });
+        if (no_sync && drifted) || shrunk {
+            pool.is_rebasing = true;
+            flagged.push(pool.address);
+        }
+    }
+    if !flagged.is_empty() {
+        info!("{} pools flagged as rebasing", flagged.len());
+    }
+    flagged
+}
+
+/*
+Runs flag_rebasing over pools with snapshot_balances at from and to, REBASE_MIN_DRIFT_PPM
+being the threshold. Both blocks' state must still be available (an archive node for
+anything older than the last 128 blocks). Returns the pools that got newly flagged.
+*/
+pub async fn detect_rebasing<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pools: &mut [Pool],
+    from: u64,
+    to: u64,
+) -> Result<Vec<H160>> {
+    let before = snapshot_balances(provider.clone(), pools, from).await?;
+    let after = snapshot_balances(provider, pools, to).await?;
+    Ok(flag_rebasing(pools, &before, &after, REBASE_MIN_DRIFT_PPM))
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PoolDiff {
+    pub added: Vec<Pool>,
+    pub removed: Vec<Pool>,
+}
+
+/*
+What changed between two pool sets, matched by address: pools only in new are added,
+pools only in old are removed. Each list keeps the order of the set it came from.
+Feed removed to paths::drop_paths_through and generate paths for added only.
+*/
+pub fn diff(old: &[Pool], new: &[Pool]) -> PoolDiff {
+    let old_addresses: HashSet<H160> = old.iter().map(|pool| pool.address).collect();
+    let new_addresses: HashSet<H160> = new.iter().map(|pool| pool.address).collect();
+    PoolDiff {
+        added: new
+            .iter()
+            .filter(|pool| !old_addresses.contains(&pool.address))
+            .cloned()
+            .collect(),
+        removed: old
+            .iter()
+            .filter(|pool| !new_addresses.contains(&pool.address))
+            .cloned()
+            .collect(),
+    }
+}
+
+/*
+Drops pools too shallow to matter, measured in token_in wei.
+A pool is kept if either side's reserve is worth at least min_value_in_token_in:
+the token_in side counts as is, other tokens are priced through their deepest pool against token_in.
+Pools without reserves, or whose tokens have no pool against token_in, can't be part of a
+token_in cycle and are dropped as well.
+*/
+pub fn filter_by_min_reserve(
+    pools: &[Pool],
+    reserves: &HashMap<H160, Reserve>,
+    token_in: H160,
+    min_value_in_token_in: U256,
+) -> Vec<Pool> {
+    // token -> (reserve_in, reserve_token) of its deepest pool against token_in
+    let mut prices: HashMap<H160, (U256, U256)> = HashMap::new();
+    for pool in pools {
+        let reserve = match reserves.get(&pool.address) {
+            Some(reserve) => reserve,
+            None => continue,
+        };
+        let (token, reserve_in, reserve_token) = if pool.token0 == token_in {
+            (pool.token1, reserve.reserve0, reserve.reserve1)
+        } else if pool.token1 == token_in {
+            (pool.token0, reserve.reserve1, reserve.reserve0)
+        } else {
+            continue;
+        };
+        if reserve_token.is_zero() {
+            continue;
+        }
+        let deeper = match prices.get(&token) {
+            Some((best_in, _)) => reserve_in > *best_in,
+            None => true,
+        };
+        if deeper {
+            prices.insert(token, (reserve_in, reserve_token));
+        }
+    }
+
+    let value_in_token_in = |token: H160, amount: U256| -> Option<U256> {
+        if token == token_in {
+            return Some(amount);
+        }
+        let (reserve_in, reserve_token) = prices.get(&token)?;
+        let value = amount.full_mul(*reserve_in) / U512::from(*reserve_token);
+        Some(U256::try_from(value).unwrap_or(U256::MAX))
+    };
+
+    pools
+        .iter()
+        .filter(|pool| match reserves.get(&pool.address) {
+            Some(reserve) => {
+                let value0 = value_in_token_in(pool.token0, reserve.reserve0);
+                let value1 = value_in_token_in(pool.token1, reserve.reserve1);
+                matches!(value0.max(value1), Some(v) if v >= min_value_in_token_in)
+            }
+            None => false,
+        })
+        .cloned()
+        .collect()
+}
+
+/*
+Scores every pool by liquidity, deepest first, as (index into pools, score).
+Liquidity is the geometric mean of the decimal-adjusted reserves, sqrt(x * y), which is the
+constant product's L (V3 pools use their in-range liquidity directly), then divided by the
+deepest pool's so scores fall in [0, 1]. It's in token units rather than a common currency,
+so it ranks venues of the same token against each other best.
+Pools without reserves score 0. Ties keep the order of pools.
+*/
+pub fn rank_pools(pools: &[Pool], reserves: &HashMap<H160, Reserve>) -> Vec<(usize, f64)> {
+    let liquidity: Vec<f64> = pools
+        .iter()
+        .map(|pool| {
+            let reserve = match reserves.get(&pool.address) {
+                Some(reserve) => reserve,
+                None => return 0.0,
+            };
+            let scale = 10f64.powf((pool.decimals0 as f64 + pool.decimals1 as f64) / 2.0);
+            let depth = match pool.dex {
+                DexKind::UniswapV3 { .. } => reserve.liquidity as f64,
+                _ => (u256_to_f64(reserve.reserve0) * u256_to_f64(reserve.reserve1)).sqrt(),
+            };
+            if depth.is_finite() {
+                depth / scale
+            } else {
+                0.0
+            }
+        })
+        .collect();
+
+    let deepest = liquidity.iter().cloned().fold(0.0, f64::max);
+    let mut ranked: Vec<(usize, f64)> = liquidity
+        .into_iter()
+        .enumerate()
+        .map(|(i, depth)| (i, if deepest > 0.0 { depth / deepest } else { 0.0 }))
+        .collect();
+    ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+    ranked
+}
+
+/*
+SQLite cache of the pool list plus the last block whose creation logs were scanned,
+so restarts only need to look at blocks produced since the previous run.
+*/
+pub struct PoolStore {
+    conn: Connection,
+}
+
+impl PoolStore {
+    pub fn open(path: &Path) -> Result<Self> {
+        Self::init(Connection::open(path)?)
+    }
+
+    pub fn open_in_memory() -> Result<Self> {
+        Self::init(Connection::open_in_memory()?)
+    }
+
+    fn init(conn: Connection) -> Result<Self> {
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS pools (
+                address TEXT PRIMARY KEY,
+                dex TEXT NOT NULL,
+                token0 TEXT NOT NULL,
+                token1 TEXT NOT NULL,
+                decimals0 INTEGER NOT NULL,
+                decimals1 INTEGER NOT NULL,
+                fee INTEGER NOT NULL,
+                stable INTEGER NOT NULL,
+                symbol0 TEXT NOT NULL DEFAULT '',
+                symbol1 TEXT NOT NULL DEFAULT '',
+                is_rebasing INTEGER NOT NULL DEFAULT 0
+            );
+            CREATE TABLE IF NOT EXISTS sync_state (
+                id INTEGER PRIMARY KEY CHECK (id = 0),
+                last_block INTEGER NOT NULL
+            );",
+        )?;
+        // Stores created before the rebasing check have no is_rebasing column yet
+        let has_rebasing = conn
+            .prepare("SELECT 1 FROM pragma_table_info('pools') WHERE name = 'is_rebasing'")?
+            .exists([])?;
+        if !has_rebasing {
+            conn.execute_batch(
+                "ALTER TABLE pools ADD COLUMN is_rebasing INTEGER NOT NULL DEFAULT 0",
+            )?;
+        }
+        Ok(Self { conn })
+    }
+
+    pub fn load(&self) -> Result<Vec<Pool>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT address, dex, token0, token1, decimals0, decimals1, fee, stable, symbol0, symbol1,
+                    is_rebasing
+             FROM pools ORDER BY rowid",
+        )?;
+        let rows = stmt.query_map([], |row| {
+            std::result::Result::Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, String>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, u8>(4)?,
+                row.get::<_, u8>(5)?,
+                row.get::<_, u32>(6)?,
+                row.get::<_, bool>(7)?,
+                row.get::<_, String>(8)?,
+                row.get::<_, String>(9)?,
+                row.get::<_, bool>(10)?,
+            ))
+        })?;
+
+        let mut pools = Vec::new();
+        for row in rows {
+            let (
+                address,
+                dex,
+                token0,
+                token1,
+                decimals0,
+                decimals1,
+                fee,
+                stable,
+                symbol0,
+                symbol1,
+                is_rebasing,
+            ) = row?;
+            pools.push(Pool {
+                address: H160::from_str(&address)?,
+                dex: serde_json::from_str(&dex)?,
+                token0: H160::from_str(&token0)?,
+                token1: H160::from_str(&token1)?,
+                decimals0,
+                decimals1,
+                fee,
+                stable,
+                symbol0,
+                symbol1,
+                is_rebasing,
+                ..Default::default()
+            });
+        }
+        Ok(pools)
+    }
+
+    // Pools already in the store are left untouched
+    pub fn save(&mut self, pools: &[Pool]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "INSERT OR IGNORE INTO pools
+                 (address, dex, token0, token1, decimals0, decimals1, fee, stable, symbol0, symbol1,
+                  is_rebasing)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+            )?;
+            for pool in pools {
+                stmt.execute(params![
+                    format!("{:?}", pool.address),
+                    serde_json::to_string(&pool.dex)?,
+                    format!("{:?}", pool.token0),
+                    format!("{:?}", pool.token1),
+                    pool.decimals0,
+                    pool.decimals1,
+                    pool.fee,
+                    pool.stable,
+                    pool.symbol0,
+                    pool.symbol1,
+                    pool.is_rebasing,
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    // Sets is_rebasing on already stored pools, see flag_rebasing
+    pub fn set_rebasing(&mut self, addresses: &[H160]) -> Result<()> {
+        let tx = self.conn.transaction()?;
+        {
+            let mut stmt = tx.prepare("UPDATE pools SET is_rebasing = 1 WHERE address = ?1")?;
+            for address in addresses {
+                stmt.execute(params![format!("{:?}", address)])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn last_synced_block(&self) -> Result<Option<u64>> {
+        let block = self
+            .conn
+            .query_row(
+                "SELECT last_block FROM sync_state WHERE id = 0",
+                [],
+                |row| row.get::<_, i64>(0),
+            )
+            .optional()?;
+        Ok(block.map(|b| b as u64))
+    }
+
+    pub fn set_last_synced_block(&self, block: u64) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO sync_state (id, last_block) VALUES (0, ?1)
+             ON CONFLICT(id) DO UPDATE SET last_block = excluded.last_block",
+            params![block as i64],
+        )?;
+        Ok(())
+    }
+}
+
+/*
+Brings the store up to the latest block and returns the full pool list.
+The first run scans from `from_block`, later runs resume at `last_synced_block + 1`.
+Every run also checks the stored pools for rebasing tokens over the last
+REBASE_CHECK_BLOCKS blocks (see detect_rebasing) and persists what it flags, a failed
+check is logged and leaves the flags as they were.
+*/
+pub async fn sync_pool_store<M: Middleware + 'static>(
+    provider: Arc<M>,
+    store: &mut PoolStore,
+    factories: &[(H160, DexKind)],
+    from_block: u64,
+) -> Result<Vec<Pool>> {
+    let start_block = match store.last_synced_block()? {
+        Some(block) => block + 1,
+        None => from_block,
+    };
+    let latest_block = provider
+        .get_block_number()
+        .await
+        .map_err(|e| anyhow!("get_block_number failed: {}", e))?
+        .as_u64();
+
+    if start_block <= latest_block {
+        let new_pools =
+            load_all_pools(provider.clone(), factories, start_block, latest_block).await?;
+        info!(
+            "Found {} new pools in blocks {}..={}",
+            new_pools.len(),
+            start_block,
+            latest_block
+        );
+        store.save(&new_pools)?;
+        store.set_last_synced_block(latest_block)?;
+    }
+
+    let mut pools = store.load()?;
+    let from = latest_block.saturating_sub(REBASE_CHECK_BLOCKS);
+    match detect_rebasing(provider, &mut pools, from, latest_block).await {
+        Ok(flagged) => store.set_rebasing(&flagged)?,
+        Err(e) => warn!("Rebasing check failed: {:?}", e),
+    }
+    Ok(pools)
+}
+
+#[cfg(test)]
+mod pools_tests {
+    use super::*;
+    use ethers::abi::encode;
+    use ethers::providers::Provider;
+    use ethers::types::Bytes;
+    use ethers::types::U64;
+
+    fn addr(n: u64) -> H160 {
+        H160::from_low_u64_be(n)
+    }
+
+    fn pair_created(factory: H160, token0: H160, token1: H160, pair: H160) -> Log {
+        Log {
+            address: factory,
+            topics: vec![
+                H256::from(keccak256("PairCreated(address,address,address,uint256)")),
+                H256::from(token0),
+                H256::from(token1),
+            ],
+            data: encode(&[Token::Address(pair), Token::Uint(U256::one())]).into(),
+            ..Default::default()
+        }
+    }
+
+    fn pool_created(factory: H160, token0: H160, token1: H160, fee: u32, pool: H160) -> Log {
+        Log {
+            address: factory,
+            topics: vec![
+                H256::from(keccak256(
+                    "PoolCreated(address,address,uint24,int24,address)",
+                )),
+                H256::from(token0),
+                H256::from(token1),
+                H256::from_low_u64_be(fee as u64),
+            ],
+            data: encode(&[Token::Int(U256::from(60)), Token::Address(pool)]).into(),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn overlapping_factories_are_deduplicated() {
+        let (provider, mock) = Provider::mocked();
+        let (factory_a, factory_b) = (addr(1000), addr(2000));
+        let (weth, usdc, dai) = (addr(1), addr(2), addr(3));
+
+        // MockProvider answers in LIFO order: factory_b's response goes in first
+        mock.push::<Vec<Log>, _>(vec![
+            pool_created(factory_b, weth, usdc, 500, addr(11)),
+            pool_created(factory_b, weth, dai, 3000, addr(13)),
+        ])
+        .unwrap();
+        mock.push::<Vec<Log>, _>(vec![
+            pair_created(factory_a, weth, usdc, addr(11)),
+            pair_created(factory_a, usdc, dai, addr(12)),
+        ])
+        .unwrap();
+
+        let factories = [
+            (factory_a, DexKind::UniswapV2),
+            (factory_b, DexKind::UniswapV3 { fee_tier: 0 }),
+        ];
+        let pools = load_all_pools(Arc::new(provider), &factories, 0, 100)
+            .await
+            .unwrap();
+
+        let addresses: Vec<H160> = pools.iter().map(|p| p.address).collect();
+        assert_eq!(addresses, vec![addr(11), addr(12), addr(13)]);
+
+        // The shared address keeps the tag of the first factory that reported it
+        assert_eq!(pools[0].dex, DexKind::UniswapV2);
+        assert_eq!(pools[0].fee, 300);
+        assert_eq!(pools[2].dex, DexKind::UniswapV3 { fee_tier: 3000 });
+        assert_eq!((pools[2].token0, pools[2].token1), (weth, dai));
+    }
+
+    #[tokio::test]
+    async fn logs_are_queried_in_block_ranges() {
+        let (provider, mock) = Provider::mocked();
+        for _ in 0..3 {
+            mock.push::<Vec<Log>, _>(Vec::<Log>::new()).unwrap();
+        }
+
+        let factories = [(addr(1000), DexKind::UniswapV2)];
+        let to_block = 2 * LOG_BLOCK_RANGE + 5;
+        load_all_pools(Arc::new(provider), &factories, 0, to_block)
+            .await
+            .unwrap();
+
+        let starts = [0, LOG_BLOCK_RANGE, 2 * LOG_BLOCK_RANGE];
+        for start in starts {
+            let end = std::cmp::min(start + LOG_BLOCK_RANGE - 1, to_block);
+            let filter = Filter::new()
+                .address(addr(1000))
+                .topic0(H256::from(keccak256(
+                    "PairCreated(address,address,address,uint256)",
+                )))
+                .from_block(start)
+                .to_block(end);
+            mock.assert_request("eth_getLogs", [&filter]).unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn store_resumes_from_last_synced_block() {
+        let mut store = PoolStore::open_in_memory().unwrap();
+        let factory = addr(1000);
+        let seeded = Pool {
+            decimals1: 6,
+            ..test_pool(11, 1, 2)
+        };
+        store.save(&[seeded.clone()]).unwrap();
+        store.set_last_synced_block(500).unwrap();
+        assert_eq!(store.last_synced_block().unwrap(), Some(500));
+
+        let (provider, mock) = Provider::mocked();
+        // LIFO: the block number is requested first, then the logs
+        mock.push::<Vec<Log>, _>(vec![pair_created(factory, addr(2), addr(3), addr(12))])
+            .unwrap();
+        mock.push::<U64, _>(U64::from(600)).unwrap();
+
+        let pools = sync_pool_store(
+            Arc::new(provider),
+            &mut store,
+            &[(factory, DexKind::UniswapV2)],
+            0,
+        )
+        .await
+        .unwrap();
+
+        mock.assert_request("eth_blockNumber", ()).unwrap();
+        let filter = Filter::new()
+            .address(factory)
+            .topic0(H256::from(keccak256(
+                "PairCreated(address,address,address,uint256)",
+            )))
+            .from_block(501u64)
+            .to_block(600u64);
+        mock.assert_request("eth_getLogs", [&filter]).unwrap();
+
+        assert_eq!(pools.len(), 2);
+        assert_eq!(pools[0], seeded);
+        assert_eq!(pools[1].address, addr(12));
+        assert_eq!(store.last_synced_block().unwrap(), Some(600));
+    }
+
+    fn aggregate3_response(results: Vec<Vec<u8>>) -> Bytes {
+        let items = results
+            .into_iter()
+            .map(|data| Token::Tuple(vec![Token::Bool(true), Token::Bytes(data)]))
+            .collect();
+        encode(&[Token::Array(items)]).into()
+    }
+
+    #[test]
+    fn price_is_decimal_adjusted_in_both_directions() {
+        // USDC (6 decimals) / WETH (18 decimals) at 2,000 USDC per WETH
+        let mut pool = test_pool(11, 1, 2);
+        pool.decimals0 = 6;
+        pool.decimals1 = 18;
+        let reserve = Reserve {
+            reserve0: U256::from(20_000_000u64) * U256::exp10(6),
+            reserve1: U256::from(10_000u64) * U256::exp10(18),
+            ..Default::default()
+        };
+
+        assert!((pool.price(&reserve, false) - 2000.0).abs() < 1e-9);
+        assert!((pool.price(&reserve, true) - 0.0005).abs() < 1e-15);
+        assert_eq!(pool.price(&Reserve::default(), true), 0.0);
+    }
+
+    #[test]
+    fn other_token_crosses_the_pool() {
+        let pool = test_pool(11, 1, 2);
+
+        assert!(pool.contains_token(addr(1)));
+        assert!(pool.contains_token(addr(2)));
+        assert!(!pool.contains_token(addr(3)));
+
+        assert_eq!(pool.other_token(addr(1)), Some(addr(2)));
+        assert_eq!(pool.other_token(addr(2)), Some(addr(1)));
+        assert_eq!(pool.other_token(addr(3)), None);
+    }
+
+    #[test]
+    fn fee_pips_normalizes_every_dex() {
+        let mut pool = test_pool(11, 1, 2);
+        assert_eq!(pool.fee_pips(), 3000);
+
+        // 5bps stable pair and a 1% fork
+        pool.stable = true;
+        pool.fee = 50;
+        assert_eq!(pool.fee_pips(), 500);
+        pool.stable = false;
+        pool.fee = 1000;
+        assert_eq!(pool.fee_pips(), 10_000);
+
+        // V3 fees are already in pips
+        pool.dex = DexKind::UniswapV3 { fee_tier: 500 };
+        pool.fee = 500;
+        assert_eq!(pool.fee_pips(), 500);
+    }
+
+    #[test]
+    fn diff_reports_added_and_removed_pools() {
+        use crate::paths::{drop_paths_through, ArbPath};
+
+        let old = vec![
+            test_pool(11, 1, 2),
+            test_pool(12, 2, 3),
+            test_pool(13, 3, 1),
+        ];
+        let new = vec![
+            test_pool(11, 1, 2),
+            test_pool(13, 3, 1),
+            test_pool(14, 2, 3),
+        ];
+
+        let changes = diff(&old, &new);
+        assert_eq!(changes.added, vec![new[2].clone()]);
+        assert_eq!(changes.removed, vec![old[1].clone()]);
+        assert_eq!(diff(&new, &new), PoolDiff::default());
+
+        let through_removed = ArbPath::new(old.clone(), vec![true, true, true]);
+        let through_added = ArbPath::new(new.clone(), vec![true, false, false]);
+        let paths = vec![through_removed, through_added.clone()];
+        assert_eq!(
+            drop_paths_through(paths, &changes.removed),
+            vec![through_added]
+        );
+    }
+
+    #[test]
+    fn reserve_of_picks_the_matching_side() {
+        let pool = test_pool(11, 1, 2);
+        let reserve = Reserve {
+            reserve0: U256::from(100),
+            reserve1: U256::from(200),
+            ..Default::default()
+        };
+
+        assert_eq!(pool.reserve_of(&reserve, addr(1)), Some(U256::from(100)));
+        assert_eq!(pool.reserve_of(&reserve, addr(2)), Some(U256::from(200)));
+        assert_eq!(pool.reserve_of(&reserve, addr(3)), None);
+    }
+
+    #[tokio::test]
+    async fn metadata_handles_string_and_bytes32_symbols() {
+        let (usdc, mkr) = (addr(1), addr(2));
+        let mut pools = vec![test_pool(11, 1, 2)];
+
+        let mut mkr_symbol = [0u8; 32];
+        mkr_symbol[..3].copy_from_slice(b"MKR");
+
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(aggregate3_response(vec![
+            encode(&[Token::String("USDC".to_string())]),
+            encode(&[Token::Uint(U256::from(6))]),
+            mkr_symbol.to_vec(),
+            encode(&[Token::Uint(U256::from(18))]),
+        ]))
+        .unwrap();
+
+        enrich_token_metadata(Arc::new(provider), &mut pools)
+            .await
+            .unwrap();
+
+        assert_eq!(pools[0].symbol0, "USDC");
+        assert_eq!(pools[0].decimals0, 6);
+        assert_eq!(pools[0].symbol1, "MKR");
+        assert_eq!(pools[0].decimals1, 18);
+    }
+
+    // getReserves() and both balanceOf() results of every pool, as aggregate3 returns them
+    fn balances_response(snapshots: &[(u64, u64, u64, u64)]) -> Bytes {
+        aggregate3_response(
+            snapshots
+                .iter()
+                .flat_map(|&(reserve0, reserve1, balance0, balance1)| {
+                    [
+                        encode(&[
+                            Token::Uint(U256::from(reserve0)),
+                            Token::Uint(U256::from(reserve1)),
+                            Token::Uint(U256::zero()),
+                        ]),
+                        encode(&[Token::Uint(U256::from(balance0))]),
+                        encode(&[Token::Uint(U256::from(balance1))]),
+                    ]
+                })
+                .collect(),
+        )
+    }
+
+    #[tokio::test]
+    async fn balance_drift_without_sync_flags_rebasing_pools() {
+        let mut pools = vec![
+            test_pool(11, 1, 2),
+            test_pool(12, 1, 3),
+            test_pool(13, 1, 4),
+            test_pool(14, 1, 5),
+        ];
+        let e18 = 10u64.pow(18);
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+        // LIFO: block 101 is queried second
+        mock.push::<Bytes, _>(balances_response(&[
+            // untouched
+            (1000, 2000, 1000, 2000),
+            // no Sync, yet token3's balance grew 1%
+            (1000, 2000, 1000, 2020),
+            // swapped, reserves follow the balances
+            (1100, 1820, 1100, 1820),
+            // no Sync, someone sent the pool 1 wei
+            (e18, e18, e18, e18 + 1),
+        ]))
+        .unwrap();
+        mock.push::<Bytes, _>(balances_response(&[
+            (1000, 2000, 1000, 2000),
+            (1000, 2000, 1000, 2000),
+            (1000, 2000, 1000, 2000),
+            (e18, e18, e18, e18),
+        ]))
+        .unwrap();
+
+        let before = snapshot_balances(provider.clone(), &pools, 100)
+            .await
+            .unwrap();
+        let after = snapshot_balances(provider, &pools, 101).await.unwrap();
+        assert_eq!(
+            after[&addr(12)],
+            BalanceSnapshot {
+                reserve0: U256::from(1000),
+                reserve1: U256::from(2000),
+                balance0: U256::from(1000),
+                balance1: U256::from(2020),
+            }
+        );
+
+        assert_eq!(
+            flag_rebasing(&mut pools, &before, &after, REBASE_MIN_DRIFT_PPM),
+            vec![addr(12)]
+        );
+        let flagged: Vec<bool> = pools.iter().map(|pool| pool.is_rebasing).collect();
+        assert_eq!(flagged, vec![false, true, false, false]);
+
+        // Already flagged pools aren't counted again
+        assert!(flag_rebasing(&mut pools, &before, &after, REBASE_MIN_DRIFT_PPM).is_empty());
+    }
+
+    #[test]
+    fn cache_rows_keep_every_dex_kind() {
+        let reload = |pool: &Pool| {
+            let mut writer = csv::Writer::from_writer(vec![]);
+            writer.serialize(pool.cache_row()).unwrap();
+            let data = writer.into_inner().unwrap();
+            let mut reader = csv::ReaderBuilder::new()
+                .has_headers(false)
+                .from_reader(data.as_slice());
+            Pool::try_from(reader.records().next().unwrap().unwrap())
+        };
+
+        for (dex, fee) in [
+            (DexKind::UniswapV2, 300),
+            (DexKind::UniswapV3 { fee_tier: 500 }, 500),
+            (DexKind::Curve { amp: 200 }, 4),
+            (
+                DexKind::Balancer {
+                    weight0: 8 * 10u64.pow(17),
+                    weight1: 2 * 10u64.pow(17),
+                },
+                3_000,
+            ),
+        ] {
+            let pool = Pool {
+                dex,
+                fee,
+                decimals0: 6,
+                decimals1: 18,
+                ..test_pool(1, 2, 3)
+            };
+            assert_eq!(reload(&pool).unwrap(), pool);
+        }
+
+        let unknown = StringRecord::from(vec![
+            "0x0000000000000000000000000000000000000001",
+            "7",
+            "0x0000000000000000000000000000000000000002",
+            "0x0000000000000000000000000000000000000003",
+            "18",
+            "18",
+            "300",
+        ]);
+        assert!(Pool::try_from(unknown).is_err());
+    }
+
+    #[test]
+    fn rebasing_flags_are_stored() {
+        // A store from before the is_rebasing column
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(
+            "CREATE TABLE pools (
+                address TEXT PRIMARY KEY,
+                dex TEXT NOT NULL,
+                token0 TEXT NOT NULL,
+                token1 TEXT NOT NULL,
+                decimals0 INTEGER NOT NULL,
+                decimals1 INTEGER NOT NULL,
+                fee INTEGER NOT NULL,
+                stable INTEGER NOT NULL,
+                symbol0 TEXT NOT NULL DEFAULT '',
+                symbol1 TEXT NOT NULL DEFAULT ''
+            );
+            INSERT INTO pools (address, dex, token0, token1, decimals0, decimals1, fee, stable)
+            VALUES ('0x000000000000000000000000000000000000000b', '\"UniswapV2\"',
+                    '0x0000000000000000000000000000000000000001',
+                    '0x0000000000000000000000000000000000000002', 18, 18, 300, 0);",
+        )
+        .unwrap();
+        let mut store = PoolStore::init(conn).unwrap();
+        assert_eq!(store.load().unwrap(), vec![test_pool(11, 1, 2)]);
+
+        let rebasing = Pool {
+            is_rebasing: true,
+            ..test_pool(12, 1, 3)
+        };
+        store.save(&[rebasing.clone()]).unwrap();
+        store.set_rebasing(&[addr(11)]).unwrap();
+        let loaded = store.load().unwrap();
+        assert!(loaded[0].is_rebasing);
+        assert_eq!(loaded[1], rebasing);
+    }
+
+    #[tokio::test]
+    async fn fees_are_read_from_the_pool_or_fall_back() {
+        let (provider, mock) = Provider::mocked();
+        let provider = Arc::new(provider);
+
+        mock.push::<Bytes, _>(encode(&[Token::Uint(U256::from(3000))]).into())
+            .unwrap();
+        let v3 = DexKind::UniswapV3 { fee_tier: 500 };
+        assert_eq!(detect_fee(provider.clone(), addr(11), v3).await, Some(3000));
+
+        // Nothing mocked: the call fails and the tier from the creation log is kept
+        assert_eq!(detect_fee(provider.clone(), addr(11), v3).await, Some(500));
+
+        // V2 never calls out
+        assert_eq!(
+            detect_fee(provider.clone(), addr(12), DexKind::UniswapV2).await,
+            Some(DEFAULT_V2_FEE)
+        );
+
+        // 0.04% Curve fee, 1e10 scaled
+        mock.push::<Bytes, _>(encode(&[Token::Uint(U256::from(4_000_000))]).into())
+            .unwrap();
+        let curve = DexKind::Curve { amp: 100 };
+        assert_eq!(
+            detect_fee(provider.clone(), addr(13), curve).await,
+            Some(40)
+        );
+        // 200%, 1e10 scaled
+        mock.push::<Bytes, _>(encode(&[Token::Uint(U256::from(20_000_000_000u64))]).into())
+            .unwrap();
+        assert_eq!(detect_fee(provider.clone(), addr(13), curve).await, None);
+        assert_eq!(detect_fee(provider, addr(13), curve).await, None);
+    }
+
+    fn reserve(reserve0: U256, reserve1: U256) -> Reserve {
+        Reserve {
+            reserve0,
+            reserve1,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn shallow_pools_are_filtered_out() {
+        let (usdc, weth, dai, shitcoin) = (1, 2, 3, 4);
+        let usdc_unit = U256::exp10(6);
+        let eth_unit = U256::exp10(18);
+
+        let pools = vec![
+            test_pool(11, usdc, weth), // 5 USDC of liquidity
+            test_pool(12, usdc, weth), // 2M USDC, prices WETH at 2000 USDC
+            test_pool(13, weth, dai),  // 100 WETH, valued via pool 12
+            test_pool(14, shitcoin, weth),
+            test_pool(15, dai, shitcoin), // no reserves known
+        ];
+        let reserves = HashMap::from([
+            (addr(11), reserve(usdc_unit * 5, U256::exp10(15) * 2)),
+            (addr(12), reserve(usdc_unit * 2_000_000, eth_unit * 1000)),
+            (addr(13), reserve(eth_unit * 100, eth_unit * 200_000)),
+            (addr(14), reserve(eth_unit * 1_000_000, U256::exp10(17))),
+        ]);
+
+        let kept = filter_by_min_reserve(&pools, &reserves, addr(usdc), usdc_unit * 1000);
+        let kept: Vec<H160> = kept.iter().map(|p| p.address).collect();
+        assert_eq!(kept, vec![addr(12), addr(13)]);
+    }
+
+    #[test]
+    fn seed_files_load_from_csv_and_json() {
+        let csv = format!(
+            "address,token0,token1,dex,fee\n\n{:?},{:?},{:?},v2,300\n{:?}, {:?}, {:?}, V3, 500 # 5bps\n",
+            addr(11),
+            addr(1),
+            addr(2),
+            addr(12),
+            addr(2),
+            addr(3)
+        );
+        let file = std::env::temp_dir().join("mev-templates-seed-pools.csv");
+        std::fs::write(&file, csv).unwrap();
+        let pools = load_from_file(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+
+        assert_eq!(pools.len(), 2);
+        assert_eq!(pools[0].address, addr(11));
+        assert_eq!(pools[0].dex, DexKind::UniswapV2);
+        assert_eq!(pools[0].fee_pips(), 3000);
+        assert_eq!((pools[0].token0, pools[0].token1), (addr(1), addr(2)));
+        assert_eq!(pools[1].dex, DexKind::UniswapV3 { fee_tier: 500 });
+        assert_eq!(pools[1].fee_pips(), 500);
+        assert_eq!(pools[1].decimals0, 18);
+
+        // Same pools as JSON, without the optional fields
+        let mut json: Vec<serde_json::Value> = pools
+            .iter()
+            .map(|pool| serde_json::to_value(pool).unwrap())
+            .collect();
+        json[1].as_object_mut().unwrap().remove("symbol0");
+        json[1].as_object_mut().unwrap().remove("is_rebasing");
+        let file = std::env::temp_dir().join("mev-templates-seed-pools.json");
+        std::fs::write(&file, serde_json::to_string(&json).unwrap()).unwrap();
+        let loaded = load_from_file(&file).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert_eq!(loaded, pools);
+
+        // Neither JSON nor CSV
+        let file = std::env::temp_dir().join("mev-templates-seed-pools.txt");
+        assert!(load_from_file(&file).is_err());
+    }
+
+    #[test]
+    fn malformed_seed_rows_name_their_line() {
+        let good = format!("{:?},{:?},{:?},v2,300", addr(11), addr(1), addr(2));
+        let broken = [
+            // bad token address
+            format!("{:?},0x1234,{:?},v2,300", addr(12), addr(2)),
+            // missing fee
+            format!("{:?},{:?},{:?},v2", addr(12), addr(1), addr(2)),
+            // Curve needs its amp, not in a row
+            format!("{:?},{:?},{:?},curve,4", addr(12), addr(1), addr(2)),
+            // same token twice
+            format!("{:?},{:?},{:?},v2,300", addr(12), addr(1), addr(1)),
+        ];
+        let file = std::env::temp_dir().join("mev-templates-seed-pools-broken.csv");
+        for row in broken {
+            std::fs::write(
+                &file,
+                format!("address,token0,token1,dex,fee\n{}\n{}\n", good, row),
+            )
+            .unwrap();
+            let err = load_from_file(&file).unwrap_err().to_string();
+            assert!(err.contains("line 3"), "{}", err);
+        }
+        std::fs::remove_file(&file).unwrap();
+    }
+}
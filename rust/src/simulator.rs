@@ -1,4 +1,26 @@
-use ethers::types::U256;
+use anyhow::{anyhow, Result};
+use ethers::{
+    abi::parse_abi,
+    contract::BaseContract,
+    providers::Middleware,
+    types::{BlockId, BlockNumber, TransactionRequest, H160, U256, U512},
+    utils::keccak256,
+};
+use revm::{
+    db::{CacheDB, DatabaseRef, EthersDB},
+    primitives::{
+        Address as rAddress, Bytes as rBytes, ExecutionResult, TransactTo, U256 as rU256,
+    },
+    EVM,
+};
+use std::{fmt::Debug, sync::Arc};
+
+use crate::abi::arb_calldata;
+use crate::paths::ArbPath;
+use crate::utils::u256_to_f64;
+
+// Fee denominator: simulators take fees in pips (1e-6), 3000 = 30bps
+pub const PIPS: u32 = 1_000_000;
 
 pub struct UniswapV2Simulator;
 
@@ -28,16 +50,1486 @@ impl UniswapV2Simulator {
         }
     }
 
+    // fee_pips is in 1e-6 (3000 for a 30bps pair), see Pool::fee_pips
     pub fn get_amount_out(
         amount_in: U256,
         reserve_in: U256,
         reserve_out: U256,
-        fee: U256,
+        fee_pips: u32,
+    ) -> Option<U256> {
+        Self::get_amount_out_with_denominator(
+            amount_in,
+            reserve_in,
+            reserve_out,
+            PIPS.checked_sub(fee_pips)?,
+            PIPS,
+        )
+    }
+
+    /*
+    get_amount_out for a fork's own fee scale, as written in its pair contract:
+    amountIn * fee_numerator / (reserveIn * fee_denominator + amountIn * fee_numerator),
+    so fee_numerator is the share that's kept, 997/1000 for Uniswap, 9975/10000 for PancakeSwap.
+    Any fraction with fee_denominator dividing PIPS gives the same result as get_amount_out.
+    */
+    pub fn get_amount_out_with_denominator(
+        amount_in: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        fee_numerator: u32,
+        fee_denominator: u32,
     ) -> Option<U256> {
-        let fee = fee / U256::from(100);
-        let amount_in_with_fee = amount_in * (U256::from(1000) - fee);
-        let numerator = amount_in_with_fee * reserve_out;
-        let denominator = (reserve_in * 1000) + amount_in_with_fee;
+        if fee_numerator > fee_denominator {
+            return None;
+        }
+        let amount_in_with_fee = amount_in.checked_mul(U256::from(fee_numerator))?;
+        let numerator = amount_in_with_fee.checked_mul(reserve_out)?;
+        let denominator = reserve_in
+            .checked_mul(U256::from(fee_denominator))?
+            .checked_add(amount_in_with_fee)?;
         numerator.checked_div(denominator)
     }
+
+    /*
+    Pair reserves after swapping amount_in through it, as (reserve_in, reserve_out): the whole
+    amount_in (fee included, it stays with the LPs) comes in, get_amount_out goes out.
+    Nothing is mutated, this is a preview of what the swap would leave behind.
+    */
+    pub fn apply_swap(
+        reserve_in: U256,
+        reserve_out: U256,
+        amount_in: U256,
+        fee_pips: u32,
+    ) -> Option<(U256, U256)> {
+        let amount_out = Self::get_amount_out(amount_in, reserve_in, reserve_out, fee_pips)?;
+        Some((
+            reserve_in.checked_add(amount_in)?,
+            reserve_out.checked_sub(amount_out)?,
+        ))
+    }
+
+    pub fn get_amount_in(
+        amount_out: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        fee_pips: u32,
+    ) -> Option<U256> {
+        if amount_out >= reserve_out {
+            return None;
+        }
+        let fee_complement = U256::from(PIPS.checked_sub(fee_pips)?);
+        let numerator = reserve_in
+            .checked_mul(amount_out)?
+            .checked_mul(U256::from(PIPS))?;
+        let denominator = (reserve_out - amount_out).checked_mul(fee_complement)?;
+        Some(numerator.checked_div(denominator)? + 1)
+    }
+}
+
+/*
+Per-hop amounts quoted by a Uniswap V2 style router's getAmountsOut for path_tokens, read at
+block (None for latest). Like simulate_v2_path_verbose the input amount is left out, so the two
+can be compared entry by entry before submitting. None if the call reverts (e.g. a pair on the
+path doesn't exist on that router) or the node can't be reached.
+*/
+pub async fn router_amounts_out<M: Middleware + 'static>(
+    provider: Arc<M>,
+    router: H160,
+    path_tokens: &[H160],
+    amount_in: U256,
+    block: Option<u64>,
+) -> Option<Vec<U256>> {
+    let router_abi = BaseContract::from(
+        parse_abi(&[
+            "function getAmountsOut(uint amountIn, address[] path) external view returns (uint[] amounts)",
+        ])
+        .ok()?,
+    );
+    let calldata = router_abi
+        .encode("getAmountsOut", (amount_in, path_tokens.to_vec()))
+        .ok()?;
+    let tx = TransactionRequest::new().to(router).data(calldata);
+    let output = provider
+        .call(&tx.into(), block.map(BlockId::from))
+        .await
+        .ok()?;
+    let amounts: Vec<U256> = router_abi.decode_output("getAmountsOut", output).ok()?;
+    amounts.get(1..).map(|amounts| amounts.to_vec())
+}
+
+pub struct UniswapV3Simulator;
+
+impl UniswapV3Simulator {
+    // TickMath bounds, sqrt prices at MIN_TICK / MAX_TICK
+    pub const MIN_TICK: i32 = -887272;
+    pub const MAX_TICK: i32 = 887272;
+    // 4295128739
+    pub const MIN_SQRT_RATIO: U256 = U256([0x1000276a3, 0, 0, 0]);
+    // 1461446703485210103287273052203988822378723970342
+    pub const MAX_SQRT_RATIO: U256 = U256([0x5d951d5263988d26, 0xefd1fc6a50648849, 0xfffd8963, 0]);
+
+    /*
+    Exact input swap against the current tick's liquidity, mirroring SwapMath.computeSwapStep
+    for a step that never reaches the next initialized tick.
+    Swaps large enough to leave the current range are priced as if the in-range liquidity
+    extended indefinitely, use get_amount_out_crossing_ticks when the tick data is at hand.
+    Returns None on zero liquidity/price or when an intermediate value overflows.
+    */
+    pub fn get_amount_out(
+        amount_in: U256,
+        sqrt_price_x96: U256,
+        liquidity: u128,
+        fee_pips: u32,
+        zero_for_one: bool,
+    ) -> Option<U256> {
+        let fee_complement = PIPS.checked_sub(fee_pips)?;
+        let amount_in_less_fee = mul_div(amount_in, U256::from(fee_complement), U256::from(PIPS))?;
+        let sqrt_price_next = Self::get_next_sqrt_price_from_input(
+            sqrt_price_x96,
+            liquidity,
+            amount_in_less_fee,
+            zero_for_one,
+        )?;
+
+        if zero_for_one {
+            Self::get_amount1_delta(sqrt_price_next, sqrt_price_x96, liquidity, false)
+        } else {
+            Self::get_amount0_delta(sqrt_price_x96, sqrt_price_next, liquidity, false)
+        }
+    }
+
+    /*
+    Exact input swap walking across initialized ticks, like UniswapV3Pool.swap (and so the
+    quoter) without a price limit: each step runs to the next initialized tick or until
+    amount_in is used up, and liquidity picks up the tick's liquidity_net as it's crossed.
+    ticks are (tick, liquidity_net) of the initialized ticks, sorted by tick. tick is the
+    pool's current tick from slot0.
+    None if the liquidity runs out before amount_in is consumed, or on overflow.
+    */
+    pub fn get_amount_out_crossing_ticks(
+        amount_in: U256,
+        sqrt_price_x96: U256,
+        tick: i32,
+        liquidity: u128,
+        ticks: &[(i32, i128)],
+        fee_pips: u32,
+        zero_for_one: bool,
+    ) -> Option<U256> {
+        let mut amount_remaining = amount_in;
+        let mut amount_out = U256::zero();
+        let mut sqrt_price = sqrt_price_x96;
+        let mut tick = tick;
+        let mut liquidity = liquidity;
+
+        while !amount_remaining.is_zero() {
+            // Next initialized tick in the swap direction: at or below the current one for
+            // zero_for_one, strictly above it otherwise
+            let next = if zero_for_one {
+                let below = ticks.partition_point(|(t, _)| *t <= tick);
+                below.checked_sub(1).map(|i| ticks[i])
+            } else {
+                ticks
+                    .get(ticks.partition_point(|(t, _)| *t <= tick))
+                    .copied()
+            };
+            let sqrt_price_target = match next {
+                Some((next_tick, _)) => Self::get_sqrt_ratio_at_tick(next_tick)?,
+                // Past the last initialized tick the liquidity is usually zero, run to the bound
+                None if zero_for_one => Self::MIN_SQRT_RATIO + 1,
+                None => Self::MAX_SQRT_RATIO - 1,
+            };
+
+            if liquidity == 0 {
+                // Nothing to trade against until the next tick adds liquidity
+                sqrt_price = sqrt_price_target;
+            } else {
+                let (sqrt_price_next, step_in, step_out, fee_amount) = Self::compute_swap_step(
+                    sqrt_price,
+                    sqrt_price_target,
+                    liquidity,
+                    amount_remaining,
+                    fee_pips,
+                )?;
+                amount_remaining =
+                    amount_remaining.checked_sub(step_in.checked_add(fee_amount)?)?;
+                amount_out = amount_out.checked_add(step_out)?;
+                sqrt_price = sqrt_price_next;
+            }
+
+            if sqrt_price == sqrt_price_target {
+                let (next_tick, liquidity_net) = next?;
+                // liquidity_net is added going up, subtracted going down
+                let delta = if zero_for_one {
+                    liquidity_net.checked_neg()?
+                } else {
+                    liquidity_net
+                };
+                liquidity = if delta < 0 {
+                    liquidity.checked_sub(delta.unsigned_abs())?
+                } else {
+                    liquidity.checked_add(delta as u128)?
+                };
+                tick = if zero_for_one {
+                    next_tick - 1
+                } else {
+                    next_tick
+                };
+            }
+        }
+        Some(amount_out)
+    }
+
+    /*
+    SwapMath.computeSwapStep for an exact input amount: swaps amount_remaining (fee included)
+    from sqrt_price_current towards sqrt_price_target, the direction following from which of
+    the two is higher. Returns (sqrt_price_next, amount_in, amount_out, fee_amount), reaching
+    the target only if amount_remaining covers it.
+    */
+    pub fn compute_swap_step(
+        sqrt_price_current: U256,
+        sqrt_price_target: U256,
+        liquidity: u128,
+        amount_remaining: U256,
+        fee_pips: u32,
+    ) -> Option<(U256, U256, U256, U256)> {
+        let zero_for_one = sqrt_price_current >= sqrt_price_target;
+        let fee_complement = PIPS.checked_sub(fee_pips)?;
+        let amount_remaining_less_fee = mul_div(
+            amount_remaining,
+            U256::from(fee_complement),
+            U256::from(PIPS),
+        )?;
+
+        let amount_to_target = if zero_for_one {
+            Self::get_amount0_delta(sqrt_price_target, sqrt_price_current, liquidity, true)?
+        } else {
+            Self::get_amount1_delta(sqrt_price_current, sqrt_price_target, liquidity, true)?
+        };
+        let sqrt_price_next = if amount_remaining_less_fee >= amount_to_target {
+            sqrt_price_target
+        } else {
+            Self::get_next_sqrt_price_from_input(
+                sqrt_price_current,
+                liquidity,
+                amount_remaining_less_fee,
+                zero_for_one,
+            )?
+        };
+        let reached_target = sqrt_price_next == sqrt_price_target;
+
+        let (amount_in, amount_out) = if zero_for_one {
+            (
+                if reached_target {
+                    amount_to_target
+                } else {
+                    Self::get_amount0_delta(sqrt_price_next, sqrt_price_current, liquidity, true)?
+                },
+                Self::get_amount1_delta(sqrt_price_next, sqrt_price_current, liquidity, false)?,
+            )
+        } else {
+            (
+                if reached_target {
+                    amount_to_target
+                } else {
+                    Self::get_amount1_delta(sqrt_price_current, sqrt_price_next, liquidity, true)?
+                },
+                Self::get_amount0_delta(sqrt_price_current, sqrt_price_next, liquidity, false)?,
+            )
+        };
+        // A step that stops short keeps whatever is left as fee, as on-chain
+        let fee_amount = if reached_target {
+            mul_div_rounding_up(amount_in, U256::from(fee_pips), U256::from(fee_complement))?
+        } else {
+            amount_remaining.checked_sub(amount_in)?
+        };
+        Some((sqrt_price_next, amount_in, amount_out, fee_amount))
+    }
+
+    // TickMath.getSqrtRatioAtTick: sqrt(1.0001^tick) * 2^96, rounded up
+    pub fn get_sqrt_ratio_at_tick(tick: i32) -> Option<U256> {
+        // 2^128 / sqrt(1.0001)^(2^i) for bits 1 to 19 of |tick|, bit 0 seeds ratio below
+        const RATIOS: [U256; 19] = [
+            U256([0x59a46990580e213a, 0xfff97272373d4132, 0, 0]),
+            U256([0xef12357cf3c7fdcc, 0xfff2e50f5f656932, 0, 0]),
+            U256([0x1c3624eaa0941cd0, 0xffe5caca7e10e4e6, 0, 0]),
+            U256([0xc9db58835c926644, 0xffcb9843d60f6159, 0, 0]),
+            U256([0x472e6896dfb254c0, 0xff973b41fa98c081, 0, 0]),
+            U256([0x43ec78b326b52861, 0xff2ea16466c96a38, 0, 0]),
+            U256([0x11c461f1969c3053, 0xfe5dee046a99a2a8, 0, 0]),
+            U256([0xdcffc83b479aa3a4, 0xfcbe86c7900a88ae, 0, 0]),
+            U256([0x6f2b074cf7815e54, 0xf987a7253ac41317, 0, 0]),
+            U256([0x940c7a398e4b70f3, 0xf3392b0822b70005, 0, 0]),
+            U256([0x43b29c7fa6e889d9, 0xe7159475a2c29b74, 0, 0]),
+            U256([0x845ad8f792aa5825, 0xd097f3bdfd2022b8, 0, 0]),
+            U256([0x8a65dc1f90e061e5, 0xa9f746462d870fdf, 0, 0]),
+            U256([0x90bb3df62baf32f7, 0x70d869a156d2a1b8, 0, 0]),
+            U256([0x81231505542fcfa6, 0x31be135f97d08fd9, 0, 0]),
+            U256([0xc677de54f3e99bc9, 0x9aa508b5b7a84e1, 0, 0]),
+            U256([0x6699c329225ee604, 0x5d6af8dedb8119, 0, 0]),
+            U256([0x1ea926041bedfe98, 0x2216e584f5fa, 0, 0]),
+            U256([0x91f7dc42444e8fa2, 0x48a1703, 0, 0]),
+        ];
+        let abs_tick = tick.unsigned_abs();
+        if abs_tick > Self::MAX_TICK as u32 {
+            return None;
+        }
+
+        let mut ratio = if abs_tick & 1 != 0 {
+            U256([0xaa2d162d1a594001, 0xfffcb933bd6fad37, 0, 0])
+        } else {
+            U256::one() << 128
+        };
+        for (bit, factor) in RATIOS.iter().enumerate() {
+            if abs_tick & (2 << bit) != 0 {
+                ratio = (ratio * *factor) >> 128;
+            }
+        }
+        if tick > 0 {
+            ratio = U256::MAX / ratio;
+        }
+        let rounding = if (ratio & U256::from(u32::MAX)).is_zero() {
+            0
+        } else {
+            1
+        };
+        Some((ratio >> 32) + rounding)
+    }
+
+    pub fn get_next_sqrt_price_from_input(
+        sqrt_price_x96: U256,
+        liquidity: u128,
+        amount_in: U256,
+        zero_for_one: bool,
+    ) -> Option<U256> {
+        if sqrt_price_x96.is_zero() || liquidity == 0 {
+            return None;
+        }
+        let liquidity = U256::from(liquidity);
+
+        if zero_for_one {
+            // token0 in, rounding up: L * P / (L + amount * P)
+            if amount_in.is_zero() {
+                return Some(sqrt_price_x96);
+            }
+            let numerator = liquidity << 96;
+            if let Some(product) = amount_in.checked_mul(sqrt_price_x96) {
+                if let Some(denominator) = numerator.checked_add(product) {
+                    return mul_div_rounding_up(numerator, sqrt_price_x96, denominator);
+                }
+            }
+            div_rounding_up(
+                numerator,
+                (numerator / sqrt_price_x96).checked_add(amount_in)?,
+            )
+        } else {
+            // token1 in, rounding down: P + amount / L
+            let quotient = mul_div(amount_in, U256::one() << 96, liquidity)?;
+            let sqrt_price_next = sqrt_price_x96.checked_add(quotient)?;
+            // sqrt prices are uint160 on-chain
+            if sqrt_price_next.bits() > 160 {
+                None
+            } else {
+                Some(sqrt_price_next)
+            }
+        }
+    }
+
+    pub fn get_amount0_delta(
+        sqrt_price_a_x96: U256,
+        sqrt_price_b_x96: U256,
+        liquidity: u128,
+        round_up: bool,
+    ) -> Option<U256> {
+        let (lower, upper) = if sqrt_price_a_x96 > sqrt_price_b_x96 {
+            (sqrt_price_b_x96, sqrt_price_a_x96)
+        } else {
+            (sqrt_price_a_x96, sqrt_price_b_x96)
+        };
+        if lower.is_zero() {
+            return None;
+        }
+        let numerator1 = U256::from(liquidity) << 96;
+        let numerator2 = upper - lower;
+
+        if round_up {
+            div_rounding_up(mul_div_rounding_up(numerator1, numerator2, upper)?, lower)
+        } else {
+            mul_div(numerator1, numerator2, upper)?.checked_div(lower)
+        }
+    }
+
+    pub fn get_amount1_delta(
+        sqrt_price_a_x96: U256,
+        sqrt_price_b_x96: U256,
+        liquidity: u128,
+        round_up: bool,
+    ) -> Option<U256> {
+        let (lower, upper) = if sqrt_price_a_x96 > sqrt_price_b_x96 {
+            (sqrt_price_b_x96, sqrt_price_a_x96)
+        } else {
+            (sqrt_price_a_x96, sqrt_price_b_x96)
+        };
+        let liquidity = U256::from(liquidity);
+
+        if round_up {
+            mul_div_rounding_up(liquidity, upper - lower, U256::one() << 96)
+        } else {
+            mul_div(liquidity, upper - lower, U256::one() << 96)
+        }
+    }
+}
+
+pub struct StableSwapSimulator;
+
+impl StableSwapSimulator {
+    /*
+    Solidly (Velodrome/Aerodrome) stable curve: x^3 * y + y^3 * x = k.
+    Reserves and amounts are normalized to 18 decimals before solving for y.
+    fee_pips is in 1e-6, like UniswapV2Simulator::get_amount_out.
+    */
+    pub fn get_amount_out(
+        amount_in: U256,
+        reserve_in: U256,
+        reserve_out: U256,
+        decimals_in: u8,
+        decimals_out: u8,
+        fee_pips: u32,
+    ) -> Option<U256> {
+        let one = U256::exp10(18);
+        let unit_in = U256::exp10(decimals_in as usize);
+        let unit_out = U256::exp10(decimals_out as usize);
+
+        // a fee over 100% (bad pool data) can't be quoted
+        let amount_in = amount_in
+            .checked_sub(amount_in.checked_mul(U256::from(fee_pips))? / U256::from(PIPS))?;
+
+        let reserve_in = reserve_in.checked_mul(one)? / unit_in;
+        let reserve_out = reserve_out.checked_mul(one)? / unit_out;
+        let amount_in = amount_in.checked_mul(one)? / unit_in;
+
+        let xy = Self::k(reserve_in, reserve_out)?;
+        let y = Self::get_y(amount_in.checked_add(reserve_in)?, xy, reserve_out)?;
+        let amount_out = reserve_out.checked_sub(y)?;
+        Some(amount_out.checked_mul(unit_out)? / one)
+    }
+
+    fn k(x: U256, y: U256) -> Option<U256> {
+        let one = U256::exp10(18);
+        let a = x.checked_mul(y)? / one;
+        let b = x.checked_mul(x)? / one + y.checked_mul(y)? / one;
+        Some(a.checked_mul(b)? / one)
+    }
+
+    fn f(x0: U256, y: U256) -> Option<U256> {
+        let one = U256::exp10(18);
+        let y3 = (y.checked_mul(y)? / one).checked_mul(y)? / one;
+        let x3 = (x0.checked_mul(x0)? / one).checked_mul(x0)? / one;
+        Some(x0.checked_mul(y3)? / one + x3.checked_mul(y)? / one)
+    }
+
+    fn d(x0: U256, y: U256) -> Option<U256> {
+        let one = U256::exp10(18);
+        let y2 = y.checked_mul(y)? / one;
+        let x3 = (x0.checked_mul(x0)? / one).checked_mul(x0)? / one;
+        Some(U256::from(3).checked_mul(x0)?.checked_mul(y2)? / one + x3)
+    }
+
+    // Newton iteration for y given x0 and the invariant xy, as in the Solidly pair contract
+    fn get_y(x0: U256, xy: U256, mut y: U256) -> Option<U256> {
+        let one = U256::exp10(18);
+        for _ in 0..255 {
+            let k = Self::f(x0, y)?;
+            let d = Self::d(x0, y)?;
+            if k < xy {
+                let mut dy = (xy - k).checked_mul(one)?.checked_div(d)?;
+                if dy.is_zero() {
+                    if Self::f(x0, y + 1)? > xy {
+                        return Some(y + 1);
+                    }
+                    dy = U256::one();
+                }
+                y = y.checked_add(dy)?;
+            } else {
+                let mut dy = (k - xy).checked_mul(one)?.checked_div(d)?;
+                if dy.is_zero() {
+                    if k == xy || Self::f(x0, y.checked_sub(U256::one())?)? < xy {
+                        return Some(y);
+                    }
+                    dy = U256::one();
+                }
+                y = y.checked_sub(dy)?;
+            }
+        }
+        None
+    }
+}
+
+pub struct WeightedSimulator;
+
+impl WeightedSimulator {
+    /*
+    Balancer weighted pool swap (WeightedMath._calcOutGivenIn):
+        out = balance_out * (1 - (balance_in / (balance_in + amount_in_after_fee)) ^ (weight_in / weight_out))
+    fee_pips comes off amount_in first. Only the ratio of the weights matters, so Balancer's
+    normalized 1e18 weights can be passed as they are. Like the Vault, inputs above 30% of
+    balance_in are rejected.
+    The power is taken in f64 through ln_1p/exp_m1, which stays accurate to ~1e-12 even for
+    tiny trades but won't match the pool's LogExpMath to the last wei.
+    */
+    pub fn get_amount_out(
+        amount_in: U256,
+        balance_in: U256,
+        weight_in: u64,
+        balance_out: U256,
+        weight_out: u64,
+        fee_pips: u32,
+    ) -> Option<U256> {
+        if balance_in.is_zero() || balance_out.is_zero() || weight_in == 0 || weight_out == 0 {
+            return None;
+        }
+        if amount_in > balance_in.checked_mul(U256::from(3))? / 10 {
+            return None;
+        }
+        let amount_in = mul_div(
+            amount_in,
+            U256::from(PIPS.checked_sub(fee_pips)?),
+            U256::from(PIPS),
+        )?;
+
+        let exponent = weight_in as f64 / weight_out as f64;
+        let growth = u256_to_f64(amount_in) / u256_to_f64(balance_in);
+        // 1 - (1 / (1 + growth)) ^ exponent
+        let share_out = -(-exponent * growth.ln_1p()).exp_m1();
+        if !(0.0..1.0).contains(&share_out) {
+            return None;
+        }
+        let one = U256::exp10(18);
+        mul_div(balance_out, U256::from((share_out * 1e18) as u128), one)
+    }
+}
+
+fn mul_div(a: U256, b: U256, denominator: U256) -> Option<U256> {
+    if denominator.is_zero() {
+        return None;
+    }
+    let result = a.full_mul(b) / U512::from(denominator);
+    U256::try_from(result).ok()
+}
+
+fn mul_div_rounding_up(a: U256, b: U256, denominator: U256) -> Option<U256> {
+    if denominator.is_zero() {
+        return None;
+    }
+    let (quotient, remainder) = a.full_mul(b).div_mod(U512::from(denominator));
+    let result = U256::try_from(quotient).ok()?;
+    if remainder.is_zero() {
+        Some(result)
+    } else {
+        result.checked_add(U256::one())
+    }
+}
+
+fn div_rounding_up(a: U256, b: U256) -> Option<U256> {
+    let (quotient, remainder) = a.div_mod(b);
+    if remainder.is_zero() {
+        Some(quotient)
+    } else {
+        quotient.checked_add(U256::one())
+    }
+}
+
+pub struct CurveSimulator;
+
+impl CurveSimulator {
+    const FEE_DENOMINATOR: u64 = 10_000_000_000;
+    const MAX_ITERATIONS: usize = 255;
+
+    /*
+    StableSwap get_dy for balances that are already normalized to 18 decimals
+    (xp in Curve's terms). amp is the plain A (no A_PRECISION), fee uses Curve's
+    1e10 denominator (3pool: 1_000_000 = 0.01%).
+    */
+    pub fn get_dy(
+        i: usize,
+        j: usize,
+        dx: U256,
+        balances: &[U256],
+        amp: u64,
+        fee: u64,
+    ) -> Option<U256> {
+        let rates = vec![U256::exp10(18); balances.len()];
+        Self::get_dy_with_rates(i, j, dx, balances, &rates, amp, fee)
+    }
+
+    /*
+    Mirrors StableSwap3Pool.get_dy: balances and dx in raw token units, rates being
+    Curve's RATES (10^(36 - decimals), e.g. 1e30 for USDC).
+    */
+    pub fn get_dy_with_rates(
+        i: usize,
+        j: usize,
+        dx: U256,
+        balances: &[U256],
+        rates: &[U256],
+        amp: u64,
+        fee: u64,
+    ) -> Option<U256> {
+        let precision = U256::exp10(18);
+        if i == j || i >= balances.len() || j >= balances.len() || rates.len() != balances.len() {
+            return None;
+        }
+
+        let xp = balances
+            .iter()
+            .zip(rates)
+            .map(|(balance, rate)| Some(balance.checked_mul(*rate)? / precision))
+            .collect::<Option<Vec<U256>>>()?;
+        let x = xp[i].checked_add(dx.checked_mul(rates[i])? / precision)?;
+        let y = Self::get_y(i, j, x, &xp, amp)?;
+
+        let dy = xp[j]
+            .checked_sub(y)?
+            .checked_sub(U256::one())?
+            .checked_mul(precision)?
+            / rates[j];
+        let fee = dy.checked_mul(U256::from(fee))? / U256::from(Self::FEE_DENOMINATOR);
+        dy.checked_sub(fee)
+    }
+
+    // StableSwap invariant D for normalized balances, by Newton's method
+    pub fn get_d(xp: &[U256], amp: u64) -> Option<U256> {
+        let n = U256::from(xp.len());
+        let s = xp
+            .iter()
+            .try_fold(U256::zero(), |sum, x| sum.checked_add(*x))?;
+        if s.is_zero() {
+            return Some(U256::zero());
+        }
+
+        let ann = U256::from(amp).checked_mul(n)?;
+        let mut d = s;
+        for _ in 0..Self::MAX_ITERATIONS {
+            let mut d_p = d;
+            for x in xp {
+                d_p = d_p.checked_mul(d)?.checked_div(x.checked_mul(n)?)?;
+            }
+            let d_prev = d;
+            let numerator = ann
+                .checked_mul(s)?
+                .checked_add(d_p.checked_mul(n)?)?
+                .checked_mul(d)?;
+            let denominator = ann
+                .checked_sub(U256::one())?
+                .checked_mul(d)?
+                .checked_add(n.checked_add(U256::one())?.checked_mul(d_p)?)?;
+            d = numerator.checked_div(denominator)?;
+            if abs_diff(d, d_prev) <= U256::one() {
+                return Some(d);
+            }
+        }
+        None
+    }
+
+    // Balance of coin j that keeps D unchanged once coin i's balance is x
+    fn get_y(i: usize, j: usize, x: U256, xp: &[U256], amp: u64) -> Option<U256> {
+        let n = U256::from(xp.len());
+        let d = Self::get_d(xp, amp)?;
+        let ann = U256::from(amp).checked_mul(n)?;
+
+        let mut c = d;
+        let mut s = U256::zero();
+        for (k, balance) in xp.iter().enumerate() {
+            let x_k = if k == i {
+                x
+            } else if k != j {
+                *balance
+            } else {
+                continue;
+            };
+            s = s.checked_add(x_k)?;
+            c = c.checked_mul(d)?.checked_div(x_k.checked_mul(n)?)?;
+        }
+        c = c.checked_mul(d)?.checked_div(ann.checked_mul(n)?)?;
+        let b = s.checked_add(d.checked_div(ann)?)?;
+
+        let mut y = d;
+        for _ in 0..Self::MAX_ITERATIONS {
+            let y_prev = y;
+            y = y.checked_mul(y)?.checked_add(c)?.checked_div(
+                U256::from(2)
+                    .checked_mul(y)?
+                    .checked_add(b)?
+                    .checked_sub(d)?,
+            )?;
+            if abs_diff(y, y_prev) <= U256::one() {
+                return Some(y);
+            }
+        }
+        None
+    }
+}
+
+fn abs_diff(a: U256, b: U256) -> U256 {
+    if a > b {
+        a - b
+    } else {
+        b - a
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ForkSimResult {
+    pub success: bool,
+    // input token the executor received back, zero if the call reverted
+    pub amount_out: U256,
+    pub gas_used: u64,
+}
+
+/*
+Executes the real ArbExecutor calldata on a local revm fork, as a ground truth check of the
+analytical simulation before submitting. State is pulled lazily from the provider at the
+forked block and cached, so repeated simulations at the same block get cheaper.
+Calls are never committed: every simulation starts from the forked block's state.
+
+EthersDB fetches state with block_in_place, so simulate_path has to run on a multi-threaded
+runtime, ideally inside spawn_blocking. from_db runs on any other revm database instead,
+e.g. a CacheDB over EmptyDB with the contracts inserted by hand.
+*/
+pub struct ForkSimulator<DB: DatabaseRef> {
+    evm: EVM<CacheDB<DB>>,
+    pub block_number: u64,
+}
+
+impl<M: Middleware + 'static> ForkSimulator<EthersDB<M>> {
+    pub async fn new(provider: Arc<M>, block: BlockNumber) -> Result<Self> {
+        let block = provider
+            .get_block(block)
+            .await
+            .map_err(|e| anyhow!("get_block failed: {}", e))?
+            .ok_or_else(|| anyhow!("Block {:?} not found", block))?;
+        let block_number = block
+            .number
+            .ok_or_else(|| anyhow!("Block has no number"))?
+            .as_u64();
+
+        let db = EthersDB::new(provider, Some(BlockId::from(block_number)))
+            .ok_or_else(|| anyhow!("Failed to create fork at block {}", block_number))?;
+        let mut simulator = Self::from_db(CacheDB::new(db), block_number);
+        simulator.evm.env.block.timestamp = rU256::from_limbs(block.timestamp.0);
+        simulator.evm.env.block.coinbase = rAddress::from(block.author.unwrap_or_default().0);
+        Ok(simulator)
+    }
+}
+
+impl<DB: DatabaseRef> ForkSimulator<DB>
+where
+    DB::Error: Debug,
+{
+    pub fn from_db(db: CacheDB<DB>, block_number: u64) -> Self {
+        let mut evm = EVM::new();
+        evm.database(db);
+        evm.env.block.number = rU256::from(block_number);
+        // Gas is measured, not paid, so the caller doesn't need an ETH balance
+        evm.env.block.basefee = rU256::ZERO;
+        evm.env.tx.gas_price = rU256::ZERO;
+        evm.env.tx.gas_limit = 10_000_000;
+
+        Self { evm, block_number }
+    }
+
+    /*
+    Runs executor.execute(path, amount_in) from caller, which has to be allowed to call
+    the executor. amount_out is the sum of the path's input token Transfers into the
+    executor, i.e. what the last hop paid out.
+    */
+    pub fn simulate_path(
+        &mut self,
+        path: &ArbPath,
+        amount_in: U256,
+        executor: H160,
+        caller: H160,
+    ) -> Result<ForkSimResult> {
+        let token_in = if path.zero_for_one[0] {
+            path.pools[0].token0
+        } else {
+            path.pools[0].token1
+        };
+
+        self.evm.env.tx.caller = rAddress::from(caller.0);
+        self.evm.env.tx.transact_to = TransactTo::Call(rAddress::from(executor.0));
+        // No minimum out: the point is to measure the output, not to revert on it
+        let calldata = arb_calldata(path, amount_in, U256::zero());
+        self.evm.env.tx.data = rBytes::from(calldata.to_vec());
+        self.evm.env.tx.value = rU256::ZERO;
+
+        let result = self
+            .evm
+            .transact()
+            .map_err(|e| anyhow!("EVM error: {:?}", e))?
+            .result;
+        match result {
+            ExecutionResult::Success { gas_used, logs, .. } => {
+                let transfer_topic = keccak256("Transfer(address,address,uint256)");
+                let mut to_executor = [0u8; 32];
+                to_executor[12..].copy_from_slice(executor.as_bytes());
+
+                let amount_out = logs
+                    .iter()
+                    .filter(|log| {
+                        log.address.0 == token_in.0
+                            && log.topics.len() == 3
+                            && log.topics[0].0 == transfer_topic
+                            && log.topics[2].0 == to_executor
+                    })
+                    .fold(U256::zero(), |sum, log| {
+                        sum + U256::from_big_endian(&log.data[..32.min(log.data.len())])
+                    });
+                Ok(ForkSimResult {
+                    success: true,
+                    amount_out,
+                    gas_used,
+                })
+            }
+            ExecutionResult::Revert { gas_used, .. } | ExecutionResult::Halt { gas_used, .. } => {
+                Ok(ForkSimResult {
+                    success: false,
+                    amount_out: U256::zero(),
+                    gas_used,
+                })
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod simulator_tests {
+    use super::*;
+    use crate::multi::Reserve;
+    use crate::pools::{test_pool, Pool};
+    use ethers::{
+        abi::{encode, Token},
+        providers::Provider,
+        types::{transaction::eip2718::TypedTransaction, Bytes},
+    };
+    use std::collections::HashMap;
+
+    #[tokio::test]
+    async fn router_quotes_line_up_with_the_analytical_hops() {
+        let token = |i: u64| H160::from_low_u64_be(i);
+        let path = ArbPath::new(
+            vec![test_pool(11, 1, 2), test_pool(12, 2, 3)],
+            vec![true, true],
+        );
+        let mut reserves = HashMap::new();
+        for address in [11, 12] {
+            reserves.insert(
+                token(address),
+                Reserve {
+                    reserve0: U256::exp10(21),
+                    reserve1: U256::exp10(21) * 2,
+                    ..Default::default()
+                },
+            );
+        }
+        let amount_in = U256::exp10(18);
+        let expected = path.simulate_v2_path_verbose(amount_in, &reserves).unwrap();
+
+        // The router answers with the input amount first, then one amount per hop
+        let (provider, mock) = Provider::mocked();
+        let mut amounts = vec![Token::Uint(amount_in)];
+        amounts.extend(expected.iter().map(|amount| Token::Uint(*amount)));
+        mock.push::<Bytes, _>(encode(&[Token::Array(amounts)]).into())
+            .unwrap();
+
+        let router = token(999);
+        let path_tokens = path.token_path();
+        let quoted = router_amounts_out(
+            Arc::new(provider),
+            router,
+            &path_tokens,
+            amount_in,
+            Some(17_000_000),
+        )
+        .await
+        .unwrap();
+        assert_eq!(quoted, expected);
+
+        let calldata = BaseContract::from(
+            parse_abi(&["function getAmountsOut(uint amountIn, address[] path) external view returns (uint[] amounts)"])
+                .unwrap(),
+        )
+        .encode("getAmountsOut", (amount_in, path_tokens))
+        .unwrap();
+        let tx: TypedTransaction = TransactionRequest::new().to(router).data(calldata).into();
+        mock.assert_request("eth_call", (tx, BlockId::from(17_000_000u64)))
+            .unwrap();
+    }
+
+    /*
+    Expected outputs are _calcOutGivenIn evaluated with 60 digit decimals.
+    80/20 pool of 2M BAL and 1000 WETH with a 1% fee, traded both ways.
+    */
+    #[test]
+    fn weighted_swaps_match_the_balancer_formula() {
+        let e18 = U256::exp10(18);
+        let close = |got: U256, expected: u128| {
+            let expected = U256::from(expected);
+            let diff = if got > expected {
+                got - expected
+            } else {
+                expected - got
+            };
+            assert!(
+                diff <= expected / U256::exp10(12),
+                "{} vs {}",
+                got,
+                expected
+            );
+        };
+        let (bal, weth) = (e18 * 2_000_000, e18 * 1000);
+        let (weight_bal, weight_weth) = (800_000_000_000_000_000, 200_000_000_000_000_000);
+
+        // 10 WETH in
+        let out =
+            WeightedSimulator::get_amount_out(e18 * 10, weth, weight_weth, bal, weight_bal, 10_000);
+        close(out.unwrap(), 4_919_597_474_829_192_143_300);
+
+        // 10k BAL in
+        let out = WeightedSimulator::get_amount_out(
+            e18 * 10_000,
+            bal,
+            weight_bal,
+            weth,
+            weight_weth,
+            10_000,
+        );
+        close(out.unwrap(), 19_557_379_899_658_526_315);
+
+        // 50/50 without a fee is constant product
+        let out =
+            WeightedSimulator::get_amount_out(e18, e18 * 100, 5, e18 * 200_000, 5, 0).unwrap();
+        let v2 = UniswapV2Simulator::get_amount_out(e18, e18 * 100, e18 * 200_000, 0).unwrap();
+        close(out, v2.as_u128());
+
+        // The Vault caps a swap at 30% of the input balance
+        assert!(WeightedSimulator::get_amount_out(
+            weth / 2,
+            weth,
+            weight_weth,
+            bal,
+            weight_bal,
+            10_000
+        )
+        .is_none());
+        // A balance too large for that cap is bad data, not a panic
+        assert!(WeightedSimulator::get_amount_out(
+            e18,
+            U256::MAX,
+            weight_weth,
+            bal,
+            weight_bal,
+            10_000
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn get_amount_in_round_trips_get_amount_out() {
+        let reserve_in = U256::from(5_000_000u64) * U256::exp10(18);
+        let reserve_out = U256::from(9_000_000_000u64) * U256::exp10(6);
+        let fee = 3000;
+
+        for amount_out in [1u64, 1_000, 1_000_000, 123_456_789_000] {
+            let amount_out = U256::from(amount_out);
+            let amount_in =
+                UniswapV2Simulator::get_amount_in(amount_out, reserve_in, reserve_out, fee)
+                    .unwrap();
+
+            let out = UniswapV2Simulator::get_amount_out(amount_in, reserve_in, reserve_out, fee)
+                .unwrap();
+            assert!(out >= amount_out);
+
+            let out_less =
+                UniswapV2Simulator::get_amount_out(amount_in - 1, reserve_in, reserve_out, fee)
+                    .unwrap();
+            assert!(out_less <= amount_out);
+        }
+    }
+
+    #[test]
+    fn swaps_keep_the_constant_product_minus_fees() {
+        let reserve_in = U256::from(5_000_000u64) * U256::exp10(18);
+        let reserve_out = U256::from(9_000_000_000u64) * U256::exp10(6);
+        let k = reserve_in * reserve_out;
+        let fee = 3000;
+
+        for amount_in in [1u64, 1_000_000, 10u64.pow(18), 10u64.pow(19)] {
+            let amount_in = U256::from(amount_in);
+            let (new_in, new_out) =
+                UniswapV2Simulator::apply_swap(reserve_in, reserve_out, amount_in, fee).unwrap();
+            let amount_out =
+                UniswapV2Simulator::get_amount_out(amount_in, reserve_in, reserve_out, fee)
+                    .unwrap();
+            assert_eq!(new_in, reserve_in + amount_in);
+            assert_eq!(new_out, reserve_out - amount_out);
+
+            // The fee stays in the pool, k can only grow
+            assert!(new_in * new_out >= k);
+            // Without the fee, k holds up to the rounding of amount_out
+            let pips = U256::from(PIPS);
+            let scaled_in = reserve_in * pips + amount_in * U256::from(PIPS - fee);
+            assert!(scaled_in * new_out >= k * pips);
+            assert!(scaled_in * new_out < k * pips + scaled_in);
+        }
+    }
+
+    #[test]
+    fn fee_denominators_match_each_forks_pair_contract() {
+        let amount_in = U256::exp10(18);
+        let reserve_in = U256::from(100u64) * U256::exp10(18);
+        let reserve_out = U256::from(200_000u64) * U256::exp10(6);
+        let out = |numerator, denominator| {
+            UniswapV2Simulator::get_amount_out_with_denominator(
+                amount_in,
+                reserve_in,
+                reserve_out,
+                numerator,
+                denominator,
+            )
+            .unwrap()
+        };
+
+        // Uniswap: 997/1000
+        assert_eq!(out(997, 1000), U256::from(1_974_316_068u64));
+        // PancakeSwap: 9975/10000, i.e. 25bps
+        assert_eq!(out(9975, 10000), U256::from(1_975_296_418u64));
+
+        // Same answers in pips
+        let pips = |fee_pips| {
+            UniswapV2Simulator::get_amount_out(amount_in, reserve_in, reserve_out, fee_pips)
+                .unwrap()
+        };
+        assert_eq!(pips(3000), out(997, 1000));
+        assert_eq!(pips(2500), out(9975, 10000));
+
+        // A fee fraction above one is nonsense, not a bonus
+        assert!(UniswapV2Simulator::get_amount_out_with_denominator(
+            amount_in,
+            reserve_in,
+            reserve_out,
+            1001,
+            1000
+        )
+        .is_none());
+    }
+
+    #[test]
+    fn get_amount_in_rejects_draining_the_pool() {
+        let reserve = U256::from(1000);
+        let fee = 3000;
+        assert!(UniswapV2Simulator::get_amount_in(reserve, reserve, reserve, fee).is_none());
+        assert!(UniswapV2Simulator::get_amount_in(reserve + 1, reserve, reserve, fee).is_none());
+    }
+
+    // Expected values follow v3-core / v3-sdk SwapMath for a single step inside the current tick
+    #[test]
+    fn v3_get_amount_out_matches_swap_math() {
+        // USDC/WETH 5bps: ~1598 USDC per WETH, 1.5e19 in-range liquidity
+        let sqrt_price_x96 = U256::from_dec_str("1982131849221638389548305618779456").unwrap();
+        let liquidity = 15_000_000_000_000_000_000u128;
+
+        let weth_out = UniswapV3Simulator::get_amount_out(
+            U256::from(10_000) * U256::exp10(6),
+            sqrt_price_x96,
+            liquidity,
+            500,
+            true,
+        );
+        assert_eq!(weth_out, Some(U256::from(6255780053477862355u64)));
+
+        let usdc_out = UniswapV3Simulator::get_amount_out(
+            U256::from(5) * U256::exp10(18),
+            sqrt_price_x96,
+            liquidity,
+            500,
+            false,
+        );
+        assert_eq!(usdc_out, Some(U256::from(7984378324u64)));
+
+        // 1:1 pool, 30bps: symmetric in both directions
+        let sqrt_price_x96 = U256::one() << 96;
+        let liquidity = 2_000_000_000_000_000_000_000_000u128;
+        let expected = U256::from_dec_str("665331998665331998665331").unwrap();
+        for zero_for_one in [true, false] {
+            let out = UniswapV3Simulator::get_amount_out(
+                U256::exp10(24),
+                sqrt_price_x96,
+                liquidity,
+                3000,
+                zero_for_one,
+            );
+            assert_eq!(out, Some(expected));
+        }
+    }
+
+    #[test]
+    fn v3_get_amount_out_rejects_empty_or_overflowing_state() {
+        let sqrt_price_x96 = U256::one() << 96;
+        assert!(
+            UniswapV3Simulator::get_amount_out(U256::one(), sqrt_price_x96, 0, 3000, true)
+                .is_none()
+        );
+        assert!(
+            UniswapV3Simulator::get_amount_out(U256::MAX, sqrt_price_x96, 1, 3000, false).is_none()
+        );
+    }
+
+    #[test]
+    fn v3_sqrt_ratio_matches_tick_math() {
+        let at = UniswapV3Simulator::get_sqrt_ratio_at_tick;
+        assert_eq!(at(0), Some(U256::one() << 96));
+        assert_eq!(
+            at(UniswapV3Simulator::MIN_TICK),
+            Some(UniswapV3Simulator::MIN_SQRT_RATIO)
+        );
+        assert_eq!(
+            at(UniswapV3Simulator::MAX_TICK),
+            Some(UniswapV3Simulator::MAX_SQRT_RATIO)
+        );
+        assert_eq!(
+            at(-60),
+            Some(U256::from_dec_str("78990846045029531151608375686").unwrap())
+        );
+        assert_eq!(
+            at(-120),
+            Some(U256::from_dec_str("78754240422856966435523493930").unwrap())
+        );
+        assert_eq!(at(UniswapV3Simulator::MAX_TICK + 1), None);
+    }
+
+    /*
+    Expected outputs are what QuoterV2.quoteExactInputSingle returns for this pool state
+    (UniswapV3Pool.swap's loop with TickMath / SwapMath, replayed in exact integer arithmetic).
+    1:1 pool at tick 0, 30bps, positions [-600, 600] 1e21, [-120, 120] 5e20 and [-60, 60] 2e21.
+    */
+    #[test]
+    fn v3_swaps_cross_initialized_ticks() {
+        let ticks = [
+            (-600, 1_000_000_000_000_000_000_000i128),
+            (-120, 500_000_000_000_000_000_000),
+            (-60, 2_000_000_000_000_000_000_000),
+            (60, -2_000_000_000_000_000_000_000),
+            (120, -500_000_000_000_000_000_000),
+            (600, -1_000_000_000_000_000_000_000),
+        ];
+        let sqrt_price_x96 = U256::one() << 96;
+        let liquidity = 3_500_000_000_000_000_000_000u128;
+        let swap = |amount_in: U256, zero_for_one: bool| {
+            UniswapV3Simulator::get_amount_out_crossing_ticks(
+                amount_in,
+                sqrt_price_x96,
+                0,
+                liquidity,
+                &ticks,
+                3000,
+                zero_for_one,
+            )
+        };
+
+        // 20 tokens in: crosses -60 and -120 (or 60 and 120), ends in the widest range
+        let amount_in = U256::from(20) * U256::exp10(18);
+        let expected = U256::from_dec_str("19785993129719617400").unwrap();
+        assert_eq!(swap(amount_in, true), Some(expected));
+        assert_eq!(swap(amount_in, false), Some(expected));
+        // The single range model keeps 3.5e21 of liquidity all the way and overquotes
+        let single =
+            UniswapV3Simulator::get_amount_out(amount_in, sqrt_price_x96, liquidity, 3000, true);
+        assert_eq!(
+            single,
+            Some(U256::from_dec_str("19827042506406359199").unwrap())
+        );
+
+        // Inside the current range both agree
+        let small = U256::exp10(18);
+        assert_eq!(
+            swap(small, true),
+            UniswapV3Simulator::get_amount_out(small, sqrt_price_x96, liquidity, 3000, true)
+        );
+        assert_eq!(swap(small, true), Some(U256::from(996716078305694063u64)));
+
+        // More than all the liquidity down to tick -600 can absorb
+        assert_eq!(swap(U256::exp10(24), true), None);
+    }
+
+    // Expected values from a port of the Solidly pair's _getAmountOut/_get_y arithmetic
+    #[test]
+    fn stable_swap_prices_pegged_pools_near_one() {
+        let usdc = U256::exp10(6);
+        let dai = U256::exp10(18);
+        let fee = 500;
+
+        let out = StableSwapSimulator::get_amount_out(
+            U256::from(100_000) * usdc,
+            U256::from(5_000_000) * usdc,
+            U256::from(5_000_000) * usdc,
+            6,
+            6,
+            fee,
+        );
+        assert_eq!(out, Some(U256::from(99949600802u64)));
+
+        let out = StableSwapSimulator::get_amount_out(
+            U256::from(100_000) * usdc,
+            U256::from(5_000_000) * usdc,
+            U256::from(4_800_000) * dai,
+            6,
+            18,
+            fee,
+        );
+        assert_eq!(
+            out,
+            Some(U256::from_dec_str("99943632341388864201776").unwrap())
+        );
+
+        let out = StableSwapSimulator::get_amount_out(
+            U256::from(100_000) * dai,
+            U256::from(4_800_000) * dai,
+            U256::from(5_000_000) * usdc,
+            18,
+            6,
+            fee,
+        );
+        assert_eq!(out, Some(U256::from(99950424993u64)));
+
+        // a fee over 100% is bad pool data, not a quote
+        let out = StableSwapSimulator::get_amount_out(
+            U256::from(100_000) * usdc,
+            U256::from(5_000_000) * usdc,
+            U256::from(5_000_000) * usdc,
+            6,
+            6,
+            PIPS + 1,
+        );
+        assert_eq!(out, None);
+
+        // constant product would lose ~2.25% on the same trade
+        let cp_out = UniswapV2Simulator::get_amount_out(
+            U256::from(100_000) * usdc,
+            U256::from(5_000_000) * usdc,
+            U256::from(5_000_000) * usdc,
+            3000,
+        );
+        assert_eq!(cp_out, Some(U256::from(97750848089u64)));
+    }
+
+    /*
+    3pool-shaped balances (160M DAI, 170M USDC, 70M USDT, A = 2000, fee 0.01%).
+    Expected amounts come from a line-by-line port of StableSwap3Pool's Vyper get_dy/get_y/get_D.
+    */
+    #[test]
+    fn curve_get_dy_matches_3pool_reference() {
+        let balances = [
+            U256::from(160_000_000u64) * U256::exp10(18),
+            U256::from(170_000_000u64) * U256::exp10(6),
+            U256::from(70_000_000u64) * U256::exp10(6),
+        ];
+        let rates = [U256::exp10(18), U256::exp10(30), U256::exp10(30)];
+        let get_dy = |i, j, dx| {
+            CurveSimulator::get_dy_with_rates(i, j, dx, &balances, &rates, 2000, 1_000_000)
+        };
+
+        // 1,000 DAI -> USDC
+        assert_eq!(
+            get_dy(0, 1, U256::exp10(21)),
+            Some(U256::from(999_930_487u64))
+        );
+        // 1M USDC -> USDT, the scarce coin
+        assert_eq!(
+            get_dy(1, 2, U256::exp10(12)),
+            Some(U256::from(999_190_596_338u64))
+        );
+        // 10M USDT -> DAI, the plentiful one
+        assert_eq!(
+            get_dy(2, 0, U256::exp10(13)),
+            Some(U256::from_dec_str("10004528766533523576314230").unwrap())
+        );
+        assert_eq!(get_dy(1, 1, U256::exp10(6)), None);
+    }
+
+    #[test]
+    fn curve_get_dy_on_normalized_two_coin_pool() {
+        let balances = [U256::exp10(24), U256::exp10(24)];
+        let dy = CurveSimulator::get_dy(0, 1, U256::exp10(21), &balances, 100, 4_000_000).unwrap();
+        assert_eq!(dy, U256::from_dec_str("999590103058584712249").unwrap());
+        // Balanced pool: almost 1:1, minus the 0.04% fee
+        assert!(dy < U256::exp10(21) * 9996 / 10000);
+    }
+
+    #[test]
+    fn fork_simulator_runs_the_executor_on_a_local_db() {
+        use revm::{
+            db::EmptyDB,
+            primitives::{AccountInfo, Bytecode},
+        };
+
+        // Clear of the precompiles at 0x01..0x09
+        let (usdc, weth) = (H160::from_low_u64_be(0x1001), H160::from_low_u64_be(0x1002));
+        let executor = H160::from_low_u64_be(0xe0e0);
+        let caller = H160::from_low_u64_be(0xcaca);
+        let path = ArbPath::new(
+            vec![
+                Pool {
+                    token0: usdc,
+                    token1: weth,
+                    ..test_pool(11, 0, 0)
+                },
+                Pool {
+                    token0: usdc,
+                    token1: weth,
+                    ..test_pool(12, 0, 0)
+                },
+            ],
+            vec![true, false],
+        );
+        let amount_out = U256::exp10(6) * 1_001;
+
+        // Token stand-in: any call logs Transfer(pool 12 -> executor, amount_out)
+        let mut token_code = vec![0x7f];
+        token_code.extend(encode(&[Token::Uint(amount_out)])); // PUSH32 amount
+        token_code.extend([0x60, 0x00, 0x52]); // MSTORE at 0
+        token_code.push(0x73);
+        token_code.extend(executor.as_bytes()); // PUSH20 to
+        token_code.push(0x73);
+        token_code.extend(H160::from_low_u64_be(12).as_bytes()); // PUSH20 from
+        token_code.push(0x7f);
+        token_code.extend(keccak256("Transfer(address,address,uint256)")); // PUSH32 topic
+        token_code.extend([0x60, 0x20, 0x60, 0x00, 0xa3, 0x00]); // LOG3(0, 32), STOP
+
+        // Executor stand-in: calls the token, whatever the calldata
+        let mut executor_code = vec![0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00, 0x60, 0x00];
+        executor_code.push(0x73);
+        executor_code.extend(usdc.as_bytes());
+        executor_code.extend([0x5a, 0xf1, 0x50, 0x00]); // CALL(gas, usdc, 0, ...), POP, STOP
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        for (address, code) in [(usdc, token_code), (executor, executor_code)] {
+            db.insert_account_info(
+                rAddress::from(address.0),
+                AccountInfo {
+                    code: Some(Bytecode::new_raw(rBytes::from(code))),
+                    ..Default::default()
+                },
+            );
+        }
+        let mut simulator = ForkSimulator::from_db(db, 100);
+
+        let result = simulator
+            .simulate_path(&path, U256::exp10(9), executor, caller)
+            .unwrap();
+        assert!(result.success);
+        assert_eq!(result.amount_out, amount_out);
+        assert!(result.gas_used > 21_000);
+
+        // An executor that reverts is a failed simulation, not an error
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.insert_account_info(
+            rAddress::from(executor.0),
+            AccountInfo {
+                code: Some(Bytecode::new_raw(rBytes::from(vec![
+                    0x60, 0x00, 0x60, 0x00, 0xfd,
+                ]))),
+                ..Default::default()
+            },
+        );
+        let result = ForkSimulator::from_db(db, 100)
+            .simulate_path(&path, U256::exp10(9), executor, caller)
+            .unwrap();
+        assert!(!result.success);
+        assert!(result.amount_out.is_zero());
+    }
+
+    /*
+    Ground truth check against mainnet at a pinned block: a WETH -> USDC -> WETH round trip
+    through Uniswap V2 and Sushiswap on the fork must match the constant product math on the
+    same block's reserves, up to a wei of rounding per hop.
+    Needs an archive HTTPS_URL, a deployed executor holding WETH at that block (ARB_EXECUTOR)
+    and its owner (BOT_ADDRESS), so it only runs with --ignored.
+    */
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore]
+    async fn fork_matches_analytical_round_trip() {
+        use ethers::{providers::Http, utils::id};
+        use std::str::FromStr;
+
+        const PINNED_BLOCK: u64 = 18_000_000;
+        let provider =
+            Arc::new(Provider::<Http>::try_from(std::env::var("HTTPS_URL").unwrap()).unwrap());
+        let executor = H160::from_str(&std::env::var("ARB_EXECUTOR").unwrap()).unwrap();
+        let caller = H160::from_str(&std::env::var("BOT_ADDRESS").unwrap()).unwrap();
+
+        let usdc = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
+        let weth = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+        let pool = |address: &str| Pool {
+            address: H160::from_str(address).unwrap(),
+            token0: usdc,
+            token1: weth,
+            decimals0: 6,
+            ..Default::default()
+        };
+        let path = ArbPath::new(
+            vec![
+                pool("0xB4e16d0168e52d35CaCD2c6185b44281Ec28C9Dc"),
+                pool("0x397FF1542f962076d0BFE58eA045FfA2d347ACa0"),
+            ],
+            vec![false, true],
+        );
+
+        let mut reserves = HashMap::new();
+        for pool in &path.pools {
+            let call: TypedTransaction = TransactionRequest::new()
+                .to(pool.address)
+                .data(id("getReserves()").to_vec())
+                .into();
+            let output = provider
+                .call(&call, Some(BlockId::from(PINNED_BLOCK)))
+                .await
+                .unwrap();
+            reserves.insert(
+                pool.address,
+                Reserve {
+                    reserve0: U256::from_big_endian(&output[0..32]),
+                    reserve1: U256::from_big_endian(&output[32..64]),
+                    ..Default::default()
+                },
+            );
+        }
+
+        let amount_in = U256::exp10(17);
+        let expected = path.simulate_v2_path(amount_in, &reserves).unwrap();
+
+        // Reserves and fork both see the state at the end of the pinned block
+        let mut simulator = ForkSimulator::new(provider, BlockNumber::from(PINNED_BLOCK))
+            .await
+            .unwrap();
+        let result = tokio::task::spawn_blocking(move || {
+            simulator.simulate_path(&path, amount_in, executor, caller)
+        })
+        .await
+        .unwrap()
+        .unwrap();
+
+        assert!(result.success);
+        assert!(result.gas_used > 0);
+        let diff = if result.amount_out > expected {
+            result.amount_out - expected
+        } else {
+            expected - result.amount_out
+        };
+        assert!(
+            diff <= U256::from(2),
+            "fork {} vs analytical {}",
+            result.amount_out,
+            expected
+        );
+    }
 }
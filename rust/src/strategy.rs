@@ -1,155 +1,2920 @@
+use anyhow::{anyhow, Result};
 use ethers::{
-    providers::{Provider, Ws},
-    types::{Address, H160, U256},
+    abi::Token,
+    providers::Middleware,
+    types::{
+        transaction::eip2718::TypedTransaction, Address, Block, BlockNumber,
+        Eip1559TransactionRequest, TransactionReceipt, H160, H256, I256, U256, U512, U64,
+    },
+    utils::keccak256,
 };
-use log::info;
-use std::{collections::HashMap, str::FromStr, sync::Arc};
-use tokio::sync::broadcast::Sender;
-
-use crate::constants::{get_blacklist_tokens, Env, WEI};
-use crate::multi::batch_get_uniswap_v2_reserves;
-use crate::paths::generate_triangular_paths;
-use crate::pools::{load_all_pools_from_v2, Pool};
-use crate::simulator::UniswapV2Simulator;
-use crate::streams::Event;
-use crate::utils::get_touched_pool_reserves;
-
-pub async fn event_handler(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::{broadcast, mpsc::Receiver};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, field, info, info_span, warn};
+
+use crate::abi::{self, arb_calldata};
+use crate::constants::{get_blacklist_tokens, WEI};
+use crate::metrics;
+use crate::multi::{
+    batch_get_uniswap_v2_reserves, get_uniswap_v2_reserves_at, missing_reserves, Reserve,
+    ReserveFetchConfig, ReserveHistory,
+};
+use crate::output::JsonLines;
+use crate::paths::{
+    generate_triangular_paths_with, load_blacklist, sample_indices, ArbPath, PathIndex, PathOptions,
+};
+use crate::pools::{detect_rebasing, load_all_pools_from_v2, DexKind, Pool, REBASE_CHECK_BLOCKS};
+use crate::simulator::{UniswapV2Simulator, PIPS};
+use crate::streams::{next_event, Event, NewBlock};
+use crate::submitter::{
+    NullSubmitter, SimulationReverted, SubmitReceipt, Submitter, SubmitterKind,
+};
+use crate::utils::{get_touched_pool_reserves, u256_to_f64, with_timeout, ProviderSet, RpcTimeout};
+
+pub async fn event_handler(
+    providers: ProviderSet,
+    mut events: Receiver<Event>,
+    opportunities: broadcast::Sender<Opportunity>,
+    submitter: Box<dyn Submitter>,
+    cfg: StrategyConfig,
+    shutdown: CancellationToken,
+) {
     /*
     Current addresses are all from the Ethereum network.
     Please change them according to your chain of interest.
     */
-    let env = Env::new();
-
     let factory_addresses = vec!["0xC0AEe478e3658e2610c5F7A4A2E1777cE9e4f2Ac"];
     let router_addresses = vec!["0xd9e1cE17f2641f24aE83637ab66a2cca9C378B9F"];
     let factory_blocks = vec![10794229u64];
 
-    let pools_vec = load_all_pools_from_v2(env.wss_url.clone(), factory_addresses, factory_blocks)
-        .await
-        .unwrap();
+    let mut pools_vec =
+        load_all_pools_from_v2(providers.http.clone(), factory_addresses, factory_blocks)
+            .await
+            .unwrap();
     info!("Initial pool count: {}", pools_vec.len());
 
+    // Rebasing tokens break the simulators, path generation leaves their pools out
+    let rebasing = match providers.http.get_block_number().await {
+        Ok(latest) => {
+            let latest = latest.as_u64();
+            let from = latest.saturating_sub(REBASE_CHECK_BLOCKS);
+            detect_rebasing(providers.http.clone(), &mut pools_vec, from, latest).await
+        }
+        Err(e) => Err(anyhow!("get_block_number failed: {}", e)),
+    };
+    if let Err(e) = rebasing {
+        warn!(error = ?e, "rebasing check failed, no pool is skipped for it");
+    }
+
     // Performing USDC triangular arbitrage
     let usdc_address = H160::from_str("0xA0b86991c6218b36c1d19D4a2e9Eb0cE3606eB48").unwrap();
     let usdc_decimals = 6;
+    // ETH gets wrapped into it for paths with requires_wrap
+    let weth_address = H160::from_str("0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2").unwrap();
+    let mut runner = Runner::new(
+        providers.http.clone(),
+        cfg.clone(),
+        submitter,
+        usdc_address,
+        weth_address,
+    );
 
-    let paths = generate_triangular_paths(&pools_vec, usdc_address);
+    // BLACKLIST_FILE adds to the built-in list, see paths::load_blacklist for the format
+    let mut blacklist_tokens = get_blacklist_tokens();
+    if let Ok(file) = std::env::var("BLACKLIST_FILE") {
+        match load_blacklist(Path::new(&file)) {
+            Ok(tokens) => blacklist_tokens.extend(tokens),
+            Err(e) => {
+                warn!(error = ?e, %file, "blacklist file not loaded, using the built-in list")
+            }
+        }
+    }
 
-    let blacklist_tokens = get_blacklist_tokens();
+    // In JSON mode the progress bar would corrupt the stream, every path is emitted instead
+    let options = PathOptions {
+        blacklist: &blacklist_tokens,
+        quiet: cfg.json.is_some(),
+        ..Default::default()
+    };
+    let paths = generate_triangular_paths_with(&pools_vec, usdc_address, &options);
 
     let mut pools = HashMap::new();
 
     for path in &paths {
-        if !path.should_blacklist(&blacklist_tokens) {
-            pools.insert(path.pool_1.address.clone(), path.pool_1.clone());
-            pools.insert(path.pool_2.address.clone(), path.pool_2.clone());
-            pools.insert(path.pool_3.address.clone(), path.pool_3.clone());
+        for pool in &path.pools {
+            pools.insert(pool.address.clone(), pool.clone());
         }
     }
     info!("New pool count: {:?}", pools.len());
 
-    let pools_vec: Vec<Pool> = pools.values().cloned().collect();
-    let mut reserves =
-        batch_get_uniswap_v2_reserves(env.https_url.clone(), pools_vec.clone()).await;
-
-    let mut event_receiver = event_sender.subscribe();
-
-    loop {
-        match event_receiver.recv().await {
-            Ok(event) => match event {
-                Event::Block(block) => {
-                    info!("{:?}", block);
-                    let touched_reserves =
-                        match get_touched_pool_reserves(provider.clone(), block.block_number).await
-                        {
-                            Ok(response) => response,
-                            Err(e) => {
-                                info!("Error from get_touched_pool_reserves: {:?}", e);
-                                HashMap::new()
-                            }
-                        };
-                    let mut touched_pools = Vec::new();
-                    for (address, reserve) in touched_reserves.into_iter() {
-                        if reserves.contains_key(&address) {
-                            reserves.insert(address, reserve);
-                            touched_pools.push(address);
-                        }
+    let mut pools_vec: Vec<Pool> = pools.values().cloned().collect();
+    let fetch_config = ReserveFetchConfig {
+        call_timeout: cfg.rpc_timeout,
+        ..Default::default()
+    };
+    let mut reserves = batch_get_uniswap_v2_reserves(
+        providers.http.clone(),
+        pools_vec.clone(),
+        &fetch_config,
+        None,
+    )
+    .await
+    .unwrap();
+
+    // Pools that couldn't be read (no longer pairs, ...) can't be priced, nor any path using them
+    let missing = missing_reserves(&pools_vec, &reserves);
+    if !missing.is_empty() {
+        warn!(pools = missing.len(), "dropping pools without reserves");
+        pools_vec.retain(|pool| reserves.contains_key(&pool.address));
+        pools.retain(|address, _| reserves.contains_key(address));
+    }
+    let paths: Vec<ArbPath> = paths
+        .into_iter()
+        .filter(|path| {
+            path.pools
+                .iter()
+                .all(|pool| pools.contains_key(&pool.address))
+        })
+        .collect();
+    if let Some(json) = &cfg.json {
+        for path in &paths {
+            if let Err(e) = json.emit(path) {
+                warn!(error = ?e, "writing path failed");
+            }
+        }
+    }
+    let index = PathIndex::new(paths);
+    let mut head = ChainHead::default();
+    let mut history = ReserveHistory::new(RESERVE_HISTORY_DEPTH);
+
+    // Stops between events: whatever the current block is submitting finishes first
+    while let Some(event) = next_event(&mut events, &shutdown).await {
+        match event {
+            Event::Block(block) => {
+                if let Some(nonces) = runner.nonces.as_mut() {
+                    if nonces.on_block(&block) {
+                        warn!(block_number = ?block.number, "reorg detected, nonce reset");
                     }
-                    info!("{:?}", touched_pools);
-
-                    let mut spreads = HashMap::new();
-                    for (idx, path) in (&paths).iter().enumerate() {
-                        let touched_path = touched_pools
-                            .iter()
-                            .map(|pool| path.has_pool(&pool) as i32)
-                            .sum::<i32>()
-                            >= 1;
-
-                        if touched_path {
-                            let one_token_in = U256::from(1);
-                            let simulated = path.simulate_v2_path(one_token_in, &reserves);
-
-                            match simulated {
-                                Some(price_quote) => {
-                                    let one_usdc_in = one_token_in * U256::from(usdc_decimals);
-                                    let _out = price_quote.as_u128() as i128;
-                                    let _in = one_usdc_in.as_u128() as i128;
-                                    let spread = _out - _in;
-
-                                    if spread > 0 {
-                                        spreads.insert(idx, spread);
-                                    }
+                }
+                if let Some(orphaned) = head.on_block(&block) {
+                    // Undo the orphaned blocks, this one's reserves are fetched below
+                    let fork = orphaned.as_u64().saturating_sub(1);
+                    match history.rollback_to(&mut reserves, fork) {
+                        Ok(restored) => {
+                            warn!(
+                                block_number = ?block.number,
+                                restored = restored.len(),
+                                "reorg detected, reserves rolled back"
+                            );
+                        }
+                        Err(e) => {
+                            warn!(
+                                error = ?e,
+                                "reorg deeper than the reserve history, refetching reserves"
+                            );
+                            match refetch_reserves(
+                                providers.http.clone(),
+                                &mut pools_vec,
+                                &fetch_config,
+                            )
+                            .await
+                            {
+                                Ok(fresh) => reserves = fresh,
+                                Err(e) => {
+                                    warn!(error = ?e, "refreshing reserves after reorg failed")
                                 }
-                                None => {}
                             }
+                            history.clear();
                         }
                     }
+                }
+                let block = NewBlock::from(&block);
+                debug!(?block, "new block");
+                let touched_reserves = match with_timeout(
+                    "Sync log query",
+                    cfg.rpc_timeout,
+                    get_touched_pool_reserves(providers.http.clone(), block.block_number),
+                )
+                .await
+                {
+                    Ok(response) => response,
+                    // Trading on reserves that may be a block behind is worse than skipping it
+                    Err(e) if e.downcast_ref::<RpcTimeout>().is_some() => {
+                        warn!(error = %e, block_number = ?block.block_number, "skipping block");
+                        continue;
+                    }
+                    Err(e) => {
+                        warn!(error = ?e, "get_touched_pool_reserves failed");
+                        HashMap::new()
+                    }
+                };
+                let touched_pools = history.apply_reserves(
+                    &mut reserves,
+                    block.block_number.as_u64(),
+                    touched_reserves,
+                );
+                info!(
+                    block_number = block.block_number.as_u64(),
+                    touched = touched_pools.len(),
+                    "reserves refreshed"
+                );
+
+                let one_usdc_in = U256::from(10).pow(U256::from(usdc_decimals));
+                let spreads = evaluate_touched_paths(
+                    block.block_number.as_u64(),
+                    &index,
+                    &touched_pools,
+                    &reserves,
+                    one_usdc_in,
+                    cfg.path_budget,
+                    // a different sample every block, replayable from the seed
+                    cfg.sample_seed ^ block.block_number.as_u64(),
+                );
 
-                    let usdc_weth_address =
-                        Address::from_str("0x397FF1542f962076d0BFE58eA045FfA2d347ACa0").unwrap();
-                    let pool = pools.get(&usdc_weth_address).unwrap();
-                    let reserve = reserves.get(&usdc_weth_address).unwrap();
-                    let weth_price = UniswapV2Simulator::reserves_to_price(
-                        reserve.reserve0,
-                        reserve.reserve1,
-                        pool.decimals0,
-                        pool.decimals1,
-                        false,
-                    );
-
-                    let base_fee = block.next_base_fee;
-                    let estimated_gas_usage = U256::from(550000);
-                    let gas_cost_in_wei = base_fee * estimated_gas_usage;
-                    let gas_cost_in_wmatic =
-                        (gas_cost_in_wei.as_u64() as f64) / ((*WEI).as_u64() as f64);
-                    let gas_cost_in_usdc = weth_price * gas_cost_in_wmatic;
-                    let gas_cost_in_usdc =
-                        U256::from((gas_cost_in_usdc * ((10 as f64).powi(usdc_decimals))) as u64);
-
-                    let mut sorted_spreads: Vec<_> = spreads.iter().collect();
-                    sorted_spreads.sort_by_key(|x| x.1);
-                    sorted_spreads.reverse();
-
-                    for spread in sorted_spreads {
-                        let path_idx = spread.0;
-                        let path = &paths[*path_idx];
-                        let opt = path.optimize_amount_in(U256::from(1000), 10, &reserves);
-                        let excess_profit =
-                            (opt.1.as_u128() as i128) - (gas_cost_in_usdc.as_u128() as i128);
-
-                        // TODO
-                        if excess_profit > 0 {}
+                let usdc_weth_address =
+                    Address::from_str("0x397FF1542f962076d0BFE58eA045FfA2d347ACa0").unwrap();
+                // Pruned at startup if its reserves didn't load, nothing to price gas with then
+                let (pool, reserve) = match (
+                    pools.get(&usdc_weth_address),
+                    reserves.get(&usdc_weth_address),
+                ) {
+                    (Some(pool), Some(reserve)) => (pool, reserve),
+                    _ => {
+                        warn!(
+                            block_number = block.block_number.as_u64(),
+                            "no USDC/WETH reserves to price gas with, skipping the block"
+                        );
+                        continue;
                     }
+                };
+                let weth_price = UniswapV2Simulator::reserves_to_price(
+                    reserve.reserve0,
+                    reserve.reserve1,
+                    pool.decimals0,
+                    pool.decimals1,
+                    false,
+                );
+
+                let base_fee = block.next_base_fee;
+                let estimated_gas_usage = U256::from(ARB_GAS_LIMIT);
+                let gas_cost_in_wei = base_fee * estimated_gas_usage;
+                let gas_cost_in_wmatic =
+                    (gas_cost_in_wei.as_u64() as f64) / ((*WEI).as_u64() as f64);
+                let gas_cost_in_usdc = weth_price * gas_cost_in_wmatic;
+                let gas_cost_in_usdc =
+                    U256::from((gas_cost_in_usdc * ((10 as f64).powi(usdc_decimals))) as u64);
+
+                let mut sorted_spreads: Vec<_> = spreads.iter().collect();
+                sorted_spreads.sort_by_key(|x| x.1);
+                sorted_spreads.reverse();
+
+                for spread in sorted_spreads {
+                    let path_idx = spread.0;
+                    let path = &index.paths()[*path_idx];
+                    let opt = path.optimize_amount_in(U256::from(1000), 10, &reserves);
+                    let excess_profit = match I256::try_from(gas_cost_in_usdc) {
+                        Ok(gas_cost) => opt.1 - gas_cost,
+                        Err(_) => continue,
+                    };
+
+                    if excess_profit > I256::zero() {
+                        let amount_in = opt.0 * one_usdc_in;
+                        let expected_out = match path.simulate_v2_path(amount_in, &reserves) {
+                            Some(amount_out) => amount_out,
+                            None => continue,
+                        };
+                        let opportunity = Opportunity::new(
+                            *path_idx,
+                            amount_in,
+                            expected_out,
+                            excess_profit,
+                            block.block_number.as_u64(),
+                        );
+                        if let Some(json) = &cfg.json {
+                            if let Err(e) = json.emit(&opportunity) {
+                                warn!(error = ?e, "writing opportunity failed");
+                            }
+                        }
+                        emit_opportunity(&opportunities, opportunity.clone());
+                        // GasStrategy bids in wei, excess_profit is in USDC units
+                        let profit_in_wei = u256_to_f64(excess_profit.into_raw())
+                            / 10f64.powi(usdc_decimals)
+                            / weth_price
+                            * 1e18;
+                        runner
+                            .submit(
+                                path,
+                                &opportunity,
+                                block.next_base_fee,
+                                I256::from(profit_in_wei as i128),
+                            )
+                            .await;
+                    }
+                }
+            }
+            Event::PendingTx(_) => {
+                // not using pending tx
+            }
+            Event::Log(_) => {
+                // not using logs
+            }
+            Event::ResetCircuitBreaker => {
+                runner.breaker.reset();
+                info!("circuit breaker reset by the operator");
+            }
+            Event::Reconnected => {
+                // Sync events may have been missed while disconnected, refetch everything
+                match refetch_reserves(providers.http.clone(), &mut pools_vec, &fetch_config).await
+                {
+                    Ok(fresh) => {
+                        reserves = fresh;
+                        history.clear();
+                    }
+                    Err(e) => warn!(error = ?e, "refreshing reserves after reconnect failed"),
+                }
+            }
+        }
+    }
+    let ledger = &runner.ledger;
+    info!(
+        opportunities = ledger.opportunities,
+        simulated_pnl = %ledger.simulated_pnl,
+        landed = ledger.landed,
+        realized_pnl = %ledger.realized_pnl,
+        "strategy stopped"
+    );
+}
+
+/*
+Reserves of every pool in pools_vec at the latest block, for when the incremental updates
+can't be trusted anymore. Pools that can't be read are dropped from pools_vec, paths through
+them simply stop simulating.
+*/
+async fn refetch_reserves<M: Middleware + 'static>(
+    provider: Arc<M>,
+    pools_vec: &mut Vec<Pool>,
+    fetch_config: &ReserveFetchConfig,
+) -> Result<HashMap<H160, Reserve>> {
+    let fresh =
+        batch_get_uniswap_v2_reserves(provider, pools_vec.clone(), fetch_config, None).await?;
+    let missing = missing_reserves(pools_vec, &fresh);
+    if !missing.is_empty() {
+        warn!(pools = missing.len(), "dropping pools without reserves");
+        pools_vec.retain(|pool| fresh.contains_key(&pool.address));
+    }
+    Ok(fresh)
+}
+
+// Blocks of reserve updates kept to undo a reorg, mainnet reorgs deeper than this are rare
+pub const RESERVE_HISTORY_DEPTH: usize = 16;
+
+// Gas limit the arb tx is submitted with
+pub const ARB_GAS_LIMIT: u64 = 600_000;
+
+/*
+How the arb tx bids for inclusion, turned into EIP-1559 fees by compute_gas.
+Fixed pays the same fees whatever the block, BaseFeePlusTip tips a constant amount on top of
+the base fee, FractionOfProfit tips bps of the expected net profit (spread over ARB_GAS_LIMIT,
+so the tip never adds up to more than that share of the profit).
+The default tips DEFAULT_TIP_GWEI on top of the base fee: a bundle that pays the builder
+nothing is unlikely to be included.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum GasStrategy {
+    Fixed {
+        max_fee_gwei: u64,
+        priority_fee_gwei: u64,
+    },
+    BaseFeePlusTip {
+        tip_gwei: u64,
+    },
+    // 10_000 bps or more tips the whole profit
+    FractionOfProfit {
+        bps: u32,
+    },
+}
+
+pub const DEFAULT_TIP_GWEI: u64 = 2;
+
+impl Default for GasStrategy {
+    fn default() -> Self {
+        Self::BaseFeePlusTip {
+            tip_gwei: DEFAULT_TIP_GWEI,
+        }
+    }
+}
+
+impl GasStrategy {
+    /*
+    (max_fee_per_gas, priority_fee_per_gas) in wei for a tx landing on a block with base_fee.
+    Except for Fixed, max_fee_per_gas leaves room for the base fee to double before the tx is
+    priced out. profit is the expected net profit in wei, only FractionOfProfit looks at it
+    and bids nothing on a loss.
+    */
+    pub fn compute_gas(&self, profit: I256, base_fee: U256) -> (U256, U256) {
+        let gwei = U256::exp10(9);
+        let priority_fee = match *self {
+            Self::Fixed {
+                max_fee_gwei,
+                priority_fee_gwei,
+            } => {
+                let priority_fee = U256::from(priority_fee_gwei) * gwei;
+                return (U256::from(max_fee_gwei) * gwei, priority_fee);
+            }
+            Self::BaseFeePlusTip { tip_gwei } => U256::from(tip_gwei) * gwei,
+            Self::FractionOfProfit { bps } => {
+                if profit <= I256::zero() {
+                    U256::zero()
+                } else {
+                    let bid = profit.into_raw() * U256::from(bps.min(10_000)) / U256::from(10_000);
+                    bid / U256::from(ARB_GAS_LIMIT)
+                }
+            }
+        };
+        (base_fee * 2 + priority_fee, priority_fee)
+    }
+}
+
+// Everything about one arb that build_arb_tx and build_arb_txs encode besides its path
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ArbTxParams {
+    pub amount_in: U256,
+    // simulated output for amount_in, see min_amount_out
+    pub expected_out: U256,
+    pub slippage_bps: u32,
+    // the deployed ArbExecutor
+    pub executor: H160,
+    pub gas: GasStrategy,
+    // in wei, what GasStrategy::FractionOfProfit bids a share of
+    pub expected_profit: I256,
+    pub base_fee: U256,
+    // first nonce, from the caller's NonceManager
+    pub nonce: U256,
+}
+
+/*
+Builds the EIP-1559 tx calling ArbExecutor.execute(pools, zeroForOne, amountIn, minAmountOut)
+for the path. The executor reverts if the arb pays out less than expected_out minus
+slippage_bps (see min_amount_out), so a sandwich or a stale simulation costs gas instead of
+capital.
+Fees come from gas (see GasStrategy::compute_gas) for the expected net profit and base_fee.
+The gas limit is ARB_GAS_LIMIT.
+*/
+pub fn build_arb_tx(path: &ArbPath, params: &ArbTxParams) -> TypedTransaction {
+    let calldata = arb_calldata(
+        path,
+        params.amount_in,
+        min_amount_out(params.expected_out, params.slippage_bps),
+    );
+    let (max_fee, priority_fee) = params
+        .gas
+        .compute_gas(params.expected_profit, params.base_fee);
+
+    let tx = Eip1559TransactionRequest::new()
+        .to(params.executor)
+        .data(calldata)
+        .value(U256::zero())
+        .gas(ARB_GAS_LIMIT)
+        .nonce(params.nonce)
+        .max_priority_fee_per_gas(priority_fee)
+        .max_fee_per_gas(max_fee);
+    TypedTransaction::Eip1559(tx)
+}
+
+/*
+The txs to bundle for the path, in order, arb_tx_count(path) of them.
+Without path.requires_wrap that's just build_arb_tx. With it the sender's ETH is wrapped first
+(WETH.deposit() with amount_in as value) and the WETH handed to the executor
+(WETH.transfer(executor, amount_in)), which trades with what it holds. The output stays in the
+executor as WETH until the owner takes it out with ArbExecutor.recoverToken.
+Nonces count up from nonce, one per returned tx: reserve that many from the NonceManager.
+All of them are priced the same way, from gas.
+*/
+pub fn build_arb_txs(path: &ArbPath, params: &ArbTxParams, weth: H160) -> Vec<TypedTransaction> {
+    if !path.requires_wrap {
+        return vec![build_arb_tx(path, params)];
+    }
+
+    let (max_fee, priority_fee) = params
+        .gas
+        .compute_gas(params.expected_profit, params.base_fee);
+    let weth_call = |calldata: Vec<u8>, value: U256, nonce: U256| {
+        let tx = Eip1559TransactionRequest::new()
+            .to(weth)
+            .data(calldata)
+            .value(value)
+            .nonce(nonce)
+            .max_priority_fee_per_gas(priority_fee)
+            .max_fee_per_gas(max_fee);
+        TypedTransaction::Eip1559(tx)
+    };
+    let deposit = abi::weth()
+        .function("deposit")
+        .unwrap()
+        .encode_input(&[])
+        .unwrap();
+    let transfer = abi::weth()
+        .function("transfer")
+        .unwrap()
+        .encode_input(&[
+            Token::Address(params.executor),
+            Token::Uint(params.amount_in),
+        ])
+        .unwrap();
+
+    let arb = ArbTxParams {
+        nonce: params.nonce + U256::from(2),
+        ..params.clone()
+    };
+    vec![
+        weth_call(deposit, params.amount_in, params.nonce),
+        weth_call(transfer, U256::zero(), params.nonce + U256::one()),
+        build_arb_tx(path, &arb),
+    ]
+}
+
+// How many txs (and nonces) build_arb_txs needs for path
+pub fn arb_tx_count(path: &ArbPath) -> usize {
+    if path.requires_wrap {
+        3
+    } else {
+        1
+    }
+}
+
+// expected_out less slippage_bps, rounded down. 10000 bps or more accepts any output
+pub fn min_amount_out(expected_out: U256, slippage_bps: u32) -> U256 {
+    let keep = 10_000 - slippage_bps.min(10_000);
+    expected_out * U256::from(keep) / U256::from(10_000)
+}
+
+#[derive(Debug, Clone)]
+pub struct StrategyConfig {
+    // minimum net profit (after gas) in the input token's smallest unit
+    pub min_profit_wei: U256,
+    // e.g. 0.01 rejects trades moving the path's price by more than 1%
+    pub max_price_impact: f64,
+    // multiplier on the gas bill, 1.2 budgets 20% above the estimate
+    pub gas_buffer: f64,
+    pub gas_per_hop: u64,
+    // see ArbPath::net_profit
+    pub token_in_per_eth: U256,
+    // reserves older than this many blocks are considered stale
+    pub max_reserve_age: u64,
+    // updated by the strategy loop on every new block
+    pub gas_price: U256,
+    pub current_block: u64,
+    // detect and build txs as usual, but log and tally them instead of submitting
+    pub dry_run: bool,
+    // deployed ArbExecutor the arb txs call
+    pub executor: H160,
+    // how far below the simulated output the executor still completes, 50 = 0.5%
+    pub slippage_bps: u32,
+    // when set, generated paths and opportunities are written here as JSON lines
+    pub json: Option<JsonLines>,
+    /*
+    deadline of every RPC call the loop makes (reserves, logs, nonces), also given to the
+    submitter's Bundler for the relay calls of a submission (see Bundler::with_rpc_timeout)
+    */
+    pub rpc_timeout: Duration,
+    // fees the arb tx bids, see GasStrategy
+    pub gas: GasStrategy,
+    // where txs go outside of dry runs
+    pub submitter: SubmitterKind,
+    // see CircuitBreaker: this many failed submissions in a row within revert_window pause
+    // submitting for breaker_cooldown
+    pub max_consecutive_reverts: u32,
+    pub revert_window: Duration,
+    pub breaker_cooldown: Duration,
+    // paths simulated per block at most, a seeded sample is taken above it (None: no limit)
+    pub path_budget: Option<usize>,
+    pub sample_seed: u64,
+}
+
+impl Default for StrategyConfig {
+    fn default() -> Self {
+        Self {
+            min_profit_wei: U256::zero(),
+            max_price_impact: 0.01,
+            gas_buffer: 1.2,
+            gas_per_hop: 60_000,
+            token_in_per_eth: U256::exp10(18),
+            max_reserve_age: 1,
+            gas_price: U256::zero(),
+            current_block: 0,
+            dry_run: true,
+            executor: H160::zero(),
+            slippage_bps: 50,
+            json: None,
+            rpc_timeout: Duration::from_secs(10),
+            gas: GasStrategy::default(),
+            submitter: SubmitterKind::default(),
+            max_consecutive_reverts: 3,
+            revert_window: Duration::from_secs(600),
+            breaker_cooldown: Duration::from_secs(1800),
+            path_budget: None,
+            sample_seed: 0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    StaleReserves,
+    PriceImpact,
+    BelowMinProfit,
+}
+
+/*
+The single gate every opportunity goes through before a tx is built, checked cheapest first:
+reserve staleness, price impact, then net profit with the gas bill inflated by gas_buffer.
+*/
+pub fn check_execution(
+    path: &ArbPath,
+    amount_in: U256,
+    reserves: &HashMap<H160, Reserve>,
+    cfg: &StrategyConfig,
+) -> std::result::Result<(), Rejection> {
+    if path.reserves_stale(reserves, cfg.current_block, cfg.max_reserve_age) {
+        return Err(Rejection::StaleReserves);
+    }
+
+    match path.price_impact(amount_in, reserves) {
+        Some(impact) if impact <= cfg.max_price_impact => {}
+        _ => return Err(Rejection::PriceImpact),
+    }
+
+    let buffered_gas_price =
+        cfg.gas_price * U256::from((cfg.gas_buffer.max(0.0) * 1000.0) as u64) / U256::from(1000);
+    let profit = path
+        .net_profit(
+            amount_in,
+            reserves,
+            buffered_gas_price,
+            cfg.gas_per_hop,
+            cfg.token_in_per_eth,
+        )
+        .ok_or(Rejection::BelowMinProfit)?;
+    let min_profit = I256::try_from(cfg.min_profit_wei).unwrap_or(I256::MAX);
+    if profit <= I256::zero() || profit < min_profit {
+        return Err(Rejection::BelowMinProfit);
+    }
+
+    Ok(())
+}
+
+pub fn should_execute(
+    path: &ArbPath,
+    amount_in: U256,
+    reserves: &HashMap<H160, Reserve>,
+    cfg: &StrategyConfig,
+) -> bool {
+    check_execution(path, amount_in, reserves, cfg).is_ok()
+}
+
+// Relative gap between a pool's price and the reference that sanity_check_prices accepts
+pub const PRICE_TOLERANCE: f64 = 0.1;
+
+/*
+Rejects paths built on garbage reserves: each hop's mid price (Pool::price) is compared with
+the one implied by reference_prices, e.g. Chainlink USD prices per whole token, and the path
+fails if any hop is off by more than PRICE_TOLERANCE either way. A single stale pool is
+usually what makes a fake arb look huge. Hops with a token missing from reference_prices
+aren't checked, hops without reserves fail.
+*/
+pub fn sanity_check_prices(
+    path: &ArbPath,
+    reserves: &HashMap<H160, Reserve>,
+    reference_prices: &HashMap<H160, f64>,
+) -> bool {
+    sanity_check_prices_with(path, reserves, reference_prices, PRICE_TOLERANCE)
+}
+
+pub fn sanity_check_prices_with(
+    path: &ArbPath,
+    reserves: &HashMap<H160, Reserve>,
+    reference_prices: &HashMap<H160, f64>,
+    tolerance: f64,
+) -> bool {
+    for (pool, &zero_for_one) in path.pools.iter().zip(&path.zero_for_one) {
+        let reserve = match reserves.get(&pool.address) {
+            Some(reserve) => reserve,
+            None => return false,
+        };
+        let (token_in, token_out) = if zero_for_one {
+            (pool.token0, pool.token1)
+        } else {
+            (pool.token1, pool.token0)
+        };
+        let (price_in, price_out) = match (
+            reference_prices.get(&token_in),
+            reference_prices.get(&token_out),
+        ) {
+            (Some(price_in), Some(price_out)) if *price_out > 0.0 => (price_in, price_out),
+            _ => continue,
+        };
+        let expected = price_in / price_out;
+        let implied = pool.price(reserve, zero_for_one);
+        if implied <= 0.0 || (implied / expected - 1.0).abs() > tolerance {
+            debug!(
+                pool = ?pool.address,
+                implied,
+                expected,
+                "hop price is off its reference"
+            );
+            return false;
+        }
+    }
+    true
+}
+
+/*
+A trade the strategy decided to take, published before it's built or submitted,
+so dashboards and alerts see every detection whether or not the bundle goes out.
+net_profit is after gas, in the path's input token like amount_in.
+*/
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Opportunity {
+    // index into the strategy's PathIndex
+    pub path_index: usize,
+    pub amount_in: U256,
+    pub gross_out: U256,
+    pub net_profit: I256,
+    pub block_number: u64,
+    // unix seconds at detection
+    pub timestamp: u64,
+}
+
+impl Opportunity {
+    pub fn new(
+        path_index: usize,
+        amount_in: U256,
+        gross_out: U256,
+        net_profit: I256,
+        block_number: u64,
+    ) -> Self {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|elapsed| elapsed.as_secs())
+            .unwrap_or_default();
+        Self {
+            path_index,
+            amount_in,
+            gross_out,
+            net_profit,
+            block_number,
+            timestamp,
+        }
+    }
+}
+
+// Returns how many subscribers got it, having none is fine
+pub fn emit_opportunity(
+    sender: &broadcast::Sender<Opportunity>,
+    opportunity: Opportunity,
+) -> usize {
+    sender.send(opportunity).unwrap_or(0)
+}
+
+/*
+Running tally of what dry-run mode would have submitted, and of what submitted arbs actually
+made once they landed (see decode_execution).
+*/
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct PaperLedger {
+    pub opportunities: usize,
+    pub simulated_pnl: I256,
+    pub landed: usize,
+    // in the base token's smallest unit, see decode_execution
+    pub realized_pnl: I256,
+}
+
+impl PaperLedger {
+    pub fn record(&mut self, expected_profit: I256) {
+        self.opportunities += 1;
+        self.simulated_pnl += expected_profit;
+    }
+
+    pub fn record_execution(&mut self, realized_profit: I256) {
+        self.landed += 1;
+        self.realized_pnl += realized_profit;
+    }
+}
+
+/*
+Realized profit of a landed arb: what the executor received minus what it sent of token
+(the token the path starts and ends in, see ArbPath::base_token), read from the receipt's
+ERC20 Transfer logs. The result is in token's smallest unit, e.g. 1e-6 USDC for the
+strategy's USDC paths, so it's only comparable to profits quoted in the same token.
+Gas isn't included, it's paid in ETH by the sender, not the executor.
+None for a reverted tx or one that never moved token in or out of the executor.
+*/
+pub fn decode_execution(receipt: &TransactionReceipt, token: H160, executor: H160) -> Option<I256> {
+    if receipt.status == Some(U64::zero()) {
+        return None;
+    }
+    let transfer_topic = H256::from(keccak256("Transfer(address,address,uint256)"));
+    let mut received = U256::zero();
+    let mut sent = U256::zero();
+    let mut touched = false;
+    for log in receipt.logs.iter().filter(|log| log.address == token) {
+        if log.topics.len() != 3 || log.topics[0] != transfer_topic || log.data.len() < 32 {
+            continue;
+        }
+        let from = H160::from(log.topics[1]);
+        let to = H160::from(log.topics[2]);
+        let amount = U256::from_big_endian(&log.data[..32]);
+        if to == executor {
+            received = received.checked_add(amount)?;
+            touched = true;
+        }
+        if from == executor {
+            sent = sent.checked_add(amount)?;
+            touched = true;
+        }
+    }
+    if !touched {
+        return None;
+    }
+    let received = I256::try_from(received).ok()?;
+    let sent = I256::try_from(sent).ok()?;
+    received.checked_sub(sent)
+}
+
+// Lossy, for metrics and logs
+fn i256_to_f64(value: I256) -> f64 {
+    let magnitude = u256_to_f64(value.unsigned_abs());
+    if value.is_negative() {
+        -magnitude
+    } else {
+        magnitude
+    }
+}
+
+/*
+Stops submitting once the executor keeps failing (stale data, a bad selector, a broken
+deployment), so we don't keep paying priority fees for reverts.
+max_reverts reverted submissions in a row (a bundle reverting in the relay's simulation or a
+status 0 receipt), each within window of the latest, trip it. Timeouts and bundles that just
+didn't land aren't reverts. A tripped breaker blocks every submission until reset() (an operator
+sends the process SIGUSR1, see streams::reset_breaker_on_signal) or until cooldown has passed
+since it tripped.
+Any success in between starts the count over. The *_at variants take the current time.
+*/
+#[derive(Debug, Clone)]
+pub struct CircuitBreaker {
+    pub max_reverts: u32,
+    pub window: Duration,
+    pub cooldown: Duration,
+    // times of the current run of consecutive reverts, oldest first
+    reverts: VecDeque<Instant>,
+    tripped_at: Option<Instant>,
+}
+
+impl CircuitBreaker {
+    pub fn new(max_reverts: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            max_reverts: max_reverts.max(1),
+            window,
+            cooldown,
+            reverts: VecDeque::new(),
+            tripped_at: None,
+        }
+    }
+
+    pub fn allows(&mut self) -> bool {
+        self.allows_at(Instant::now())
+    }
+
+    pub fn allows_at(&mut self, now: Instant) -> bool {
+        match self.tripped_at {
+            Some(tripped_at) if now.duration_since(tripped_at) < self.cooldown => false,
+            Some(_) => {
+                info!("circuit breaker cooldown over, submitting again");
+                self.reset();
+                true
+            }
+            None => true,
+        }
+    }
+
+    pub fn record_success(&mut self) {
+        self.reverts.clear();
+    }
+
+    pub fn record_revert(&mut self) {
+        self.record_revert_at(Instant::now())
+    }
+
+    pub fn record_revert_at(&mut self, now: Instant) {
+        while let Some(&oldest) = self.reverts.front() {
+            if now.duration_since(oldest) <= self.window {
+                break;
+            }
+            self.reverts.pop_front();
+        }
+        self.reverts.push_back(now);
+        if self.tripped_at.is_none() && self.reverts.len() >= self.max_reverts as usize {
+            self.tripped_at = Some(now);
+            error!(
+                reverts = self.reverts.len(),
+                window = ?self.window,
+                cooldown = ?self.cooldown,
+                "CIRCUIT BREAKER TRIPPED: submissions keep reverting, pausing all submissions"
+            );
+        }
+    }
+
+    pub fn is_tripped(&self) -> bool {
+        self.tripped_at.is_some()
+    }
+
+    pub fn reset(&mut self) {
+        self.reverts.clear();
+        self.tripped_at = None;
+    }
+}
+
+/*
+The last header seen on the block stream. A header that doesn't extend it means a reorg,
+the last header delivered again (e.g. after a reconnect) isn't one.
+Only what the headers reveal is known to be orphaned: blocks from the new header's number up
+to our head, or our head alone when the new header is its would-be child.
+*/
+#[derive(Debug, Clone, Default)]
+pub struct ChainHead {
+    // (number, hash) of the last header seen
+    head: Option<(U64, H256)>,
+}
+
+impl ChainHead {
+    // Feed every header from the block stream, returns the first orphaned block on a reorg
+    pub fn on_block(&mut self, block: &Block<H256>) -> Option<U64> {
+        let (number, hash) = match (block.number, block.hash) {
+            (Some(number), Some(hash)) => (number, hash),
+            _ => return None,
+        };
+        let orphaned = match self.head {
+            // the stream re-delivered our head, nothing changed
+            Some((_, head_hash)) if hash == head_hash => return None,
+            Some((head_number, _)) if number <= head_number => Some(number),
+            Some((head_number, head_hash))
+                if number == head_number + U64::one() && block.parent_hash != head_hash =>
+            {
+                Some(head_number)
+            }
+            _ => None,
+        };
+        self.head = Some((number, hash));
+        orphaned
+    }
+}
+
+/*
+Next nonce of the submitting account, tracked locally so bundles sent in consecutive blocks
+don't all reuse the on-chain count before the first one lands.
+Seeded from the pending transaction count on first use and after every reset.
+On a reorg (see ChainHead) the mined nonce may have gone back, so the local count is dropped
+and reseeded.
+*/
+#[derive(Debug, Clone)]
+pub struct NonceManager {
+    pub address: H160,
+    next: Option<U256>,
+    head: ChainHead,
+}
+
+impl NonceManager {
+    pub fn new(address: H160) -> Self {
+        Self {
+            address,
+            next: None,
+            head: ChainHead::default(),
+        }
+    }
+
+    // Hands out the next nonce and moves past it
+    pub async fn next<M: Middleware>(&mut self, provider: &M) -> Result<U256> {
+        self.reserve(provider, 1).await
+    }
+
+    // Hands out n consecutive nonces for one bundle and moves past all of them, returns the first
+    pub async fn reserve<M: Middleware>(&mut self, provider: &M, n: usize) -> Result<U256> {
+        let nonce = match self.next {
+            Some(nonce) => nonce,
+            None => provider
+                .get_transaction_count(self.address, Some(BlockNumber::Pending.into()))
+                .await
+                .map_err(|e| anyhow!("get_transaction_count failed: {e}"))?,
+        };
+        self.next = Some(nonce + U256::from(n));
+        Ok(nonce)
+    }
+
+    pub fn reset(&mut self) {
+        self.next = None;
+    }
+
+    // Feed every header from the block stream, returns true (and resets) on a reorg
+    pub fn on_block(&mut self, block: &Block<H256>) -> bool {
+        let reorged = self.head.on_block(block).is_some();
+        if reorged {
+            self.reset();
+        }
+        reorged
+    }
+}
+
+/*
+Last step of the pipeline: hands the built txs to submitter for target_block. With
+cfg.dry_run they go to NullSubmitter instead, whatever submitter is, after being logged and
+expected_profit added to the ledger.
+Returns the receipt of whichever submitter took them.
+*/
+pub async fn execute_or_paper_trade(
+    txs: Vec<TypedTransaction>,
+    expected_profit: I256,
+    cfg: &StrategyConfig,
+    ledger: &mut PaperLedger,
+    submitter: &dyn Submitter,
+    target_block: U64,
+) -> Result<SubmitReceipt> {
+    if !cfg.dry_run {
+        return submitter.submit(txs, target_block).await;
+    }
+    ledger.record(expected_profit);
+    for tx in &txs {
+        info!(
+            to = ?tx.to_addr(),
+            calldata = %tx.data().cloned().unwrap_or_default(),
+            %expected_profit,
+            opportunities = ledger.opportunities,
+            simulated_pnl = %ledger.simulated_pnl,
+            "dry run: bundle not submitted"
+        );
+    }
+    NullSubmitter.submit(txs, target_block).await
+}
+
+/*
+What event_handler does with an opportunity worth taking: checks the circuit breaker,
+reserves the nonces, builds the txs and goes through execute_or_paper_trade, then reads the
+last tx's receipt to feed the breaker and the ledger. Holds the state that outlives a block.
+Realized profits are in token (see decode_execution), wrapped paths wrap into weth.
+*/
+pub struct Runner<M> {
+    pub provider: Arc<M>,
+    pub cfg: StrategyConfig,
+    pub submitter: Box<dyn Submitter>,
+    pub ledger: PaperLedger,
+    pub breaker: CircuitBreaker,
+    // None when the submitter never signs, see Submitter::sender
+    pub nonces: Option<NonceManager>,
+    pub token: H160,
+    pub weth: H160,
+}
+
+impl<M: Middleware + 'static> Runner<M> {
+    pub fn new(
+        provider: Arc<M>,
+        cfg: StrategyConfig,
+        submitter: Box<dyn Submitter>,
+        token: H160,
+        weth: H160,
+    ) -> Self {
+        let breaker = CircuitBreaker::new(
+            cfg.max_consecutive_reverts,
+            cfg.revert_window,
+            cfg.breaker_cooldown,
+        );
+        // Dry runs never sign, so there is no account to track
+        let nonces = match cfg.dry_run {
+            true => None,
+            false => submitter.sender().map(NonceManager::new),
+        };
+        Self {
+            provider,
+            cfg,
+            submitter,
+            ledger: PaperLedger::default(),
+            breaker,
+            nonces,
+            token,
+            weth,
+        }
+    }
+
+    /*
+    Submits path for opportunity, targeting the block after the one it was found in.
+    base_fee is that block's, expected_profit_wei the opportunity's net profit in wei (what
+    GasStrategy bids from). Returns the realized profit when the arb landed.
+    */
+    pub async fn submit(
+        &mut self,
+        path: &ArbPath,
+        opportunity: &Opportunity,
+        base_fee: U256,
+        expected_profit_wei: I256,
+    ) -> Option<I256> {
+        if !self.breaker.allows() {
+            warn!(
+                path = opportunity.path_index,
+                "circuit breaker open, not submitting"
+            );
+            return None;
+        }
+        let nonce = match self.nonces.as_mut() {
+            Some(nonces) => match with_timeout(
+                "Nonce lookup",
+                self.cfg.rpc_timeout,
+                nonces.reserve(self.provider.as_ref(), arb_tx_count(path)),
+            )
+            .await
+            {
+                Ok(nonce) => nonce,
+                Err(e) => {
+                    warn!(error = ?e, "nonce lookup failed");
+                    return None;
+                }
+            },
+            None => U256::zero(),
+        };
+        let txs = build_arb_txs(
+            path,
+            &ArbTxParams {
+                amount_in: opportunity.amount_in,
+                expected_out: opportunity.gross_out,
+                slippage_bps: self.cfg.slippage_bps,
+                executor: self.cfg.executor,
+                gas: self.cfg.gas,
+                expected_profit: expected_profit_wei,
+                base_fee,
+                nonce,
+            },
+            self.weth,
+        );
+        // The submitter bounds its own RPC calls, waiting for inclusion isn't one
+        let result = execute_or_paper_trade(
+            txs,
+            opportunity.net_profit,
+            &self.cfg,
+            &mut self.ledger,
+            self.submitter.as_ref(),
+            U64::from(opportunity.block_number + 1),
+        )
+        .await;
+        let receipt = match result {
+            Ok(receipt) => receipt,
+            Err(e) => {
+                warn!(error = ?e, path = opportunity.path_index, "submission failed");
+                // Only a revert says the arb is broken, a slow relay or lost auction doesn't
+                if e.downcast_ref::<SimulationReverted>().is_some() {
+                    self.breaker.record_revert();
+                }
+                // The nonce may not have been used, reseed from the chain
+                if let Some(nonces) = self.nonces.as_mut() {
+                    nonces.reset();
+                }
+                return None;
+            }
+        };
+
+        // Nothing was sent (a dry run)
+        let tx_hash = receipt.tx_hashes.last().copied()?;
+        let receipt = with_timeout("Receipt lookup", self.cfg.rpc_timeout, async {
+            self.provider
+                .get_transaction_receipt(tx_hash)
+                .await
+                .map_err(|e| anyhow!("{}", e))
+        })
+        .await;
+        let realized = match receipt {
+            Ok(Some(receipt)) => {
+                if receipt.status == Some(U64::zero()) {
+                    self.breaker.record_revert();
+                } else {
+                    self.breaker.record_success();
+                }
+                decode_execution(&receipt, self.token, self.cfg.executor)?
+            }
+            Ok(None) => return None,
+            Err(e) => {
+                warn!(error = ?e, ?tx_hash, "receipt lookup failed");
+                return None;
+            }
+        };
+        self.ledger.record_execution(realized);
+        metrics::record_realized_pnl(i256_to_f64(self.ledger.realized_pnl));
+        info!(
+            ?tx_hash,
+            %realized,
+            expected = %opportunity.net_profit,
+            realized_pnl = %self.ledger.realized_pnl,
+            "arb landed"
+        );
+        Some(realized)
+    }
+}
+
+/*
+Simulates amount_in through every path that uses one of touched_pools and returns the
+positive spreads (amount_out - amount_in) by path index.
+With a budget, only that many of those paths are simulated, a sample picked with seed
+(see paths::sample_indices) when there are more.
+Runs inside a "block" span carrying block_number, paths_evaluated and best_profit.
+*/
+pub fn evaluate_touched_paths(
+    block_number: u64,
+    index: &PathIndex,
+    touched_pools: &[H160],
+    reserves: &HashMap<H160, Reserve>,
+    amount_in: U256,
+    budget: Option<usize>,
+    seed: u64,
+) -> HashMap<usize, I256> {
+    let span = info_span!(
+        "block",
+        block_number,
+        paths_evaluated = field::Empty,
+        best_profit = field::Empty
+    );
+    let _entered = span.enter();
+
+    let mut candidates = index.paths_touching_any(touched_pools);
+    if let Some(budget) = budget.filter(|&budget| candidates.len() > budget) {
+        warn!(
+            candidates = candidates.len(),
+            budget, "over the path budget, simulating a sample"
+        );
+        candidates = sample_indices(candidates.len(), budget, seed)
+            .into_iter()
+            .map(|i| candidates[i])
+            .collect();
+    }
+
+    let mut spreads = HashMap::new();
+    let mut evaluated = 0;
+    for idx in candidates {
+        let path = &index.paths()[idx];
+        evaluated += 1;
+        match path.gross_profit(amount_in, reserves) {
+            Some(spread) if spread > I256::zero() => {
+                spreads.insert(idx, spread);
+            }
+            Some(_) => {}
+            None => debug!(path = idx, "simulation failed"),
+        }
+    }
+
+    let best_profit = spreads.values().max().copied().unwrap_or(I256::zero());
+    span.record("paths_evaluated", evaluated);
+    span.record("best_profit", field::display(best_profit));
+    metrics::record_paths_evaluated(evaluated);
+    metrics::record_profitable_paths(spreads.len());
+    info!(profitable = spreads.len(), "paths evaluated");
+
+    spreads
+}
+
+/*
+Scores every path in parallel at each amount of amount_grid plus its optimal_amount_in
+and returns (index, amount_in, profit) of the best one, profit being amount_out - amount_in
+in the input token's smallest unit. Paths without a profitable amount are skipped.
+Ties go to the lowest path index, then the smallest amount_in, so the result doesn't depend
+on how rayon splits the work.
+*/
+pub fn best_path(
+    paths: &[ArbPath],
+    reserves: &HashMap<H160, Reserve>,
+    amount_grid: &[U256],
+) -> Option<(usize, U256, I256)> {
+    paths
+        .par_iter()
+        .enumerate()
+        .filter_map(|(idx, path)| {
+            let mut best: Option<(U256, I256)> = None;
+            let candidates = amount_grid
+                .iter()
+                .cloned()
+                .chain(path.optimal_amount_in(reserves));
+            for amount_in in candidates {
+                let profit = match path.gross_profit(amount_in, reserves) {
+                    Some(profit) if profit > I256::zero() => profit,
+                    _ => continue,
+                };
+                match best {
+                    Some((best_in, best_profit))
+                        if profit < best_profit
+                            || (profit == best_profit && amount_in >= best_in) => {}
+                    _ => best = Some((amount_in, profit)),
                 }
-                Event::PendingTx(_) => {
-                    // not using pending tx
+            }
+            best.map(|(amount_in, profit)| (idx, amount_in, profit))
+        })
+        .reduce_with(|a, b| {
+            if b.2 > a.2 || (b.2 == a.2 && b.0 < a.0) {
+                b
+            } else {
+                a
+            }
+        })
+}
+
+/*
+Converts profit (in token's smallest unit) into WETH wei at the spot price of the
+deepest token/WETH pool, deepest meaning the most WETH on its side.
+None if no pool of pools pairs token with WETH or has reserves for it.
+*/
+pub fn normalize_profit_to_weth(
+    profit: U256,
+    token: H160,
+    pools: &[Pool],
+    reserves: &HashMap<H160, Reserve>,
+    weth: H160,
+) -> Option<U256> {
+    if token == weth {
+        return Some(profit);
+    }
+
+    let mut deepest: Option<(U256, U256)> = None;
+    for pool in pools {
+        let reserve = match reserves.get(&pool.address) {
+            Some(reserve) => reserve,
+            None => continue,
+        };
+        let (reserve_weth, reserve_token) = match (
+            pool.reserve_of(reserve, weth),
+            pool.reserve_of(reserve, token),
+        ) {
+            (Some(reserve_weth), Some(reserve_token)) => (reserve_weth, reserve_token),
+            _ => continue,
+        };
+        if reserve_token.is_zero() {
+            continue;
+        }
+        match deepest {
+            Some((best_weth, _)) if reserve_weth <= best_weth => {}
+            _ => deepest = Some((reserve_weth, reserve_token)),
+        }
+    }
+
+    let (reserve_weth, reserve_token) = deepest?;
+    let value = profit.full_mul(reserve_weth) / U512::from(reserve_token);
+    U256::try_from(value).ok()
+}
+
+/*
+Combined net_profit of running the allocation in order, each path on the reserves left
+by the previous ones. None if any path fails to simulate.
+*/
+pub fn allocation_profit(
+    paths: &[ArbPath],
+    allocation: &[(usize, U256)],
+    reserves: &HashMap<H160, Reserve>,
+    cfg: &StrategyConfig,
+) -> Option<I256> {
+    let mut reserves = reserves.clone();
+    let mut total = I256::zero();
+    for &(idx, amount_in) in allocation {
+        let path = paths.get(idx)?;
+        total += path.net_profit(
+            amount_in,
+            &reserves,
+            cfg.gas_price,
+            cfg.gas_per_hop,
+            cfg.token_in_per_eth,
+        )?;
+        let moved = path.resulting_reserves(amount_in, &reserves);
+        if moved.is_empty() {
+            return None;
+        }
+        reserves.extend(moved);
+    }
+    Some(total)
+}
+
+// Multipliers (in %) tried on each allocated amount during the refine pass
+const REFINE_STEPS: [u64; 6] = [50, 75, 90, 110, 125, 150];
+
+/*
+Splits total_amount of token_in across parallel pools trading the same pair so the combined
+output is maximal. That's where every used pool's marginal rate dy/dx is the same (λ):
+for a constant product pool with fee share γ that gives
+    x = (sqrt(γ * reserve_in * reserve_out / λ) - reserve_in) / γ
+(0 if the pool's rate is below λ even at x = 0), and λ is bisected until the x sum to
+total_amount. Only V2 constant product pools take part, others get nothing.
+Returns (pool address, amount in) for every pool that gets a share, in the order given;
+the amounts add up to total_amount exactly.
+*/
+pub fn split_route(
+    pairs: &[(Pool, Reserve)],
+    token_in: H160,
+    total_amount: U256,
+) -> Vec<(H160, U256)> {
+    // (address, gamma, reserve_in, reserve_out)
+    let pools: Vec<(H160, f64, f64, f64)> = pairs
+        .iter()
+        .filter(|(pool, _)| pool.dex == DexKind::UniswapV2 && !pool.stable)
+        .filter_map(|(pool, reserve)| {
+            let reserve_in = pool.reserve_of(reserve, token_in)?;
+            let reserve_out = if token_in == pool.token0 {
+                reserve.reserve1
+            } else {
+                reserve.reserve0
+            };
+            let gamma = 1.0 - pool.fee_pips() as f64 / PIPS as f64;
+            let (reserve_in, reserve_out) = (u256_to_f64(reserve_in), u256_to_f64(reserve_out));
+            if reserve_in <= 0.0 || reserve_out <= 0.0 || gamma <= 0.0 {
+                return None;
+            }
+            Some((pool.address, gamma, reserve_in, reserve_out))
+        })
+        .collect();
+    if pools.is_empty() || total_amount.is_zero() {
+        return Vec::new();
+    }
+
+    let amounts_at = |rate: f64| -> Vec<f64> {
+        pools
+            .iter()
+            .map(|(_, gamma, reserve_in, reserve_out)| {
+                (((gamma * reserve_in * reserve_out / rate).sqrt() - reserve_in) / gamma).max(0.0)
+            })
+            .collect()
+    };
+    let total = u256_to_f64(total_amount);
+    // Best starting rate first: above it nothing is allocated, lowering it only adds input
+    let mut high = pools
+        .iter()
+        .map(|(_, gamma, reserve_in, reserve_out)| gamma * reserve_out / reserve_in)
+        .fold(0.0, f64::max);
+    let mut low = high * 1e-30;
+    for _ in 0..200 {
+        let mid = (low * high).sqrt();
+        if amounts_at(mid).iter().sum::<f64>() > total {
+            low = mid;
+        } else {
+            high = mid;
+        }
+    }
+    let amounts = amounts_at(high);
+    let sum: f64 = amounts.iter().sum();
+    if sum <= 0.0 {
+        return Vec::new();
+    }
+
+    // Shares in 1e-9 keep the arithmetic in U256, rounding dust goes to the biggest share
+    let scale = 1_000_000_000u64;
+    let mut split: Vec<(H160, U256)> = pools
+        .iter()
+        .zip(&amounts)
+        .map(|((address, ..), amount)| {
+            let share = (amount / sum * scale as f64).round() as u64;
+            (
+                *address,
+                total_amount * U256::from(share) / U256::from(scale),
+            )
+        })
+        .collect();
+    let allocated = split
+        .iter()
+        .fold(U256::zero(), |sum, (_, amount)| sum + *amount);
+    if let Some(biggest) = split.iter_mut().max_by_key(|(_, amount)| *amount) {
+        if allocated <= total_amount {
+            biggest.1 += total_amount - allocated;
+        } else {
+            biggest.1 -= allocated - total_amount;
+        }
+    }
+    split.retain(|(_, amount)| !amount.is_zero());
+    split
+}
+
+/*
+Splits total_capital across paths to maximize combined net_profit (gas priced from cfg).
+Greedy: on the reserves left by earlier picks, take the path whose optimal_amount_in
+(capped by the remaining capital) nets the most, until nothing profitable is left.
+Refine: scale each picked amount by REFINE_STEPS and keep any change that raises the
+combined profit, re-simulating the whole allocation sequentially so shared pools are
+depleted in order. Returns (index into paths, amount_in) in execution order.
+*/
+pub fn allocate(
+    paths: &[ArbPath],
+    total_capital: U256,
+    reserves: &HashMap<H160, Reserve>,
+    cfg: &StrategyConfig,
+) -> Vec<(usize, U256)> {
+    let mut allocation: Vec<(usize, U256)> = Vec::new();
+    let mut depleted = reserves.clone();
+    let mut remaining = total_capital;
+
+    while !remaining.is_zero() {
+        let mut pick: Option<(usize, U256, I256)> = None;
+        for (idx, path) in paths.iter().enumerate() {
+            if allocation.iter().any(|(used, _)| *used == idx) {
+                continue;
+            }
+            let amount_in = match path.optimal_amount_in(&depleted) {
+                Some(amount_in) => amount_in.min(remaining),
+                None => continue,
+            };
+            let profit = match path.net_profit(
+                amount_in,
+                &depleted,
+                cfg.gas_price,
+                cfg.gas_per_hop,
+                cfg.token_in_per_eth,
+            ) {
+                Some(profit) if profit > I256::zero() => profit,
+                _ => continue,
+            };
+            match pick {
+                Some((_, _, best)) if profit <= best => {}
+                _ => pick = Some((idx, amount_in, profit)),
+            }
+        }
+
+        let (idx, amount_in, _) = match pick {
+            Some(pick) => pick,
+            None => break,
+        };
+        let moved = paths[idx].resulting_reserves(amount_in, &depleted);
+        if moved.is_empty() {
+            break;
+        }
+        depleted.extend(moved);
+        allocation.push((idx, amount_in));
+        remaining -= amount_in;
+    }
+
+    let mut best_profit = match allocation_profit(paths, &allocation, reserves, cfg) {
+        Some(profit) => profit,
+        None => return allocation,
+    };
+    for i in 0..allocation.len() {
+        for step in REFINE_STEPS {
+            let mut candidate = allocation.clone();
+            candidate[i].1 = allocation[i].1 * step / 100;
+            let used = candidate
+                .iter()
+                .fold(U256::zero(), |sum, (_, amount_in)| sum + *amount_in);
+            if candidate[i].1.is_zero() || used > total_capital {
+                continue;
+            }
+            if let Some(profit) = allocation_profit(paths, &candidate, reserves, cfg) {
+                if profit > best_profit {
+                    best_profit = profit;
+                    allocation = candidate;
                 }
-                Event::Log(_) => {
-                    // not using logs
+            }
+        }
+    }
+
+    allocation
+}
+
+#[derive(Debug, Clone)]
+pub struct BacktestConfig {
+    // gas is priced the same at every sampled block
+    pub gas_price: U256,
+    pub gas_per_hop: u64,
+    // see ArbPath::net_profit, 1e18 when the paths start from WETH
+    pub token_in_per_eth: U256,
+    // pools per aggregate3 call
+    pub chunk_size: usize,
+}
+
+impl Default for BacktestConfig {
+    fn default() -> Self {
+        Self {
+            gas_price: U256::from(30_000_000_000u64),
+            gas_per_hop: 60_000,
+            token_in_per_eth: U256::exp10(18),
+            chunk_size: 1000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BacktestRecord {
+    pub block_number: u64,
+    // paths with a positive net profit at their optimal amount_in
+    pub profitable_paths: usize,
+    // (index into paths, amount_in, net profit) of the most profitable one
+    pub best: Option<(usize, U256, I256)>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct BacktestReport {
+    pub records: Vec<BacktestRecord>,
+    // share of sampled blocks with at least one profitable path
+    pub hit_rate: f64,
+    // sum of the best path's profit over all blocks, as if one arb was taken per block
+    pub total_profit: I256,
+}
+
+/*
+Replays the strategy over history: every step blocks from from_block to to_block (inclusive),
+reserves of all pools used by the paths are read at that block, each path is sized with
+optimal_amount_in and scored with net_profit.
+Only constant product math is used, so the numbers are an upper bound of what was capturable.
+*/
+pub async fn backtest<M: Middleware + 'static>(
+    paths: &[ArbPath],
+    provider: Arc<M>,
+    from_block: u64,
+    to_block: u64,
+    step: u64,
+    config: &BacktestConfig,
+) -> Result<BacktestReport> {
+    let mut seen = HashSet::new();
+    let pools: Vec<Pool> = paths
+        .iter()
+        .flat_map(|path| path.pools.iter())
+        .filter(|pool| seen.insert(pool.address))
+        .cloned()
+        .collect();
+
+    let mut records = Vec::new();
+    for block_number in (from_block..=to_block).step_by(step.max(1) as usize) {
+        let reserves =
+            get_uniswap_v2_reserves_at(provider.clone(), &pools, block_number, config.chunk_size)
+                .await?;
+
+        let mut profitable_paths = 0;
+        let mut best: Option<(usize, U256, I256)> = None;
+        for (idx, path) in paths.iter().enumerate() {
+            let amount_in = match path.optimal_amount_in(&reserves) {
+                Some(amount_in) => amount_in,
+                None => continue,
+            };
+            let profit = match path.net_profit(
+                amount_in,
+                &reserves,
+                config.gas_price,
+                config.gas_per_hop,
+                config.token_in_per_eth,
+            ) {
+                Some(profit) if profit > I256::zero() => profit,
+                _ => continue,
+            };
+            profitable_paths += 1;
+            match best {
+                // ties keep the lowest index
+                Some((_, _, best_profit)) if profit <= best_profit => {}
+                _ => best = Some((idx, amount_in, profit)),
+            }
+        }
+
+        records.push(BacktestRecord {
+            block_number,
+            profitable_paths,
+            best,
+        });
+    }
+
+    let hits = records.iter().filter(|r| r.best.is_some()).count();
+    let hit_rate = if records.is_empty() {
+        0.0
+    } else {
+        hits as f64 / records.len() as f64
+    };
+    let total_profit = records
+        .iter()
+        .filter_map(|r| r.best.map(|(_, _, profit)| profit))
+        .fold(I256::zero(), |sum, profit| sum + profit);
+    info!(
+        "Backtest over {} blocks: hit rate {:.2}, total profit {}",
+        records.len(),
+        hit_rate,
+        total_profit
+    );
+
+    Ok(BacktestReport {
+        records,
+        hit_rate,
+        total_profit,
+    })
+}
+
+#[cfg(test)]
+mod strategy_tests {
+    use super::*;
+    use crate::pools::test_pool as pool;
+    use ethers::abi::{decode, encode, ParamType};
+    use ethers::providers::Provider;
+    use ethers::types::Bytes;
+    use ethers::utils::id;
+
+    #[test]
+    fn split_route_favours_the_deeper_pool_and_beats_a_single_pool() {
+        let e18 = U256::exp10(18);
+        let reserve = |reserve0: u64, reserve1: u64| Reserve {
+            reserve0: e18 * reserve0,
+            reserve1: e18 * reserve1,
+            ..Default::default()
+        };
+        // Same price, the first pool is ten times deeper
+        let deep = (pool(11, 1, 2), reserve(1_000, 2_000_000));
+        let shallow = (pool(12, 1, 2), reserve(100, 200_000));
+        let token_in = H160::from_low_u64_be(1);
+        let total = e18 * 50;
+
+        let split = split_route(&[deep.clone(), shallow.clone()], token_in, total);
+
+        assert_eq!(split.len(), 2);
+        let (deep_in, shallow_in) = (split[0].1, split[1].1);
+        assert_eq!(split[0].0, deep.0.address);
+        assert!(deep_in > shallow_in * 9);
+        assert_eq!(deep_in + shallow_in, total);
+
+        let out = |amount_in, reserve: &Reserve| {
+            UniswapV2Simulator::get_amount_out(amount_in, reserve.reserve0, reserve.reserve1, 3000)
+                .unwrap()
+        };
+        let split_out = out(deep_in, &deep.1) + out(shallow_in, &shallow.1);
+        assert!(split_out > out(total, &deep.1));
+
+        // Selling the other token walks the same pools the other way
+        let split = split_route(&[deep, shallow], H160::from_low_u64_be(2), e18 * 100_000);
+        assert_eq!(split.len(), 2);
+        assert!(split[0].1 > split[1].1);
+    }
+
+    #[test]
+    fn a_pool_far_off_its_reference_price_fails_the_sanity_check() {
+        // 1 and 3 are dollar stables, 2 is ETH at $2000
+        let path = ArbPath::new(
+            vec![pool(11, 1, 2), pool(12, 2, 3), pool(13, 3, 1)],
+            vec![true, true, true],
+        );
+        let e18 = U256::exp10(18);
+        let reserve = |reserve0: u64, reserve1: u64| Reserve {
+            reserve0: e18 * reserve0,
+            reserve1: e18 * reserve1,
+            ..Default::default()
+        };
+        let mut reserves = HashMap::new();
+        reserves.insert(H160::from_low_u64_be(11), reserve(2_000_000, 1_000));
+        reserves.insert(H160::from_low_u64_be(12), reserve(1_000, 2_000_000));
+        reserves.insert(H160::from_low_u64_be(13), reserve(1_000_000, 1_010_000));
+        let mut reference_prices = HashMap::new();
+        reference_prices.insert(H160::from_low_u64_be(1), 1.0);
+        reference_prices.insert(H160::from_low_u64_be(2), 2000.0);
+        reference_prices.insert(H160::from_low_u64_be(3), 1.0);
+
+        assert!(sanity_check_prices(&path, &reserves, &reference_prices));
+
+        // Stale reserves pricing ETH at $20k on the middle hop
+        reserves.insert(H160::from_low_u64_be(12), reserve(1_000, 20_000_000));
+        assert!(!sanity_check_prices(&path, &reserves, &reference_prices));
+
+        // Without a reference for ETH that hop can't be judged
+        reference_prices.remove(&H160::from_low_u64_be(2));
+        assert!(sanity_check_prices(&path, &reserves, &reference_prices));
+    }
+
+    #[tokio::test]
+    async fn every_subscriber_receives_an_opportunity() {
+        let (sender, mut dashboard) = broadcast::channel(16);
+        let mut alerts = sender.subscribe();
+        let opportunity = Opportunity::new(
+            3,
+            U256::exp10(18),
+            U256::exp10(18) + U256::exp10(16),
+            I256::from(7_000_000_000_000_000i64),
+            17_000_000,
+        );
+
+        assert_eq!(emit_opportunity(&sender, opportunity.clone()), 2);
+
+        assert_eq!(dashboard.recv().await.unwrap(), opportunity);
+        assert_eq!(alerts.recv().await.unwrap(), opportunity);
+        assert!(opportunity.timestamp > 0);
+
+        // Nobody listening doesn't stop the strategy
+        drop(dashboard);
+        drop(alerts);
+        assert_eq!(emit_opportunity(&sender, opportunity), 0);
+    }
+
+    #[test]
+    fn arb_tx_encodes_pools_and_directions() {
+        let path = ArbPath::new(
+            vec![pool(11, 1, 2), pool(12, 3, 2), pool(13, 3, 1)],
+            vec![true, false, true],
+        );
+        let executor = H160::from_low_u64_be(999);
+        let amount_in = U256::exp10(6) * 500;
+        let base_fee = U256::from(30_000_000_000u64);
+        let priority_fee = U256::from(2_000_000_000u64);
+
+        let tx = build_arb_tx(
+            &path,
+            &ArbTxParams {
+                amount_in,
+                expected_out: amount_in * 2,
+                slippage_bps: 50,
+                executor,
+                gas: GasStrategy::BaseFeePlusTip { tip_gwei: 2 },
+                base_fee,
+                nonce: U256::from(7),
+                ..Default::default()
+            },
+        );
+
+        assert_eq!(tx.to_addr(), Some(&executor));
+        assert_eq!(tx.nonce(), Some(&U256::from(7)));
+        assert_eq!(tx.gas(), Some(&U256::from(ARB_GAS_LIMIT)));
+        let calldata = tx.data().unwrap();
+        assert_eq!(
+            calldata[..4],
+            id("execute(address[],bool[],uint256,uint256)")
+        );
+
+        let decoded = decode(
+            &[
+                ParamType::Array(Box::new(ParamType::Address)),
+                ParamType::Array(Box::new(ParamType::Bool)),
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+            ],
+            &calldata[4..],
+        )
+        .unwrap();
+        assert_eq!(
+            decoded[0],
+            Token::Array(vec![
+                Token::Address(H160::from_low_u64_be(11)),
+                Token::Address(H160::from_low_u64_be(12)),
+                Token::Address(H160::from_low_u64_be(13)),
+            ])
+        );
+        assert_eq!(
+            decoded[1],
+            Token::Array(vec![
+                Token::Bool(true),
+                Token::Bool(false),
+                Token::Bool(true)
+            ])
+        );
+        assert_eq!(decoded[2], Token::Uint(amount_in));
+        assert_eq!(decoded[3], Token::Uint(min_amount_out(amount_in * 2, 50)));
+
+        match tx {
+            TypedTransaction::Eip1559(tx) => {
+                assert_eq!(tx.max_priority_fee_per_gas, Some(priority_fee));
+                assert_eq!(tx.max_fee_per_gas, Some(base_fee * 2 + priority_fee));
+            }
+            _ => panic!("expected an EIP-1559 tx"),
+        }
+    }
+
+    #[test]
+    fn min_amount_out_is_encoded_below_the_expected_output() {
+        let path = ArbPath::new(vec![pool(11, 1, 2), pool(12, 2, 1)], vec![true, true]);
+        // 1.02 ETH expected out at 50bps: 1.02 * 0.995 = 1.0149
+        let expected_out = U256::from(1_020_000_000_000_000_000u128);
+        let tx = build_arb_tx(
+            &path,
+            &ArbTxParams {
+                amount_in: U256::exp10(18),
+                expected_out,
+                slippage_bps: 50,
+                executor: H160::from_low_u64_be(999),
+                ..Default::default()
+            },
+        );
+
+        let decoded = decode(
+            &[
+                ParamType::Array(Box::new(ParamType::Address)),
+                ParamType::Array(Box::new(ParamType::Bool)),
+                ParamType::Uint(256),
+                ParamType::Uint(256),
+            ],
+            &tx.data().unwrap()[4..],
+        )
+        .unwrap();
+        let min_out = U256::from(1_014_900_000_000_000_000u128);
+        assert_eq!(min_amount_out(expected_out, 50), min_out);
+        assert_eq!(decoded[3], Token::Uint(min_out));
+
+        // Rounds down, and a tolerance past 100% never underflows
+        assert_eq!(min_amount_out(U256::from(999), 50), U256::from(994));
+        assert_eq!(min_amount_out(expected_out, 20_000), U256::zero());
+    }
+
+    #[test]
+    fn fixed_gas_ignores_profit_and_base_fee() {
+        let gas = GasStrategy::Fixed {
+            max_fee_gwei: 100,
+            priority_fee_gwei: 3,
+        };
+        let expected = (U256::exp10(11), U256::from(3_000_000_000u64));
+        assert_eq!(gas.compute_gas(I256::zero(), U256::zero()), expected);
+        assert_eq!(
+            gas.compute_gas(I256::exp10(18), U256::from(50_000_000_000u64)),
+            expected
+        );
+    }
+
+    #[test]
+    fn base_fee_plus_tip_leaves_room_for_the_base_fee_to_double() {
+        let gas = GasStrategy::BaseFeePlusTip { tip_gwei: 2 };
+        let base_fee = U256::from(30_000_000_000u64);
+        assert_eq!(
+            gas.compute_gas(I256::exp10(18), base_fee),
+            (U256::from(62_000_000_000u64), U256::from(2_000_000_000u64))
+        );
+    }
+
+    #[test]
+    fn default_gas_strategy_tips_the_builder() {
+        let (_, priority_fee) =
+            GasStrategy::default().compute_gas(I256::zero(), U256::from(30_000_000_000u64));
+        assert_eq!(priority_fee, U256::from(DEFAULT_TIP_GWEI) * U256::exp10(9));
+        assert!(!priority_fee.is_zero());
+    }
+
+    #[test]
+    fn fraction_of_profit_tips_a_share_of_the_profit() {
+        let base_fee = U256::from(30_000_000_000u64);
+        let profit = I256::exp10(17);
+
+        // 20% of 0.1 ETH over 600k gas
+        let gas = GasStrategy::FractionOfProfit { bps: 2_000 };
+        let (max_fee, priority_fee) = gas.compute_gas(profit, base_fee);
+        assert_eq!(priority_fee, U256::exp10(16) * 2 / ARB_GAS_LIMIT);
+        assert_eq!(max_fee, base_fee * 2 + priority_fee);
+
+        // Losses bid nothing on top of the base fee
+        assert_eq!(
+            gas.compute_gas(-profit, base_fee),
+            (base_fee * 2, U256::zero())
+        );
+    }
+
+    #[test]
+    fn fraction_of_profit_never_tips_more_than_the_profit() {
+        let profit = I256::from(123_456_789_012_345_678u64);
+        for bps in [1, 5_000, 10_000, 50_000, u32::MAX] {
+            let gas = GasStrategy::FractionOfProfit { bps };
+            let (_, priority_fee) = gas.compute_gas(profit, U256::exp10(10));
+            assert!(
+                priority_fee * ARB_GAS_LIMIT <= profit.into_raw(),
+                "bps {}",
+                bps
+            );
+        }
+    }
+
+    #[test]
+    fn wrapped_paths_deposit_and_hand_the_weth_to_the_executor() {
+        let mut path = ArbPath::new(
+            vec![pool(11, 1, 2), pool(12, 3, 2), pool(13, 3, 1)],
+            vec![true, false, true],
+        );
+        let executor = H160::from_low_u64_be(999);
+        let weth = H160::from_low_u64_be(1);
+        let amount_in = U256::exp10(18);
+        let build = |path: &ArbPath| {
+            build_arb_txs(
+                path,
+                &ArbTxParams {
+                    amount_in,
+                    expected_out: amount_in,
+                    executor,
+                    base_fee: U256::exp10(10),
+                    nonce: U256::from(7),
+                    ..Default::default()
+                },
+                weth,
+            )
+        };
+
+        let txs = build(&path);
+        assert_eq!(txs.len(), arb_tx_count(&path));
+        assert_eq!(txs[0].to_addr(), Some(&executor));
+
+        path.requires_wrap = true;
+        let txs = build(&path);
+        assert_eq!(txs.len(), arb_tx_count(&path));
+
+        let (deposit, transfer, arb) = (&txs[0], &txs[1], &txs[2]);
+        assert_eq!(deposit.to_addr(), Some(&weth));
+        assert_eq!(deposit.data().unwrap()[..], id("deposit()"));
+        assert_eq!(deposit.value(), Some(&amount_in));
+
+        // The executor trades with its own balance, so the wrapped ETH has to end up there
+        assert_eq!(transfer.to_addr(), Some(&weth));
+        let calldata = transfer.data().unwrap();
+        assert_eq!(calldata[..4], id("transfer(address,uint256)"));
+        assert_eq!(
+            decode(&[ParamType::Address, ParamType::Uint(256)], &calldata[4..]).unwrap(),
+            vec![Token::Address(executor), Token::Uint(amount_in)]
+        );
+
+        assert_eq!(arb.to_addr(), Some(&executor));
+        assert_eq!(arb.data(), Some(&arb_calldata(&path, amount_in, amount_in)));
+
+        let nonces: Vec<_> = txs.iter().map(|tx| *tx.nonce().unwrap()).collect();
+        assert_eq!(nonces, vec![U256::from(7), U256::from(8), U256::from(9)]);
+    }
+
+    fn reserves_response(reserves: &[(u64, u64)]) -> Bytes {
+        let items = reserves
+            .iter()
+            .map(|(reserve0, reserve1)| {
+                let data = encode(&[
+                    Token::Uint(U256::from(*reserve0) * U256::exp10(18)),
+                    Token::Uint(U256::from(*reserve1) * U256::exp10(18)),
+                    Token::Uint(U256::zero()),
+                ]);
+                Token::Tuple(vec![Token::Bool(true), Token::Bytes(data)])
+            })
+            .collect();
+        encode(&[Token::Array(items)]).into()
+    }
+
+    #[tokio::test]
+    async fn backtest_reports_profitable_blocks() {
+        let paths = vec![ArbPath::new(
+            vec![pool(100, 1, 2), pool(101, 2, 1)],
+            vec![true, true],
+        )];
+
+        // Mocked responses are served last in, first out: block 110 is answered second
+        let (provider, mock) = Provider::mocked();
+        mock.push::<Bytes, _>(reserves_response(&[
+            (1_000_000, 1_000_000),
+            (1_000_000, 1_020_000),
+        ]))
+        .unwrap();
+        mock.push::<Bytes, _>(reserves_response(&[
+            (1_000_000, 1_000_000),
+            (1_000_000, 1_000_000),
+        ]))
+        .unwrap();
+
+        let report = backtest(
+            &paths,
+            Arc::new(provider),
+            100,
+            110,
+            10,
+            &BacktestConfig::default(),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(report.records.len(), 2);
+        // Balanced pools at block 100 leave nothing after fees
+        assert_eq!(report.records[0].block_number, 100);
+        assert_eq!(report.records[0].profitable_paths, 0);
+        assert_eq!(report.records[0].best, None);
+        // A 2% gap at block 110 does
+        assert_eq!(report.records[1].block_number, 110);
+        assert_eq!(report.records[1].profitable_paths, 1);
+        let (idx, amount_in, profit) = report.records[1].best.unwrap();
+        assert_eq!(idx, 0);
+        assert!(profit > I256::zero());
+        assert_eq!(report.total_profit, profit);
+        assert_eq!(report.hit_rate, 0.5);
+
+        let reserves = HashMap::from([
+            (
+                H160::from_low_u64_be(100),
+                Reserve {
+                    reserve0: U256::exp10(24),
+                    reserve1: U256::exp10(24),
+                    ..Default::default()
+                },
+            ),
+            (
+                H160::from_low_u64_be(101),
+                Reserve {
+                    reserve0: U256::exp10(24),
+                    reserve1: U256::from(1_020_000) * U256::exp10(18),
+                    ..Default::default()
+                },
+            ),
+        ]);
+        assert_eq!(paths[0].optimal_amount_in(&reserves), Some(amount_in));
+    }
+
+    #[test]
+    fn best_path_matches_serial_scan() {
+        // path i trades against a pool priced (1 + i%7 / 100) in its favour, i%7 == 0 loses
+        let mut paths = Vec::new();
+        let mut reserves = HashMap::new();
+        for i in 0..40u64 {
+            let (first, second) = (1_000 + 2 * i, 1_001 + 2 * i);
+            paths.push(ArbPath::new(
+                vec![pool(first, 1, 2), pool(second, 2, 1)],
+                vec![true, true],
+            ));
+            reserves.insert(
+                H160::from_low_u64_be(first),
+                Reserve {
+                    reserve0: U256::exp10(24),
+                    reserve1: U256::exp10(24),
+                    ..Default::default()
+                },
+            );
+            reserves.insert(
+                H160::from_low_u64_be(second),
+                Reserve {
+                    reserve0: U256::exp10(24),
+                    reserve1: U256::exp10(22) * (100 + i % 7),
+                    ..Default::default()
+                },
+            );
+        }
+        let grid: Vec<U256> = (1..=5).map(|n| U256::exp10(18) * n).collect();
+
+        let mut serial: Option<(usize, U256, I256)> = None;
+        for (idx, path) in paths.iter().enumerate() {
+            let candidates = grid
+                .iter()
+                .cloned()
+                .chain(path.optimal_amount_in(&reserves));
+            for amount_in in candidates {
+                if let Some(amount_out) = path.simulate_v2_path(amount_in, &reserves) {
+                    if amount_out <= amount_in {
+                        continue;
+                    }
+                    let profit = I256::try_from(amount_out - amount_in).unwrap();
+                    if !matches!(serial, Some((_, _, best)) if profit <= best) {
+                        serial = Some((idx, amount_in, profit));
+                    }
                 }
+            }
+        }
+
+        let best = best_path(&paths, &reserves, &grid);
+        assert_eq!(best, serial);
+        // i = 6 is the first path with the widest gap
+        assert_eq!(best.unwrap().0, 6);
+        for _ in 0..5 {
+            assert_eq!(best_path(&paths, &reserves, &grid), best);
+        }
+        assert_eq!(best_path(&paths[..1], &reserves, &grid), None);
+    }
+
+    fn gated_path() -> (ArbPath, HashMap<H160, Reserve>, StrategyConfig) {
+        let path = ArbPath::new(vec![pool(100, 1, 2), pool(101, 2, 1)], vec![true, true]);
+        // 2% gap between the two pools, both read at block 50
+        let reserves = HashMap::from([
+            (
+                H160::from_low_u64_be(100),
+                Reserve {
+                    reserve0: U256::exp10(24),
+                    reserve1: U256::exp10(24),
+                    block_number: 50,
+                    ..Default::default()
+                },
+            ),
+            (
+                H160::from_low_u64_be(101),
+                Reserve {
+                    reserve0: U256::exp10(24),
+                    reserve1: U256::exp10(22) * 102,
+                    block_number: 50,
+                    ..Default::default()
+                },
+            ),
+        ]);
+        let cfg = StrategyConfig {
+            min_profit_wei: U256::exp10(16),
+            gas_price: U256::from(20_000_000_000u64),
+            current_block: 50,
+            ..Default::default()
+        };
+        (path, reserves, cfg)
+    }
+
+    #[test]
+    fn should_execute_accepts_a_fresh_profitable_trade() {
+        let (path, reserves, cfg) = gated_path();
+        // 10 ETH in earns ~0.14 ETH gross at a negligible price impact
+        assert_eq!(
+            check_execution(&path, U256::exp10(19), &reserves, &cfg),
+            Ok(())
+        );
+        assert!(should_execute(&path, U256::exp10(19), &reserves, &cfg));
+    }
+
+    #[test]
+    fn should_execute_rejects_stale_reserves() {
+        let (path, reserves, mut cfg) = gated_path();
+        cfg.current_block = 52;
+        assert_eq!(
+            check_execution(&path, U256::exp10(19), &reserves, &cfg),
+            Err(Rejection::StaleReserves)
+        );
+    }
+
+    #[test]
+    fn should_execute_rejects_high_price_impact() {
+        let (path, reserves, cfg) = gated_path();
+        // 10k ETH into 1M ETH pools moves the price ~2%
+        assert_eq!(
+            check_execution(&path, U256::exp10(22), &reserves, &cfg),
+            Err(Rejection::PriceImpact)
+        );
+    }
+
+    #[test]
+    fn should_execute_rejects_profit_below_minimum() {
+        let (path, reserves, mut cfg) = gated_path();
+        // 0.1 ETH in earns ~1.4e15 gross, less than the 1e16 minimum even before gas
+        assert_eq!(
+            check_execution(&path, U256::exp10(17), &reserves, &cfg),
+            Err(Rejection::BelowMinProfit)
+        );
+
+        // Gas alone: 1 ETH in earns ~1.4e16 gross, enough for 170k gas at 20 gwei * 1.2
+        // (4.08e15) but not at 100 gwei * 1.2 (2.04e16)
+        cfg.min_profit_wei = U256::zero();
+        assert!(should_execute(&path, U256::exp10(18), &reserves, &cfg));
+        cfg.gas_price = U256::from(100_000_000_000u64);
+        assert_eq!(
+            check_execution(&path, U256::exp10(18), &reserves, &cfg),
+            Err(Rejection::BelowMinProfit)
+        );
+    }
+
+    #[test]
+    fn allocate_beats_full_size_on_a_shared_pool() {
+        // Both paths buy token 2 in pool 100, then sell it into their own 2% rich pool
+        let paths = vec![
+            ArbPath::new(vec![pool(100, 1, 2), pool(101, 2, 1)], vec![true, true]),
+            ArbPath::new(vec![pool(100, 1, 2), pool(102, 2, 1)], vec![true, true]),
+        ];
+        let reserve = |reserve1: U256| Reserve {
+            reserve0: U256::exp10(24),
+            reserve1,
+            ..Default::default()
+        };
+        let reserves = HashMap::from([
+            (H160::from_low_u64_be(100), reserve(U256::exp10(24))),
+            (H160::from_low_u64_be(101), reserve(U256::exp10(22) * 102)),
+            (H160::from_low_u64_be(102), reserve(U256::exp10(22) * 102)),
+        ]);
+        let cfg = StrategyConfig {
+            gas_price: U256::from(20_000_000_000u64),
+            ..Default::default()
+        };
+        let capital = U256::exp10(18) * 10_000;
+
+        // Naive: each path at the size that is optimal on the untouched reserves
+        let naive: Vec<(usize, U256)> = paths
+            .iter()
+            .enumerate()
+            .map(|(idx, path)| (idx, path.optimal_amount_in(&reserves).unwrap()))
+            .collect();
+        let naive_profit = allocation_profit(&paths, &naive, &reserves, &cfg).unwrap();
+
+        let allocation = allocate(&paths, capital, &reserves, &cfg);
+        let allocated_profit = allocation_profit(&paths, &allocation, &reserves, &cfg).unwrap();
+
+        assert!(!allocation.is_empty());
+        let used = allocation
+            .iter()
+            .fold(U256::zero(), |sum, (_, amount_in)| sum + *amount_in);
+        assert!(used <= capital);
+        assert!(
+            allocated_profit > naive_profit,
+            "allocated {} vs naive {}",
+            allocated_profit,
+            naive_profit
+        );
+        // Sizing each path for the untouched pool over-trades the shared one
+        let naive_used = naive
+            .iter()
+            .fold(U256::zero(), |sum, (_, amount_in)| sum + *amount_in);
+        assert!(used < naive_used);
+    }
+
+    #[test]
+    fn usdc_profit_is_converted_to_weth() {
+        let (usdc, weth) = (H160::from_low_u64_be(1), H160::from_low_u64_be(2));
+        let mut shallow = pool(100, 1, 2);
+        shallow.decimals0 = 6;
+        let mut deep = pool(101, 1, 2);
+        deep.decimals0 = 6;
+        let pools = vec![shallow, deep];
+
+        // 2,000 USDC per WETH in the deep pool, a stale 1,000 in the shallow one
+        let reserves = HashMap::from([
+            (
+                H160::from_low_u64_be(100),
+                Reserve {
+                    reserve0: U256::from(1_000_000u64) * U256::exp10(6),
+                    reserve1: U256::exp10(18) * 1_000,
+                    ..Default::default()
+                },
+            ),
+            (
+                H160::from_low_u64_be(101),
+                Reserve {
+                    reserve0: U256::from(20_000_000u64) * U256::exp10(6),
+                    reserve1: U256::exp10(18) * 10_000,
+                    ..Default::default()
+                },
+            ),
+        ]);
+
+        // 500 USDC = 0.25 WETH
+        let profit = U256::from(500u64) * U256::exp10(6);
+        assert_eq!(
+            normalize_profit_to_weth(profit, usdc, &pools, &reserves, weth),
+            Some(U256::exp10(16) * 25)
+        );
+        assert_eq!(
+            normalize_profit_to_weth(profit, weth, &pools, &reserves, weth),
+            Some(profit)
+        );
+        assert_eq!(
+            normalize_profit_to_weth(profit, H160::from_low_u64_be(3), &pools, &reserves, weth),
+            None
+        );
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn block_span_carries_evaluation_fields() {
+        let paths = vec![
+            ArbPath::new(vec![pool(100, 1, 2), pool(101, 2, 1)], vec![true, true]),
+            ArbPath::new(vec![pool(102, 1, 2), pool(103, 2, 1)], vec![true, true]),
+        ];
+        let reserve = |reserve1: U256| Reserve {
+            reserve0: U256::exp10(24),
+            reserve1,
+            ..Default::default()
+        };
+        let reserves = HashMap::from([
+            (H160::from_low_u64_be(100), reserve(U256::exp10(24))),
+            (H160::from_low_u64_be(101), reserve(U256::exp10(22) * 102)),
+            (H160::from_low_u64_be(102), reserve(U256::exp10(24))),
+            (H160::from_low_u64_be(103), reserve(U256::exp10(24))),
+        ]);
+
+        // Only the first path touches pool 101
+        let spreads = evaluate_touched_paths(
+            17_000_000,
+            &PathIndex::new(paths),
+            &[H160::from_low_u64_be(101)],
+            &reserves,
+            U256::exp10(18),
+            None,
+            0,
+        );
+
+        assert_eq!(spreads.len(), 1);
+        let best = spreads[&0];
+        assert!(logs_contain("block{block_number=17000000"));
+        assert!(logs_contain("paths_evaluated=1"));
+        assert!(logs_contain(&format!("best_profit={}", best)));
+        assert!(logs_contain("paths evaluated"));
+    }
+
+    #[test]
+    #[tracing_test::traced_test]
+    fn path_budget_simulates_a_seeded_sample() {
+        // Ten profitable paths, all through pool 101
+        let paths: Vec<ArbPath> = (0..10)
+            .map(|i| ArbPath::new(vec![pool(200 + i, 1, 2), pool(101, 2, 1)], vec![true, true]))
+            .collect();
+        let reserve = |reserve1: U256| Reserve {
+            reserve0: U256::exp10(24),
+            reserve1,
+            ..Default::default()
+        };
+        let mut reserves: HashMap<H160, Reserve> = (0..10)
+            .map(|i| (H160::from_low_u64_be(200 + i), reserve(U256::exp10(24))))
+            .collect();
+        reserves.insert(H160::from_low_u64_be(101), reserve(U256::exp10(22) * 102));
+        let index = PathIndex::new(paths);
+        let evaluate = |seed: u64| {
+            let spreads = evaluate_touched_paths(
+                17_000_000,
+                &index,
+                &[H160::from_low_u64_be(101)],
+                &reserves,
+                U256::exp10(18),
+                Some(4),
+                seed,
+            );
+            let mut picked: Vec<usize> = spreads.into_keys().collect();
+            picked.sort_unstable();
+            picked
+        };
+
+        let picked = evaluate(7);
+        assert_eq!(picked.len(), 4);
+        assert!(logs_contain("paths_evaluated=4"));
+        assert!(!logs_contain("paths_evaluated=10"));
+        assert!(logs_contain("over the path budget, simulating a sample"));
+        // The same seed replays the same sample
+        assert_eq!(evaluate(7), picked);
+        assert_eq!(picked, sample_indices(10, 4, 7));
+    }
+
+    fn transfer(token: H160, from: H160, to: H160, amount: U256) -> ethers::types::Log {
+        ethers::types::Log {
+            address: token,
+            topics: vec![
+                H256::from(keccak256("Transfer(address,address,uint256)")),
+                H256::from(from),
+                H256::from(to),
+            ],
+            data: encode(&[Token::Uint(amount)]).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn execution_profit_is_decoded_from_transfer_logs() {
+        let (weth, usdc, executor) = (
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(999),
+        );
+        let (pool_a, pool_b) = (H160::from_low_u64_be(11), H160::from_low_u64_be(12));
+        let e18 = U256::exp10(18);
+        // 1 WETH -> 2,000 USDC -> 1.01 WETH, the USDC leg never touches the executor
+        let mut receipt = TransactionReceipt {
+            status: Some(U64::one()),
+            logs: vec![
+                transfer(weth, executor, pool_a, e18),
+                transfer(usdc, pool_a, pool_b, U256::from(2_000_000_000u64)),
+                transfer(weth, pool_b, executor, e18 * 101 / 100),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_execution(&receipt, weth, executor),
+            Some(I256::exp10(16))
+        );
+
+        // Nothing moved in or out of the executor
+        assert_eq!(
+            decode_execution(&receipt, weth, H160::from_low_u64_be(998)),
+            None
+        );
+
+        // The same receipt read in USDC: the executor neither sent nor received any
+        assert_eq!(decode_execution(&receipt, usdc, executor), None);
+
+        receipt.status = Some(U64::zero());
+        assert_eq!(decode_execution(&receipt, weth, executor), None);
+    }
+
+    #[test]
+    fn execution_profit_is_in_the_base_tokens_units() {
+        let (usdc, weth, executor) = (
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(1),
+            H160::from_low_u64_be(999),
+        );
+        let pool = H160::from_low_u64_be(11);
+        // 2,000 USDC in, 2,004.5 USDC back: 4.5 USDC is 4_500_000 of its 6 decimal units
+        let receipt = TransactionReceipt {
+            status: Some(U64::one()),
+            logs: vec![
+                transfer(usdc, executor, pool, U256::from(2_000_000_000u64)),
+                transfer(weth, pool, pool, U256::exp10(18)),
+                transfer(usdc, pool, executor, U256::from(2_004_500_000u64)),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(
+            decode_execution(&receipt, usdc, executor),
+            Some(I256::from(4_500_000))
+        );
+    }
+
+    // Records what it's handed instead of sending it
+    #[derive(Default)]
+    struct RecordingSubmitter {
+        calls: std::sync::Mutex<Vec<(Vec<TypedTransaction>, U64)>>,
+    }
+
+    #[async_trait::async_trait]
+    impl Submitter for RecordingSubmitter {
+        async fn submit(
+            &self,
+            txs: Vec<TypedTransaction>,
+            target_block: U64,
+        ) -> Result<SubmitReceipt> {
+            let tx_hashes = txs.iter().map(|tx| tx.sighash()).collect();
+            self.calls.lock().unwrap().push((txs, target_block));
+            Ok(SubmitReceipt {
+                tx_hashes,
+                bundle_hash: None,
+                target_block,
+            })
+        }
+
+        fn sender(&self) -> Option<H160> {
+            Some(H160::from_low_u64_be(1))
+        }
+    }
+
+    #[tokio::test]
+    #[tracing_test::traced_test]
+    async fn dry_run_logs_instead_of_submitting() {
+        let path = ArbPath::new(vec![pool(100, 1, 2), pool(101, 2, 1)], vec![true, true]);
+        let cfg = StrategyConfig {
+            dry_run: true,
+            ..Default::default()
+        };
+        let mut ledger = PaperLedger::default();
+        let submitter = RecordingSubmitter::default();
+
+        for profit in [1_000i64, 2_500] {
+            let tx = build_arb_tx(
+                &path,
+                &ArbTxParams {
+                    amount_in: U256::exp10(18),
+                    expected_out: U256::exp10(18),
+                    executor: H160::from_low_u64_be(999),
+                    ..Default::default()
+                },
+            );
+            let receipt = execute_or_paper_trade(
+                vec![tx],
+                I256::from(profit),
+                &cfg,
+                &mut ledger,
+                &submitter,
+                U64::from(101),
+            )
+            .await
+            .unwrap();
+            // NullSubmitter took them
+            assert!(receipt.tx_hashes.is_empty());
+            assert_eq!(receipt.target_block, U64::from(101));
+        }
+
+        assert!(submitter.calls.lock().unwrap().is_empty());
+        assert_eq!(ledger.opportunities, 2);
+        assert_eq!(ledger.simulated_pnl, I256::from(3_500));
+        assert!(logs_contain("dry run: bundle not submitted"));
+        assert!(logs_contain("null submitter: nothing sent"));
+        assert_eq!(NullSubmitter.sender(), None);
+    }
+
+    #[tokio::test]
+    async fn live_runs_hand_the_built_txs_to_the_submitter() {
+        let mut path = ArbPath::new(vec![pool(100, 1, 2), pool(101, 2, 1)], vec![true, true]);
+        path.requires_wrap = true;
+        let mut ledger = PaperLedger::default();
+        let submitter = RecordingSubmitter::default();
+
+        let txs = build_arb_txs(
+            &path,
+            &ArbTxParams {
+                amount_in: U256::exp10(18),
+                expected_out: U256::exp10(18),
+                executor: H160::from_low_u64_be(999),
+                base_fee: U256::exp10(10),
+                nonce: U256::from(7),
+                ..Default::default()
             },
-            Err(_) => {}
+            H160::from_low_u64_be(1),
+        );
+        let receipt = execute_or_paper_trade(
+            txs.clone(),
+            I256::from(1),
+            &StrategyConfig {
+                dry_run: false,
+                ..Default::default()
+            },
+            &mut ledger,
+            &submitter,
+            U64::from(101),
+        )
+        .await
+        .unwrap();
+
+        // deposit, transfer, arb, in one call for the target block
+        assert_eq!(txs.len(), 3);
+        assert_eq!(
+            *submitter.calls.lock().unwrap(),
+            vec![(txs.clone(), U64::from(101))]
+        );
+        assert_eq!(receipt.target_block, U64::from(101));
+        assert_eq!(
+            receipt.tx_hashes,
+            txs.iter().map(|tx| tx.sighash()).collect::<Vec<_>>()
+        );
+        // Live trades aren't paper trades
+        assert_eq!(ledger.opportunities, 0);
+    }
+
+    // A Runner on a mocked node, submitting to a RecordingSubmitter it shares
+    fn runner(
+        cfg: StrategyConfig,
+    ) -> (
+        Runner<Provider<ethers::providers::MockProvider>>,
+        ethers::providers::MockProvider,
+        Arc<RecordingSubmitter>,
+    ) {
+        let (provider, mock) = Provider::mocked();
+        let submitter = Arc::new(RecordingSubmitter::default());
+        let runner = Runner::new(
+            Arc::new(provider),
+            cfg,
+            Box::new(submitter.clone()),
+            H160::from_low_u64_be(2),
+            H160::from_low_u64_be(1),
+        );
+        (runner, mock, submitter)
+    }
+
+    #[async_trait::async_trait]
+    impl Submitter for Arc<RecordingSubmitter> {
+        async fn submit(
+            &self,
+            txs: Vec<TypedTransaction>,
+            target_block: U64,
+        ) -> Result<SubmitReceipt> {
+            self.as_ref().submit(txs, target_block).await
+        }
+
+        fn sender(&self) -> Option<H160> {
+            self.as_ref().sender()
+        }
+    }
+
+    #[tokio::test]
+    async fn runner_submits_and_books_the_landed_arb() {
+        let executor = H160::from_low_u64_be(999);
+        let (mut runner, mock, submitter) = runner(StrategyConfig {
+            dry_run: false,
+            executor,
+            ..Default::default()
+        });
+        let path = ArbPath::new(vec![pool(100, 2, 3), pool(101, 3, 2)], vec![true, true]);
+        let opportunity =
+            Opportunity::new(0, U256::from(1_000), U256::from(1_100), I256::from(90), 100);
+
+        // LIFO: the nonce is looked up first, the receipt last
+        let token = H160::from_low_u64_be(2);
+        let landed = TransactionReceipt {
+            status: Some(U64::one()),
+            logs: vec![
+                transfer(
+                    token,
+                    executor,
+                    H160::from_low_u64_be(100),
+                    U256::from(1_000),
+                ),
+                transfer(
+                    token,
+                    H160::from_low_u64_be(101),
+                    executor,
+                    U256::from(1_095),
+                ),
+            ],
+            ..Default::default()
+        };
+        mock.push(landed).unwrap();
+        mock.push(U256::from(7)).unwrap();
+
+        let realized = runner
+            .submit(&path, &opportunity, U256::exp10(10), I256::exp10(15))
+            .await;
+
+        assert_eq!(realized, Some(I256::from(95)));
+        let calls = submitter.calls.lock().unwrap();
+        assert_eq!(calls.len(), 1);
+        let (txs, target_block) = &calls[0];
+        assert_eq!(*target_block, U64::from(101));
+        assert_eq!(txs[0].nonce(), Some(&U256::from(7)));
+        assert_eq!(runner.ledger.landed, 1);
+        assert_eq!(runner.ledger.realized_pnl, I256::from(95));
+        // Live trades aren't paper trades
+        assert_eq!(runner.ledger.opportunities, 0);
+    }
+
+    #[tokio::test]
+    async fn dry_runs_go_through_the_null_submitter() {
+        let (mut runner, _mock, submitter) = runner(StrategyConfig {
+            dry_run: true,
+            ..Default::default()
+        });
+        let path = ArbPath::new(vec![pool(100, 2, 3), pool(101, 3, 2)], vec![true, true]);
+        let opportunity =
+            Opportunity::new(0, U256::from(1_000), U256::from(1_100), I256::from(90), 100);
+
+        // Nothing is mocked: a nonce or receipt lookup would fail
+        assert_eq!(
+            runner
+                .submit(&path, &opportunity, U256::exp10(10), I256::exp10(15))
+                .await,
+            None
+        );
+        assert!(runner.nonces.is_none());
+        assert!(submitter.calls.lock().unwrap().is_empty());
+        assert_eq!(runner.ledger.opportunities, 1);
+        assert_eq!(runner.ledger.simulated_pnl, I256::from(90));
+    }
+
+    // Fails every submission with the error make builds
+    struct FailingSubmitter {
+        make: fn() -> anyhow::Error,
+    }
+
+    #[async_trait::async_trait]
+    impl Submitter for FailingSubmitter {
+        async fn submit(&self, _: Vec<TypedTransaction>, _: U64) -> Result<SubmitReceipt> {
+            Err((self.make)())
+        }
+
+        fn sender(&self) -> Option<H160> {
+            Some(H160::from_low_u64_be(1))
         }
     }
+
+    #[tokio::test]
+    async fn only_reverts_trip_the_circuit_breaker() {
+        let path = ArbPath::new(vec![pool(100, 2, 3), pool(101, 3, 2)], vec![true, true]);
+        let opportunity =
+            Opportunity::new(0, U256::from(1_000), U256::from(1_100), I256::from(90), 100);
+        let failing = |make: fn() -> anyhow::Error| {
+            let (provider, mock) = Provider::mocked();
+            let runner = Runner::new(
+                Arc::new(provider),
+                StrategyConfig {
+                    dry_run: false,
+                    max_consecutive_reverts: 2,
+                    ..Default::default()
+                },
+                Box::new(FailingSubmitter { make }),
+                H160::from_low_u64_be(2),
+                H160::from_low_u64_be(1),
+            );
+            (runner, mock)
+        };
+
+        // A lost auction or a timeout says nothing about the arb itself
+        let (mut runner, mock) = failing(|| anyhow!("Bundle not included in target block"));
+        for _ in 0..3 {
+            // the nonces are reseeded after every failure
+            mock.push(U256::from(7)).unwrap();
+            runner
+                .submit(&path, &opportunity, U256::exp10(10), I256::exp10(15))
+                .await;
+        }
+        assert!(!runner.breaker.is_tripped());
+        let (mut runner, mock) = failing(|| {
+            RpcTimeout {
+                operation: "Bundle send".to_string(),
+                deadline: Duration::from_secs(10),
+            }
+            .into()
+        });
+        for _ in 0..3 {
+            mock.push(U256::from(7)).unwrap();
+            runner
+                .submit(&path, &opportunity, U256::exp10(10), I256::exp10(15))
+                .await;
+        }
+        assert!(!runner.breaker.is_tripped());
+
+        let (mut runner, mock) =
+            failing(|| SimulationReverted("execution reverted".to_string()).into());
+        for _ in 0..2 {
+            mock.push(U256::from(7)).unwrap();
+            runner
+                .submit(&path, &opportunity, U256::exp10(10), I256::exp10(15))
+                .await;
+        }
+        assert!(runner.breaker.is_tripped());
+        // An operator's reset (SIGUSR1) lets submissions through again
+        runner.breaker.reset();
+        assert!(runner.breaker.allows());
+    }
+
+    #[test]
+    fn circuit_breaker_blocks_after_consecutive_reverts() {
+        let minute = Duration::from_secs(60);
+        let mut breaker = CircuitBreaker::new(3, 10 * minute, 30 * minute);
+        let start = Instant::now();
+
+        // A success in between starts the count over
+        breaker.record_revert_at(start);
+        breaker.record_revert_at(start);
+        breaker.record_success();
+        for i in 0..2 {
+            breaker.record_revert_at(start + minute * i);
+            assert!(breaker.allows_at(start + minute * i));
+        }
+        breaker.record_revert_at(start + 2 * minute);
+        assert!(breaker.is_tripped());
+        assert!(!breaker.allows_at(start + 3 * minute));
+
+        // Blocked until the cooldown runs out, or a manual reset
+        assert!(!breaker.allows_at(start + 31 * minute));
+        assert!(breaker.allows_at(start + 33 * minute));
+        assert!(!breaker.is_tripped());
+
+        for i in 0..3 {
+            breaker.record_revert_at(start + 40 * minute + minute * i);
+        }
+        assert!(!breaker.allows_at(start + 43 * minute));
+        breaker.reset();
+        assert!(breaker.allows_at(start + 43 * minute));
+
+        // Reverts spread wider than the window never add up
+        for i in 0..5 {
+            breaker.record_revert_at(start + 60 * minute + 11 * minute * i);
+        }
+        assert!(!breaker.is_tripped());
+    }
+
+    #[tokio::test]
+    async fn nonces_increase_per_submission_and_reset_on_reorg() {
+        let (provider, mock) = Provider::mocked();
+        let mut nonces = NonceManager::new(H160::from_low_u64_be(1));
+        let header = |number: u64, hash: u64, parent: u64| Block::<H256> {
+            number: Some(number.into()),
+            hash: Some(H256::from_low_u64_be(hash)),
+            parent_hash: H256::from_low_u64_be(parent),
+            ..Default::default()
+        };
+
+        // Seeded once from the pending count, then counted locally
+        mock.push(U256::from(7)).unwrap();
+        assert!(!nonces.on_block(&header(100, 0xa, 0x9)));
+        assert_eq!(nonces.next(&provider).await.unwrap(), U256::from(7));
+        assert!(!nonces.on_block(&header(101, 0xb, 0xa)));
+        assert_eq!(nonces.next(&provider).await.unwrap(), U256::from(8));
+
+        // The same header twice is a re-delivery, not a reorg, the count carries on
+        assert!(!nonces.on_block(&header(101, 0xb, 0xa)));
+        assert_eq!(nonces.next(&provider).await.unwrap(), U256::from(9));
+
+        // 101 again with a different hash: the chain reorged, so the count is reseeded
+        assert!(nonces.on_block(&header(101, 0xc, 0xa)));
+        mock.push(U256::from(8)).unwrap();
+        assert_eq!(nonces.next(&provider).await.unwrap(), U256::from(8));
+        assert!(!nonces.on_block(&header(101, 0xc, 0xa)));
+
+        // A child that doesn't build on our head is a reorg too
+        assert!(nonces.on_block(&header(102, 0xd, 0xb)));
+        mock.push(U256::from(8)).unwrap();
+        assert_eq!(nonces.next(&provider).await.unwrap(), U256::from(8));
+        assert_eq!(nonces.next(&provider).await.unwrap(), U256::from(9));
+    }
+
+    #[test]
+    fn chain_head_reports_the_first_orphaned_block() {
+        let header = |number: u64, hash: u64, parent: u64| Block::<H256> {
+            number: Some(number.into()),
+            hash: Some(H256::from_low_u64_be(hash)),
+            parent_hash: H256::from_low_u64_be(parent),
+            ..Default::default()
+        };
+        let mut head = ChainHead::default();
+        assert_eq!(head.on_block(&header(100, 0xa, 0x9)), None);
+        assert_eq!(head.on_block(&header(101, 0xb, 0xa)), None);
+        assert_eq!(head.on_block(&header(101, 0xb, 0xa)), None);
+        // 100 replaced while our head was 101
+        assert_eq!(head.on_block(&header(100, 0xc, 0x9)), Some(100.into()));
+        // a 101 that doesn't build on 0xc orphans it
+        assert_eq!(head.on_block(&header(101, 0xd, 0xa)), Some(100.into()));
+        assert_eq!(head.on_block(&header(102, 0xe, 0xd)), None);
+    }
+
+    #[tokio::test]
+    async fn wrapped_bundles_reserve_a_nonce_per_tx() {
+        let (provider, mock) = Provider::mocked();
+        let mut nonces = NonceManager::new(H160::from_low_u64_be(1));
+        let mut path = ArbPath::new(vec![pool(100, 1, 2), pool(101, 2, 1)], vec![true, true]);
+        path.requires_wrap = true;
+
+        mock.push(U256::from(7)).unwrap();
+        let first = nonces
+            .reserve(&provider, arb_tx_count(&path))
+            .await
+            .unwrap();
+        assert_eq!(first, U256::from(7));
+        // deposit, transfer and arb took 7, 8 and 9, the next bundle starts after them
+        assert_eq!(nonces.next(&provider).await.unwrap(), U256::from(10));
+    }
 }
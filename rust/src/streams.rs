@@ -1,12 +1,26 @@
+use anyhow::{anyhow, Result};
 use ethers::{
-    providers::{Provider, Ws},
-    types::{Filter, Log, Transaction, U256, U64},
+    providers::{GetTransactionError, Provider, PubsubClient, Ws},
+    types::{Block, Bytes, Filter, Log, Transaction, H160, H256, U256, U64},
 };
 use ethers_providers::Middleware;
-use std::sync::Arc;
-use tokio::sync::broadcast::Sender;
-use tokio_stream::StreamExt;
+use futures::stream::select_all;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{self, error::TrySendError};
+use tokio_stream::{wrappers::ReceiverStream, Stream, StreamExt};
+use tokio_util::sync::CancellationToken;
 
+use crate::metrics;
 use crate::utils::calculate_next_block_base_fee;
 
 #[derive(Default, Debug, Clone)]
@@ -16,59 +30,869 @@ pub struct NewBlock {
     pub next_base_fee: U256,
 }
 
+impl From<&Block<H256>> for NewBlock {
+    fn from(block: &Block<H256>) -> Self {
+        Self {
+            block_number: block.number.unwrap_or_default(),
+            base_fee: block.base_fee_per_gas.unwrap_or_default(),
+            next_base_fee: U256::from(calculate_next_block_base_fee(
+                block.gas_used,
+                block.gas_limit,
+                block.base_fee_per_gas.unwrap_or_default(),
+            )),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub enum Event {
-    Block(NewBlock),
+    Block(Block<H256>),
     PendingTx(Transaction),
     Log(Log),
+    // One of the underlying subscriptions reconnected, events may have been missed
+    Reconnected,
+    // The operator asked to resume submitting (SIGUSR1, see reset_breaker_on_signal)
+    ResetCircuitBreaker,
+}
+
+#[derive(Debug, Clone)]
+pub enum Subscription {
+    NewBlocks,
+    PendingTxs,
+    Logs(Filter),
+}
+
+/*
+Bounded hand-off from the stream tasks to the strategy worker.
+When the worker falls behind, pending txs are shed (counted in dropped() and the
+mev_events_dropped_total metric) while every other event waits for room,
+so blocks, logs and reconnect notices are never lost to a mempool burst.
+*/
+#[derive(Debug, Clone)]
+pub struct EventQueue {
+    sender: mpsc::Sender<Event>,
+    dropped: Arc<AtomicU64>,
 }
 
-pub async fn stream_new_blocks(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+impl EventQueue {
+    pub fn new(capacity: usize) -> (Self, mpsc::Receiver<Event>) {
+        let (sender, receiver) = mpsc::channel(capacity.max(1));
+        let queue = Self {
+            sender,
+            dropped: Arc::new(AtomicU64::new(0)),
+        };
+        (queue, receiver)
+    }
+
+    // Errors once the worker has stopped, the producer should stop too
+    pub async fn push(&self, event: Event) -> Result<()> {
+        match event {
+            Event::PendingTx(_) => match self.sender.try_send(event) {
+                Ok(_) => Ok(()),
+                Err(TrySendError::Full(_)) => {
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    metrics::record_event_dropped();
+                    Ok(())
+                }
+                Err(TrySendError::Closed(_)) => Err(anyhow!("event worker stopped")),
+            },
+            event => self
+                .sender
+                .send(event)
+                .await
+                .map_err(|_| anyhow!("event worker stopped")),
+        }
+    }
+
+    // Pending txs shed so far, across every clone of this queue
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+/*
+Next event for a strategy worker, None once shutdown is cancelled or every EventQueue is gone.
+Cancellation only interrupts the wait: an event already returned is handled to completion,
+so a bundle being submitted when Ctrl-C arrives still goes out.
+*/
+pub async fn next_event(
+    receiver: &mut mpsc::Receiver<Event>,
+    shutdown: &CancellationToken,
+) -> Option<Event> {
+    tokio::select! {
+        biased;
+        _ = shutdown.cancelled() => None,
+        event = receiver.recv() => event,
+    }
+}
+
+/*
+Pushes Event::ResetCircuitBreaker each time the process gets SIGUSR1 (kill -USR1 <pid>),
+so an operator can resume a tripped strategy::CircuitBreaker without restarting the bot.
+*/
+#[cfg(unix)]
+pub async fn reset_breaker_on_signal(queue: EventQueue) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut signals = match signal(SignalKind::user_defined1()) {
+        Ok(signals) => signals,
+        Err(e) => {
+            warn!(
+                "listening for SIGUSR1 failed, the circuit breaker can't be reset: {:?}",
+                e
+            );
+            return;
+        }
+    };
+    while signals.recv().await.is_some() {
+        if queue.push(Event::ResetCircuitBreaker).await.is_err() {
+            break;
+        }
+    }
+}
+
+// These three subscribe, so they need a pubsub (websocket) transport, e.g. ProviderSet::ws
+pub async fn broadcast_new_blocks<M>(provider: Arc<M>, queue: EventQueue)
+where
+    M: Middleware,
+    M::Provider: PubsubClient,
+{
     let stream = provider.subscribe_blocks().await.unwrap();
-    let mut stream = stream.filter_map(|block| match block.number {
-        Some(number) => Some(NewBlock {
-            block_number: number,
-            base_fee: block.base_fee_per_gas.unwrap_or_default(),
-            next_base_fee: U256::from(calculate_next_block_base_fee(
-                block.gas_used,
-                block.gas_limit,
-                block.base_fee_per_gas.unwrap_or_default(),
-            )),
-        }),
-        None => None,
-    });
+    let mut stream = stream.filter(|block| block.number.is_some());
 
     while let Some(block) = stream.next().await {
-        match event_sender.send(Event::Block(block)) {
-            Ok(_) => {}
-            Err(_) => {}
+        if queue.push(Event::Block(block)).await.is_err() {
+            break;
         }
     }
 }
 
-pub async fn stream_pending_transactions(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+pub async fn stream_pending_transactions<M>(provider: Arc<M>, queue: EventQueue)
+where
+    M: Middleware,
+    M::Provider: PubsubClient,
+{
     let stream = provider.subscribe_pending_txs().await.unwrap();
     let mut stream = stream.transactions_unordered(256).fuse();
 
     while let Some(result) = stream.next().await {
-        match result {
-            Ok(tx) => match event_sender.send(Event::PendingTx(tx)) {
-                Ok(_) => {}
-                Err(_) => {}
-            },
-            Err(_) => {}
-        };
+        if let Ok(tx) = result {
+            if queue.push(Event::PendingTx(tx)).await.is_err() {
+                break;
+            }
+        }
     }
 }
 
-pub async fn stream_uniswap_v2_events(provider: Arc<Provider<Ws>>, event_sender: Sender<Event>) {
+pub async fn stream_uniswap_v2_events<M>(provider: Arc<M>, queue: EventQueue)
+where
+    M: Middleware,
+    M::Provider: PubsubClient,
+{
     let filter = Filter::new().event("Sync(uint112,uint112)");
     let mut stream = provider.subscribe_logs(&filter).await.unwrap();
 
     while let Some(result) = stream.next().await {
-        match event_sender.send(Event::Log(result)) {
-            Ok(_) => {}
-            Err(_) => {}
+        if queue.push(Event::Log(result)).await.is_err() {
+            break;
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum StreamEvent<T> {
+    Item(T),
+    // The connection dropped and was re-established, anything in between was missed
+    Reconnected,
+}
+
+#[derive(Debug, Clone)]
+pub struct ReconnectConfig {
+    // waits base_delay_ms * 2^(attempt - 1), capped at max_delay_ms, between attempts
+    pub base_delay_ms: u64,
+    pub max_delay_ms: u64,
+    // consecutive failed attempts before the stream gives up and ends
+    pub max_attempts: u32,
+}
+
+impl Default for ReconnectConfig {
+    fn default() -> Self {
+        Self {
+            base_delay_ms: 500,
+            max_delay_ms: 30_000,
+            max_attempts: 10,
+        }
+    }
+}
+
+impl ReconnectConfig {
+    // Wait before the given (1-based) attempt
+    fn delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .base_delay_ms
+            .saturating_mul(1 << attempt.saturating_sub(1).min(20))
+            .min(self.max_delay_ms);
+        Duration::from_millis(delay)
+    }
+}
+
+/*
+Keeps a websocket subscription alive across disconnects.
+forward gets a fresh connection and pushes items into the sender until its subscription ends.
+Whenever that happens (or connecting fails) we back off and connect again,
+and every successful reconnect is announced with StreamEvent::Reconnected.
+ethers' own ws reconnects are turned off so that a dropped connection ends the subscription
+instead of resuming it silently.
+*/
+pub fn reconnecting_stream<T, F, Fut>(
+    ws_url: String,
+    config: ReconnectConfig,
+    forward: F,
+) -> impl Stream<Item = StreamEvent<T>>
+where
+    T: Send + 'static,
+    F: Fn(Provider<Ws>, mpsc::Sender<StreamEvent<T>>) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<()>> + Send,
+{
+    let (sender, receiver) = mpsc::channel(256);
+
+    tokio::spawn(async move {
+        let mut attempts = 0u32;
+        let mut connected_before = false;
+
+        loop {
+            match Ws::connect_with_reconnects(ws_url.as_str(), 0).await {
+                Ok(ws) => {
+                    if connected_before && sender.send(StreamEvent::Reconnected).await.is_err() {
+                        return;
+                    }
+                    connected_before = true;
+                    attempts = 0;
+
+                    match forward(Provider::new(ws), sender.clone()).await {
+                        Ok(_) => warn!("Subscription to {} ended", ws_url),
+                        Err(e) => warn!("Subscription to {} failed: {:?}", ws_url, e),
+                    }
+                    if sender.is_closed() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("Failed to connect to {}: {:?}", ws_url, e),
+            }
+
+            attempts += 1;
+            if attempts > config.max_attempts {
+                warn!(
+                    "Giving up on {} after {} attempts",
+                    ws_url, config.max_attempts
+                );
+                return;
+            }
+            tokio::time::sleep(config.delay(attempts)).await;
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+/*
+Full bodies of pending txs seen by the node at ws_url.
+Hashes from the newPendingTransactions subscription are resolved with eth_getTransactionByHash
+(up to 256 at a time), txs that were already mined or dropped by then are skipped.
+*/
+pub fn stream_pending_txs(ws_url: String) -> impl Stream<Item = StreamEvent<Transaction>> {
+    stream_pending_txs_with(ws_url, ReconnectConfig::default())
+}
+
+pub fn stream_pending_txs_with(
+    ws_url: String,
+    config: ReconnectConfig,
+) -> impl Stream<Item = StreamEvent<Transaction>> {
+    reconnecting_stream(ws_url, config, forward_pending_txs)
+}
+
+async fn forward_pending_txs(
+    provider: Provider<Ws>,
+    sender: mpsc::Sender<StreamEvent<Transaction>>,
+) -> Result<()> {
+    let stream = provider.subscribe_pending_txs().await?;
+    let mut stream = stream.transactions_unordered(256).fuse();
+
+    while let Some(result) = stream.next().await {
+        match result {
+            Ok(tx) => {
+                if sender.send(StreamEvent::Item(tx)).await.is_err() {
+                    // receiver dropped
+                    return Ok(());
+                }
+            }
+            Err(GetTransactionError::NotFound(_)) => {}
+            Err(e) => warn!("Failed to fetch pending tx: {:?}", e),
+        }
+    }
+    Ok(())
+}
+
+/*
+Every newHeads header, as the node sends them.
+During a reorg the same block number can show up again (or numbers can go backwards),
+headers are passed through as is and it's up to the consumer to dedup by number or hash.
+Pair with multi::refresh_reserves_at_block to keep reserves current on every header.
+*/
+pub fn stream_new_blocks(ws_url: String) -> impl Stream<Item = StreamEvent<Block<H256>>> {
+    stream_new_blocks_with(ws_url, ReconnectConfig::default())
+}
+
+pub fn stream_new_blocks_with(
+    ws_url: String,
+    config: ReconnectConfig,
+) -> impl Stream<Item = StreamEvent<Block<H256>>> {
+    reconnecting_stream(ws_url, config, forward_new_blocks)
+}
+
+async fn forward_new_blocks(
+    provider: Provider<Ws>,
+    sender: mpsc::Sender<StreamEvent<Block<H256>>>,
+) -> Result<()> {
+    let mut stream = provider.subscribe_blocks().await?;
+
+    while let Some(block) = stream.next().await {
+        if sender.send(StreamEvent::Item(block)).await.is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+pub fn stream_logs(ws_url: String, filter: Filter) -> impl Stream<Item = StreamEvent<Log>> {
+    stream_logs_with(ws_url, filter, ReconnectConfig::default())
+}
+
+pub fn stream_logs_with(
+    ws_url: String,
+    filter: Filter,
+    config: ReconnectConfig,
+) -> impl Stream<Item = StreamEvent<Log>> {
+    reconnecting_stream(ws_url, config, move |provider, sender| {
+        forward_logs(provider, filter.clone(), sender)
+    })
+}
+
+async fn forward_logs(
+    provider: Provider<Ws>,
+    filter: Filter,
+    sender: mpsc::Sender<StreamEvent<Log>>,
+) -> Result<()> {
+    let mut stream = provider.subscribe_logs(&filter).await?;
+
+    while let Some(log) = stream.next().await {
+        if sender.send(StreamEvent::Item(log)).await.is_err() {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+// Log of a MEV-Share hint, only the fields the sender chose to reveal are set
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct HintLog {
+    pub address: H160,
+    #[serde(default)]
+    pub topics: Vec<H256>,
+    #[serde(default)]
+    pub data: Option<Bytes>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HintTx {
+    #[serde(default)]
+    pub to: Option<H160>,
+    #[serde(default)]
+    pub function_selector: Option<Bytes>,
+    #[serde(default)]
+    pub call_data: Option<Bytes>,
+}
+
+/*
+One MEV-Share event: a pending tx (or bundle) hash plus whatever hints its sender shared.
+Anything not shared is missing from the JSON, so every hint field is optional.
+*/
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ShareHint {
+    pub hash: H256,
+    #[serde(default)]
+    pub logs: Vec<HintLog>,
+    #[serde(default)]
+    pub txs: Vec<HintTx>,
+}
+
+impl ShareHint {
+    // Whether the hinted tx emitted a log from, or calls, address (e.g. a pool to backrun)
+    pub fn touches(&self, address: H160) -> bool {
+        self.logs.iter().any(|log| log.address == address)
+            || self.txs.iter().any(|tx| tx.to == Some(address))
+    }
+}
+
+/*
+MEV-Share hints from the server-sent events endpoint at sse_url
+(https://mev-share.flashbots.net on mainnet), to be matched against pools for backruns.
+The connection is reopened with backoff whenever it drops, as reconnecting_stream does for
+websockets. Hints sent while disconnected are lost, and events that don't parse are skipped.
+*/
+pub fn stream_mevshare(sse_url: String) -> impl Stream<Item = ShareHint> {
+    stream_mevshare_with(sse_url, ReconnectConfig::default())
+}
+
+pub fn stream_mevshare_with(
+    sse_url: String,
+    config: ReconnectConfig,
+) -> impl Stream<Item = ShareHint> {
+    let (sender, receiver) = mpsc::channel(256);
+
+    tokio::spawn(async move {
+        let client = reqwest::Client::new();
+        let mut attempts = 0u32;
+
+        loop {
+            let response = client
+                .get(&sse_url)
+                .header("Accept", "text/event-stream")
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            match response {
+                Ok(response) => {
+                    attempts = 0;
+                    match forward_share_hints(response, &sender).await {
+                        Ok(_) => warn!("Event stream {} ended", sse_url),
+                        Err(e) => warn!("Event stream {} failed: {:?}", sse_url, e),
+                    }
+                    if sender.is_closed() {
+                        return;
+                    }
+                }
+                Err(e) => warn!("Failed to connect to {}: {:?}", sse_url, e),
+            }
+
+            attempts += 1;
+            if attempts > config.max_attempts {
+                warn!(
+                    "Giving up on {} after {} attempts",
+                    sse_url, config.max_attempts
+                );
+                return;
+            }
+            tokio::time::sleep(config.delay(attempts)).await;
+        }
+    });
+
+    ReceiverStream::new(receiver)
+}
+
+async fn forward_share_hints(
+    mut response: reqwest::Response,
+    sender: &mpsc::Sender<ShareHint>,
+) -> Result<()> {
+    // bytes of the event being received, events end with a blank line
+    let mut buffer: Vec<u8> = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend(chunk.iter().filter(|&&b| b != b'\r'));
+        while let Some(end) = buffer.windows(2).position(|w| w == b"\n\n") {
+            let event: Vec<u8> = buffer.drain(..end + 2).collect();
+            if let Some(hint) = parse_share_event(&String::from_utf8_lossy(&event)) {
+                if sender.send(hint).await.is_err() {
+                    // receiver dropped
+                    return Ok(());
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// data: lines of one SSE event, comments (keep-alives) and other fields are ignored
+fn parse_share_event(event: &str) -> Option<ShareHint> {
+    let data: Vec<&str> = event
+        .lines()
+        .filter_map(|line| line.strip_prefix("data:"))
+        .map(|data| data.strip_prefix(' ').unwrap_or(data))
+        .collect();
+    if data.is_empty() {
+        return None;
+    }
+    match serde_json::from_str(&data.join("\n")) {
+        Ok(hint) => Some(hint),
+        Err(e) => {
+            warn!("Skipping MEV-Share event that doesn't parse: {:?}", e);
+            None
+        }
+    }
+}
+
+/*
+All requested subscriptions merged into a single stream of Events, each on its own connection
+(with reconnects, see reconnecting_stream). Events are yielded in the order they arrive.
+*/
+pub fn stream_all(ws_url: String, subscriptions: &[Subscription]) -> impl Stream<Item = Event> {
+    let streams: Vec<_> = subscriptions
+        .iter()
+        .map(|subscription| match subscription {
+            Subscription::NewBlocks => into_events(stream_new_blocks(ws_url.clone()), Event::Block),
+            Subscription::PendingTxs => {
+                into_events(stream_pending_txs(ws_url.clone()), Event::PendingTx)
+            }
+            Subscription::Logs(filter) => {
+                into_events(stream_logs(ws_url.clone(), filter.clone()), Event::Log)
+            }
+        })
+        .collect();
+    select_all(streams)
+}
+
+fn into_events<T, S>(stream: S, wrap: fn(T) -> Event) -> Pin<Box<dyn Stream<Item = Event> + Send>>
+where
+    S: Stream<Item = StreamEvent<T>> + Send + 'static,
+{
+    Box::pin(stream.map(move |event| match event {
+        StreamEvent::Item(item) => wrap(item),
+        StreamEvent::Reconnected => Event::Reconnected,
+    }))
+}
+
+#[cfg(test)]
+mod streams_tests {
+    use super::*;
+    use ethers::types::H160;
+    use futures::SinkExt;
+    use serde_json::{json, Value};
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::{accept_async, tungstenite::Message};
+
+    #[tokio::test]
+    async fn runner_stops_once_shutdown_is_cancelled() {
+        let (sender, mut receiver) = mpsc::channel(16);
+        let shutdown = CancellationToken::new();
+
+        let runner = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move {
+                let mut handled = 0;
+                while let Some(_event) = next_event(&mut receiver, &shutdown).await {
+                    handled += 1;
+                }
+                handled
+            }
+        });
+
+        sender.send(Event::Reconnected).await.unwrap();
+        sender.send(Event::Reconnected).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // The sender is still alive, only the token ends the loop
+        shutdown.cancel();
+
+        let handled = tokio::time::timeout(Duration::from_secs(1), runner)
+            .await
+            .expect("runner did not stop")
+            .unwrap();
+        assert_eq!(handled, 2);
+        drop(sender);
+    }
+
+    #[tokio::test]
+    async fn full_queue_sheds_pending_txs_but_keeps_blocks() {
+        let (queue, mut receiver) = EventQueue::new(4);
+        let block = |number: u64| {
+            Event::Block(Block {
+                number: Some(U64::from(number)),
+                ..Default::default()
+            })
         };
+
+        // Nobody is reading: the first txs fill the queue, the rest of the burst is shed
+        queue.push(block(1)).await.unwrap();
+        for _ in 0..100 {
+            queue
+                .push(Event::PendingTx(Transaction::default()))
+                .await
+                .unwrap();
+        }
+        assert_eq!(queue.dropped(), 97);
+
+        // A block arriving while full waits for the worker instead
+        let pushing = tokio::spawn({
+            let queue = queue.clone();
+            async move { queue.push(block(2)).await }
+        });
+        let mut blocks = vec![];
+        let mut txs = 0;
+        while blocks.len() < 2 {
+            match receiver.recv().await.unwrap() {
+                Event::Block(block) => blocks.push(block.number.unwrap().as_u64()),
+                Event::PendingTx(_) => txs += 1,
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        pushing.await.unwrap().unwrap();
+
+        assert_eq!(blocks, vec![1, 2]);
+        assert_eq!(txs, 3);
+        assert_eq!(queue.dropped(), 97);
+
+        // Producers notice once the worker is gone
+        drop(receiver);
+        assert!(queue.push(block(3)).await.is_err());
+    }
+
+    /*
+    Websocket JSON-RPC node. Connection i is served sessions[i] (the last session for any extra
+    connections), a list of (subscription kind, notification) pairs.
+    eth_subscribe is answered with subscription "0x1" followed by that session's notifications
+    of the requested kind, every other request is answered with respond(method, params).
+    Connections served by any session but the last are closed once their notifications went out.
+    */
+    async fn mock_ws_node<F>(sessions: Vec<Vec<(&'static str, Value)>>, respond: F) -> String
+    where
+        F: Fn(&str, &Value) -> Value + Send + Sync + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("ws://{}", listener.local_addr().unwrap());
+        let respond = Arc::new(respond);
+
+        tokio::spawn(async move {
+            for i in 0.. {
+                let (socket, _) = listener.accept().await.unwrap();
+                let session = sessions[i.min(sessions.len() - 1)].clone();
+                let close_after_notifications = i + 1 < sessions.len();
+                let respond = respond.clone();
+
+                tokio::spawn(async move {
+                    let mut ws = accept_async(socket).await.unwrap();
+
+                    while let Some(Ok(message)) = ws.next().await {
+                        let request: Value = match message {
+                            Message::Text(text) => serde_json::from_str(&text).unwrap(),
+                            _ => continue,
+                        };
+                        let method = request["method"].as_str().unwrap().to_string();
+                        let result = if method == "eth_subscribe" {
+                            json!("0x1")
+                        } else {
+                            respond(&method, &request["params"])
+                        };
+                        let response =
+                            json!({"jsonrpc": "2.0", "id": request["id"], "result": result});
+                        ws.send(Message::Text(response.to_string())).await.unwrap();
+
+                        if method == "eth_subscribe" {
+                            let kind = request["params"][0].as_str().unwrap();
+                            tokio::time::sleep(Duration::from_millis(50)).await;
+                            for (_, notification) in session.iter().filter(|(k, _)| *k == kind) {
+                                let message = json!({
+                                    "jsonrpc": "2.0",
+                                    "method": "eth_subscription",
+                                    "params": {"subscription": "0x1", "result": notification},
+                                });
+                                ws.send(Message::Text(message.to_string())).await.unwrap();
+                            }
+                            if close_after_notifications {
+                                tokio::time::sleep(Duration::from_millis(100)).await;
+                                let _ = ws.close(None).await;
+                                break;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+
+        url
+    }
+
+    fn header(number: u64, hash: u64) -> (&'static str, Value) {
+        let block = Block::<H256> {
+            number: Some(U64::from(number)),
+            hash: Some(H256::from_low_u64_be(hash)),
+            ..Default::default()
+        };
+        ("newHeads", serde_json::to_value(&block).unwrap())
+    }
+
+    async fn next_item<S: Stream + Unpin>(stream: &mut S) -> S::Item {
+        tokio::time::timeout(Duration::from_secs(5), stream.next())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn pending_txs_are_fetched_and_missing_ones_skipped() {
+        let dropped = H256::from_low_u64_be(1);
+        let pending = H256::from_low_u64_be(2);
+        let pending_tx = Transaction {
+            hash: pending,
+            ..Default::default()
+        };
+        let pending_tx_json = serde_json::to_value(&pending_tx).unwrap();
+
+        let url = mock_ws_node(
+            vec![vec![
+                ("newPendingTransactions", json!(dropped)),
+                ("newPendingTransactions", json!(pending)),
+            ]],
+            move |method, params| {
+                assert_eq!(method, "eth_getTransactionByHash");
+                if params[0] == json!(pending) {
+                    pending_tx_json.clone()
+                } else {
+                    Value::Null
+                }
+            },
+        )
+        .await;
+
+        let mut stream = Box::pin(stream_pending_txs(url));
+        match next_item(&mut stream).await {
+            StreamEvent::Item(tx) => assert_eq!(tx.hash, pending),
+            event => panic!("unexpected {:?}", event),
+        }
+
+        // The dropped tx never shows up
+        let next = tokio::time::timeout(Duration::from_millis(200), stream.next()).await;
+        assert!(next.is_err());
+    }
+
+    #[tokio::test]
+    async fn stream_resumes_after_disconnect() {
+        let url = mock_ws_node(vec![vec![header(1, 1)], vec![header(2, 2)]], |_, _| {
+            Value::Null
+        })
+        .await;
+        let config = ReconnectConfig {
+            base_delay_ms: 10,
+            max_delay_ms: 10,
+            max_attempts: 3,
+        };
+
+        let stream = reconnecting_stream(url, config, |provider, sender| async move {
+            let mut blocks = provider.subscribe_blocks().await?;
+            while let Some(block) = blocks.next().await {
+                let number = block.number.unwrap_or_default().as_u64();
+                sender.send(StreamEvent::Item(number)).await?;
+            }
+            anyhow::Ok(())
+        });
+        let mut stream = Box::pin(stream);
+
+        assert_eq!(next_item(&mut stream).await, StreamEvent::Item(1));
+        assert_eq!(next_item(&mut stream).await, StreamEvent::Reconnected);
+        assert_eq!(next_item(&mut stream).await, StreamEvent::Item(2));
+    }
+
+    #[tokio::test]
+    async fn headers_are_passed_through_including_reorged_numbers() {
+        // Block 2 is replaced by a sibling with the same number
+        let headers = vec![header(1, 1), header(2, 2), header(2, 22)];
+        let url = mock_ws_node(vec![headers], |_, _| Value::Null).await;
+
+        let mut stream = Box::pin(stream_new_blocks(url));
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            match next_item(&mut stream).await {
+                StreamEvent::Item(block) => received.push((
+                    block.number.unwrap().as_u64(),
+                    block.hash.unwrap().to_low_u64_be(),
+                )),
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert_eq!(received, vec![(1, 1), (2, 2), (2, 22)]);
+    }
+
+    #[tokio::test]
+    async fn stream_all_merges_blocks_and_logs() {
+        let log = Log {
+            address: H160::from_low_u64_be(1),
+            block_number: Some(U64::from(7)),
+            ..Default::default()
+        };
+        let session = vec![header(7, 7), ("logs", serde_json::to_value(&log).unwrap())];
+        let url = mock_ws_node(vec![session], |_, _| Value::Null).await;
+
+        let filter = Filter::new().event("Sync(uint112,uint112)");
+        let subscriptions = [Subscription::NewBlocks, Subscription::Logs(filter)];
+        let mut stream = Box::pin(stream_all(url, &subscriptions));
+
+        let (mut blocks, mut logs) = (0, 0);
+        for _ in 0..2 {
+            match next_item(&mut stream).await {
+                Event::Block(block) => {
+                    assert_eq!(block.number, Some(U64::from(7)));
+                    blocks += 1;
+                }
+                Event::Log(received) => {
+                    assert_eq!(received.address, log.address);
+                    logs += 1;
+                }
+                event => panic!("unexpected {:?}", event),
+            }
+        }
+        assert_eq!((blocks, logs), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn mevshare_hints_are_read_from_the_event_stream() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let pool = H160::from_low_u64_be(0xabc);
+        let hint = json!({
+            "hash": format!("{:?}", H256::from_low_u64_be(7)),
+            "logs": [{
+                "address": format!("{:?}", pool),
+                "topics": [format!("{:?}", H256::from_low_u64_be(1))],
+                "data": "0x"
+            }],
+            "txs": [{
+                "to": format!("{:?}", H160::from_low_u64_be(0xdef)),
+                "functionSelector": "0x022c0d9f"
+            }],
+            "mevGasPrice": "0x1"
+        });
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            let (mut socket, _) = listener.accept().await.unwrap();
+            let mut request = [0u8; 1024];
+            let _ = socket.read(&mut request).await.unwrap();
+            let body = format!(": keep-alive\r\n\r\ndata: {}\r\n\r\n", hint);
+            socket
+                .write_all(
+                    format!(
+                        "HTTP/1.1 200 OK\r\ncontent-type: text/event-stream\r\n\r\n{}",
+                        body
+                    )
+                    .as_bytes(),
+                )
+                .await
+                .unwrap();
+            // Keep the connection open, the stream only ends when the server goes away
+            tokio::time::sleep(Duration::from_secs(10)).await;
+        });
+
+        let mut stream = Box::pin(stream_mevshare(url));
+        let received = next_item(&mut stream).await;
+
+        assert_eq!(received.hash, H256::from_low_u64_be(7));
+        assert_eq!(received.logs[0].address, pool);
+        assert_eq!(received.logs[0].data, Some(Bytes::default()));
+        assert_eq!(received.txs[0].to, Some(H160::from_low_u64_be(0xdef)));
+        assert_eq!(
+            received.txs[0].function_selector,
+            Some(Bytes::from(vec![0x02, 0x2c, 0x0d, 0x9f]))
+        );
+        assert!(received.touches(pool));
+        assert!(!received.touches(H160::from_low_u64_be(1)));
     }
 }
@@ -0,0 +1,255 @@
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use ethers::{
+    providers::Middleware,
+    signers::Signer,
+    types::{transaction::eip2718::TypedTransaction, Bytes, TxHash, H160, H256, U256, U64},
+    utils::keccak256,
+};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::info;
+
+use crate::bundler::Bundler;
+use crate::strategy::ARB_GAS_LIMIT;
+use crate::utils::with_timeout;
+
+// What a Submitter did with the txs it was handed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SubmitReceipt {
+    // hash of every submitted tx, in submission order, empty if nothing was sent
+    pub tx_hashes: Vec<TxHash>,
+    // set by relays that take bundles
+    pub bundle_hash: Option<H256>,
+    pub target_block: U64,
+}
+
+/*
+The bundle reverted in the relay's simulation, so it was never sent. Submitters surface it
+as an anyhow error, downcast_ref::<SimulationReverted>() to tell a broken arb apart from one
+that merely didn't get through (a relay error, a lost auction, a timeout).
+*/
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("Simulation revert: {0}")]
+pub struct SimulationReverted(pub String);
+
+/*
+Where built arb txs go. txs are unsigned EIP-1559 txs (see strategy::build_arb_txs), the
+submitter signs them, sends them for target_block and resolves once they landed or failed to.
+*/
+#[async_trait]
+pub trait Submitter: Send + Sync {
+    async fn submit(&self, txs: Vec<TypedTransaction>, target_block: U64) -> Result<SubmitReceipt>;
+
+    // Account the txs are sent from, None if nothing is ever signed
+    fn sender(&self) -> Option<H160>;
+}
+
+// Which Submitter the strategy sends through when it isn't a dry run
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SubmitterKind {
+    #[default]
+    Flashbots,
+    PublicMempool,
+}
+
+impl SubmitterKind {
+    pub fn build(self, bundler: Bundler) -> Box<dyn Submitter> {
+        match self {
+            Self::Flashbots => Box::new(FlashbotsSubmitter::new(bundler)),
+            Self::PublicMempool => Box::new(PublicMempoolSubmitter::new(bundler)),
+        }
+    }
+}
+
+// Signs every tx from the bundler's sender, filling in from, chain id and the gas limit if unset
+async fn sign_all(bundler: &Bundler, txs: Vec<TypedTransaction>) -> Result<Vec<Bytes>> {
+    let (from, _, chain_id) = with_timeout(
+        "Nonce lookup",
+        bundler.rpc_timeout,
+        bundler._common_fields(),
+    )
+    .await?;
+    let mut signed = Vec::with_capacity(txs.len());
+    for tx in txs {
+        let tx = match tx {
+            TypedTransaction::Eip1559(tx) => tx,
+            _ => return Err(anyhow!("Expected EIP-1559 txs")),
+        };
+        let gas = tx.gas.unwrap_or_else(|| U256::from(ARB_GAS_LIMIT));
+        let tx = tx.from(from).chain_id(chain_id.as_u64()).gas(gas);
+        signed.push(bundler.sign_tx(tx).await?);
+    }
+    Ok(signed)
+}
+
+/*
+Sends the txs as one Flashbots bundle: simulated first, then sent to the relay, resolving
+once it's included (see Bundler::send_bundle).
+*/
+pub struct FlashbotsSubmitter {
+    bundler: Bundler,
+}
+
+impl FlashbotsSubmitter {
+    pub fn new(bundler: Bundler) -> Self {
+        Self { bundler }
+    }
+}
+
+#[async_trait]
+impl Submitter for FlashbotsSubmitter {
+    async fn submit(&self, txs: Vec<TypedTransaction>, target_block: U64) -> Result<SubmitReceipt> {
+        let signed = sign_all(&self.bundler, txs).await?;
+        let tx_hashes = signed.iter().map(|tx| H256::from(keccak256(tx))).collect();
+        // to_bundle targets the block after the one it's given
+        let bundle = self.bundler.to_bundle(signed, target_block - 1);
+        let bundle_hash = self.bundler.send_bundle(bundle).await?;
+        Ok(SubmitReceipt {
+            tx_hashes,
+            bundle_hash: Some(bundle_hash),
+            target_block,
+        })
+    }
+
+    fn sender(&self) -> Option<H160> {
+        Some(self.bundler.sender.address())
+    }
+}
+
+/*
+Broadcasts every tx with eth_sendRawTransaction, in order, then waits for the last one to be
+mined, giving up once the chain is MEMPOOL_GRACE_BLOCKS past target_block. Nothing is private:
+the txs can be seen, copied and sandwiched while pending, and a tx given up on may still land
+later since the mempool can't be asked for a block.
+*/
+pub struct PublicMempoolSubmitter {
+    bundler: Bundler,
+}
+
+impl PublicMempoolSubmitter {
+    pub fn new(bundler: Bundler) -> Self {
+        Self { bundler }
+    }
+}
+
+#[async_trait]
+impl Submitter for PublicMempoolSubmitter {
+    async fn submit(&self, txs: Vec<TypedTransaction>, target_block: U64) -> Result<SubmitReceipt> {
+        let signed = sign_all(&self.bundler, txs).await?;
+        let mut tx_hashes = Vec::with_capacity(signed.len());
+        let mut last = None;
+        for tx in signed {
+            let pending = with_timeout("eth_sendRawTransaction", self.bundler.rpc_timeout, async {
+                self.bundler
+                    .provider
+                    .send_raw_transaction(tx)
+                    .await
+                    .map_err(|e| anyhow!("eth_sendRawTransaction failed: {}", e))
+            })
+            .await?;
+            tx_hashes.push(*pending);
+            last = Some(pending);
+        }
+        if let Some(pending) = last {
+            let deadline = target_block + MEMPOOL_GRACE_BLOCKS;
+            tokio::select! {
+                mined = pending => {
+                    mined?.ok_or_else(|| anyhow!("Tx dropped from the mempool"))?;
+                }
+                passed = wait_past_block(&self.bundler.provider, deadline, self.bundler.rpc_timeout) => {
+                    passed?;
+                    return Err(anyhow!("Tx not mined by block {}", deadline));
+                }
+            }
+        }
+        Ok(SubmitReceipt {
+            tx_hashes,
+            bundle_hash: None,
+            target_block,
+        })
+    }
+
+    fn sender(&self) -> Option<H160> {
+        Some(self.bundler.sender.address())
+    }
+}
+
+// Blocks after target_block PublicMempoolSubmitter still waits for its txs to be mined
+pub const MEMPOOL_GRACE_BLOCKS: u64 = 2;
+
+// How often wait_past_block asks for the block number
+const BLOCK_POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+// Resolves once the chain's head is past block, each lookup bounded by rpc_timeout
+async fn wait_past_block<M: Middleware>(
+    provider: &M,
+    block: U64,
+    rpc_timeout: Duration,
+) -> Result<()> {
+    loop {
+        let head = with_timeout("Block number lookup", rpc_timeout, async {
+            provider
+                .get_block_number()
+                .await
+                .map_err(|e| anyhow!("get_block_number failed: {}", e))
+        })
+        .await?;
+        if head > block {
+            return Ok(());
+        }
+        tokio::time::sleep(BLOCK_POLL_INTERVAL).await;
+    }
+}
+
+// Dry-run backend: never signs or sends anything, only logs what it was given
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NullSubmitter;
+
+#[async_trait]
+impl Submitter for NullSubmitter {
+    async fn submit(&self, txs: Vec<TypedTransaction>, target_block: U64) -> Result<SubmitReceipt> {
+        info!(
+            txs = txs.len(),
+            target_block = target_block.as_u64(),
+            "null submitter: nothing sent"
+        );
+        Ok(SubmitReceipt {
+            target_block,
+            ..Default::default()
+        })
+    }
+
+    fn sender(&self) -> Option<H160> {
+        None
+    }
+}
+
+#[cfg(test)]
+mod submitter_tests {
+    use super::*;
+    use ethers::providers::Provider;
+
+    #[tokio::test(start_paused = true)]
+    async fn waiting_for_a_block_polls_until_the_head_is_past_it() {
+        let (provider, mock) = Provider::mocked();
+        // LIFO: 100, then 101, then 102
+        for head in [102u64, 101, 100] {
+            mock.push(U64::from(head)).unwrap();
+        }
+        wait_past_block(&provider, U64::from(101), Duration::from_secs(1))
+            .await
+            .unwrap();
+        for _ in 0..3 {
+            mock.assert_request("eth_blockNumber", ()).unwrap();
+        }
+
+        // A node that stops answering fails the wait instead of hanging it
+        assert!(
+            wait_past_block(&provider, U64::from(101), Duration::from_secs(1))
+                .await
+                .is_err()
+        );
+    }
+}
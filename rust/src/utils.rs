@@ -1,17 +1,41 @@
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use ethers::{
     self,
     abi::{decode, ParamType, Token},
-    providers::{Middleware, Provider, Ws},
+    providers::{Http, Middleware, MiddlewareError, Provider, ProviderError, Ws},
     types::{Filter, H160, U256, U64},
 };
 use fern::colors::{Color, ColoredLevelConfig};
 use log::LevelFilter;
 use rand::Rng;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
+use tracing_subscriber::EnvFilter;
 
 use crate::multi::Reserve;
 
+/*
+HTTP and websocket connections to the same node.
+Only subscriptions need the websocket: the block, pending tx and log streams in streams.rs.
+Everything else is request/response (reserve multicalls, pool syncing, eth_getLogs, calls)
+and is generic over Middleware, so it can go over either. Prefer http for those.
+*/
+#[derive(Clone)]
+pub struct ProviderSet {
+    pub http: Arc<Provider<Http>>,
+    pub ws: Arc<Provider<Ws>>,
+}
+
+impl ProviderSet {
+    pub async fn connect(https_url: &str, wss_url: &str) -> Result<Self> {
+        let http = Provider::<Http>::try_from(https_url)?;
+        let ws = Provider::new(Ws::connect(wss_url).await?);
+        Ok(Self {
+            http: Arc::new(http),
+            ws: Arc::new(ws),
+        })
+    }
+}
+
 pub fn setup_logger() -> Result<()> {
     let colors = ColoredLevelConfig {
         trace: Color::Cyan,
@@ -39,6 +63,39 @@ pub fn setup_logger() -> Result<()> {
     Ok(())
 }
 
+/*
+tracing counterpart of setup_logger: this crate logs at `level`, dependencies only errors.
+log records (from modules still using the log macros) are forwarded to the same subscriber,
+so it replaces setup_logger rather than running next to it. RUST_LOG overrides the filter.
+*/
+pub fn init_tracing(level: tracing::Level) -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_filter(level))
+        .try_init()
+        .map_err(|e| anyhow!("Failed to install tracing subscriber: {}", e))
+}
+
+// Same as init_tracing but logs to stderr, leaving stdout to JSON output
+pub fn init_tracing_stderr(level: tracing::Level) -> Result<()> {
+    tracing_subscriber::fmt()
+        .with_env_filter(tracing_filter(level))
+        .with_writer(std::io::stderr)
+        .try_init()
+        .map_err(|e| anyhow!("Failed to install tracing subscriber: {}", e))
+}
+
+fn tracing_filter(level: tracing::Level) -> EnvFilter {
+    EnvFilter::try_from_default_env()
+        .unwrap_or_else(|_| EnvFilter::new(format!("error,rust={}", level)))
+}
+
+// Lossy conversion that, unlike as_u128(), never panics on values above 2^128
+pub fn u256_to_f64(value: U256) -> f64 {
+    value.0.iter().rev().fold(0.0, |acc, limb| {
+        acc * 18446744073709551616.0 + (*limb as f64)
+    })
+}
+
 pub fn calculate_next_block_base_fee(
     gas_used: U256,
     gas_limit: U256,
@@ -69,8 +126,85 @@ pub fn calculate_next_block_base_fee(
     new_base_fee + seed
 }
 
-pub async fn get_touched_pool_reserves(
-    provider: Arc<Provider<Ws>>,
+/*
+An RPC call (or a batch of them) that didn't answer within its deadline.
+Surfaces as an anyhow error, downcast_ref::<RpcTimeout>() to tell it apart from failures.
+*/
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{operation} timed out after {deadline:?}")]
+pub struct RpcTimeout {
+    pub operation: String,
+    pub deadline: Duration,
+}
+
+/*
+An RPC call that failed, classified while the middleware's typed error is still at hand
+(it's generic over the middleware, so it can't be downcast once it's inside an anyhow error).
+transient is set for transport failures, HTTP 429 / 5xx and the JSON-RPC rate limit codes,
+reverts and responses that don't decode are final.
+*/
+#[derive(Debug, Clone, thiserror::Error)]
+#[error("{operation} failed: {message}")]
+pub struct RpcCallError {
+    pub operation: String,
+    pub message: String,
+    pub transient: bool,
+}
+
+impl RpcCallError {
+    pub fn new<E: MiddlewareError>(operation: &str, e: &E) -> Self {
+        let transient = match e.as_error_response() {
+            Some(response) => TRANSIENT_RPC_CODES.contains(&response.code),
+            None => e
+                .as_provider_error()
+                .is_some_and(is_transient_provider_error),
+        };
+        Self {
+            operation: operation.to_string(),
+            message: e.to_string(),
+            transient,
+        }
+    }
+}
+
+// JSON-RPC error codes that mean "try again later": HTTP-style 429 and EIP-1474 limit exceeded
+pub const TRANSIENT_RPC_CODES: [i64; 2] = [429, -32005];
+
+fn is_transient_provider_error(e: &ProviderError) -> bool {
+    match e {
+        ProviderError::HTTPError(e) => {
+            e.is_timeout()
+                || e.is_connect()
+                || e.is_request()
+                || e.status()
+                    .is_some_and(|status| status.as_u16() == 429 || status.is_server_error())
+        }
+        // No response at all (dropped connection, closed websocket) unless it was a body
+        // that didn't decode
+        ProviderError::JsonRpcClientError(e) => {
+            e.as_error_response().is_none() && e.as_serde_error().is_none()
+        }
+        _ => false,
+    }
+}
+
+// Fails fut with RpcTimeout once deadline passes, operation names the call in the error
+pub async fn with_timeout<T, Fut>(operation: &str, deadline: Duration, fut: Fut) -> Result<T>
+where
+    Fut: Future<Output = Result<T>>,
+{
+    match tokio::time::timeout(deadline, fut).await {
+        Ok(result) => result,
+        Err(_) => Err(RpcTimeout {
+            operation: operation.to_string(),
+            deadline,
+        }
+        .into()),
+    }
+}
+
+pub async fn get_touched_pool_reserves<M: Middleware + 'static>(
+    provider: Arc<M>,
     block_number: U64,
 ) -> Result<HashMap<H160, Reserve>> {
     let sync_event = "Sync(uint112,uint112)";
@@ -101,7 +235,12 @@ pub async fn get_touched_pool_reserves(
                         Token::Uint(rs) => rs,
                         _ => U256::zero(),
                     };
-                    let reserve = Reserve { reserve0, reserve1 };
+                    let reserve = Reserve {
+                        reserve0,
+                        reserve1,
+                        block_number: block_number.as_u64(),
+                        ..Default::default()
+                    };
 
                     reserves.insert(log.address, reserve);
                     tx_idx.insert(log.address, idx);